@@ -0,0 +1,243 @@
+use std::sync::Arc;
+
+use axum::extract::{Extension, FromRequestParts};
+use axum::http::{header, request::Parts, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde_json::json;
+use sqlx::{types::Uuid, PgPool};
+
+use voda_common::EnvVars;
+use voda_database::{QueryCriteria, SqlxFilterQuery};
+use voda_runtime::user::{
+    AUTH_SCOPE_ALL, SCOPE_ADMIN, SCOPE_CHARACTERS_READ, SCOPE_CHARACTERS_WRITE, SCOPE_GRAPHQL_READ,
+    SCOPE_LOREBOOKS_WRITE, SCOPE_ROLEPLAY_CHAT, SCOPE_USER_WRITE,
+};
+use voda_runtime::{AuthClaims, AuthTokenError, AuthTokenKind, RevokedToken, User, UserApiKey, UserRole};
+
+use crate::env::ApiServerEnv;
+use crate::response::GenericResponse;
+use crate::utils::extract_bearer_token;
+
+/// Why a request was not authenticated, sent back as `data.reason`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    MissingToken,
+    InvalidToken,
+    ExpiredToken,
+    RevokedToken,
+    Forbidden,
+    Internal,
+}
+
+impl AuthError {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            AuthError::MissingToken => "missing_token",
+            AuthError::InvalidToken => "invalid_token",
+            AuthError::ExpiredToken => "expired_token",
+            AuthError::RevokedToken => "revoked_token",
+            AuthError::Forbidden => "forbidden",
+            AuthError::Internal => "internal_error",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            AuthError::Forbidden => StatusCode::FORBIDDEN,
+            AuthError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        tracing::debug!("[AuthError] Rejected request: {}", self.reason());
+        (
+            self.status(),
+            GenericResponse::new(self.status(), "Authentication failed", json!({ "reason": self.reason() })),
+        ).into_response()
+    }
+}
+
+/// The user behind a valid bearer token or API key. Rejects the request with 401 otherwise, and
+/// with 403 when the credential lacks the scope of the route, see `required_scope`.
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    /// `User::user_id`
+    pub user_id: String,
+    /// `None` for legacy tokens, which carry no claims
+    pub claims: Option<AuthClaims>,
+    /// the `UserApiKey` used instead of a token
+    pub api_key: Option<Uuid>,
+}
+
+/// `AuthUser` for routes open to anonymous users: `None` without a bearer token, while a bad
+/// token is still rejected.
+#[derive(Debug, Clone)]
+pub struct OptionalAuthUser(pub Option<AuthUser>);
+
+/// Rejects non-admins with 403, meant for `middleware::from_extractor::<RequireAdmin>()`
+#[derive(Debug, Clone)]
+pub struct RequireAdmin(pub AuthUser);
+
+impl<S: Send + Sync> FromRequestParts<S> for AuthUser {
+    type Rejection = AuthError;
+
+    /// Resolved once per request, by `rate_limit` for most routes, and cached in the extensions
+    /// along with a rejection, so later layers and handlers don't verify the credential again
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(auth_user) = parts.extensions.get::<AuthUser>() {
+            return Ok(auth_user.clone());
+        }
+        if let Some(e) = parts.extensions.get::<AuthError>() {
+            return Err(*e);
+        }
+
+        match resolve_auth_user(parts, state).await {
+            Ok(auth_user) => {
+                parts.extensions.insert(auth_user.clone());
+                Ok(auth_user)
+            }
+            Err(e) => {
+                parts.extensions.insert(e);
+                Err(e)
+            }
+        }
+    }
+}
+
+async fn resolve_auth_user<S: Send + Sync>(parts: &mut Parts, state: &S) -> Result<AuthUser, AuthError> {
+    if !parts.headers.contains_key(header::AUTHORIZATION) {
+        return Err(AuthError::MissingToken);
+    }
+    let token = extract_bearer_token(&parts.headers).map_err(|_| AuthError::InvalidToken)?;
+
+    let Extension(db) = Extension::<Arc<PgPool>>::from_request_parts(parts, state).await
+        .map_err(|_| AuthError::Internal)?;
+    let auth_user = verify_bearer_token(&token, &ApiServerEnv::load(), &db).await?;
+
+    if let Some(claims) = &auth_user.claims {
+        if !claims.has_scope(required_scope(parts.uri.path())) {
+            return Err(AuthError::Forbidden);
+        }
+    }
+    Ok(auth_user)
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for OptionalAuthUser {
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match AuthUser::from_request_parts(parts, state).await {
+            Ok(auth_user) => Ok(Self(Some(auth_user))),
+            Err(AuthError::MissingToken) => Ok(Self(None)),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for RequireAdmin {
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        // checked by the route layer already, handlers taking `RequireAdmin` reuse it
+        if let Some(admin) = parts.extensions.get::<RequireAdmin>() {
+            return Ok(admin.clone());
+        }
+        let auth_user = AuthUser::from_request_parts(parts, state).await?;
+
+        let role = match &auth_user.claims {
+            Some(claims) => claims.role.clone(),
+            // legacy tokens carry no role, so look it up
+            None => {
+                let Extension(db) = Extension::<Arc<PgPool>>::from_request_parts(parts, state).await
+                    .map_err(|_| AuthError::Internal)?;
+                let criteria = QueryCriteria::new().add_valued_filter("user_id", "=", auth_user.user_id.clone())
+                    .map_err(|_| AuthError::Internal)?;
+                User::find_one_by_criteria(criteria, &*db).await
+                    .map_err(|_| AuthError::Internal)?
+                    .ok_or(AuthError::Forbidden)?
+                    .role
+            }
+        };
+
+        if role != UserRole::Admin {
+            return Err(AuthError::Forbidden);
+        }
+        let admin = Self(auth_user);
+        parts.extensions.insert(admin.clone());
+        Ok(admin)
+    }
+}
+
+/// The scope a credential needs for `path`. Tokens issued at login hold every scope, so this
+/// mostly narrows down API keys.
+fn required_scope(path: &str) -> &'static str {
+    const ROUTE_SCOPES: &[(&str, &str)] = &[
+        ("/runtime/roleplay/", SCOPE_ROLEPLAY_CHAT),
+        ("/tts/", SCOPE_ROLEPLAY_CHAT),
+        ("/runtime/character-creation/", SCOPE_CHARACTERS_WRITE),
+        ("/character/import", SCOPE_CHARACTERS_WRITE),
+        ("/character/update/", SCOPE_CHARACTERS_WRITE),
+        ("/character/", SCOPE_CHARACTERS_READ),
+        ("/lorebook/", SCOPE_LOREBOOKS_WRITE),
+        ("/user/", SCOPE_USER_WRITE),
+        ("/graphql", SCOPE_GRAPHQL_READ),
+        ("/admin/", SCOPE_ADMIN),
+    ];
+
+    ROUTE_SCOPES.iter()
+        .find(|(prefix, _)| path.starts_with(prefix))
+        .map(|(_, scope)| *scope)
+        .unwrap_or(AUTH_SCOPE_ALL)
+}
+
+fn auth_error_from(e: &anyhow::Error) -> AuthError {
+    match e.downcast_ref::<AuthTokenError>() {
+        Some(AuthTokenError::Expired) => AuthError::ExpiredToken,
+        Some(AuthTokenError::Revoked) => AuthError::RevokedToken,
+        Some(_) => AuthError::InvalidToken,
+        None => {
+            tracing::error!("[verify_bearer_token] Failed to verify credential: {}", e);
+            AuthError::Internal
+        }
+    }
+}
+
+async fn verify_bearer_token(token: &str, env: &ApiServerEnv, db: &PgPool) -> Result<AuthUser, AuthError> {
+    if UserApiKey::is_api_key(token) {
+        let (api_key, owner) = UserApiKey::authenticate(token, db).await
+            .map_err(|e| auth_error_from(&e))?;
+        return Ok(AuthUser {
+            user_id: owner.user_id.clone(),
+            claims: Some(api_key.claims(&owner)),
+            api_key: Some(api_key.id),
+        });
+    }
+
+    let claims = match AuthClaims::verify(token, &env.auth_token_config(), AuthTokenKind::Access) {
+        Ok(claims) => claims,
+        Err(e) if e.downcast_ref::<AuthTokenError>() == Some(&AuthTokenError::Expired) => {
+            return Err(AuthError::ExpiredToken);
+        }
+        Err(_) => {
+            if !env.legacy_auth_token_enabled() {
+                return Err(AuthError::InvalidToken);
+            }
+            let user_id = User::verify_auth_token(token, &env.get_env_var("SECRET_SALT"))
+                .map_err(|_| AuthError::InvalidToken)?;
+            return Ok(AuthUser { user_id, claims: None, api_key: None });
+        }
+    };
+
+    let revoked = RevokedToken::is_revoked(&claims.jti, db).await
+        .map_err(|e| {
+            tracing::error!("[verify_bearer_token] Failed to check the revocation list: {}", e);
+            AuthError::Internal
+        })?;
+    if revoked {
+        return Err(AuthError::RevokedToken);
+    }
+    Ok(AuthUser { user_id: claims.uid.clone(), claims: Some(claims), api_key: None })
+}
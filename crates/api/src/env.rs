@@ -1,7 +1,21 @@
 use voda_common::EnvVars;
+use voda_runtime::{
+    user::{DEFAULT_ACCESS_TOKEN_TTL, DEFAULT_REFRESH_TOKEN_TTL, DEFAULT_TELEGRAM_INIT_DATA_MAX_AGE},
+    AuthTokenConfig, IdentityConfig,
+};
 
 pub struct ApiServerEnv {
     pub secret_salt: String,
+    pub auth_token_secret: String,
+    pub access_token_ttl: String,
+    pub refresh_token_ttl: String,
+    pub legacy_auth_token: String,
+    pub telegram_bot_token: String,
+    pub telegram_init_data_max_age: String,
+    pub siwe_domain: String,
+    pub siwe_chain_ids: String,
+    pub login_link_base_url: String,
+    pub rate_limit_backend: String,
     pub fish_audio_api_key: String,
     pub hasura_graphql_url: String,
     pub hasura_graphql_admin_secret: String,
@@ -11,6 +25,16 @@ impl EnvVars for ApiServerEnv {
     fn load() -> Self {
         Self {
             secret_salt: std::env::var("SECRET_SALT").unwrap(),
+            auth_token_secret: std::env::var("AUTH_TOKEN_SECRET").unwrap(),
+            access_token_ttl: std::env::var("ACCESS_TOKEN_TTL").unwrap_or_else(|_| DEFAULT_ACCESS_TOKEN_TTL.to_string()),
+            refresh_token_ttl: std::env::var("REFRESH_TOKEN_TTL").unwrap_or_else(|_| DEFAULT_REFRESH_TOKEN_TTL.to_string()),
+            legacy_auth_token: std::env::var("LEGACY_AUTH_TOKEN").unwrap_or_else(|_| "false".to_string()),
+            telegram_bot_token: std::env::var("TELEGRAM_BOT_TOKEN").unwrap_or_default(),
+            telegram_init_data_max_age: std::env::var("TELEGRAM_INIT_DATA_MAX_AGE").unwrap_or_else(|_| DEFAULT_TELEGRAM_INIT_DATA_MAX_AGE.to_string()),
+            siwe_domain: std::env::var("SIWE_DOMAIN").unwrap(),
+            siwe_chain_ids: std::env::var("SIWE_CHAIN_IDS").unwrap_or_else(|_| "1".to_string()),
+            login_link_base_url: std::env::var("LOGIN_LINK_BASE_URL").unwrap(),
+            rate_limit_backend: std::env::var("RATE_LIMIT_BACKEND").unwrap_or_else(|_| "memory".to_string()),
             fish_audio_api_key: std::env::var("FISH_AUDIO_API_KEY").unwrap(),
             hasura_graphql_url: std::env::var("HASURA_GRAPHQL_URL").unwrap(),
             hasura_graphql_admin_secret: std::env::var("HASURA_GRAPHQL_ADMIN_SECRET").unwrap(),
@@ -20,6 +44,16 @@ impl EnvVars for ApiServerEnv {
     fn get_env_var(&self, key: &str) -> String {
         match key {
             "SECRET_SALT" => self.secret_salt.clone(),
+            "AUTH_TOKEN_SECRET" => self.auth_token_secret.clone(),
+            "ACCESS_TOKEN_TTL" => self.access_token_ttl.clone(),
+            "REFRESH_TOKEN_TTL" => self.refresh_token_ttl.clone(),
+            "LEGACY_AUTH_TOKEN" => self.legacy_auth_token.clone(),
+            "TELEGRAM_BOT_TOKEN" => self.telegram_bot_token.clone(),
+            "TELEGRAM_INIT_DATA_MAX_AGE" => self.telegram_init_data_max_age.clone(),
+            "SIWE_DOMAIN" => self.siwe_domain.clone(),
+            "SIWE_CHAIN_IDS" => self.siwe_chain_ids.clone(),
+            "LOGIN_LINK_BASE_URL" => self.login_link_base_url.clone(),
+            "RATE_LIMIT_BACKEND" => self.rate_limit_backend.clone(),
             "FISH_AUDIO_API_KEY" => self.fish_audio_api_key.clone(),
             "HASURA_GRAPHQL_URL" => self.hasura_graphql_url.clone(),
             "HASURA_GRAPHQL_ADMIN_SECRET" => self.hasura_graphql_admin_secret.clone(),
@@ -27,3 +61,30 @@ impl EnvVars for ApiServerEnv {
         }
     }
 }
+
+impl ApiServerEnv {
+    pub fn auth_token_config(&self) -> AuthTokenConfig {
+        AuthTokenConfig {
+            secret: self.auth_token_secret.clone(),
+            access_ttl: self.access_token_ttl.parse().unwrap_or(DEFAULT_ACCESS_TOKEN_TTL),
+            refresh_ttl: self.refresh_token_ttl.parse().unwrap_or(DEFAULT_REFRESH_TOKEN_TTL),
+        }
+    }
+
+    pub fn identity_config(&self) -> IdentityConfig {
+        IdentityConfig {
+            telegram_bot_token: Some(self.telegram_bot_token.clone()).filter(|token| !token.is_empty()),
+            telegram_init_data_max_age: self.telegram_init_data_max_age.parse().unwrap_or(DEFAULT_TELEGRAM_INIT_DATA_MAX_AGE),
+            siwe_domain: self.siwe_domain.clone(),
+            // comma separated
+            siwe_chain_ids: self.siwe_chain_ids.split(',')
+                .filter_map(|chain_id| chain_id.trim().parse().ok())
+                .collect(),
+        }
+    }
+
+    /// Whether the 60-second `User::generate_auth_token` is still accepted, on requests and by `/user/auth/token`
+    pub fn legacy_auth_token_enabled(&self) -> bool {
+        self.legacy_auth_token == "true"
+    }
+}
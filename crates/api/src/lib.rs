@@ -1,3 +1,4 @@
+mod auth;
 mod env;
 mod middleware;
 mod response;
@@ -5,6 +6,7 @@ mod utils;
 mod voice;
 mod routes;
 mod global_state;
+mod rate_limit;
 
 pub use routes::{
     misc_routes,
@@ -12,10 +14,15 @@ pub use routes::{
     voice_routes,
     runtime_routes,
     user_routes,
+    lorebook_routes,
+    character_routes,
+    admin_routes,
 };
 
 pub use env::ApiServerEnv;
 pub use utils::setup_tracing;
+pub use auth::{AuthError, AuthUser, OptionalAuthUser, RequireAdmin};
 pub use middleware::{authenticate, ensure_account};
+pub use rate_limit::{rate_limit, InMemoryRateLimitBackend, PostgresRateLimitBackend, RateLimitBackend, RateLimiter};
 pub use response::{AppError, AppSuccess};
 pub use global_state::GlobalState;
\ No newline at end of file
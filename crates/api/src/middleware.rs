@@ -3,49 +3,31 @@ use axum::http::StatusCode;
 use axum::{extract::Request, response::Response};
 use axum::middleware::Next;
 
-use voda_common::EnvVars;
 use voda_database::{QueryCriteria, SqlxFilterQuery, SqlxCrud};
 use voda_runtime::{RuntimeClient, User};
 
+use crate::auth::AuthUser;
 use crate::response::AppError;
-use crate::utils::extract_bearer_token;
-use crate::env::ApiServerEnv;
 
+/// Rejects requests without a valid bearer token, see `AuthUser`. Handlers take the cached
+/// `AuthUser` as an extractor.
 pub async fn authenticate(
-    mut req: Request, next: Next
+    _auth_user: AuthUser,
+    req: Request, next: Next
 ) -> Result<Response<Body>, AppError> {
-
-    let env = ApiServerEnv::load();
-    let maybe_bearer_token = extract_bearer_token(&req);
-
-    let user_id = maybe_bearer_token.and_then(|token| {
-        match User::verify_auth_token(&token, &env.get_env_var("SECRET_SALT")) {
-            Ok(uid) => {
-                Ok(uid)
-            }
-            Err(e) => {
-                Err(AppError::new(StatusCode::UNAUTHORIZED, e))
-            }
-        }
-    }).unwrap_or_default();
-
-    req.extensions_mut().insert(user_id.clone());
-
     let response = next.run(req).await;
     Ok(response)
 }
 
+/// The account of `auth_user`, charged `price` when it is positive. Credentials are only issued
+/// to existing users, so a missing account means it was removed since and the credential is
+/// rejected.
 pub async fn ensure_account<S: RuntimeClient>(
-    state: &S, user_id_str: &String, price: i64,
-) -> Result<Option<User>, AppError> {
-
-    if user_id_str.is_empty() {
-        return Ok(None);
-    }
-
+    state: &S, auth_user: &AuthUser, price: i64,
+) -> Result<User, AppError> {
     let mut tx = state.get_db().begin().await?;
     match User::find_one_by_criteria(
-        QueryCriteria::new().add_valued_filter("user_id", "=", user_id_str.clone())?,
+        QueryCriteria::new().add_valued_filter("user_id", "=", auth_user.user_id.clone())?,
         &mut *tx
     ).await? {
         Some(mut user) => {
@@ -58,11 +40,11 @@ pub async fn ensure_account<S: RuntimeClient>(
                 user.clone().update(&mut *tx).await?;
                 tx.commit().await?;
             }
-            Ok(Some(user))
+            Ok(user)
         }
         None => {
             tx.rollback().await?;
-            Ok(None)
+            Err(AppError::new(StatusCode::UNAUTHORIZED, anyhow::anyhow!("[ensure_account] Account no longer exists")))
         },
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::body::Body;
+use axum::extract::{ConnectInfo, FromRequestParts, Request, State};
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde_json::json;
+use sqlx::PgPool;
+use tokio::sync::RwLock;
+
+use voda_common::{get_current_timestamp, EnvVars};
+use voda_database::{QueryCriteria, SqlxFilterQuery};
+use voda_runtime::{RateLimitDecision, RateLimitRule, TokenBucket};
+
+use crate::auth::AuthUser;
+use crate::env::ApiServerEnv;
+use crate::response::GenericResponse;
+
+/// how long rules are cached before they are read from the database again, in seconds
+const RULES_TTL: i64 = 60;
+
+/// Where token buckets live
+#[async_trait]
+pub trait RateLimitBackend: Send + Sync {
+    async fn take(&self, rule: &RateLimitRule, client: &str) -> Result<RateLimitDecision>;
+    /// Drops the buckets that have refilled completely, which are no different from missing ones
+    async fn prune(&self) -> Result<u64>;
+}
+
+/// Buckets in process memory, enough for a single instance
+#[derive(Default)]
+pub struct InMemoryRateLimitBackend {
+    /// the buckets and when they are full again
+    buckets: Mutex<HashMap<String, (TokenBucket, i64)>>,
+}
+
+#[async_trait]
+impl RateLimitBackend for InMemoryRateLimitBackend {
+    async fn take(&self, rule: &RateLimitRule, client: &str) -> Result<RateLimitDecision> {
+        let now_ms = get_current_timestamp_ms();
+        let mut buckets = self.buckets.lock().expect("rate limit buckets poisoned");
+        let (bucket, full_at) = buckets.entry(rule.bucket_key(client)).or_insert_with(|| (rule.new_bucket(now_ms), now_ms));
+        let decision = bucket.take(rule, now_ms);
+        *full_at = bucket.full_at(rule);
+        Ok(decision)
+    }
+
+    async fn prune(&self) -> Result<u64> {
+        let now_ms = get_current_timestamp_ms();
+        let mut buckets = self.buckets.lock().expect("rate limit buckets poisoned");
+        let before = buckets.len();
+        buckets.retain(|_, (_, full_at)| *full_at > now_ms);
+        Ok((before - buckets.len()) as u64)
+    }
+}
+
+/// Buckets in `rate_limit_buckets`, shared by every instance. Each take locks its bucket row,
+/// inserting it first for new clients so their first requests also queue up on the lock.
+pub struct PostgresRateLimitBackend {
+    db: Arc<PgPool>,
+}
+
+impl PostgresRateLimitBackend {
+    pub fn new(db: Arc<PgPool>) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait]
+impl RateLimitBackend for PostgresRateLimitBackend {
+    async fn take(&self, rule: &RateLimitRule, client: &str) -> Result<RateLimitDecision> {
+        let key = rule.bucket_key(client);
+        let now_ms = get_current_timestamp_ms();
+
+        let mut tx = self.db.begin().await?;
+        let new_bucket = rule.new_bucket(now_ms);
+        sqlx::query(
+            "INSERT INTO rate_limit_buckets (key, tokens, refilled_at, full_at) VALUES ($1, $2, $3, $3)
+            ON CONFLICT (key) DO NOTHING"
+        )
+            .bind(&key)
+            .bind(new_bucket.tokens)
+            .bind(new_bucket.refilled_at)
+            .execute(&mut *tx)
+            .await?;
+
+        let (tokens, refilled_at): (f64, i64) = sqlx::query_as(
+            "SELECT tokens, refilled_at FROM rate_limit_buckets WHERE key = $1 FOR UPDATE"
+        )
+            .bind(&key)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        let mut bucket = TokenBucket { tokens, refilled_at };
+        let decision = bucket.take(rule, now_ms);
+
+        sqlx::query("UPDATE rate_limit_buckets SET tokens = $1, refilled_at = $2, full_at = $3 WHERE key = $4")
+            .bind(bucket.tokens)
+            .bind(bucket.refilled_at)
+            .bind(bucket.full_at(rule))
+            .bind(&key)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(decision)
+    }
+
+    async fn prune(&self) -> Result<u64> {
+        let result = sqlx::query("DELETE FROM rate_limit_buckets WHERE full_at <= $1")
+            .bind(get_current_timestamp_ms())
+            .execute(&*self.db)
+            .await?;
+        Ok(result.rows_affected())
+    }
+}
+
+/// Limits requests by the `RateLimitRule` rows, keyed by the authenticated user or else the IP.
+/// Apply with `middleware::from_fn_with_state(limiter, rate_limit)`, inside the layer providing
+/// the `Arc<PgPool>` extension so users can be told apart.
+#[derive(Clone)]
+pub struct RateLimiter {
+    db: Arc<PgPool>,
+    backend: Arc<dyn RateLimitBackend>,
+    /// the rules and when they were loaded
+    rules: Arc<RwLock<(i64, Vec<RateLimitRule>)>>,
+}
+
+impl RateLimiter {
+    pub fn new(db: Arc<PgPool>, backend: Arc<dyn RateLimitBackend>) -> Self {
+        Self {
+            db,
+            backend,
+            rules: Arc::new(RwLock::new((0, Vec::new()))),
+        }
+    }
+
+    /// Picks the backend from `RATE_LIMIT_BACKEND`, `memory` or `postgres`
+    pub fn from_env(db: Arc<PgPool>) -> Self {
+        let backend: Arc<dyn RateLimitBackend> = match ApiServerEnv::load().get_env_var("RATE_LIMIT_BACKEND").as_str() {
+            "postgres" => Arc::new(PostgresRateLimitBackend::new(db.clone())),
+            _ => Arc::new(InMemoryRateLimitBackend::default()),
+        };
+        Self::new(db, backend)
+    }
+
+    /// Drops full buckets, meant to run every minute or so to keep the buckets of one-off
+    /// clients from piling up
+    pub async fn prune(&self) -> Result<u64> {
+        self.backend.prune().await
+    }
+
+    async fn rule_for_path(&self, path: &str) -> Result<Option<RateLimitRule>> {
+        {
+            let rules = self.rules.read().await;
+            if get_current_timestamp() - rules.0 < RULES_TTL {
+                return Ok(RateLimitRule::find_for_path(&rules.1, path).cloned());
+            }
+        }
+
+        let loaded = RateLimitRule::find_by_criteria(
+            QueryCriteria::new().add_valued_filter("enabled", "=", true)?,
+            &*self.db
+        ).await?;
+        let rule = RateLimitRule::find_for_path(&loaded, path).cloned();
+        *self.rules.write().await = (get_current_timestamp(), loaded);
+        Ok(rule)
+    }
+}
+
+pub async fn rate_limit(
+    State(limiter): State<RateLimiter>,
+    req: Request, next: Next
+) -> Response<Body> {
+    let rule = match limiter.rule_for_path(req.uri().path()).await {
+        Ok(Some(rule)) => rule,
+        Ok(None) => return next.run(req).await,
+        Err(e) => {
+            // failing open, an unreadable rule table must not take the API down
+            tracing::warn!("[rate_limit] Failed to load rate limit rules: {}", e);
+            return next.run(req).await;
+        }
+    };
+
+    // the outcome is cached in the extensions, `authenticate` and the handlers reuse it
+    let (mut parts, body) = req.into_parts();
+    let client = match AuthUser::from_request_parts(&mut parts, &()).await {
+        Ok(auth_user) => format!("user:{}", auth_user.user_id),
+        Err(_) => match parts.extensions.get::<ConnectInfo<SocketAddr>>() {
+            Some(ConnectInfo(addr)) => format!("ip:{}", addr.ip()),
+            None => "ip:unknown".to_string(),
+        },
+    };
+    let req = Request::from_parts(parts, body);
+
+    match limiter.backend.take(&rule, &client).await {
+        Ok(RateLimitDecision::Allowed) => next.run(req).await,
+        Ok(RateLimitDecision::Limited { retry_after }) => {
+            let mut response = (
+                StatusCode::TOO_MANY_REQUESTS,
+                GenericResponse::new(StatusCode::TOO_MANY_REQUESTS, "Too many requests", json!({ "retry_after": retry_after })),
+            ).into_response();
+            response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+            response
+        }
+        Err(e) => {
+            tracing::warn!("[rate_limit] Failed to take a token for {}: {}", client, e);
+            next.run(req).await
+        }
+    }
+}
+
+fn get_current_timestamp_ms() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+}
@@ -0,0 +1,469 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode, middleware,
+    routing::post, Json, Router
+};
+use sqlx::types::Uuid;
+use voda_common::get_current_timestamp;
+use voda_database::{OrderDirection, QueryCriteria, SqlxCrud, SqlxFilterQuery};
+use voda_runtime::{RateLimitRule, RuntimeClient, SystemConfig, VerbatimPromptVariables};
+use voda_runtime_roleplay::{
+    validate_system_prompt, AuditLog, Character, CharacterStatus, ModerationAction, ModerationResult, ModerationRule,
+    ModerationRuleKind
+};
+
+use crate::{
+    auth::{AuthUser, RequireAdmin},
+    ensure_account,
+    middleware::authenticate,
+    response::{AppError, AppSuccess},
+    GlobalState
+};
+
+pub fn admin_routes() -> Router<GlobalState> {
+    Router::new()
+        .route("/admin/character/review_queue",
+            post(review_queue)
+            .route_layer(middleware::from_extractor::<RequireAdmin>())
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/admin/character/approve/{character_id}",
+            post(approve_character)
+            .route_layer(middleware::from_extractor::<RequireAdmin>())
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/admin/character/reject/{character_id}",
+            post(reject_character)
+            .route_layer(middleware::from_extractor::<RequireAdmin>())
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/admin/character/archive/{character_id}",
+            post(archive_character)
+            .route_layer(middleware::from_extractor::<RequireAdmin>())
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/admin/character/audit_log/{character_id}",
+            post(character_audit_log)
+            .route_layer(middleware::from_extractor::<RequireAdmin>())
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/admin/moderation/rules",
+            post(list_moderation_rules)
+            .route_layer(middleware::from_extractor::<RequireAdmin>())
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/admin/moderation/rule/create",
+            post(create_moderation_rule)
+            .route_layer(middleware::from_extractor::<RequireAdmin>())
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/admin/moderation/rule/update/{rule_id}",
+            post(update_moderation_rule)
+            .route_layer(middleware::from_extractor::<RequireAdmin>())
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/admin/moderation/rule/delete/{rule_id}",
+            post(delete_moderation_rule)
+            .route_layer(middleware::from_extractor::<RequireAdmin>())
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/admin/moderation/results",
+            post(list_moderation_results)
+            .route_layer(middleware::from_extractor::<RequireAdmin>())
+            .route_layer(middleware::from_fn(authenticate))
+        )
+
+        .route("/admin/system_configs",
+            post(list_system_configs)
+            .route_layer(middleware::from_extractor::<RequireAdmin>())
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/admin/system_config/update/{config_id}",
+            post(update_system_config)
+            .route_layer(middleware::from_extractor::<RequireAdmin>())
+            .route_layer(middleware::from_fn(authenticate))
+        )
+
+        .route("/admin/rate_limit/rules",
+            post(list_rate_limit_rules)
+            .route_layer(middleware::from_extractor::<RequireAdmin>())
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/admin/rate_limit/rule/create",
+            post(create_rate_limit_rule)
+            .route_layer(middleware::from_extractor::<RequireAdmin>())
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/admin/rate_limit/rule/update/{rule_id}",
+            post(update_rate_limit_rule)
+            .route_layer(middleware::from_extractor::<RequireAdmin>())
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/admin/rate_limit/rule/delete/{rule_id}",
+            post(delete_rate_limit_rule)
+            .route_layer(middleware::from_extractor::<RequireAdmin>())
+            .route_layer(middleware::from_fn(authenticate))
+        )
+}
+
+/// Characters waiting for review, the longest waiting first
+async fn review_queue(
+    State(state): State<GlobalState>,
+    _admin: RequireAdmin,
+) -> Result<AppSuccess, AppError> {
+    let mut characters = Character::find_by_criteria(
+        QueryCriteria::new().add_valued_filter("status", "=", CharacterStatus::Reviewing.to_string())?,
+        &**state.roleplay_client.get_db()
+    ).await?;
+    characters.sort_by_key(|character| character.updated_at);
+
+    Ok(AppSuccess::new(StatusCode::OK, "Review queue listed successfully", json!(characters)))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ModerationRequest {
+    /// shown to the creator for rejections and archivals, kept in the audit log either way
+    #[serde(default)]
+    pub notes: String,
+}
+
+async fn moderate(
+    state: &GlobalState, auth_user: &AuthUser, character_id: Uuid,
+    next: impl FnOnce(String) -> CharacterStatus, notes: String,
+) -> Result<AuditLog, AppError> {
+    let admin = ensure_account(&state.roleplay_client, auth_user, 0).await?;
+
+    let mut tx = state.roleplay_client.get_db().begin().await?;
+    let mut character = Character::find_one_by_criteria(
+        QueryCriteria::new().add_valued_filter("id", "=", character_id)?,
+        &mut *tx
+    ).await?
+        .ok_or(AppError::new(StatusCode::NOT_FOUND, anyhow!("[moderate] Character not found")))?;
+
+    let log = AuditLog::transition(&mut character, next(notes.clone()), &admin, &notes, &mut tx).await
+        .map_err(|e| AppError::new(StatusCode::CONFLICT, e))?;
+    tx.commit().await?;
+
+    // recommendations are best effort, a failed embedding only keeps the character out of them
+    if let Err(e) = state.roleplay_client.recommender().index(&character).await {
+        tracing::warn!("[moderate] Failed to index character {}: {}", character.id, e);
+    }
+    Ok(log)
+}
+
+async fn approve_character(
+    State(state): State<GlobalState>,
+    RequireAdmin(auth_user): RequireAdmin,
+    Path(character_id): Path<Uuid>,
+    Json(payload): Json<ModerationRequest>,
+) -> Result<AppSuccess, AppError> {
+    let log = moderate(&state, &auth_user, character_id, |_| CharacterStatus::Published, payload.notes).await?;
+    Ok(AppSuccess::new(StatusCode::OK, "Character approved successfully", json!(log)))
+}
+
+async fn reject_character(
+    State(state): State<GlobalState>,
+    RequireAdmin(auth_user): RequireAdmin,
+    Path(character_id): Path<Uuid>,
+    Json(payload): Json<ModerationRequest>,
+) -> Result<AppSuccess, AppError> {
+    if payload.notes.trim().is_empty() {
+        return Err(AppError::new(StatusCode::BAD_REQUEST, anyhow!("[reject_character] A reason is required")));
+    }
+    let log = moderate(&state, &auth_user, character_id, CharacterStatus::Rejected, payload.notes).await?;
+    Ok(AppSuccess::new(StatusCode::OK, "Character rejected successfully", json!(log)))
+}
+
+async fn archive_character(
+    State(state): State<GlobalState>,
+    RequireAdmin(auth_user): RequireAdmin,
+    Path(character_id): Path<Uuid>,
+    Json(payload): Json<ModerationRequest>,
+) -> Result<AppSuccess, AppError> {
+    let log = moderate(&state, &auth_user, character_id, CharacterStatus::Archived, payload.notes).await?;
+    Ok(AppSuccess::new(StatusCode::OK, "Character archived successfully", json!(log)))
+}
+
+async fn character_audit_log(
+    State(state): State<GlobalState>,
+    _admin: RequireAdmin,
+    Path(character_id): Path<Uuid>,
+) -> Result<AppSuccess, AppError> {
+    let mut logs = AuditLog::find_by_criteria(
+        QueryCriteria::new().add_valued_filter("character", "=", character_id)?,
+        &**state.roleplay_client.get_db()
+    ).await?;
+    logs.sort_by_key(|log| log.created_at);
+
+    Ok(AppSuccess::new(StatusCode::OK, "Audit log listed successfully", json!(logs)))
+}
+
+async fn list_moderation_rules(
+    State(state): State<GlobalState>,
+    _admin: RequireAdmin,
+) -> Result<AppSuccess, AppError> {
+    let mut rules = ModerationRule::find_by_criteria(QueryCriteria::new(), &**state.roleplay_client.get_db()).await?;
+    rules.sort_by_key(|rule| rule.created_at);
+
+    Ok(AppSuccess::new(StatusCode::OK, "Moderation rules listed successfully", json!(rules)))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateModerationRuleRequest {
+    pub name: String,
+    pub kind: ModerationRuleKind,
+    pub patterns: Vec<String>,
+    #[serde(default)]
+    pub case_sensitive: bool,
+    pub action: ModerationAction,
+    /// used by `Rewrite` rules
+    #[serde(default)]
+    pub replacement: String,
+}
+async fn create_moderation_rule(
+    State(state): State<GlobalState>,
+    RequireAdmin(auth_user): RequireAdmin,
+    Json(payload): Json<CreateModerationRuleRequest>,
+) -> Result<AppSuccess, AppError> {
+    let admin = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let rule = ModerationRule {
+        id: Uuid::default(),
+        created_by: admin.id,
+        name: payload.name,
+        kind: payload.kind,
+        patterns: payload.patterns,
+        case_sensitive: payload.case_sensitive,
+        action: payload.action,
+        replacement: payload.replacement,
+        enabled: true,
+        updated_at: get_current_timestamp(),
+        created_at: get_current_timestamp(),
+    };
+    rule.validate()?;
+    let rule = rule.create(&**state.roleplay_client.get_db()).await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Moderation rule created successfully", json!({
+        "rule_id": rule.id,
+    })))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateModerationRuleRequest {
+    pub name: Option<String>,
+    pub patterns: Option<Vec<String>>,
+    pub case_sensitive: Option<bool>,
+    pub action: Option<ModerationAction>,
+    pub replacement: Option<String>,
+    pub enabled: Option<bool>,
+}
+async fn update_moderation_rule(
+    State(state): State<GlobalState>,
+    _admin: RequireAdmin,
+    Path(rule_id): Path<Uuid>,
+    Json(payload): Json<UpdateModerationRuleRequest>,
+) -> Result<AppSuccess, AppError> {
+    let mut rule = find_moderation_rule(&state, rule_id).await?;
+    if let Some(name) = payload.name { rule.name = name; }
+    if let Some(patterns) = payload.patterns { rule.patterns = patterns; }
+    if let Some(case_sensitive) = payload.case_sensitive { rule.case_sensitive = case_sensitive; }
+    if let Some(action) = payload.action { rule.action = action; }
+    if let Some(replacement) = payload.replacement { rule.replacement = replacement; }
+    if let Some(enabled) = payload.enabled { rule.enabled = enabled; }
+    rule.validate()?;
+    rule.updated_at = get_current_timestamp();
+    rule.update(&**state.roleplay_client.get_db()).await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Moderation rule updated successfully", json!(())))
+}
+
+async fn delete_moderation_rule(
+    State(state): State<GlobalState>,
+    _admin: RequireAdmin,
+    Path(rule_id): Path<Uuid>,
+) -> Result<AppSuccess, AppError> {
+    let rule = find_moderation_rule(&state, rule_id).await?;
+    rule.delete(&**state.roleplay_client.get_db()).await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Moderation rule deleted successfully", json!(())))
+}
+
+async fn find_moderation_rule(state: &GlobalState, rule_id: Uuid) -> Result<ModerationRule, AppError> {
+    ModerationRule::find_one_by_criteria(
+        QueryCriteria::new().add_valued_filter("id", "=", rule_id)?,
+        &**state.roleplay_client.get_db()
+    ).await?
+        .ok_or(AppError::new(StatusCode::NOT_FOUND, anyhow!("[find_moderation_rule] Moderation rule not found")))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListModerationResultsRequest {
+    /// results for one character
+    pub target_id: Option<Uuid>,
+    /// results for the messages of one session
+    pub session_id: Option<Uuid>,
+    pub limit: Option<i64>,
+}
+/// The latest moderation results, newest first
+async fn list_moderation_results(
+    State(state): State<GlobalState>,
+    _admin: RequireAdmin,
+    Json(payload): Json<ListModerationResultsRequest>,
+) -> Result<AppSuccess, AppError> {
+    let mut criteria = QueryCriteria::new();
+    if let Some(target_id) = payload.target_id {
+        criteria = criteria.add_valued_filter("target_id", "=", target_id)?;
+    }
+    if let Some(session_id) = payload.session_id {
+        criteria = criteria.add_valued_filter("session_id", "=", session_id)?;
+    }
+    let results = ModerationResult::find_by_criteria(
+        criteria
+            .order_by("created_at", OrderDirection::Desc)?
+            .limit(payload.limit.unwrap_or(100).clamp(1, 500))?,
+        &**state.roleplay_client.get_db()
+    ).await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Moderation results listed successfully", json!(results)))
+}
+
+async fn list_system_configs(
+    State(state): State<GlobalState>,
+    _admin: RequireAdmin,
+) -> Result<AppSuccess, AppError> {
+    let mut configs = SystemConfig::find_by_criteria(QueryCriteria::new(), &**state.roleplay_client.get_db()).await?;
+    configs.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(AppSuccess::new(StatusCode::OK, "System configs listed successfully", json!(configs)))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateSystemConfigRequest {
+    pub system_prompt: Option<String>,
+    pub openai_model: Option<String>,
+    pub openai_temperature: Option<f32>,
+    pub openai_max_tokens: Option<i32>,
+    pub openai_context_window: Option<i32>,
+    pub agent_max_steps: Option<i32>,
+}
+/// Edits a system config, checking its prompt against the variables it is used with.
+/// Hasura only lets admins write system configs, and does not check prompts.
+async fn update_system_config(
+    State(state): State<GlobalState>,
+    _admin: RequireAdmin,
+    Path(config_id): Path<Uuid>,
+    Json(payload): Json<UpdateSystemConfigRequest>,
+) -> Result<AppSuccess, AppError> {
+    let mut config = SystemConfig::find_one_by_criteria(
+        QueryCriteria::new().add_valued_filter("id", "=", config_id)?,
+        &**state.roleplay_client.get_db()
+    ).await?
+        .ok_or(AppError::new(StatusCode::NOT_FOUND, anyhow!("[update_system_config] System config not found")))?;
+
+    if let Some(system_prompt) = payload.system_prompt {
+        if system_prompt != config.system_prompt {
+            config.system_prompt = system_prompt;
+            config.system_prompt_version += 1;
+        }
+    }
+    if let Some(openai_model) = payload.openai_model { config.openai_model = openai_model; }
+    if let Some(openai_temperature) = payload.openai_temperature { config.openai_temperature = openai_temperature; }
+    if let Some(openai_max_tokens) = payload.openai_max_tokens { config.openai_max_tokens = openai_max_tokens; }
+    if let Some(openai_context_window) = payload.openai_context_window { config.openai_context_window = openai_context_window; }
+    if let Some(agent_max_steps) = payload.agent_max_steps { config.agent_max_steps = agent_max_steps; }
+    let validated = if config.name == state.character_creation_client.system_config_name() {
+        config.validate_prompt::<VerbatimPromptVariables>()
+    } else {
+        validate_system_prompt(&config)
+    };
+    validated.map_err(|e| AppError::new(StatusCode::BAD_REQUEST, e))?;
+    let config = config.update(&**state.roleplay_client.get_db()).await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "System config updated successfully", json!({
+        "system_prompt_version": config.system_prompt_version,
+    })))
+}
+
+async fn list_rate_limit_rules(
+    State(state): State<GlobalState>,
+    _admin: RequireAdmin,
+) -> Result<AppSuccess, AppError> {
+    let mut rules = RateLimitRule::find_by_criteria(QueryCriteria::new(), &**state.roleplay_client.get_db()).await?;
+    rules.sort_by(|a, b| a.route_prefix.cmp(&b.route_prefix));
+
+    Ok(AppSuccess::new(StatusCode::OK, "Rate limit rules listed successfully", json!(rules)))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateRateLimitRuleRequest {
+    pub route_prefix: String,
+    pub capacity: i64,
+    pub refill_per_minute: i64,
+}
+/// Rule changes reach the rate limiter within a minute, when it reloads its rules
+async fn create_rate_limit_rule(
+    State(state): State<GlobalState>,
+    _admin: RequireAdmin,
+    Json(payload): Json<CreateRateLimitRuleRequest>,
+) -> Result<AppSuccess, AppError> {
+    let rule = RateLimitRule {
+        id: Uuid::default(),
+        route_prefix: payload.route_prefix,
+        capacity: payload.capacity,
+        refill_per_minute: payload.refill_per_minute,
+        enabled: true,
+        updated_at: get_current_timestamp(),
+        created_at: get_current_timestamp(),
+    };
+    rule.validate()?;
+    let rule = rule.create(&**state.roleplay_client.get_db()).await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Rate limit rule created successfully", json!({
+        "rule_id": rule.id,
+    })))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateRateLimitRuleRequest {
+    pub capacity: Option<i64>,
+    pub refill_per_minute: Option<i64>,
+    pub enabled: Option<bool>,
+}
+async fn update_rate_limit_rule(
+    State(state): State<GlobalState>,
+    _admin: RequireAdmin,
+    Path(rule_id): Path<Uuid>,
+    Json(payload): Json<UpdateRateLimitRuleRequest>,
+) -> Result<AppSuccess, AppError> {
+    let mut rule = find_rate_limit_rule(&state, rule_id).await?;
+    if let Some(capacity) = payload.capacity { rule.capacity = capacity; }
+    if let Some(refill_per_minute) = payload.refill_per_minute { rule.refill_per_minute = refill_per_minute; }
+    if let Some(enabled) = payload.enabled { rule.enabled = enabled; }
+    rule.validate()?;
+    rule.updated_at = get_current_timestamp();
+    rule.update(&**state.roleplay_client.get_db()).await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Rate limit rule updated successfully", json!(())))
+}
+
+async fn delete_rate_limit_rule(
+    State(state): State<GlobalState>,
+    _admin: RequireAdmin,
+    Path(rule_id): Path<Uuid>,
+) -> Result<AppSuccess, AppError> {
+    let rule = find_rate_limit_rule(&state, rule_id).await?;
+    rule.delete(&**state.roleplay_client.get_db()).await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Rate limit rule deleted successfully", json!(())))
+}
+
+async fn find_rate_limit_rule(state: &GlobalState, rule_id: Uuid) -> Result<RateLimitRule, AppError> {
+    RateLimitRule::find_one_by_criteria(
+        QueryCriteria::new().add_valued_filter("id", "=", rule_id)?,
+        &**state.roleplay_client.get_db()
+    ).await?
+        .ok_or(AppError::new(StatusCode::NOT_FOUND, anyhow!("[find_rate_limit_rule] Rate limit rule not found")))
+}
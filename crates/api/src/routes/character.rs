@@ -0,0 +1,388 @@
+use anyhow::anyhow;
+use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode, middleware,
+    routing::post, Json, Router
+};
+use sqlx::types::Uuid;
+use voda_database::{QueryCriteria, SqlxCrud, SqlxFilterQuery};
+use voda_runtime::{decode_data_url, RuntimeClient, User};
+use voda_runtime_roleplay::{
+    AuditLog, CHARACTER_SEARCH_DEFAULT_LIMIT, CHARACTER_SEARCH_MAX_LIMIT, Character, CharacterCard, CharacterGender, CharacterLanguage,
+    CharacterRevision, CharacterSearch, CharacterSnapshot, CharacterStatus, ModerationAction, ModerationTarget
+};
+
+use crate::{
+    auth::AuthUser,
+    ensure_account,
+    middleware::authenticate,
+    response::{AppError, AppSuccess},
+    GlobalState
+};
+
+pub fn character_routes() -> Router<GlobalState> {
+    Router::new()
+        .route("/character/search",
+            post(search_characters)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/character/similar/{character_id}",
+            post(similar_characters)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/character/recommendations",
+            post(recommend_characters)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/character/import",
+            post(import_character)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/character/export/{character_id}",
+            post(export_character)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/character/update/{character_id}",
+            post(update_character)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/character/revisions/{character_id}",
+            post(list_revisions)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/character/diff/{character_id}",
+            post(diff_revisions)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+}
+
+/// Published characters matching the query, one page at a time
+async fn search_characters(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Json(payload): Json<CharacterSearch>,
+) -> Result<AppSuccess, AppError> {
+    ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let mut tx = state.roleplay_client.get_db().begin().await?;
+    let page = payload.run(&mut tx).await?;
+    tx.commit().await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Characters searched successfully", json!(page)))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecommendationRequest {
+    pub limit: Option<i64>,
+}
+async fn similar_characters(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Path(character_id): Path<Uuid>,
+    Json(payload): Json<RecommendationRequest>,
+) -> Result<AppSuccess, AppError> {
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let mut tx = state.roleplay_client.get_db().begin().await?;
+    let character = find_visible_character(&user.id, character_id, &mut tx).await?;
+    tx.commit().await?;
+
+    let similar = state.roleplay_client.recommender()
+        .similar(&character.id, payload.limit.unwrap_or(CHARACTER_SEARCH_DEFAULT_LIMIT).clamp(1, CHARACTER_SEARCH_MAX_LIMIT)).await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Similar characters listed successfully", json!(similar)))
+}
+
+/// Characters like the ones the user chatted with lately
+async fn recommend_characters(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Json(payload): Json<RecommendationRequest>,
+) -> Result<AppSuccess, AppError> {
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let recommendations = state.roleplay_client.recommender()
+        .recommend_for(&user.id, payload.limit.unwrap_or(CHARACTER_SEARCH_DEFAULT_LIMIT).clamp(1, CHARACTER_SEARCH_MAX_LIMIT)).await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Characters recommended successfully", json!(recommendations)))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportCharacterRequest {
+    /// a card in JSON, V1 to V3
+    pub card: Option<Value>,
+    /// a `data:image/png;base64,` URL of a card image
+    pub png: Option<String>,
+}
+async fn import_character(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Json(payload): Json<ImportCharacterRequest>,
+) -> Result<AppSuccess, AppError> {
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let card = match (payload.card, payload.png) {
+        (Some(card), None) => CharacterCard::from_json(&serde_json::to_vec(&card)?)?,
+        (None, Some(png)) => {
+            let (mime, bytes) = decode_data_url(&png)
+                .ok_or(AppError::new(StatusCode::BAD_REQUEST, anyhow!("[import_character] Invalid PNG data url")))?;
+            if mime != "image/png" {
+                return Err(AppError::new(StatusCode::BAD_REQUEST, anyhow!("[import_character] Cards must be PNG images, got {}", mime)));
+            }
+            CharacterCard::from_png(&bytes)?
+        }
+        _ => return Err(AppError::new(StatusCode::BAD_REQUEST, anyhow!("[import_character] Provide either a card or a png"))),
+    };
+
+    let mut tx = state.roleplay_client.get_db().begin().await?;
+    let imported = card.import(&user.id, &mut tx).await?;
+
+    let moderation = state.roleplay_client.moderation();
+    let mut character = imported.clone();
+    let verdict = moderation.screen_character(&user.id, &mut character).await?;
+    if verdict.is_blocked() {
+        // the import is rolled back, so there is no character to point the results at
+        moderation.record(&verdict, ModerationTarget::Character, None, None, &user.id).await?;
+        return Err(AppError::new(StatusCode::UNPROCESSABLE_ENTITY, anyhow!("[import_character] Character blocked by {}", verdict.rule_names())));
+    }
+    moderation.record(&verdict, ModerationTarget::Character, Some(character.id), None, &user.id).await?;
+    if CharacterSnapshot::of(&character) != CharacterSnapshot::of(&imported) {
+        character = character.update(&mut *tx).await?;
+    }
+    tx.commit().await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Character imported successfully", json!({
+        "character_id": character.id,
+    })))
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CardFormat {
+    #[default]
+    Json,
+    Png,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportCharacterRequest {
+    #[serde(default)]
+    pub format: CardFormat,
+    /// `data:image/png;base64,` URL of the image to embed the card into, a blank image by default
+    pub image: Option<String>,
+}
+async fn export_character(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Path(character_id): Path<Uuid>,
+    Json(payload): Json<ExportCharacterRequest>,
+) -> Result<AppSuccess, AppError> {
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let mut tx = state.roleplay_client.get_db().begin().await?;
+    let character = find_visible_character(&user.id, character_id, &mut tx).await?;
+
+    let card = CharacterCard::export(&character, &mut tx).await?;
+    tx.commit().await?;
+
+    if payload.format == CardFormat::Json {
+        return Ok(AppSuccess::new(StatusCode::OK, "Character exported successfully", json!(card)));
+    }
+
+    let image = match payload.image {
+        Some(image) => Some(decode_data_url(&image)
+            .filter(|(mime, _)| mime == "image/png")
+            .ok_or(AppError::new(StatusCode::BAD_REQUEST, anyhow!("[export_character] Image must be a PNG data url")))?
+            .1),
+        None => None,
+    };
+    let png = card.to_png(image.as_deref())?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Character exported successfully", json!({
+        "png": format!("data:image/png;base64,{}", general_purpose::STANDARD.encode(png)),
+    })))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateCharacterRequest {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub gender: Option<CharacterGender>,
+    pub language: Option<CharacterLanguage>,
+    pub prompts_scenario: Option<String>,
+    pub prompts_personality: Option<String>,
+    pub prompts_example_dialogue: Option<String>,
+    pub prompts_first_message: Option<String>,
+    pub prompts_background_stories: Option<Vec<String>>,
+    pub prompts_behavior_traits: Option<Vec<String>>,
+    pub creator_notes: Option<String>,
+    pub tags: Option<Vec<String>>,
+}
+async fn update_character(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Path(character_id): Path<Uuid>,
+    Json(payload): Json<UpdateCharacterRequest>,
+) -> Result<AppSuccess, AppError> {
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let mut tx = state.roleplay_client.get_db().begin().await?;
+    let character = Character::find_one_by_criteria(
+        QueryCriteria::new()
+            .add_valued_filter("id", "=", character_id)?
+            .add_valued_filter("creator", "=", user.id)?,
+        &mut *tx
+    ).await?
+        .ok_or(AppError::new(StatusCode::NOT_FOUND, anyhow!("[update_character] Character not found")))?;
+
+    let mut edited = character.clone();
+    if let Some(name) = payload.name {
+        if name.trim().is_empty() {
+            return Err(AppError::new(StatusCode::BAD_REQUEST, anyhow!("[update_character] Character name must not be empty")));
+        }
+        edited.name = name;
+    }
+    if let Some(description) = payload.description { edited.description = description; }
+    if let Some(gender) = payload.gender { edited.gender = gender; }
+    if let Some(language) = payload.language { edited.language = language; }
+    if let Some(scenario) = payload.prompts_scenario { edited.prompts_scenario = scenario; }
+    if let Some(personality) = payload.prompts_personality { edited.prompts_personality = personality; }
+    if let Some(example_dialogue) = payload.prompts_example_dialogue { edited.prompts_example_dialogue = example_dialogue; }
+    if let Some(first_message) = payload.prompts_first_message { edited.prompts_first_message = first_message; }
+    if let Some(background_stories) = payload.prompts_background_stories { edited.prompts_background_stories = background_stories; }
+    if let Some(behavior_traits) = payload.prompts_behavior_traits { edited.prompts_behavior_traits = behavior_traits; }
+    if payload.creator_notes.is_some() { edited.creator_notes = payload.creator_notes; }
+    if let Some(tags) = payload.tags { edited.tags = tags; }
+
+    if CharacterSnapshot::of(&edited) == CharacterSnapshot::of(&character) {
+        return Ok(AppSuccess::new(StatusCode::OK, "Character unchanged", json!({
+            "version": character.version,
+        })));
+    }
+
+    let was_published = character.status == CharacterStatus::Published;
+    let character = commit_character_edit(&state, &user, &character, edited, &mut tx).await?;
+    tx.commit().await?;
+
+    // a published character leaves the index until it is approved again
+    if was_published {
+        if let Err(e) = state.roleplay_client.recommender().index(&character).await {
+            tracing::warn!("[update_character] Failed to index character {}: {}", character.id, e);
+        }
+    }
+
+    Ok(AppSuccess::new(StatusCode::OK, "Character updated successfully", json!({
+        "version": character.version,
+        "status": character.status,
+    })))
+}
+
+async fn list_revisions(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Path(character_id): Path<Uuid>,
+) -> Result<AppSuccess, AppError> {
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let mut tx = state.roleplay_client.get_db().begin().await?;
+    let character = find_visible_character(&user.id, character_id, &mut tx).await?;
+    let mut revisions = CharacterRevision::find_by_criteria(
+        QueryCriteria::new().add_valued_filter("character_id", "=", character.id)?,
+        &mut *tx
+    ).await?;
+    tx.commit().await?;
+
+    revisions.sort_by_key(|revision| revision.version);
+    Ok(AppSuccess::new(StatusCode::OK, "Character revisions listed successfully", json!(
+        revisions.iter()
+            .map(|revision| json!({
+                "revision_id": revision.id,
+                "version": revision.version,
+                "author": revision.author,
+                "created_at": revision.created_at,
+            }))
+            .collect::<Vec<_>>()
+    )))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiffRevisionsRequest {
+    pub from: i64,
+    /// the current version when left out
+    pub to: Option<i64>,
+}
+async fn diff_revisions(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Path(character_id): Path<Uuid>,
+    Json(payload): Json<DiffRevisionsRequest>,
+) -> Result<AppSuccess, AppError> {
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let mut tx = state.roleplay_client.get_db().begin().await?;
+    let character = find_visible_character(&user.id, character_id, &mut tx).await?;
+    let to = payload.to.unwrap_or(character.version);
+
+    let mut snapshots = vec![];
+    for version in [payload.from, to] {
+        let revision = CharacterRevision::find_one_by_criteria(
+            QueryCriteria::new()
+                .add_valued_filter("character_id", "=", character.id)?
+                .add_valued_filter("version", "=", version)?,
+            &mut *tx
+        ).await?;
+        // the current version of a character edited before versioning has no revision yet
+        let snapshot = match revision {
+            Some(revision) => revision.snapshot.0,
+            None if version == character.version => CharacterSnapshot::capture(&character, &mut tx).await?,
+            None => return Err(AppError::new(StatusCode::NOT_FOUND, anyhow!("[diff_revisions] Version {} not found", version))),
+        };
+        snapshots.push(snapshot);
+    }
+    tx.commit().await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Character revisions compared successfully", json!({
+        "from": payload.from,
+        "to": to,
+        "changes": snapshots[0].diff(&snapshots[1])?,
+    })))
+}
+
+/// Screens `edited` and stores it as the next version of `before`. Edits of published characters
+/// go back to review, so they only reach other users once a moderator approves them.
+pub(crate) async fn commit_character_edit(
+    state: &GlobalState, user: &User, before: &Character, mut edited: Character,
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<Character, AppError> {
+    let moderation = state.roleplay_client.moderation();
+    let verdict = moderation.screen_character(&user.id, &mut edited).await?;
+    moderation.record(&verdict, ModerationTarget::Character, Some(before.id), None, &user.id).await?;
+    if verdict.is_blocked() {
+        return Err(AppError::new(StatusCode::UNPROCESSABLE_ENTITY, anyhow!("[commit_character_edit] Character blocked by {}", verdict.rule_names())));
+    }
+
+    let (mut character, _) = CharacterRevision::commit_edit(before, edited, &user.id, tx).await?;
+    if before.status == CharacterStatus::Published {
+        let notes = match verdict.action() {
+            Some(ModerationAction::Flag) => format!("edited after publishing, flagged by {}", verdict.rule_names()),
+            _ => "edited after publishing".to_string(),
+        };
+        AuditLog::transition(&mut character, CharacterStatus::Reviewing, user, &notes, tx).await?;
+    }
+    Ok(character)
+}
+
+/// A character the user created, or any published one
+pub(crate) async fn find_visible_character(
+    user_id: &Uuid, character_id: Uuid, tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<Character, AppError> {
+    Character::find_one_by_criteria(
+        QueryCriteria::new().add_valued_filter("id", "=", character_id)?,
+        &mut **tx
+    ).await?
+        .filter(|character| &character.creator == user_id || character.status == CharacterStatus::Published)
+        .ok_or(AppError::new(StatusCode::NOT_FOUND, anyhow!("[find_visible_character] Character not found")))
+}
@@ -1,38 +1,48 @@
 use anyhow::anyhow;
 use axum::{
-    body::{to_bytes, Body}, extract::{Extension, State}, http::{header::{self, HeaderValue}, Request, StatusCode}, middleware, response::Response, routing::post, Router
+    body::{to_bytes, Body}, extract::State, http::{header::{self, HeaderValue}, Request, StatusCode}, response::Response, routing::post, Router
 };
 use sqlx::types::Uuid;
 use voda_common::EnvVars;
+use voda_runtime::user::SCOPE_GRAPHQL_WRITE;
 use voda_runtime::UserRole;
 
 use crate::{
-    ensure_account, env::ApiServerEnv, middleware::authenticate, response::AppError, GlobalState
+    auth::OptionalAuthUser, ensure_account, env::ApiServerEnv, response::AppError, GlobalState
 };
 
 pub fn graphql_route() -> Router<GlobalState> {
+    // anonymous users query as the `anyone` role
     Router::new().route(
         "/graphql",
-        post(proxy_to_hasura)
-            .route_layer(middleware::from_fn(authenticate)),
+        post(proxy_to_hasura),
     )
 }
 
 async fn proxy_to_hasura(
     State(state): State<GlobalState>,
-    Extension(user_id_str): Extension<String>,
+    OptionalAuthUser(auth_user): OptionalAuthUser,
     req: Request<Body>,
 ) -> Result<Response, AppError> {
     let env = ApiServerEnv::load();
     let hasura_url = env.get_env_var("HASURA_GRAPHQL_URL");
 
-    let maybe_user = ensure_account(&state.roleplay_client, &user_id_str, 0).await?;
+    let maybe_user = match &auth_user {
+        Some(auth_user) => Some(ensure_account(&state.roleplay_client, auth_user, 0).await?),
+        None => None,
+    };
+    let claims = auth_user.as_ref().and_then(|auth_user| auth_user.claims.as_ref());
 
     let (parts, body) = req.into_parts();
     let body_bytes = to_bytes(body, usize::MAX)
         .await
         .map_err(|e| AppError::new(StatusCode::BAD_REQUEST, anyhow!(e)))?;
 
+    // credentials scoped to `graphql:read` only query, whatever Hasura would let their role do
+    if claims.is_some_and(|claims| !claims.has_scope(SCOPE_GRAPHQL_WRITE)) && has_mutation(&body_bytes) {
+        return Err(AppError::new(StatusCode::FORBIDDEN, anyhow!("[proxy_to_hasura] Mutations need the graphql:write scope")));
+    }
+
     let mut headers = parts.headers.clone();
     headers.remove(header::AUTHORIZATION);
     headers.remove(header::HOST);
@@ -42,7 +52,8 @@ async fn proxy_to_hasura(
             (Uuid::nil(), "anyone")
         }
         Some(ref user) => {
-            let role = match user.role {
+            // the role of the credential, which for API keys may be lower than the owner's
+            let role = match claims.map(|claims| &claims.role).unwrap_or(&user.role) {
                 UserRole::Admin => "admin",
                 UserRole::User => "user",
             };
@@ -113,3 +124,62 @@ async fn proxy_to_hasura(
 
 
 
+
+/// Whether any operation in the request body, single or batched, is a mutation. Bodies that
+/// don't parse count as mutations, so they can't slip past the scope check.
+fn has_mutation(body: &[u8]) -> bool {
+    let Ok(payload) = serde_json::from_slice::<serde_json::Value>(body) else {
+        return true;
+    };
+    let operations = match payload {
+        serde_json::Value::Array(operations) => operations,
+        operation => vec![operation],
+    };
+
+    operations.iter().any(|operation| match operation.get("query").and_then(|query| query.as_str()) {
+        Some(query) => is_mutation_document(query),
+        None => true,
+    })
+}
+
+/// Looks for the `mutation` keyword at the top level of a GraphQL document, skipping comments,
+/// strings and selection sets
+fn is_mutation_document(query: &str) -> bool {
+    let chars = query.chars().collect::<Vec<_>>();
+    let mut depth = 0usize;
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            '#' => {
+                while i < chars.len() && chars[i] != '\n' { i += 1; }
+            }
+            '"' if chars[i..].starts_with(&['"', '"', '"']) => {
+                i += 3;
+                while i < chars.len() && !chars[i..].starts_with(&['"', '"', '"']) {
+                    // the one escape in block strings is \"""
+                    i += if chars[i..].starts_with(&['\\', '"', '"', '"']) { 4 } else { 1 };
+                }
+                i += 2;
+            }
+            '"' => {
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    i += if chars[i] == '\\' { 2 } else { 1 };
+                }
+            }
+            '{' | '(' | '[' => depth += 1,
+            '}' | ')' | ']' => depth = depth.saturating_sub(1),
+            c if c.is_alphabetic() || c == '_' => {
+                let start = i;
+                while i + 1 < chars.len() && (chars[i + 1].is_alphanumeric() || chars[i + 1] == '_') { i += 1; }
+                if depth == 0 && chars[start..=i].iter().collect::<String>() == "mutation" {
+                    return true;
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    false
+}
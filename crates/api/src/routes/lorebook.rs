@@ -0,0 +1,439 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode, middleware,
+    routing::post, Json, Router
+};
+use sqlx::types::Uuid;
+use voda_common::get_current_timestamp;
+use voda_database::{QueryCriteria, SqlxFilterQuery, SqlxCrud};
+use voda_runtime::{RuntimeClient, User};
+use voda_runtime_roleplay::{
+    Character, CharacterRevision, CharacterStatus, Lorebook, LorebookEntry, LorebookPosition, ModerationTarget,
+    RoleplaySession, LOREBOOK_DEFAULT_SCAN_DEPTH
+};
+
+use super::character::commit_character_edit;
+use crate::{
+    auth::AuthUser,
+    ensure_account,
+    middleware::authenticate,
+    response::{AppError, AppSuccess},
+    GlobalState
+};
+
+pub fn lorebook_routes() -> Router<GlobalState> {
+    Router::new()
+        .route("/lorebook/create",
+            post(create_lorebook)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/lorebook/update/{lorebook_id}",
+            post(update_lorebook)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/lorebook/delete/{lorebook_id}",
+            post(delete_lorebook)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/lorebook/entry/create/{lorebook_id}",
+            post(create_entry)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/lorebook/entry/update/{entry_id}",
+            post(update_entry)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/lorebook/entry/delete/{entry_id}",
+            post(delete_entry)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/lorebook/attach_character/{lorebook_id}",
+            post(attach_character)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/lorebook/attach_session/{lorebook_id}",
+            post(attach_session)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateLorebookRequest {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub scan_depth: Option<i32>,
+    /// 0 for no limit
+    #[serde(default)]
+    pub token_budget: i32,
+}
+async fn create_lorebook(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Json(payload): Json<CreateLorebookRequest>,
+) -> Result<AppSuccess, AppError> {
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    if payload.name.trim().is_empty() {
+        return Err(AppError::new(StatusCode::BAD_REQUEST, anyhow!("[create_lorebook] Lorebook name must not be empty")));
+    }
+
+    let lorebook = Lorebook {
+        id: Uuid::default(),
+        owner: user.id,
+        name: payload.name,
+        description: payload.description,
+        scan_depth: payload.scan_depth.unwrap_or(LOREBOOK_DEFAULT_SCAN_DEPTH).max(0),
+        token_budget: payload.token_budget.max(0),
+        updated_at: get_current_timestamp(),
+        created_at: get_current_timestamp(),
+    }.create(&**state.roleplay_client.get_db()).await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Lorebook created successfully", json!({
+        "lorebook_id": lorebook.id,
+    })))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateLorebookRequest {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub scan_depth: Option<i32>,
+    pub token_budget: Option<i32>,
+}
+async fn update_lorebook(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Path(lorebook_id): Path<Uuid>,
+    Json(payload): Json<UpdateLorebookRequest>,
+) -> Result<AppSuccess, AppError> {
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let mut tx = state.roleplay_client.get_db().begin().await?;
+    let mut lorebook = find_own_lorebook(&user, lorebook_id, &mut tx).await?;
+    let attached = pin_attached_characters(&user, lorebook.id, &mut tx).await?;
+    if let Some(name) = payload.name {
+        if name.trim().is_empty() {
+            return Err(AppError::new(StatusCode::BAD_REQUEST, anyhow!("[update_lorebook] Lorebook name must not be empty")));
+        }
+        lorebook.name = name;
+    }
+    if let Some(description) = payload.description { lorebook.description = description; }
+    if let Some(scan_depth) = payload.scan_depth { lorebook.scan_depth = scan_depth.max(0); }
+    if let Some(token_budget) = payload.token_budget { lorebook.token_budget = token_budget.max(0); }
+    lorebook.updated_at = get_current_timestamp();
+    lorebook.update(&mut *tx).await?;
+    let unlisted = commit_lore_edit(&state, &user, attached, None, &mut tx).await?;
+    tx.commit().await?;
+    reindex(&state, &unlisted).await;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Lorebook updated successfully", json!(())))
+}
+
+async fn delete_lorebook(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Path(lorebook_id): Path<Uuid>,
+) -> Result<AppSuccess, AppError> {
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let mut tx = state.roleplay_client.get_db().begin().await?;
+    let lorebook = find_own_lorebook(&user, lorebook_id, &mut tx).await?;
+    let attached = pin_attached_characters(&user, lorebook.id, &mut tx).await?;
+    LorebookEntry::delete_by_criteria(
+        QueryCriteria::new().add_valued_filter("lorebook_id", "=", lorebook.id)?,
+        &mut *tx
+    ).await?;
+    // sessions still listing the lorebook simply find nothing under its id
+    let lorebook_id = lorebook.id;
+    lorebook.delete(&mut *tx).await?;
+    let unlisted = commit_lore_edit(&state, &user, attached, Some(lorebook_id), &mut tx).await?;
+    tx.commit().await?;
+    reindex(&state, &unlisted).await;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Lorebook deleted successfully", json!(())))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LorebookEntryRequest {
+    pub keys: Vec<String>,
+    #[serde(default)]
+    pub use_regex: bool,
+    #[serde(default)]
+    pub case_sensitive: bool,
+    #[serde(default)]
+    pub constant: bool,
+    pub enabled: Option<bool>,
+    pub content: String,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default)]
+    pub position: LorebookPosition,
+}
+impl LorebookEntryRequest {
+    fn apply_to(self, entry: &mut LorebookEntry) -> Result<(), AppError> {
+        entry.keys = self.keys;
+        entry.use_regex = self.use_regex;
+        entry.case_sensitive = self.case_sensitive;
+        entry.constant = self.constant;
+        entry.enabled = self.enabled.unwrap_or(true);
+        entry.content = self.content;
+        entry.priority = self.priority;
+        entry.position = self.position;
+        entry.updated_at = get_current_timestamp();
+        entry.validate()?;
+        Ok(())
+    }
+}
+
+async fn create_entry(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Path(lorebook_id): Path<Uuid>,
+    Json(payload): Json<LorebookEntryRequest>,
+) -> Result<AppSuccess, AppError> {
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let mut tx = state.roleplay_client.get_db().begin().await?;
+    let lorebook = find_own_lorebook(&user, lorebook_id, &mut tx).await?;
+    let attached = pin_attached_characters(&user, lorebook.id, &mut tx).await?;
+    let mut entry = LorebookEntry {
+        lorebook_id: lorebook.id,
+        created_at: get_current_timestamp(),
+        ..Default::default()
+    };
+    payload.apply_to(&mut entry)?;
+    screen_entries(&state, &user, lorebook.id, std::slice::from_mut(&mut entry)).await?;
+    let entry = entry.create(&mut *tx).await?;
+    let unlisted = commit_lore_edit(&state, &user, attached, None, &mut tx).await?;
+    tx.commit().await?;
+    reindex(&state, &unlisted).await;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Lorebook entry created successfully", json!({
+        "entry_id": entry.id,
+    })))
+}
+
+async fn update_entry(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Path(entry_id): Path<Uuid>,
+    Json(payload): Json<LorebookEntryRequest>,
+) -> Result<AppSuccess, AppError> {
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let mut tx = state.roleplay_client.get_db().begin().await?;
+    let mut entry = find_own_entry(&user, entry_id, &mut tx).await?;
+    let attached = pin_attached_characters(&user, entry.lorebook_id, &mut tx).await?;
+    payload.apply_to(&mut entry)?;
+    screen_entries(&state, &user, entry.lorebook_id, std::slice::from_mut(&mut entry)).await?;
+    entry.update(&mut *tx).await?;
+    let unlisted = commit_lore_edit(&state, &user, attached, None, &mut tx).await?;
+    tx.commit().await?;
+    reindex(&state, &unlisted).await;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Lorebook entry updated successfully", json!(())))
+}
+
+async fn delete_entry(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Path(entry_id): Path<Uuid>,
+) -> Result<AppSuccess, AppError> {
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let mut tx = state.roleplay_client.get_db().begin().await?;
+    let entry = find_own_entry(&user, entry_id, &mut tx).await?;
+    let attached = pin_attached_characters(&user, entry.lorebook_id, &mut tx).await?;
+    entry.delete(&mut *tx).await?;
+    let unlisted = commit_lore_edit(&state, &user, attached, None, &mut tx).await?;
+    tx.commit().await?;
+    reindex(&state, &unlisted).await;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Lorebook entry deleted successfully", json!(())))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AttachCharacterRequest {
+    pub character_id: Uuid,
+    /// false to detach the lorebook
+    pub attach: bool,
+}
+async fn attach_character(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Path(lorebook_id): Path<Uuid>,
+    Json(payload): Json<AttachCharacterRequest>,
+) -> Result<AppSuccess, AppError> {
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let mut tx = state.roleplay_client.get_db().begin().await?;
+    let lorebook = find_own_lorebook(&user, lorebook_id, &mut tx).await?;
+    let character = Character::find_one_by_criteria(
+        QueryCriteria::new()
+            .add_valued_filter("id", "=", payload.character_id)?
+            .add_valued_filter("creator", "=", user.id)?,
+        &mut *tx
+    ).await?
+        .ok_or(AppError::new(StatusCode::NOT_FOUND, anyhow!("[attach_character] Character not found")))?;
+
+    // lorebooks are part of how the character plays, so attaching one makes a new revision
+    let mut edited = character.clone();
+    if !toggle(&mut edited.lorebooks, lorebook.id, payload.attach) {
+        tx.commit().await?;
+        return Ok(AppSuccess::new(StatusCode::OK, "Character lorebooks updated successfully", json!(character.lorebooks)));
+    }
+    if payload.attach {
+        // entries saved before they were screened go through it now
+        let mut entries = LorebookEntry::find_by_criteria(
+            QueryCriteria::new().add_valued_filter("lorebook_id", "=", lorebook.id)?,
+            &mut *tx
+        ).await?;
+        let before = entries.clone();
+        screen_entries(&state, &user, lorebook.id, &mut entries).await?;
+        for (entry, _) in entries.into_iter().zip(before).filter(|(entry, before)| entry != before) {
+            entry.update(&mut *tx).await?;
+        }
+    }
+    let edited = commit_character_edit(&state, &user, &character, edited, &mut tx).await?;
+    tx.commit().await?;
+
+    if character.status == CharacterStatus::Published {
+        if let Err(e) = state.roleplay_client.recommender().index(&edited).await {
+            tracing::warn!("[attach_character] Failed to index character {}: {}", edited.id, e);
+        }
+    }
+
+    Ok(AppSuccess::new(StatusCode::OK, "Character lorebooks updated successfully", json!(edited.lorebooks)))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AttachSessionRequest {
+    pub session_id: Uuid,
+    /// false to detach the lorebook
+    pub attach: bool,
+}
+async fn attach_session(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Path(lorebook_id): Path<Uuid>,
+    Json(payload): Json<AttachSessionRequest>,
+) -> Result<AppSuccess, AppError> {
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let mut tx = state.roleplay_client.get_db().begin().await?;
+    let lorebook = find_own_lorebook(&user, lorebook_id, &mut tx).await?;
+    let mut session = RoleplaySession::find_one_by_criteria(
+        QueryCriteria::new()
+            .add_valued_filter("id", "=", payload.session_id)?
+            .add_valued_filter("owner", "=", user.id)?,
+        &mut *tx
+    ).await?
+        .ok_or(AppError::new(StatusCode::NOT_FOUND, anyhow!("[attach_session] Session not found")))?;
+
+    let lorebooks = if toggle(&mut session.lorebooks, lorebook.id, payload.attach) {
+        session.update(&mut *tx).await?.lorebooks
+    } else {
+        session.lorebooks
+    };
+    tx.commit().await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Session lorebooks updated successfully", json!(lorebooks)))
+}
+
+/// Characters of `user` the lorebook is attached to. Their current revision is recorded before
+/// the lorebook changes, so sessions pinned to it keep the lore they started with.
+async fn pin_attached_characters(
+    user: &User, lorebook_id: Uuid, tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<Vec<Character>, AppError> {
+    let characters = Character::find_by_criteria(
+        QueryCriteria::new()
+            .add_filter("lorebooks", " @> $1", Some(vec![lorebook_id]))?
+            .add_valued_filter("creator", "=", user.id)?,
+        &mut **tx
+    ).await?;
+    for character in &characters {
+        CharacterRevision::ensure_current(character, tx).await?;
+    }
+    Ok(characters)
+}
+
+/// Stores a new revision of every character in `attached` once their lorebook changed, dropping
+/// `detached` from them. Like any other edit, published characters go back to review; those are
+/// returned to be taken out of the recommendations.
+async fn commit_lore_edit(
+    state: &GlobalState, user: &User, attached: Vec<Character>, detached: Option<Uuid>,
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<Vec<Character>, AppError> {
+    let mut unlisted = vec![];
+    for character in attached {
+        let mut edited = character.clone();
+        if let Some(detached) = detached {
+            edited.lorebooks.retain(|id| id != &detached);
+        }
+        let edited = commit_character_edit(state, user, &character, edited, tx).await?;
+        if character.status == CharacterStatus::Published {
+            unlisted.push(edited);
+        }
+    }
+    Ok(unlisted)
+}
+
+async fn reindex(state: &GlobalState, characters: &[Character]) {
+    for character in characters {
+        // recommendations are best effort, a failed embedding only keeps the character out of them
+        if let Err(e) = state.roleplay_client.recommender().index(character).await {
+            tracing::warn!("[reindex] Failed to index character {}: {}", character.id, e);
+        }
+    }
+}
+
+/// Screens the content of `entries` of the lorebook, rewriting them in place
+async fn screen_entries(
+    state: &GlobalState, user: &User, lorebook_id: Uuid, entries: &mut [LorebookEntry],
+) -> Result<(), AppError> {
+    let moderation = state.roleplay_client.moderation();
+    let mut contents = entries.iter_mut().map(|entry| &mut entry.content).collect::<Vec<_>>();
+    let verdict = moderation.screen(&user.id, &mut contents).await?;
+    moderation.record(&verdict, ModerationTarget::LorebookEntry, Some(lorebook_id), None, &user.id).await?;
+    if verdict.is_blocked() {
+        return Err(AppError::new(StatusCode::UNPROCESSABLE_ENTITY, anyhow!("[screen_entries] Lorebook entry blocked by {}", verdict.rule_names())));
+    }
+    Ok(())
+}
+
+/// Adds or removes `id`, returns whether `ids` changed
+fn toggle(ids: &mut Vec<Uuid>, id: Uuid, present: bool) -> bool {
+    match (ids.contains(&id), present) {
+        (false, true) => { ids.push(id); true }
+        (true, false) => { ids.retain(|existing| existing != &id); true }
+        _ => false,
+    }
+}
+
+async fn find_own_lorebook(
+    user: &User, lorebook_id: Uuid, tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<Lorebook, AppError> {
+    Lorebook::find_one_by_criteria(
+        QueryCriteria::new()
+            .add_valued_filter("id", "=", lorebook_id)?
+            .add_valued_filter("owner", "=", user.id)?,
+        &mut **tx
+    ).await?
+        .ok_or(AppError::new(StatusCode::NOT_FOUND, anyhow!("[find_own_lorebook] Lorebook not found")))
+}
+
+async fn find_own_entry(
+    user: &User, entry_id: Uuid, tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<LorebookEntry, AppError> {
+    let entry = LorebookEntry::find_one_by_criteria(
+        QueryCriteria::new().add_valued_filter("id", "=", entry_id)?,
+        &mut **tx
+    ).await?
+        .ok_or(AppError::new(StatusCode::NOT_FOUND, anyhow!("[find_own_entry] Lorebook entry not found")))?;
+    find_own_lorebook(user, entry.lorebook_id, tx).await?;
+    Ok(entry)
+}
@@ -3,9 +3,15 @@ mod runtime;
 mod tts;
 mod graphql;
 mod user;
+mod lorebook;
+mod character;
+mod admin;
 
 pub use misc::misc_routes;
 pub use runtime::runtime_routes;
 pub use tts::voice_routes;
 pub use graphql::graphql_route;
-pub use user::user_routes;
\ No newline at end of file
+pub use user::user_routes;
+pub use lorebook::lorebook_routes;
+pub use character::character_routes;
+pub use admin::admin_routes;
\ No newline at end of file
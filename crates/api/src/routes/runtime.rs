@@ -1,19 +1,28 @@
+use std::convert::Infallible;
+
 use anyhow::anyhow;
+use futures::{stream, Stream};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use axum::{
-    extract::{Extension, Path, State}, 
+    extract::{Path, State}, 
     http::StatusCode, middleware, 
+    response::sse::{Event, KeepAlive, Sse},
     routing::post, Json, Router
 };
 use sqlx::types::Uuid;
-use voda_runtime::RuntimeClient;
+use voda_runtime::{decode_data_url, MessageType, RuntimeClient};
 use voda_runtime_character_creation::CharacterCreationMessage;
-use voda_runtime_roleplay::{Character, CharacterStatus, RoleplayMessage, RoleplaySession};
+use voda_runtime_roleplay::{
+    AuditLog, Character, CharacterRevision, CharacterSnapshot, CharacterStatus, ModerationAction, ModerationTarget,
+    RoleplayMessage, RoleplaySession, RoleplayStreamEvent, RoleplayTurnPolicy
+};
 use voda_database::{QueryCriteria, SqlxFilterQuery, SqlxCrud};
 use voda_runtime::SystemConfig;
 
+use super::{character::find_visible_character, user::find_own_persona};
 use crate::{
+    auth::AuthUser,
     ensure_account, 
     middleware::authenticate, 
     response::{AppError, AppSuccess},
@@ -32,11 +41,50 @@ pub fn runtime_routes() -> Router<GlobalState> {
             .route_layer(middleware::from_fn(authenticate))
         )
 
+        .route("/runtime/roleplay/chat_stream/{session_id}",
+            post(roleplay_chat_stream)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+
         .route("/runtime/roleplay/rollback/{session_id}",
             post(roleplay_rollback)
             .route_layer(middleware::from_fn(authenticate))
         )
 
+        .route("/runtime/roleplay/alternatives/{message_id}",
+            post(roleplay_list_alternatives)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+
+        .route("/runtime/roleplay/select_alternative/{session_id}",
+            post(roleplay_select_alternative)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+
+        .route("/runtime/roleplay/fork/{session_id}",
+            post(roleplay_fork_session)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+
+        .route("/runtime/roleplay/upgrade_characters/{session_id}",
+            post(roleplay_upgrade_characters)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/runtime/roleplay/set_persona/{session_id}",
+            post(roleplay_set_persona)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+
+        .route("/runtime/roleplay/edit_message/{message_id}",
+            post(roleplay_edit_message)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+
+        .route("/runtime/roleplay/delete_message/{message_id}",
+            post(roleplay_delete_message)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+
         .route("/runtime/character-creation/create",
             post(character_creation_create)
             .route_layer(middleware::from_fn(authenticate))
@@ -49,29 +97,58 @@ pub fn runtime_routes() -> Router<GlobalState> {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct CreateSessionRequest { pub character_id: Uuid, pub system_config_id: Uuid }
+pub struct CreateSessionRequest { 
+    pub character_id: Uuid, 
+    pub system_config_id: Uuid,
+    /// other characters joining a group session
+    #[serde(default)]
+    pub extra_character_ids: Vec<Uuid>,
+    #[serde(default)]
+    pub turn_policy: RoleplayTurnPolicy,
+    /// the persona to play, the default persona of the user when left out
+    #[serde(default)]
+    pub persona_id: Option<Uuid>,
+}
 async fn roleplay_create_session(
     State(state): State<GlobalState>,
-    Extension(user_id_str): Extension<String>,
+    auth_user: AuthUser,
     Json(payload): Json<CreateSessionRequest>,
 ) -> Result<AppSuccess, AppError> {
-    let user = ensure_account(&state.roleplay_client, &user_id_str, 1).await?
-        .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, anyhow!("[roleplay_create_session] User not found")))?;
+    let user = ensure_account(&state.roleplay_client, &auth_user, 1).await?;
 
     let mut tx = state.roleplay_client.get_db().begin().await?;
-    let _character = Character::find_one_by_criteria(
-        QueryCriteria::new().add_valued_filter("id", "=", payload.character_id)?,
-        &mut *tx
-    ).await?
-        .ok_or(AppError::new(StatusCode::NOT_FOUND, anyhow!("[roleplay_create_session] Character not found")))?;
+    let character = find_visible_character(&user.id, payload.character_id, &mut tx).await?;
     let _system_config = SystemConfig::find_one_by_criteria(
         QueryCriteria::new().add_valued_filter("id", "=", payload.system_config_id)?,
         &mut *tx
     ).await?
         .ok_or(AppError::new(StatusCode::NOT_FOUND, anyhow!("[roleplay_create_session] System config not found")))?;
 
+    // every character plays the version it has now until the user upgrades the session
+    let mut characters = vec![];
+    let mut character_revisions = vec![CharacterRevision::ensure_current(&character, &mut tx).await?.id];
+    if !payload.extra_character_ids.is_empty() {
+        characters.push(payload.character_id);
+        for character_id in payload.extra_character_ids {
+            if characters.contains(&character_id) {
+                continue;
+            }
+            let extra_character = find_visible_character(&user.id, character_id, &mut tx).await?;
+            characters.push(character_id);
+            character_revisions.push(CharacterRevision::ensure_current(&extra_character, &mut tx).await?.id);
+        }
+    }
+
+    if let Some(persona_id) = payload.persona_id {
+        find_own_persona(&user, persona_id, &mut tx).await?;
+    }
+
     let mut session = RoleplaySession::default();
     session.character = payload.character_id;
+    session.persona = payload.persona_id;
+    session.characters = characters;
+    session.character_revisions = character_revisions;
+    session.turn_policy = payload.turn_policy;
     session.system_config = payload.system_config_id;
     session.owner = user.id;
     session.create(&mut *tx).await?;
@@ -81,33 +158,105 @@ async fn roleplay_create_session(
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct ChatRequest { pub message: String }
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatAttachment {
+    Image { url: String },
+    Audio { url: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatRequest { 
+    pub message: String,
+    #[serde(default)]
+    pub attachment: Option<ChatAttachment>,
+    /// the character to reply in a group session, picked by the turn policy of the session when left out
+    #[serde(default)]
+    pub speaker: Option<Uuid>,
+}
+
+impl ChatRequest {
+    fn into_user_message(self, session_id: &Uuid, user_id: &Uuid) -> Result<RoleplayMessage, AppError> {
+        let mut message = RoleplayMessage::user_message(
+            &self.message, session_id, user_id
+        );
+        message.speaker = self.speaker;
+
+        (message.content_type, message.attachment) = match self.attachment {
+            None => (MessageType::Text, None),
+            Some(ChatAttachment::Image { url }) => {
+                let is_remote = url.starts_with("https://") || url.starts_with("http://");
+                if !is_remote && !is_data_url(&url, "data:image/") {
+                    return Err(AppError::new(StatusCode::BAD_REQUEST, anyhow!("[into_user_message] Image must be a http(s) url or a base64 data:image/ url")));
+                }
+                (MessageType::Image(url.clone()), Some(url))
+            }
+            Some(ChatAttachment::Audio { url }) => {
+                if !is_data_url(&url, "data:audio/") {
+                    return Err(AppError::new(StatusCode::BAD_REQUEST, anyhow!("[into_user_message] Audio must be a base64 data:audio/ url")));
+                }
+                (MessageType::Audio(url.clone()), Some(url))
+            }
+        };
+
+        Ok(message)
+    }
+}
+
+/// Remote images are fetched by the model provider, while audio is transcribed here and so is
+/// only accepted inline
+fn is_data_url(url: &str, prefix: &str) -> bool {
+    url.starts_with(prefix) && decode_data_url(url).is_some()
+}
 async fn roleplay_chat(
     State(state): State<GlobalState>,
-    Extension(user_id_str): Extension<String>,
+    auth_user: AuthUser,
     Path(session_id): Path<Uuid>,
     Json(payload): Json<ChatRequest>,
 ) -> Result<AppSuccess, AppError> {
-    let user = ensure_account(&state.roleplay_client, &user_id_str, 1).await?
-        .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, anyhow!("[roleplay_chat] User not found")))?;
+    let user = ensure_account(&state.roleplay_client, &auth_user, 1).await?;
 
-    let message = RoleplayMessage::user_message(
-        &payload.message, &session_id,  &user.id
-    );
+    let message = payload.into_user_message(&session_id, &user.id)?;
 
     let response = state.roleplay_client.on_new_message(&message).await?;
 
     Ok(AppSuccess::new(StatusCode::OK, "Chat completed successfully", json!(response)))
 }
 
+async fn roleplay_chat_stream(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Path(session_id): Path<Uuid>,
+    Json(payload): Json<ChatRequest>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
+    let user = ensure_account(&state.roleplay_client, &auth_user, 1).await?;
+
+    let message = payload.into_user_message(&session_id, &user.id)?;
+
+    let rx = state.roleplay_client.on_new_message_stream(&message).await?;
+    let events = stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|event| (Ok(to_sse_event(event)), rx))
+    });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+fn to_sse_event(event: RoleplayStreamEvent) -> Event {
+    match event {
+        RoleplayStreamEvent::Delta(delta) => Event::default().event("delta").data(delta),
+        RoleplayStreamEvent::Options(options) => Event::default().event("options").data(json!(options).to_string()),
+        RoleplayStreamEvent::Done(response) => Event::default().event("done").data(json!(response).to_string()),
+        RoleplayStreamEvent::Error(error) => Event::default().event("error").data(error),
+    }
+}
+
 async fn roleplay_rollback(
     State(state): State<GlobalState>,
-    Extension(user_id_str): Extension<String>,
+    auth_user: AuthUser,
     Path(session_id): Path<Uuid>,
 ) -> Result<AppSuccess, AppError> {
-    let user = ensure_account(&state.roleplay_client, &user_id_str, 1).await?
-        .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, anyhow!("[roleplay_rollback] User not found")))?;
+    let user = ensure_account(&state.roleplay_client, &auth_user, 1).await?;
 
+    // only identifies the session and the caller, the reply is regenerated for the stored user message
     let message = RoleplayMessage::user_message(
         "rollback", &session_id,  &user.id
     );
@@ -117,16 +266,164 @@ async fn roleplay_rollback(
     Ok(AppSuccess::new(StatusCode::OK, "Last message regenerated successfully", json!(response)))
 }
 
+async fn roleplay_list_alternatives(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Path(message_id): Path<Uuid>,
+) -> Result<AppSuccess, AppError> {
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let (alternatives, selected) = state.roleplay_client.list_alternatives(&user.id, &message_id).await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Alternatives retrieved successfully", json!({
+        "alternatives": alternatives,
+        "selected": selected,
+    })))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SelectAlternativeRequest { pub index: usize }
+async fn roleplay_select_alternative(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Path(session_id): Path<Uuid>,
+    Json(payload): Json<SelectAlternativeRequest>,
+) -> Result<AppSuccess, AppError> {
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let message = state.roleplay_client.select_alternative(&user.id, &session_id, payload.index).await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Alternative selected successfully", json!(message)))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ForkSessionRequest { pub message_id: Uuid }
+async fn roleplay_fork_session(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Path(session_id): Path<Uuid>,
+    Json(payload): Json<ForkSessionRequest>,
+) -> Result<AppSuccess, AppError> {
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let session = state.roleplay_client.fork_session(&user.id, &session_id, &payload.message_id).await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Session forked successfully", json!(session)))
+}
+
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetPersonaRequest { pub persona_id: Option<Uuid> }
+async fn roleplay_set_persona(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Path(session_id): Path<Uuid>,
+    Json(payload): Json<SetPersonaRequest>,
+) -> Result<AppSuccess, AppError> {
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let mut tx = state.roleplay_client.get_db().begin().await?;
+    let mut session = RoleplaySession::find_one_by_criteria(
+        QueryCriteria::new()
+            .add_valued_filter("id", "=", session_id)?
+            .add_valued_filter("owner", "=", user.id)?,
+        &mut *tx
+    ).await?
+        .ok_or(AppError::new(StatusCode::NOT_FOUND, anyhow!("[roleplay_set_persona] Session not found")))?;
+
+    if let Some(persona_id) = payload.persona_id {
+        find_own_persona(&user, persona_id, &mut tx).await?;
+    }
+    session.persona = payload.persona_id;
+    session.update(&mut *tx).await?;
+    tx.commit().await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Session persona updated successfully", json!(())))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpgradeCharactersRequest {
+    /// the characters to move to their latest version, every character of the session when empty
+    #[serde(default)]
+    pub character_ids: Vec<Uuid>,
+}
+async fn roleplay_upgrade_characters(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Path(session_id): Path<Uuid>,
+    Json(payload): Json<UpgradeCharactersRequest>,
+) -> Result<AppSuccess, AppError> {
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let mut tx = state.roleplay_client.get_db().begin().await?;
+    let mut session = RoleplaySession::find_one_by_criteria(
+        QueryCriteria::new()
+            .add_valued_filter("id", "=", session_id)?
+            .add_valued_filter("owner", "=", user.id)?,
+        &mut *tx
+    ).await?
+        .ok_or(AppError::new(StatusCode::NOT_FOUND, anyhow!("[roleplay_upgrade_characters] Session not found")))?;
+
+    let pinned = CharacterRevision::upgrade(&mut session, &payload.character_ids, &mut tx).await?;
+    tx.commit().await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Session characters upgraded successfully", json!(
+        pinned.iter()
+            .map(|revision| json!({ "character_id": revision.character_id, "version": revision.version }))
+            .collect::<Vec<_>>()
+    )))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EditMessageRequest { 
+    pub content: String,
+    /// drop every message after the edited one
+    #[serde(default)]
+    pub truncate: bool,
+}
+async fn roleplay_edit_message(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Path(message_id): Path<Uuid>,
+    Json(payload): Json<EditMessageRequest>,
+) -> Result<AppSuccess, AppError> {
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    if payload.content.trim().is_empty() {
+        return Err(AppError::new(StatusCode::BAD_REQUEST, anyhow!("[roleplay_edit_message] Content must not be empty")));
+    }
+
+    let message = state.roleplay_client.edit_message(&user.id, &message_id, payload.content, payload.truncate).await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Message edited successfully", json!(message)))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteMessageRequest { 
+    /// drop every message after the deleted one as well
+    #[serde(default)]
+    pub truncate: bool,
+}
+async fn roleplay_delete_message(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Path(message_id): Path<Uuid>,
+    Json(payload): Json<DeleteMessageRequest>,
+) -> Result<AppSuccess, AppError> {
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let removed = state.roleplay_client.delete_message(&user.id, &message_id, payload.truncate).await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Message deleted successfully", json!(removed)))
+}
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateCharacterRequest { pub roleplay_session_id: Uuid }
 async fn character_creation_create(
     State(state): State<GlobalState>,
-    Extension(user_id_str): Extension<String>,
+    auth_user: AuthUser,
     Json(payload): Json<CreateCharacterRequest>,
 ) -> Result<AppSuccess, AppError> {
-    let user = ensure_account(&state.character_creation_client, &user_id_str, 1).await?
-        .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, anyhow!("[character_creation_create] User not found")))?;
+    let user = ensure_account(&state.character_creation_client, &auth_user, 1).await?;
 
     let message = CharacterCreationMessage::blank_user_message(
         &payload.roleplay_session_id, &user.id
@@ -138,21 +435,36 @@ async fn character_creation_create(
 
 async fn character_creation_review(
     State(state): State<GlobalState>,
-    Extension(user_id_str): Extension<String>,
+    auth_user: AuthUser,
     Path(character_id): Path<Uuid>,
 ) -> Result<AppSuccess, AppError> {
-    let _user = ensure_account(&state.character_creation_client, &user_id_str, 1).await?
-        .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, anyhow!("[character_creation_review] User not found")))?;
+    let user = ensure_account(&state.character_creation_client, &auth_user, 1).await?;
 
     let mut tx = state.character_creation_client.get_db().begin().await?;
     let mut character = Character::find_one_by_criteria(
-        QueryCriteria::new().add_filter("id", "=", Some(character_id))?,
+        QueryCriteria::new()
+            .add_valued_filter("id", "=", character_id)?
+            .add_valued_filter("creator", "=", user.id)?,
         &mut *tx
     ).await?
         .ok_or(AppError::new(StatusCode::NOT_FOUND, anyhow!("[character_creation_review] Character not found")))?;
 
-    character.status = CharacterStatus::Reviewing;
-    character.update(&mut *tx).await?;
+    let moderation = state.roleplay_client.moderation();
+    let before = character.clone();
+    let verdict = moderation.screen_character(&user.id, &mut character).await?;
+    moderation.record(&verdict, ModerationTarget::Character, Some(character.id), None, &user.id).await?;
+    if verdict.is_blocked() {
+        return Err(AppError::new(StatusCode::UNPROCESSABLE_ENTITY, anyhow!("[character_creation_review] Character blocked by {}", verdict.rule_names())));
+    }
+    if CharacterSnapshot::of(&character) != CharacterSnapshot::of(&before) {
+        (character, _) = CharacterRevision::commit_edit(&before, character, &user.id, &mut tx).await?;
+    }
+
+    let notes = match verdict.action() {
+        Some(ModerationAction::Flag) => format!("submitted for review, flagged by {}", verdict.rule_names()),
+        _ => "submitted for review".to_string(),
+    };
+    AuditLog::transition(&mut character, CharacterStatus::Reviewing, &user, &notes, &mut tx).await?;
     tx.commit().await?;
 
     Ok(AppSuccess::new(StatusCode::OK, "Character creation review completed successfully", json!(())))
@@ -1,6 +1,6 @@
 use anyhow::anyhow;
 use axum::{
-    extract::{Extension, Path, State}, 
+    extract::{Path, State}, 
     http::StatusCode, middleware, response::IntoResponse, routing::post, Json, Router
 };
 use sqlx::types::Uuid;
@@ -11,6 +11,7 @@ use voda_database::{QueryCriteria, SqlxFilterQuery};
 use voda_runtime_roleplay::{Character, CharacterFeature};
 
 use crate::{
+    auth::AuthUser,
     ensure_account, 
     middleware::authenticate, 
     voice::TTSRequest,
@@ -28,12 +29,11 @@ pub fn voice_routes() -> Router<GlobalState> {
 
 async fn tts(
     State(state): State<GlobalState>,
-    Extension(user_id_str): Extension<String>,
+    auth_user: AuthUser,
     Path(character_id): Path<Uuid>,
     Json(value): Json<Value>,
 ) -> Result<impl IntoResponse, AppError> {
-    ensure_account(&state.roleplay_client, &user_id_str, 5).await?
-        .ok_or(AppError::new(StatusCode::FORBIDDEN, anyhow!("[/tts] user not found")))?;
+    ensure_account(&state.roleplay_client, &auth_user, 5).await?;
 
     let message = value["message"].as_str().ok_or(anyhow!("[/tts] message is required"))?.to_string();
     let character = Character::find_one_by_criteria(
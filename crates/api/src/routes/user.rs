@@ -2,17 +2,23 @@ use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use axum::{
-    extract::{Extension, State}, 
+    extract::{Path, State}, 
     http::StatusCode, middleware, 
     routing::post, Json, Router
 };
 use sqlx::types::Uuid;
-use voda_common::get_current_timestamp;
-use voda_database::{QueryCriteria, SqlxFilterQuery, SqlxCrud};
-use voda_runtime::{user::{UserReferral, UserUrl}, RuntimeClient, User, UserFollow};
+use voda_common::{get_current_timestamp, EnvVars};
+use voda_database::{OrderDirection, QueryCriteria, SqlxFilterQuery, SqlxCrud};
+use voda_runtime::{
+    user::{UserReferral, UserUrl, AUTH_SCOPE_ALL},
+    AuthClaims, AuthTokenKind, AuthTokenPair, LoginChallenge, LoginProof, LoginProvider, RevokedToken,
+    RuntimeClient, User, UserApiKey, UserFollow, UserPersona, UserRole
+};
 
 use crate::{
     ensure_account, 
+    auth::AuthUser,
+    env::ApiServerEnv,
     middleware::authenticate, 
     response::{AppError, AppSuccess},
     GlobalState
@@ -26,6 +32,37 @@ pub fn user_routes() -> Router<GlobalState> {
         .route("/user/register",
             post(register)
         )
+
+        .route("/user/auth/email/start",
+            post(start_email_login)
+        )
+        .route("/user/auth/siwe/nonce",
+            post(siwe_nonce)
+        )
+        .route("/user/auth/token",
+            post(issue_token)
+        )
+        .route("/user/auth/refresh",
+            post(refresh_token)
+        )
+        .route("/user/auth/logout",
+            post(logout)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+
+        .route("/user/api_key/create",
+            post(create_api_key)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/user/api_key/list",
+            post(list_api_keys)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/user/api_key/revoke/{key_id}",
+            post(revoke_api_key)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+
         .route("/user/update_profile",
             post(update_profile)
             .route_layer(middleware::from_fn(authenticate))
@@ -47,37 +84,62 @@ pub fn user_routes() -> Router<GlobalState> {
             post(follow)
             .route_layer(middleware::from_fn(authenticate))
         )
+
+        .route("/user/persona/create",
+            post(create_persona)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/user/persona/update/{persona_id}",
+            post(update_persona)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/user/persona/delete/{persona_id}",
+            post(delete_persona)
+            .route_layer(middleware::from_fn(authenticate))
+        )
+        .route("/user/persona/set_default",
+            post(set_default_persona)
+            .route_layer(middleware::from_fn(authenticate))
+        )
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TryLoginRequest {
-    pub user_id: String,
+    pub proof: LoginProof,
 }
 
+/// Logs in with a verified identity, or reports that it has to register first. The challenge of
+/// the proof is only consumed on a successful login, so the same proof can then be used to register.
 async fn try_login(
     State(state): State<GlobalState>,
     Json(payload): Json<TryLoginRequest>,
 ) -> Result<AppSuccess, AppError> {
+    let env = ApiServerEnv::load();
     let mut tx = state.roleplay_client.get_db().begin().await?;
+    let identity = payload.proof.verify(&env.identity_config(), &mut tx).await
+        .map_err(|e| AppError::new(StatusCode::UNAUTHORIZED, e))?;
 
     // 1. check if the user already exists
     let user = User::find_one_by_criteria(
-        QueryCriteria::new().add_valued_filter("user_id", "=", payload.user_id.clone())?,
+        QueryCriteria::new().add_valued_filter("user_id", "=", identity.user_id())?,
         &mut *tx
     ).await?;
 
     match user {
         Some(mut user) => {
             let _ = user.try_claim_free_balance(100); // whatever, we don't care about the error
-            user.update(&mut *tx).await?;
+            let user = user.update(&mut *tx).await?;
             tx.commit().await?;
+
+            let tokens = AuthTokenPair::issue(&user, vec![AUTH_SCOPE_ALL.to_string()], &env.auth_token_config())?;
             return Ok(AppSuccess::new(
                 StatusCode::OK, 
                 "User already exists", 
-                json!({ "registration_required": false })
+                json!({ "registration_required": false, "tokens": tokens })
             ));
         }
         None => {
+            tx.rollback().await?;
             return Ok(AppSuccess::new(
                 StatusCode::OK, 
                 "[/user/try_login] User not found", 
@@ -89,20 +151,22 @@ async fn try_login(
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RegisterRequest {
-    pub user_id: String,
+    pub proof: LoginProof,
     pub referral_code: String,
-    pub provider: String,
 }
 
 async fn register(
     State(state): State<GlobalState>,
     Json(payload): Json<RegisterRequest>,
 ) -> Result<AppSuccess, AppError> {
+    let env = ApiServerEnv::load();
     let mut tx = state.roleplay_client.get_db().begin().await?;
+    let identity = payload.proof.verify(&env.identity_config(), &mut tx).await
+        .map_err(|e| AppError::new(StatusCode::UNAUTHORIZED, e))?;
 
     // 1. check if the user already exists
     let user = User::find_one_by_criteria(
-        QueryCriteria::new().add_valued_filter("user_id", "=", payload.user_id.clone())?,
+        QueryCriteria::new().add_valued_filter("user_id", "=", identity.user_id())?,
         &mut *tx
     ).await?;
 
@@ -121,9 +185,12 @@ async fn register(
     }
 
     let mut user = User::default();
-    user.user_id = payload.user_id.clone();
-    user.user_aka = "nono".to_string();
-    user.provider = payload.provider.clone();
+    user.user_id = identity.user_id();
+    user.user_aka = identity.display_name.clone().unwrap_or("nono".to_string());
+    user.provider = identity.provider.to_string();
+    if identity.provider == LoginProvider::Email {
+        user.email = Some(identity.subject.clone());
+    }
     let _ = user.try_claim_free_balance(100); // infallable
     let user = user.create(&mut *tx).await?;
 
@@ -132,7 +199,214 @@ async fn register(
     referral_code.update(&mut *tx).await?;
     tx.commit().await?;
 
-    Ok(AppSuccess::new(StatusCode::OK, "User registered successfully", json!(())))
+    let tokens = AuthTokenPair::issue(&user, vec![AUTH_SCOPE_ALL.to_string()], &env.auth_token_config())?;
+    Ok(AppSuccess::new(StatusCode::OK, "User registered successfully", json!({ "tokens": tokens })))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StartEmailLoginRequest {
+    pub email: String,
+}
+async fn start_email_login(
+    State(state): State<GlobalState>,
+    Json(payload): Json<StartEmailLoginRequest>,
+) -> Result<AppSuccess, AppError> {
+    let env = ApiServerEnv::load();
+    let mut tx = state.roleplay_client.get_db().begin().await?;
+    let sent = LoginChallenge::start_email_login(&payload.email, &env.get_env_var("LOGIN_LINK_BASE_URL"), &mut tx).await?;
+    tx.commit().await?;
+
+    if !sent {
+        return Err(AppError::new(StatusCode::TOO_MANY_REQUESTS, anyhow!("[start_email_login] Too many login links sent to this address, try again later")));
+    }
+
+    Ok(AppSuccess::new(StatusCode::OK, "Login link sent successfully", json!(())))
+}
+
+async fn siwe_nonce(
+    State(state): State<GlobalState>,
+) -> Result<AppSuccess, AppError> {
+    let mut tx = state.roleplay_client.get_db().begin().await?;
+    let nonce = LoginChallenge::issue_siwe_nonce(&mut tx).await?;
+    tx.commit().await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Nonce issued successfully", json!({
+        "nonce": nonce,
+        "domain": ApiServerEnv::load().get_env_var("SIWE_DOMAIN"),
+    })))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IssueTokenRequest {
+    /// a fresh `User::generate_auth_token`, minted by a trusted frontend holding `SECRET_SALT`
+    pub login_token: String,
+}
+async fn issue_token(
+    State(state): State<GlobalState>,
+    Json(payload): Json<IssueTokenRequest>,
+) -> Result<AppSuccess, AppError> {
+    let env = ApiServerEnv::load();
+    if !env.legacy_auth_token_enabled() {
+        return Err(AppError::new(StatusCode::NOT_FOUND, anyhow!("[issue_token] Legacy login tokens are disabled")));
+    }
+    let user_id = User::verify_auth_token(&payload.login_token, &env.get_env_var("SECRET_SALT"))
+        .map_err(|e| AppError::new(StatusCode::UNAUTHORIZED, e))?;
+
+    let user = User::find_one_by_criteria(
+        QueryCriteria::new().add_valued_filter("user_id", "=", user_id)?,
+        &**state.roleplay_client.get_db()
+    ).await?
+        .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, anyhow!("[issue_token] User not found")))?;
+
+    let tokens = AuthTokenPair::issue(&user, vec![AUTH_SCOPE_ALL.to_string()], &env.auth_token_config())?;
+    Ok(AppSuccess::new(StatusCode::OK, "Tokens issued successfully", json!(tokens)))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefreshTokenRequest {
+    pub refresh_token: String,
+}
+async fn refresh_token(
+    State(state): State<GlobalState>,
+    Json(payload): Json<RefreshTokenRequest>,
+) -> Result<AppSuccess, AppError> {
+    let config = ApiServerEnv::load().auth_token_config();
+    let claims = AuthClaims::verify(&payload.refresh_token, &config, AuthTokenKind::Refresh)
+        .map_err(|e| AppError::new(StatusCode::UNAUTHORIZED, e))?;
+
+    let mut tx = state.roleplay_client.get_db().begin().await?;
+    if RevokedToken::is_revoked(&claims.jti, &mut *tx).await? {
+        return Err(AppError::new(StatusCode::UNAUTHORIZED, anyhow!("[refresh_token] Token has been revoked")));
+    }
+
+    // re-read the user, the role may have changed since the refresh token was issued
+    let user = User::find_one_by_criteria(
+        QueryCriteria::new().add_valued_filter("id", "=", claims.sub)?,
+        &mut *tx
+    ).await?
+        .ok_or_else(|| AppError::new(StatusCode::UNAUTHORIZED, anyhow!("[refresh_token] User not found")))?;
+
+    // refresh tokens are single use, the new pair replaces them
+    RevokedToken::revoke(&claims, &mut tx).await?;
+    let tokens = AuthTokenPair::issue(&user, claims.scopes.clone(), &config)?;
+    tx.commit().await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Tokens refreshed successfully", json!(tokens)))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogoutRequest {
+    pub refresh_token: Option<String>,
+}
+async fn logout(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Json(payload): Json<LogoutRequest>,
+) -> Result<AppSuccess, AppError> {
+    if auth_user.api_key.is_some() {
+        return Err(AppError::new(StatusCode::BAD_REQUEST, anyhow!("[logout] API keys are revoked with /user/api_key/revoke")));
+    }
+    let claims = auth_user.claims
+        .ok_or_else(|| AppError::new(StatusCode::UNAUTHORIZED, anyhow!("[logout] Logging out requires an access token")))?;
+
+    let mut tx = state.roleplay_client.get_db().begin().await?;
+    RevokedToken::revoke(&claims, &mut tx).await?;
+
+    if let Some(refresh_token) = payload.refresh_token {
+        let refresh_claims = AuthClaims::verify(&refresh_token, &ApiServerEnv::load().auth_token_config(), AuthTokenKind::Refresh)
+            .map_err(|e| AppError::new(StatusCode::UNAUTHORIZED, e))?;
+        if refresh_claims.sub != claims.sub {
+            return Err(AppError::new(StatusCode::FORBIDDEN, anyhow!("[logout] Refresh token belongs to another user")));
+        }
+        RevokedToken::revoke(&refresh_claims, &mut tx).await?;
+    }
+    tx.commit().await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Logged out successfully", json!(())))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<i64>,
+    /// lets the key use the admin role, for admins only
+    #[serde(default)]
+    pub admin: bool,
+}
+async fn create_api_key(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Json(payload): Json<CreateApiKeyRequest>,
+) -> Result<AppSuccess, AppError> {
+    // a leaked key must not be able to mint more keys
+    if auth_user.api_key.is_some() {
+        return Err(AppError::new(StatusCode::FORBIDDEN, anyhow!("[create_api_key] API keys cannot create API keys")));
+    }
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let role = if payload.admin { UserRole::Admin } else { UserRole::User };
+    let (api_key, key) = UserApiKey::generate(&user, payload.name, payload.scopes, role, payload.expires_at)
+        .map_err(|e| AppError::new(StatusCode::BAD_REQUEST, e))?;
+    let api_key = api_key.create(&**state.roleplay_client.get_db()).await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "API key created successfully", json!({
+        "api_key_id": api_key.id,
+        "prefix": api_key.prefix,
+        // only ever shown here
+        "key": key,
+    })))
+}
+
+async fn list_api_keys(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+) -> Result<AppSuccess, AppError> {
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let api_keys = UserApiKey::find_by_criteria(
+        QueryCriteria::new()
+            .add_valued_filter("owner", "=", user.id)?
+            .order_by("created_at", OrderDirection::Desc)?,
+        &**state.roleplay_client.get_db()
+    ).await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "API keys listed successfully", json!(api_keys.into_iter().map(|api_key| json!({
+        "api_key_id": api_key.id,
+        "name": api_key.name,
+        "prefix": api_key.prefix,
+        "scopes": api_key.scopes,
+        "role": api_key.role,
+        "expires_at": api_key.expires_at,
+        "last_used_at": api_key.last_used_at,
+        "revoked_at": api_key.revoked_at,
+        "created_at": api_key.created_at,
+    })).collect::<Vec<_>>())))
+}
+
+async fn revoke_api_key(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Path(key_id): Path<Uuid>,
+) -> Result<AppSuccess, AppError> {
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let mut tx = state.roleplay_client.get_db().begin().await?;
+    let mut api_key = UserApiKey::find_one_by_criteria(
+        QueryCriteria::new()
+            .add_valued_filter("id", "=", key_id)?
+            .add_valued_filter("owner", "=", user.id)?,
+        &mut *tx
+    ).await?
+        .ok_or(AppError::new(StatusCode::NOT_FOUND, anyhow!("[revoke_api_key] API key not found")))?;
+
+    if api_key.revoked_at.is_none() {
+        api_key.revoked_at = Some(get_current_timestamp());
+        api_key.update(&mut *tx).await?;
+    }
+    tx.commit().await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "API key revoked successfully", json!(())))
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -147,11 +421,10 @@ pub struct UpdateProfileRequest {
 }
 async fn update_profile(
     State(state): State<GlobalState>,
-    Extension(user_id_str): Extension<String>,
+    auth_user: AuthUser,
     Json(payload): Json<UpdateProfileRequest>,
 ) -> Result<AppSuccess, AppError> {
-    let mut user = ensure_account(&state.roleplay_client, &user_id_str, 0).await?
-        .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, anyhow!("[update_profile] User not found")))?;
+    let mut user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
 
     let mut tx = state.roleplay_client.get_db().begin().await?;
     user.user_aka = payload.user_aka.clone().unwrap_or(user.user_aka.clone());
@@ -171,10 +444,9 @@ async fn update_profile(
 
 async fn claim_free(
     State(state): State<GlobalState>,
-    Extension(user_id_str): Extension<String>,
+    auth_user: AuthUser,
 ) -> Result<AppSuccess, AppError> {
-    let mut user = ensure_account(&state.roleplay_client, &user_id_str, 0).await?
-        .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, anyhow!("[claim_free] User not found")))?;
+    let mut user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
 
     // TODO: technicaly - we should not use roleplay_client but a user db directly
     let mut tx = state.roleplay_client.get_db().begin().await?;
@@ -191,12 +463,11 @@ pub struct BuyReferralRequest {
 }
 async fn buy_referral(
     State(state): State<GlobalState>,
-    Extension(user_id_str): Extension<String>,
+    auth_user: AuthUser,
     Json(payload): Json<BuyReferralRequest>,
 ) -> Result<AppSuccess, AppError> {
     let count = payload.count.unwrap_or(1);
-    let mut user = ensure_account(&state.roleplay_client, &user_id_str, 0).await?
-        .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, anyhow!("[buy_referral] User not found")))?;
+    let mut user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
 
     let mut tx = state.roleplay_client.get_db().begin().await?;
 
@@ -218,11 +489,10 @@ pub struct CreateUrlRequest {
 }
 async fn create_url(
     State(state): State<GlobalState>,
-    Extension(user_id_str): Extension<String>,
+    auth_user: AuthUser,
     Json(payload): Json<CreateUrlRequest>,
 ) -> Result<AppSuccess, AppError> {
-    let user = ensure_account(&state.roleplay_client, &user_id_str, 0).await?
-        .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, anyhow!("[create_url] User not found")))?;
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
 
     let mut tx = state.roleplay_client.get_db().begin().await?;
     let url = UserUrl::new(user.id, payload.path, payload.url_type);
@@ -241,11 +511,10 @@ pub struct FollowRequest {
 
 async fn follow(
     State(state): State<GlobalState>,
-    Extension(user_id_str): Extension<String>,
+    auth_user: AuthUser,
     Json(payload): Json<FollowRequest>,
 ) -> Result<AppSuccess, AppError> {
-    let follower = ensure_account(&state.roleplay_client, &user_id_str, 0).await?
-        .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, anyhow!("[follow] User not found")))?;
+    let follower = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
 
     let mut tx = state.roleplay_client.get_db().begin().await?;
     let follow = UserFollow::new(follower.id, payload.following_id);
@@ -253,4 +522,129 @@ async fn follow(
     tx.commit().await?;
 
     Ok(AppSuccess::new(StatusCode::OK, "Followed successfully", json!(())))
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreatePersonaRequest {
+    pub name: String,
+    pub description: String,
+    pub pronouns: Option<String>,
+    pub appearance: Option<String>,
+    #[serde(default)]
+    pub set_default: bool,
+}
+async fn create_persona(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Json(payload): Json<CreatePersonaRequest>,
+) -> Result<AppSuccess, AppError> {
+    let mut user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    if payload.name.trim().is_empty() {
+        return Err(AppError::new(StatusCode::BAD_REQUEST, anyhow!("[create_persona] Persona name must not be empty")));
+    }
+
+    let mut tx = state.roleplay_client.get_db().begin().await?;
+    let mut persona = UserPersona::new(user.id, payload.name, payload.description);
+    persona.pronouns = payload.pronouns;
+    persona.appearance = payload.appearance;
+    let persona = persona.create(&mut *tx).await?;
+
+    if payload.set_default {
+        user.default_persona = Some(persona.id);
+        user.update(&mut *tx).await?;
+    }
+    tx.commit().await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Persona created successfully", json!({
+        "persona_id": persona.id,
+    })))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdatePersonaRequest {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub pronouns: Option<String>,
+    pub appearance: Option<String>,
+}
+async fn update_persona(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Path(persona_id): Path<Uuid>,
+    Json(payload): Json<UpdatePersonaRequest>,
+) -> Result<AppSuccess, AppError> {
+    let user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let mut tx = state.roleplay_client.get_db().begin().await?;
+    let mut persona = find_own_persona(&user, persona_id, &mut tx).await?;
+    if let Some(name) = payload.name {
+        if name.trim().is_empty() {
+            return Err(AppError::new(StatusCode::BAD_REQUEST, anyhow!("[update_persona] Persona name must not be empty")));
+        }
+        persona.name = name;
+    }
+    if let Some(description) = payload.description { persona.description = description; }
+    if payload.pronouns.is_some() { persona.pronouns = payload.pronouns; }
+    if payload.appearance.is_some() { persona.appearance = payload.appearance; }
+    persona.updated_at = get_current_timestamp();
+    persona.update(&mut *tx).await?;
+    tx.commit().await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Persona updated successfully", json!(())))
+}
+
+async fn delete_persona(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Path(persona_id): Path<Uuid>,
+) -> Result<AppSuccess, AppError> {
+    let mut user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let mut tx = state.roleplay_client.get_db().begin().await?;
+    let persona = find_own_persona(&user, persona_id, &mut tx).await?;
+    persona.delete(&mut *tx).await?;
+
+    // sessions using the persona fall back to the default one through their foreign key
+    if user.default_persona == Some(persona_id) {
+        user.default_persona = None;
+        user.update(&mut *tx).await?;
+    }
+    tx.commit().await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Persona deleted successfully", json!(())))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetDefaultPersonaRequest {
+    pub persona_id: Option<Uuid>,
+}
+async fn set_default_persona(
+    State(state): State<GlobalState>,
+    auth_user: AuthUser,
+    Json(payload): Json<SetDefaultPersonaRequest>,
+) -> Result<AppSuccess, AppError> {
+    let mut user = ensure_account(&state.roleplay_client, &auth_user, 0).await?;
+
+    let mut tx = state.roleplay_client.get_db().begin().await?;
+    if let Some(persona_id) = payload.persona_id {
+        find_own_persona(&user, persona_id, &mut tx).await?;
+    }
+    user.default_persona = payload.persona_id;
+    user.update(&mut *tx).await?;
+    tx.commit().await?;
+
+    Ok(AppSuccess::new(StatusCode::OK, "Default persona updated successfully", json!(())))
+}
+
+pub(crate) async fn find_own_persona(
+    user: &User, persona_id: Uuid, tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<UserPersona, AppError> {
+    UserPersona::find_one_by_criteria(
+        QueryCriteria::new()
+            .add_valued_filter("id", "=", persona_id)?
+            .add_valued_filter("owner", "=", user.id)?,
+        &mut **tx
+    ).await?
+        .ok_or(AppError::new(StatusCode::NOT_FOUND, anyhow!("[find_own_persona] Persona not found")))
+}
@@ -1,12 +1,11 @@
 use anyhow::anyhow;
-use axum::extract::Request;
-use axum::http::{header, StatusCode};
+use axum::http::{header, HeaderMap, StatusCode};
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
 
 use crate::response::AppError;
 
-pub fn extract_bearer_token(req: &Request) -> Result<String, AppError> {
-    let auth_header = req.headers().get(header::AUTHORIZATION);
+pub fn extract_bearer_token(headers: &HeaderMap) -> Result<String, AppError> {
+    let auth_header = headers.get(header::AUTHORIZATION);
 
     match auth_header {
         Some(value) => {
@@ -1,14 +1,13 @@
 use std::sync::Arc;
 
 use anyhow::Result;
-use async_openai::{config::OpenAIConfig, Client};
 
 use sqlx::types::{Json, Uuid};
 use sqlx::PgPool;
-use voda_common::{get_current_timestamp, EnvVars};
-use voda_runtime::{toolcalls, ExecutableFunctionCall, LLMRunResponse, Memory, MessageRole, MessageType, RuntimeClient, RuntimeEnv, SystemConfig, UserUsage};
+use voda_common::get_current_timestamp;
+use voda_runtime::{toolcalls, ExecutableFunctionCall, LLMRunResponse, LlmProvider, LlmProviderRegistry, Memory, MessageRole, MessageToolCall, MessageType, RuntimeClient, SystemConfig, UserUsage, VerbatimPromptVariables};
 use voda_database::{SqlxCrud, QueryCriteria, SqlxFilterQuery};
-use voda_runtime_roleplay::Character;
+use voda_runtime_roleplay::{Character, ModerationPipeline, ModerationTarget};
 
 use crate::memory::CharacterCreationMemory;
 use crate::{CharacterCreationMessage, preload, SummarizeCharacterToolCall};
@@ -24,29 +23,27 @@ toolcalls!(
 pub struct CharacterCreationRuntimeClient {
     db: Arc<PgPool>,
     memory: Arc<CharacterCreationMemory>,
-    client: Client<OpenAIConfig>,
+    providers: Arc<LlmProviderRegistry>,
+    moderation: ModerationPipeline,
+    system_config_name: String,
 }
 
 impl CharacterCreationRuntimeClient {
     pub async fn new(
         db: Arc<PgPool>,
         system_config_name: String,
+        providers: Arc<LlmProviderRegistry>,
     ) -> Result<Self> {
-        let env = RuntimeEnv::load();
-        let config = OpenAIConfig::new()
-            .with_api_key(env.get_env_var("OPENAI_API_KEY"))
-            .with_api_base(env.get_env_var("OPENAI_BASE_URL"));
-
-        let client = Client::build(
-            reqwest::Client::new(),
-            config,
-            Default::default()
-        );
-
         let mut character_creation_memory = CharacterCreationMemory::new(db.clone(), system_config_name.clone());
         character_creation_memory.initialize().await?;
 
-        Ok(Self { client, db, memory: Arc::new(character_creation_memory) })
+        let moderation = ModerationPipeline::new(db.clone(), providers.clone());
+        Ok(Self { providers, db, memory: Arc::new(character_creation_memory), moderation, system_config_name })
+    }
+
+    /// The config character creation runs with, its prompt is sent without rendering
+    pub fn system_config_name(&self) -> &str {
+        &self.system_config_name
     }
 }
 
@@ -57,7 +54,7 @@ impl RuntimeClient for CharacterCreationRuntimeClient {
 
     fn get_price(&self) -> u64 { 1 }
     fn get_db(&self) -> &Arc<PgPool> { &self.db }
-    fn get_client(&self) -> &Client<OpenAIConfig> { &self.client }
+    fn get_provider(&self, system_config: &SystemConfig) -> Result<Arc<dyn LlmProvider>> { self.providers.get(system_config) }
     fn get_memory(&self) -> &Arc<CharacterCreationMemory> { &self.memory }
 
     async fn preload(db: Arc<PgPool>) -> Result<()> {
@@ -65,6 +62,8 @@ impl RuntimeClient for CharacterCreationRuntimeClient {
         let mut tx = db.begin().await?;
 
         let preload_config = preload::get_system_configs_for_char_creation();
+        // the prompt is sent as it is, without rendering
+        preload_config.validate_prompt::<VerbatimPromptVariables>()?;
         match SystemConfig::find_one_by_criteria(
             QueryCriteria::new().add_filter("name", "=", Some(preload_config.name.clone()))?,
             &mut *tx
@@ -91,10 +90,22 @@ impl RuntimeClient for CharacterCreationRuntimeClient {
                     db_config.openai_max_tokens = preload_config.openai_max_tokens;
                     updated = true;
                 }
+                if db_config.openai_context_window != preload_config.openai_context_window {
+                    db_config.openai_context_window = preload_config.openai_context_window;
+                    updated = true;
+                }
+                if db_config.agent_max_steps != preload_config.agent_max_steps {
+                    db_config.agent_max_steps = preload_config.agent_max_steps;
+                    updated = true;
+                }
                 if db_config.openai_base_url != preload_config.openai_base_url {
                     db_config.openai_base_url = preload_config.openai_base_url;
                     updated = true;
                 }
+                if db_config.openai_api_key_ref != preload_config.openai_api_key_ref {
+                    db_config.openai_api_key_ref = preload_config.openai_api_key_ref;
+                    updated = true;
+                }
 
                 if updated {
                     db_config.update(&mut *tx).await?;
@@ -122,7 +133,14 @@ impl RuntimeClient for CharacterCreationRuntimeClient {
         let tc = RuntimeToolcall::from_function_call(function_call.clone())
             .map_err(|e| anyhow::anyhow!("[CharacterCreationRuntimeClient::on_new_message] Failed to parse function call: {}", e))?;
         let result = tc.execute(&response, &()).await?;
-        let RuntimeToolcallReturn::SummarizeCharacterToolCall(character) = result;
+        let RuntimeToolcallReturn::SummarizeCharacterToolCall(mut character) = result;
+
+        let verdict = self.moderation.screen_character(&message.owner, &mut character).await?;
+        if verdict.is_blocked() {
+            self.moderation.record(&verdict, ModerationTarget::Character, None, Some(message.roleplay_session_id), &message.owner).await?;
+            return Err(anyhow::anyhow!("[CharacterCreationRuntimeClient::on_new_message] Character blocked by {}", verdict.rule_names()));
+        }
+
         let mut tx = self.db.begin().await?;
         let character = character.create(&mut *tx).await?;
         let character_creation_message = CharacterCreationMessage {
@@ -133,6 +151,8 @@ impl RuntimeClient for CharacterCreationRuntimeClient {
             role: MessageRole::Assistant,
             content_type: MessageType::Text,
             character_creation_call: Json(vec![function_call.clone()]),
+            tool_calls: Json(MessageToolCall::from_llm_response(&response)),
+            tool_call_id: None,
             character_creation_maybe_character_str: Some(serde_json::to_string(&character)?),
             character_creation_maybe_character_id: Some(character.id),
             content: response.content.clone(),
@@ -143,6 +163,7 @@ impl RuntimeClient for CharacterCreationRuntimeClient {
         let user_usage = UserUsage::from_llm_response(&response);
         user_usage.create(&mut *tx).await?;
         tx.commit().await?;
+        self.moderation.record(&verdict, ModerationTarget::Character, Some(character.id), Some(message.roleplay_session_id), &message.owner).await?;
 
         response.misc_value = Some(serde_json::json!({ "character_id": character.id }));
 
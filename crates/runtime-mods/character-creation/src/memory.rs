@@ -68,8 +68,9 @@ impl Memory for CharacterCreationMemory {
         let system_config = session.fetch_system_config(&mut *tx).await?
             .ok_or(anyhow::anyhow!("[CharacterCreationMemory::search] System config not found"))?;
         let roleplay_messages_history = session.fetch_history(&mut *tx).await?;
-        let system_message = RoleplayMessage::system(&session, &system_config, &character, &user);
-        let first_message = RoleplayMessage::first_message(&session, &character, &user);
+        let persona = session.resolve_persona(&user, &mut *tx).await?;
+        let system_message = RoleplayMessage::system(&session, &system_config, &character, &user, persona.as_ref())?;
+        let first_message = RoleplayMessage::first_message(&session, &character, &user, persona.as_ref());
 
         let mut character_creation_message = CharacterCreationMessage::from_roleplay_messages(
             &system_message,
@@ -4,7 +4,7 @@ use serde::{Deserialize, Serialize};
 use sqlx::types::{Json, Uuid};
 
 use voda_database::SqlxObject;
-use voda_runtime::{Message, MessageRole, MessageType, SystemConfig, User};
+use voda_runtime::{Message, MessageRole, MessageToolCall, MessageType, SystemConfig, User};
 use voda_runtime_roleplay::{Character, RoleplayMessage, RoleplaySession};
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default, SqlxObject)]
@@ -25,6 +25,9 @@ pub struct CharacterCreationMessage {
     pub content_type: MessageType,
 
     pub character_creation_call: Json<Vec<FunctionCall>>,
+
+    pub tool_calls: Json<Vec<MessageToolCall>>,
+    pub tool_call_id: Option<String>,
     
     pub character_creation_maybe_character_str: Option<String>,
     #[foreign_key(referenced_table = "roleplay_characters", related_rust_type = "Character")]
@@ -47,6 +50,9 @@ impl Message for CharacterCreationMessage {
     fn url_content(&self) -> Option<String> { None }
 
     fn created_at(&self) -> i64 { self.created_at }
+
+    fn tool_calls(&self) -> Vec<MessageToolCall> { self.tool_calls.0.clone() }
+    fn tool_call_id(&self) -> Option<String> { self.tool_call_id.clone() }
 }
 
 impl CharacterCreationMessage {
@@ -63,6 +69,8 @@ impl CharacterCreationMessage {
             roleplay_session_id: roleplay_session_id.clone(),
             character_creation_system_config: Uuid::default(),
             character_creation_call: Json(vec![]),
+            tool_calls: Json(vec![]),
+            tool_call_id: None,
             character_creation_maybe_character_str: None,
             character_creation_maybe_character_id: None,
             created_at: 0,
@@ -84,6 +92,8 @@ impl CharacterCreationMessage {
             roleplay_session_id: session_id.clone(),
             character_creation_system_config: system_config.id,
             character_creation_call: Json(vec![]),
+            tool_calls: Json(vec![]),
+            tool_call_id: None,
             character_creation_maybe_character_str: None,
             character_creation_maybe_character_id: None,
             created_at: 0,
@@ -137,6 +147,8 @@ impl CharacterCreationMessage {
             roleplay_session_id: session_id,
             character_creation_system_config: Uuid::default(), // to be populated later
             character_creation_call: Json(vec![]),
+            tool_calls: Json(vec![]),
+            tool_call_id: None,
             character_creation_maybe_character_str: None,
             character_creation_maybe_character_id: None,
             created_at: 0,
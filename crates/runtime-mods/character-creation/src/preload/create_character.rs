@@ -44,6 +44,7 @@ impl ExecutableFunctionCall for SummarizeCharacterToolCall {
             prompts_first_message: self.prompts_first_message.clone(),
             prompts_background_stories: self.prompts_background_stories.clone(),
             prompts_behavior_traits: self.prompts_behavior_traits.clone(),
+            lorebooks: vec![],
             tags: self.tags.clone(),
             creator: llm_response.caller.clone(),
             version: 1,
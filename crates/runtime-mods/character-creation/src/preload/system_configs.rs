@@ -70,9 +70,12 @@ pub fn get_system_configs_for_char_creation() -> SystemConfig {
 -   **任务终点**: 成功调用 `summarize_character` 函数并返回指定的文本内容，是你任务的唯一终点。"#.to_string(),
         system_prompt_version: 3,
         openai_base_url: "https://openrouter.ai/api/v1".to_string(),
+        openai_api_key_ref: None,
         openai_model: "x-ai/grok-3-mini".to_string(),
         openai_temperature: 0.7,
         openai_max_tokens: 10000,
+        openai_context_window: 1_048_576,
+        agent_max_steps: 0,
         functions: Json(functions),
         updated_at: get_current_timestamp(),
         created_at: get_current_timestamp(),
@@ -2,12 +2,11 @@ use std::sync::Arc;
 use anyhow::Result;
 
 use async_openai::types::CreateEmbeddingRequestArgs;
-use async_openai::{config::OpenAIConfig, Client};
 
 use neo4rs::{ConfigBuilder, Graph};
 use sqlx::PgPool;
 
-use voda_runtime::{toolcalls, LLMRunResponse, UserUsage};
+use voda_runtime::{toolcalls, LLMRunResponse, LlmProvider, UserUsage};
 use voda_database::SqlxCrud;
 
 use crate::pgvector::BatchUpdateSummary;
@@ -31,12 +30,15 @@ pub struct Mem0Engine {
     vector_db: Arc<PgPool>,
     graph_db: Arc<Graph>,
 
-    embeder: Client<OpenAIConfig>,
-    llm: Client<OpenAIConfig>,
+    embeder: Arc<dyn LlmProvider>,
+    llm: Arc<dyn LlmProvider>,
 }
 
 impl Mem0Engine {
-    pub async fn new(data_db: Arc<PgPool>, pgvector_db: Arc<PgPool>) -> Result<Self> {
+    pub async fn new(
+        data_db: Arc<PgPool>, pgvector_db: Arc<PgPool>,
+        llm: Arc<dyn LlmProvider>, embeder: Arc<dyn LlmProvider>,
+    ) -> Result<Self> {
         let env = crate::env::Mem0Env::load();
 
         let graph_config = ConfigBuilder::default()
@@ -50,25 +52,6 @@ impl Mem0Engine {
         let graph_db = Graph::connect(graph_config).await
             .expect("[Mem0Engine::new] Failed to connect to graph");
 
-        let embeder_config = OpenAIConfig::new()
-            .with_api_base(env.get_env_var("EMBEDDING_BASE_URL"))
-            .with_api_key(env.get_env_var("EMBEDDING_API_KEY"));
-
-        let llm_config = OpenAIConfig::new()
-            .with_api_base(env.get_env_var("OPENAI_BASE_URL"))
-            .with_api_key(env.get_env_var("OPENAI_API_KEY"));
-
-        let embeder = Client::build(
-            reqwest::Client::new(),
-            embeder_config,
-            Default::default()
-        );
-        let llm = Client::build(    
-            reqwest::Client::new(),
-            llm_config,
-            Default::default()
-        );
-
         Ok(Self { 
             data_db,
             vector_db: pgvector_db, 
@@ -79,6 +62,7 @@ impl Mem0Engine {
 
     pub async fn init(&self) -> Result<()> {
         // self.vector_db_initialize().await?;
+        self.character_embeddings_initialize().await?;
         self.graph_db_initialize().await?;
         Ok(())
     }
@@ -94,7 +78,7 @@ impl Mem0Engine {
             .input(text)
             .build()?;
 
-        let response = self.embeder.embeddings().create(request).await?;
+        let response = self.embeder.embed(request).await?;
         let embeddings = response.data
             .into_iter()
             .map(|item| item.embedding)
@@ -125,7 +109,7 @@ impl Mem0Engine {
         &self.graph_db
     }
 
-    pub fn get_llm(&self) -> &Client<OpenAIConfig> {
+    pub fn get_llm(&self) -> &Arc<dyn LlmProvider> {
         &self.llm
     }
 }
@@ -11,8 +11,11 @@ mod message;
 mod memory;
 
 pub use engine::Mem0Engine;
+pub use env::Mem0Env;
 pub use raw_message::{EmbeddingMessage, GraphEntities, EntityTag, Mem0Filter};
 pub use message::Mem0Messages;
+#[doc(hidden)]
+pub use pgvector::{CHARACTER_EMBEDDINGS_INDEX_SQL, CHARACTER_EMBEDDINGS_TABLE_SQL};
 
 pub type Embedding = Vec<f32>;
 pub const EMBEDDING_DIMS: i32 = 1024;
@@ -101,6 +104,16 @@ macro_rules! init_pgvector_pool {
                         .execute(&mut *tx)
                         .await
                         .expect("Failed to create embeddings index.");
+
+                    sqlx::query($crate::CHARACTER_EMBEDDINGS_TABLE_SQL)
+                        .execute(&mut *tx)
+                        .await
+                        .expect("Failed to create character embeddings table.");
+
+                    sqlx::query($crate::CHARACTER_EMBEDDINGS_INDEX_SQL)
+                        .execute(&mut *tx)
+                        .await
+                        .expect("Failed to create character embeddings index.");
                         
                     tx.commit().await.expect("Failed to commit transaction");}
 
@@ -72,35 +72,14 @@ pub trait LlmTool: ExecutableFunctionCall {
             .tool_choice(ChatCompletionToolChoiceOption::Auto)
             .build()?;
 
-        let response = engine.get_llm().chat().create(request).await?;
-        let choice = response.choices.first()
-            .ok_or(anyhow!("[LlmTool::call] No response from AI inference server for model {}", Self::model()))?;
-
-        let message = choice.message.clone();
-        let finish_reason = choice.finish_reason.clone();
-        let usage = response.usage
-            .ok_or(anyhow!("[LlmTool::call] Model {} returned no usage", Self::model()))?
-            .clone();
-
-        let content = message.content.clone().unwrap_or_default();
-        let maybe_function_call = message
-            .tool_calls
-            .unwrap_or_default()
-            .into_iter()
-            .map(|tool_call| tool_call.function)
-            .collect::<Vec<_>>();
-
-        let llm_response = LLMRunResponse {
-            caller: tool_input.filter().user_id,
-            content,
-            usage,
-            maybe_function_call: maybe_function_call.clone(),
-            finish_reason,
-            system_config: SystemConfig::default(),
-            misc_value: None,
+        let response = engine.get_llm().chat_completion(request).await?;
+        let system_config = SystemConfig {
+            openai_model: Self::model().to_string(),
+            ..Default::default()
         };
+        let llm_response = LLMRunResponse::from_chat_completion(tool_input.filter().user_id, response, &system_config)?;
 
-        let the_tool_call = maybe_function_call.first()
+        let the_tool_call = llm_response.maybe_function_call.first()
             .ok_or(anyhow!("[LlmTool::call] No tool calls found for tool {}", Self::name()))?;
         let mut tool_call = Self::from_function_call(the_tool_call.clone())?;
         tool_call.set_tool_input(tool_input);
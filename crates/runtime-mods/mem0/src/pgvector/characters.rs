@@ -0,0 +1,88 @@
+use anyhow::{anyhow, Result};
+use pgvector::Vector;
+use sqlx::types::Uuid;
+
+use crate::engine::Mem0Engine;
+
+pub const CHARACTER_EMBEDDINGS_TABLE_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS character_embeddings (
+        character_id UUID PRIMARY KEY,
+        version BIGINT NOT NULL,
+        content TEXT NOT NULL,
+        embedding vector(1024) NOT NULL,
+        created_at BIGINT NOT NULL DEFAULT floor(extract(epoch from now())),
+        updated_at BIGINT NOT NULL DEFAULT floor(extract(epoch from now()))
+    );
+"#;
+
+pub const CHARACTER_EMBEDDINGS_INDEX_SQL: &str = r#"
+    CREATE INDEX IF NOT EXISTS idx_character_embeddings_embedding ON character_embeddings
+    USING hnsw (embedding vector_cosine_ops);
+"#;
+
+impl Mem0Engine {
+    pub async fn character_embeddings_initialize(&self) -> Result<()> {
+        let mut tx = self.get_vector_db().begin().await?;
+        sqlx::query(CHARACTER_EMBEDDINGS_TABLE_SQL).execute(&mut *tx).await?;
+        sqlx::query(CHARACTER_EMBEDDINGS_INDEX_SQL).execute(&mut *tx).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Embeds `content` as the profile of a character at `version`, replacing the previous embedding
+    pub async fn upsert_character_embedding(&self, character_id: &Uuid, version: i64, content: &str) -> Result<()> {
+        let embedding = self.embed(vec![content.to_string()]).await?
+            .pop()
+            .ok_or(anyhow!("[Mem0Engine::upsert_character_embedding] No embedding returned"))?;
+
+        sqlx::query(r#"
+            INSERT INTO character_embeddings (character_id, version, content, embedding)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (character_id) DO UPDATE SET
+                version = EXCLUDED.version,
+                content = EXCLUDED.content,
+                embedding = EXCLUDED.embedding,
+                updated_at = floor(extract(epoch from now()))
+        "#)
+            .bind(character_id)
+            .bind(version)
+            .bind(content)
+            .bind(Vector::from(embedding))
+            .execute(&**self.get_vector_db())
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete_character_embedding(&self, character_id: &Uuid) -> Result<u64> {
+        let result = sqlx::query("DELETE FROM character_embeddings WHERE character_id = $1")
+            .bind(character_id)
+            .execute(&**self.get_vector_db())
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Characters closest to the average embedding of `seeds`, most similar first, with their cosine
+    /// similarity. Nothing is returned when none of the seeds has been embedded.
+    pub async fn nearest_characters(&self, seeds: &[Uuid], exclude: &[Uuid], limit: i64) -> Result<Vec<(Uuid, f64)>> {
+        if seeds.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let rows = sqlx::query_as::<_, (Uuid, f64)>(r#"
+            WITH seed AS (
+                SELECT AVG(embedding) AS centroid FROM character_embeddings WHERE character_id = ANY($1)
+            )
+            SELECT e.character_id, 1 - (e.embedding <=> seed.centroid) AS similarity
+            FROM character_embeddings e, seed
+            WHERE seed.centroid IS NOT NULL AND NOT (e.character_id = ANY($2))
+            ORDER BY e.embedding <=> seed.centroid
+            LIMIT $3
+        "#)
+            .bind(seeds)
+            .bind(exclude)
+            .bind(limit)
+            .fetch_all(&**self.get_vector_db())
+            .await?;
+        Ok(rows)
+    }
+}
@@ -1,5 +1,6 @@
 mod query_criteria;
 mod batch;
+mod characters;
 
 use anyhow::Result;
 use sqlx::Row;
@@ -8,6 +9,7 @@ use crate::{engine::Mem0Engine, Mem0Filter};
 use crate::raw_message::EmbeddingMessage;
 pub use query_criteria::VectorQueryCriteria;
 pub use batch::{BatchUpdateSummary, MemoryUpdateEntry, MemoryEvent};
+pub use characters::{CHARACTER_EMBEDDINGS_INDEX_SQL, CHARACTER_EMBEDDINGS_TABLE_SQL};
 
 impl Mem0Engine {
     pub async fn vector_db_initialize(&self) -> Result<()> {
@@ -1,8 +1,10 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 use sqlx::types::Uuid;
-use voda_database::SqlxObject;
-use voda_runtime::User;
+use sqlx::{Postgres, Transaction};
+use voda_common::get_current_timestamp;
+use voda_database::{SqlxCrud, SqlxObject};
+use voda_runtime::{User, UserRole};
 
 use crate::{Character, CharacterStatus};
 
@@ -24,3 +26,35 @@ pub struct AuditLog {
     pub created_at: i64,
 }
 
+impl AuditLog {
+    /// Moves `character` to `next` on behalf of `author` and records the change. Fails when the
+    /// transition is not allowed or needs a moderator and `author` is not an admin.
+    pub async fn transition(
+        character: &mut Character, next: CharacterStatus, author: &User, notes: &str,
+        tx: &mut Transaction<'_, Postgres>,
+    ) -> Result<Self> {
+        if !character.status.can_transition_to(&next) {
+            return Err(anyhow!("[AuditLog::transition] Character cannot move from {} to {}", character.status, next));
+        }
+        if CharacterStatus::requires_admin(&next) && author.role != UserRole::Admin {
+            return Err(anyhow!("[AuditLog::transition] Only admins can move a character to {}", next));
+        }
+        if !CharacterStatus::requires_admin(&next) && author.id != character.creator && author.role != UserRole::Admin {
+            return Err(anyhow!("[AuditLog::transition] Only the creator can move a character to {}", next));
+        }
+
+        let previous_status = std::mem::replace(&mut character.status, next.clone());
+        character.updated_at = get_current_timestamp();
+        *character = character.clone().update(&mut **tx).await?;
+
+        Ok(Self {
+            id: Uuid::default(),
+            character: character.id,
+            author: author.id,
+            previous_status,
+            new_status: next,
+            notes: notes.to_string(),
+            created_at: get_current_timestamp(),
+        }.create(&mut **tx).await?)
+    }
+}
@@ -0,0 +1,556 @@
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use sqlx::types::Uuid;
+use sqlx::{Postgres, Transaction};
+
+use voda_common::get_current_timestamp;
+use voda_database::{QueryCriteria, SqlxCrud, SqlxFilterQuery};
+
+use crate::{
+    Character, CharacterFeature, CharacterGender, CharacterLanguage, CharacterStatus,
+    Lorebook, LorebookEntry, LorebookPosition, LOREBOOK_DEFAULT_SCAN_DEPTH
+};
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+/// 1x1 transparent PNG, used when a card is exported without an image
+const BLANK_PNG: &str = "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAQAAAC1HAwCAAAAC0lEQVR42mNkYAAAAAYAAjCB0C8AAAAASUVORK5CYII=";
+
+/// extension key holding the `Character` fields the card spec has no place for
+const VODA_EXTENSION: &str = "voda";
+
+/// A community Character Card, spec V2 (`chara_card_v2`) or V3 (`chara_card_v3`).
+/// V1 cards, which are the bare `data` object, are read as V2. Cards setting `system_prompt`,
+/// `post_history_instructions` or `alternate_greetings` are refused, `Character` has no place for them.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CharacterCard {
+    pub spec: String,
+    pub spec_version: String,
+    pub data: CharacterCardData,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct CharacterCardData {
+    pub name: String,
+    pub description: String,
+    pub personality: String,
+    pub scenario: String,
+    pub first_mes: String,
+    pub mes_example: String,
+
+    pub creator_notes: String,
+    pub system_prompt: String,
+    pub post_history_instructions: String,
+    pub alternate_greetings: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub character_book: Option<CharacterBook>,
+
+    pub tags: Vec<String>,
+    pub creator: String,
+    pub character_version: String,
+    pub extensions: Map<String, Value>,
+
+    /// V3 and frontend specific fields, carried along untouched
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl CharacterCardData {
+    /// Card fields that are set but have no counterpart on `Character`
+    fn unsupported_fields(&self) -> Vec<&'static str> {
+        [
+            ("system_prompt", self.system_prompt.trim().is_empty()),
+            ("post_history_instructions", self.post_history_instructions.trim().is_empty()),
+            ("alternate_greetings", self.alternate_greetings.iter().all(|greeting| greeting.trim().is_empty())),
+        ]
+            .into_iter()
+            .filter(|(_, empty)| !empty)
+            .map(|(field, _)| field)
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct CharacterBook {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scan_depth: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_budget: Option<i32>,
+    pub extensions: Map<String, Value>,
+    pub entries: Vec<CharacterBookEntry>,
+
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct CharacterBookEntry {
+    pub keys: Vec<String>,
+    pub content: String,
+    pub extensions: Map<String, Value>,
+    pub enabled: bool,
+    pub insertion_order: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub case_sensitive: Option<bool>,
+    /// V3 only
+    pub use_regex: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub constant: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>,
+    /// `before_char` or `after_char`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<String>,
+
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl Default for CharacterBookEntry {
+    fn default() -> Self {
+        Self {
+            keys: vec![],
+            content: String::new(),
+            extensions: Map::new(),
+            enabled: true,
+            insertion_order: 0,
+            case_sensitive: None,
+            use_regex: false,
+            constant: None,
+            priority: None,
+            position: None,
+            extra: Map::new(),
+        }
+    }
+}
+
+/// `Character` fields outside the card spec, stored under `extensions.voda`
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+struct VodaExtension {
+    gender: CharacterGender,
+    language: CharacterLanguage,
+    background_stories: Vec<String>,
+    behavior_traits: Vec<String>,
+}
+
+impl CharacterCard {
+    pub fn from_json(bytes: &[u8]) -> Result<Self> {
+        let value: Value = serde_json::from_slice(bytes)
+            .map_err(|e| anyhow!("[CharacterCard::from_json] Invalid JSON: {}", e))?;
+
+        let card = if value.get("spec").is_some() {
+            serde_json::from_value::<Self>(value)?
+        } else {
+            Self {
+                spec: "chara_card_v2".to_string(),
+                spec_version: "2.0".to_string(),
+                data: serde_json::from_value(value)?,
+            }
+        };
+
+        if !matches!(card.spec.as_str(), "chara_card_v2" | "chara_card_v3") {
+            return Err(anyhow!("[CharacterCard::from_json] Unsupported card spec {}", card.spec));
+        }
+        if card.data.name.trim().is_empty() {
+            return Err(anyhow!("[CharacterCard::from_json] Card has no name"));
+        }
+        let unsupported = card.data.unsupported_fields();
+        if !unsupported.is_empty() {
+            return Err(anyhow!("[CharacterCard::from_json] Card sets {}, which characters do not support", unsupported.join(", ")));
+        }
+
+        Ok(card)
+    }
+
+    /// Reads the card embedded in the `ccv3` or `chara` tEXt chunk of a PNG, preferring V3
+    pub fn from_png(bytes: &[u8]) -> Result<Self> {
+        let chunks = png_chunks(bytes)?;
+        let text = |keyword: &str| chunks.iter()
+            .filter(|(chunk_type, _)| chunk_type == b"tEXt")
+            .find_map(|(_, data)| data.strip_prefix(keyword.as_bytes())?.strip_prefix(b"\0"));
+
+        let encoded = text("ccv3")
+            .or_else(|| text("chara"))
+            .ok_or(anyhow!("[CharacterCard::from_png] Image holds no character card"))?;
+        let json = general_purpose::STANDARD.decode(encoded)
+            .map_err(|e| anyhow!("[CharacterCard::from_png] Invalid base64 in card chunk: {}", e))?;
+
+        Self::from_json(&json)
+    }
+
+    /// Embeds the card into `image` as a V2 `chara` chunk and a V3 `ccv3` chunk, replacing any card
+    /// the image already holds. A blank image is used without one. The chunks go before `IEND`, so
+    /// images without one are refused.
+    pub fn to_png(&self, image: Option<&[u8]>) -> Result<Vec<u8>> {
+        let blank = general_purpose::STANDARD.decode(BLANK_PNG)?;
+        let image = image.unwrap_or(&blank);
+
+        let chunks = png_chunks(image)?;
+        if !chunks.iter().any(|(chunk_type, _)| chunk_type == b"IEND") {
+            return Err(anyhow!("[CharacterCard::to_png] Image has no IEND chunk"));
+        }
+
+        let mut png = PNG_SIGNATURE.to_vec();
+        for (chunk_type, data) in chunks {
+            let is_card = chunk_type == *b"tEXt"
+                && (data.starts_with(b"chara\0") || data.starts_with(b"ccv3\0"));
+            if is_card {
+                continue;
+            }
+
+            if chunk_type == *b"IEND" {
+                for (keyword, spec, spec_version) in [("chara", "chara_card_v2", "2.0"), ("ccv3", "chara_card_v3", "3.0")] {
+                    let card = Self { spec: spec.to_string(), spec_version: spec_version.to_string(), data: self.data.clone() };
+                    let mut text = format!("{}\0", keyword).into_bytes();
+                    text.extend(general_purpose::STANDARD.encode(serde_json::to_vec(&card)?).into_bytes());
+                    write_png_chunk(&mut png, b"tEXt", &text);
+                }
+            }
+            write_png_chunk(&mut png, &chunk_type, data);
+        }
+
+        Ok(png)
+    }
+
+    /// A V3 card of `character`, with the entries of `lorebooks` merged into its character book.
+    /// A single lorebook keeps its settings. Several are merged: names and descriptions are joined,
+    /// the deepest scan depth is kept and the budgets add up, unlimited if any lorebook is.
+    pub fn from_character(character: &Character, lorebooks: &[(Lorebook, Vec<LorebookEntry>)]) -> Self {
+        let mut extensions = Map::new();
+        extensions.insert(VODA_EXTENSION.to_string(), json!(VodaExtension {
+            gender: character.gender.clone(),
+            language: character.language.clone(),
+            background_stories: character.prompts_background_stories.clone(),
+            behavior_traits: character.prompts_behavior_traits.clone(),
+        }));
+
+        let joined = |field: fn(&Lorebook) -> &str, separator: &str| lorebooks.iter()
+            .map(|(lorebook, _)| field(lorebook).trim())
+            .filter(|value| !value.is_empty())
+            .collect::<Vec<_>>()
+            .join(separator);
+        let character_book = (!lorebooks.is_empty()).then(|| CharacterBook {
+            name: Some(joined(|lorebook| &lorebook.name, " + ")),
+            description: Some(joined(|lorebook| &lorebook.description, "\n\n")),
+            scan_depth: lorebooks.iter().map(|(lorebook, _)| lorebook.scan_depth).max(),
+            token_budget: lorebooks.iter()
+                .map(|(lorebook, _)| (lorebook.token_budget > 0).then_some(lorebook.token_budget))
+                .sum::<Option<i32>>(),
+            entries: lorebooks.iter()
+                .flat_map(|(_, entries)| entries)
+                .map(|entry| CharacterBookEntry {
+                    keys: entry.keys.clone(),
+                    content: entry.content.clone(),
+                    enabled: entry.enabled,
+                    insertion_order: entry.priority,
+                    case_sensitive: Some(entry.case_sensitive),
+                    use_regex: entry.use_regex,
+                    constant: Some(entry.constant),
+                    priority: Some(entry.priority),
+                    position: Some(match entry.position {
+                        LorebookPosition::BeforeHistory => "before_char".to_string(),
+                        LorebookPosition::BeforeMessage => "after_char".to_string(),
+                    }),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        });
+
+        Self {
+            spec: "chara_card_v3".to_string(),
+            spec_version: "3.0".to_string(),
+            data: CharacterCardData {
+                name: character.name.clone(),
+                description: character.description.clone(),
+                personality: character.prompts_personality.clone(),
+                scenario: character.prompts_scenario.clone(),
+                first_mes: character.prompts_first_message.clone(),
+                mes_example: character.prompts_example_dialogue.clone(),
+                creator_notes: character.creator_notes.clone().unwrap_or_default(),
+                character_book,
+                tags: character.tags.clone(),
+                character_version: character.version.to_string(),
+                extensions,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// A draft `Character` owned by `creator`, without its lorebook
+    pub fn to_character(&self, creator: &Uuid) -> Character {
+        let data = &self.data;
+        let voda = data.extensions.get(VODA_EXTENSION)
+            .and_then(|value| serde_json::from_value::<VodaExtension>(value.clone()).ok())
+            .unwrap_or_default();
+
+        Character {
+            id: Uuid::default(),
+            name: data.name.trim().to_string(),
+            description: data.description.clone(),
+            creator: *creator,
+            version: 1,
+            status: CharacterStatus::Draft,
+            gender: voda.gender,
+            language: voda.language,
+            features: vec![CharacterFeature::Roleplay],
+            prompts_scenario: data.scenario.clone(),
+            prompts_personality: data.personality.clone(),
+            prompts_example_dialogue: data.mes_example.clone(),
+            prompts_first_message: data.first_mes.clone(),
+            prompts_background_stories: voda.background_stories,
+            prompts_behavior_traits: voda.behavior_traits,
+            lorebooks: vec![],
+            creator_notes: (!data.creator_notes.trim().is_empty()).then(|| data.creator_notes.clone()),
+            tags: data.tags.clone(),
+            created_at: get_current_timestamp(),
+            updated_at: get_current_timestamp(),
+        }
+    }
+
+    /// The character book as a lorebook owned by `owner`. Entries that would not pass
+    /// `LorebookEntry::validate` are dropped.
+    pub fn to_lorebook(&self, owner: &Uuid) -> Option<(Lorebook, Vec<LorebookEntry>)> {
+        let book = self.data.character_book.as_ref()?;
+        let lorebook = Lorebook {
+            id: Uuid::default(),
+            owner: *owner,
+            name: book.name.clone()
+                .filter(|name| !name.trim().is_empty())
+                .unwrap_or(format!("{} lorebook", self.data.name.trim())),
+            description: book.description.clone().unwrap_or_default(),
+            scan_depth: book.scan_depth.unwrap_or(LOREBOOK_DEFAULT_SCAN_DEPTH).max(0),
+            token_budget: book.token_budget.unwrap_or(0).max(0),
+            updated_at: get_current_timestamp(),
+            created_at: get_current_timestamp(),
+        };
+
+        let entries = book.entries.iter()
+            .map(|entry| LorebookEntry {
+                id: Uuid::default(),
+                lorebook_id: lorebook.id,
+                keys: entry.keys.clone(),
+                use_regex: entry.use_regex,
+                case_sensitive: entry.case_sensitive.unwrap_or(false),
+                constant: entry.constant.unwrap_or(false),
+                enabled: entry.enabled,
+                content: entry.content.clone(),
+                priority: entry.priority.unwrap_or(entry.insertion_order),
+                position: match entry.position.as_deref() {
+                    Some("after_char") => LorebookPosition::BeforeMessage,
+                    _ => LorebookPosition::BeforeHistory,
+                },
+                updated_at: get_current_timestamp(),
+                created_at: get_current_timestamp(),
+            })
+            .filter(|entry| match entry.validate() {
+                Ok(()) => true,
+                Err(e) => {
+                    tracing::warn!("[CharacterCard::to_lorebook] Skipping entry: {:?}", e);
+                    false
+                }
+            })
+            .collect();
+
+        Some((lorebook, entries))
+    }
+
+    /// Stores the card as a draft character of `creator`, with its character book as an attached lorebook
+    pub async fn import(&self, creator: &Uuid, tx: &mut Transaction<'_, Postgres>) -> Result<Character> {
+        let mut character = self.to_character(creator);
+
+        if let Some((lorebook, entries)) = self.to_lorebook(creator) {
+            let lorebook = lorebook.create(&mut **tx).await?;
+            for entry in entries {
+                LorebookEntry { lorebook_id: lorebook.id, ..entry }.create(&mut **tx).await?;
+            }
+            character.lorebooks.push(lorebook.id);
+        }
+
+        Ok(character.create(&mut **tx).await?)
+    }
+
+    /// The card of a stored character, with the lorebooks attached to it
+    pub async fn export(character: &Character, tx: &mut Transaction<'_, Postgres>) -> Result<Self> {
+        let mut lorebooks = vec![];
+        if !character.lorebooks.is_empty() {
+            let books = Lorebook::find_by_criteria(
+                QueryCriteria::new().add_filter("id", " = ANY($1)", Some(character.lorebooks.clone()))?,
+                &mut **tx
+            ).await?;
+            let entries = LorebookEntry::find_by_criteria(
+                QueryCriteria::new().add_filter("lorebook_id", " = ANY($1)", Some(character.lorebooks.clone()))?,
+                &mut **tx
+            ).await?;
+
+            for lorebook_id in &character.lorebooks {
+                if let Some(lorebook) = books.iter().find(|book| &book.id == lorebook_id) {
+                    let mut entries = entries.iter()
+                        .filter(|entry| &entry.lorebook_id == lorebook_id)
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    entries.sort_by_key(|entry| entry.created_at);
+                    lorebooks.push((lorebook.clone(), entries));
+                }
+            }
+        }
+
+        Ok(Self::from_character(character, &lorebooks))
+    }
+}
+
+fn png_chunks(bytes: &[u8]) -> Result<Vec<([u8; 4], &[u8])>> {
+    let mut rest = bytes.strip_prefix(&PNG_SIGNATURE)
+        .ok_or(anyhow!("[png_chunks] Not a PNG image"))?;
+
+    let mut chunks = vec![];
+    while !rest.is_empty() {
+        if rest.len() < 12 {
+            return Err(anyhow!("[png_chunks] Truncated chunk"));
+        }
+        let length = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let chunk_type = [rest[4], rest[5], rest[6], rest[7]];
+        if rest.len() < 12 + length {
+            return Err(anyhow!("[png_chunks] Truncated chunk"));
+        }
+
+        chunks.push((chunk_type, &rest[8..8 + length]));
+        rest = &rest[12 + length..];
+        if &chunk_type == b"IEND" {
+            break;
+        }
+    }
+
+    Ok(chunks)
+}
+
+fn write_png_chunk(png: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(chunk_type);
+    hasher.update(data);
+
+    png.extend((data.len() as u32).to_be_bytes());
+    png.extend(chunk_type);
+    png.extend(data);
+    png.extend(hasher.finalize().to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_png_round_trip() {
+        let creator = Uuid::new_v4();
+        let character = Character {
+            name: "Alice".to_string(),
+            description: "A curious girl".to_string(),
+            gender: CharacterGender::Female,
+            prompts_scenario: "Wonderland".to_string(),
+            prompts_first_message: "Hello, {{user}}!".to_string(),
+            prompts_example_dialogue: "{{char}}: Curiouser and curiouser!".to_string(),
+            prompts_background_stories: vec!["Fell down a rabbit hole".to_string()],
+            creator_notes: Some("Keep her polite".to_string()),
+            tags: vec!["fantasy".to_string()],
+            ..Default::default()
+        };
+        let lorebook = Lorebook { id: Uuid::new_v4(), name: "Wonderland".to_string(), scan_depth: 6, ..Default::default() };
+        let entry = LorebookEntry {
+            lorebook_id: lorebook.id,
+            keys: vec!["queen".to_string()],
+            content: "The Queen of Hearts rules Wonderland.".to_string(),
+            enabled: true,
+            priority: 3,
+            position: LorebookPosition::BeforeMessage,
+            ..Default::default()
+        };
+
+        let card = CharacterCard::from_character(&character, &[(lorebook, vec![entry.clone()])]);
+        let png = card.to_png(None).unwrap();
+        // exporting again replaces the embedded card instead of adding another one
+        let png = card.to_png(Some(&png)).unwrap();
+        assert_eq!(png_chunks(&png).unwrap().iter().filter(|(chunk_type, _)| chunk_type == b"tEXt").count(), 2);
+
+        let imported = CharacterCard::from_png(&png).unwrap();
+        assert_eq!(imported, card);
+
+        let imported_character = imported.to_character(&creator);
+        assert_eq!(imported_character.name, character.name);
+        assert_eq!(imported_character.gender, character.gender);
+        assert_eq!(imported_character.prompts_first_message, character.prompts_first_message);
+        assert_eq!(imported_character.prompts_example_dialogue, character.prompts_example_dialogue);
+        assert_eq!(imported_character.prompts_background_stories, character.prompts_background_stories);
+        assert_eq!(imported_character.creator_notes, character.creator_notes);
+        assert_eq!(imported_character.tags, character.tags);
+        assert_eq!(imported_character.status, CharacterStatus::Draft);
+
+        let (imported_lorebook, imported_entries) = imported.to_lorebook(&creator).unwrap();
+        assert_eq!(imported_lorebook.scan_depth, 6);
+        assert_eq!(imported_entries.len(), 1);
+        assert_eq!(imported_entries[0].keys, entry.keys);
+        assert_eq!(imported_entries[0].priority, entry.priority);
+        assert_eq!(imported_entries[0].position, entry.position);
+    }
+
+    #[test]
+    fn test_read_v1_json() {
+        let card = CharacterCard::from_json(br#"{
+            "name": "Bob", "description": "", "personality": "grumpy", "scenario": "",
+            "first_mes": "What?", "mes_example": ""
+        }"#).unwrap();
+        assert_eq!(card.spec, "chara_card_v2");
+        assert_eq!(card.to_character(&Uuid::new_v4()).prompts_personality, "grumpy");
+
+        assert!(CharacterCard::from_json(br#"{"spec": "chara_card_v9", "spec_version": "9", "data": {"name": "X"}}"#).is_err());
+        assert!(CharacterCard::from_png(b"not a png").is_err());
+    }
+
+    #[test]
+    fn test_unsupported_fields_are_refused() {
+        assert!(CharacterCard::from_json(br#"{"name": "Bob", "system_prompt": "Stay in character."}"#).is_err());
+        assert!(CharacterCard::from_json(br#"{"name": "Bob", "post_history_instructions": "Be brief."}"#).is_err());
+        assert!(CharacterCard::from_json(br#"{"name": "Bob", "alternate_greetings": ["Hi there"]}"#).is_err());
+        assert!(CharacterCard::from_json(br#"{"name": "Bob", "system_prompt": " ", "alternate_greetings": [""]}"#).is_ok());
+    }
+
+    #[test]
+    fn test_png_without_iend_is_refused() {
+        let card = CharacterCard::from_json(br#"{"name": "Bob"}"#).unwrap();
+        let png = card.to_png(None).unwrap();
+        let chunks = png_chunks(&png).unwrap();
+
+        let mut truncated = PNG_SIGNATURE.to_vec();
+        for (chunk_type, data) in chunks.iter().filter(|(chunk_type, _)| chunk_type != b"IEND") {
+            write_png_chunk(&mut truncated, chunk_type, data);
+        }
+        assert!(card.to_png(Some(&truncated)).is_err());
+    }
+
+    #[test]
+    fn test_several_lorebooks_are_merged() {
+        let books = [("Wonderland", 6, 500), ("Looking Glass", 2, 300)]
+            .map(|(name, scan_depth, token_budget)| (
+                Lorebook { id: Uuid::new_v4(), name: name.to_string(), scan_depth, token_budget, ..Default::default() },
+                vec![]
+            ));
+        let card = CharacterCard::from_character(&Character::default(), &books);
+        let book = card.data.character_book.unwrap();
+        assert_eq!(book.name.as_deref(), Some("Wonderland + Looking Glass"));
+        assert_eq!(book.scan_depth, Some(6));
+        assert_eq!(book.token_budget, Some(800));
+
+        let mut books = books;
+        books[1].0.token_budget = 0;
+        let card = CharacterCard::from_character(&Character::default(), &books);
+        assert_eq!(card.data.character_book.unwrap().token_budget, None);
+    }
+}
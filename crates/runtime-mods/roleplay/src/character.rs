@@ -8,6 +8,8 @@ use std::str::FromStr;
 
 use voda_runtime::User;
 
+use crate::Lorebook;
+
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Display, EnumString, Default)]
 pub enum CharacterStatus {
     #[default]
@@ -19,6 +21,28 @@ pub enum CharacterStatus {
     Archived(String),
 }
 
+impl CharacterStatus {
+    /// Whether a character may move from `self` to `next`:
+    /// Draft → Reviewing → Published or Rejected, Rejected back to Draft or Reviewing, Published
+    /// back to Reviewing when its creator edits it, and anything but Published to Draft or
+    /// Archived. Archived is final.
+    pub fn can_transition_to(&self, next: &CharacterStatus) -> bool {
+        use CharacterStatus::*;
+        matches!(
+            (self, next),
+            (Draft, Reviewing)
+                | (Reviewing, Published) | (Reviewing, Rejected(_)) | (Reviewing, Draft)
+                | (Rejected(_), Reviewing) | (Rejected(_), Draft) | (Published, Reviewing)
+                | (Draft, Archived(_)) | (Rejected(_), Archived(_)) | (Published, Archived(_))
+        )
+    }
+
+    /// Transitions only moderators may make. Creators move their characters between Draft and Reviewing.
+    pub fn requires_admin(next: &CharacterStatus) -> bool {
+        matches!(next, CharacterStatus::Published | CharacterStatus::Rejected(_) | CharacterStatus::Archived(_))
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Display, EnumString, Default)]
 pub enum CharacterGender {
     #[default]
@@ -132,6 +156,10 @@ pub struct Character {
     pub prompts_background_stories: Vec<String>,
     pub prompts_behavior_traits: Vec<String>,
 
+    /// lorebooks scanned in every session with this character
+    #[foreign_key_many(referenced_table = "roleplay_lorebooks", related_rust_type = "Lorebook")]
+    pub lorebooks: Vec<Uuid>,
+
     pub creator_notes: Option<String>,
 
     pub tags: Vec<String>,
@@ -139,3 +167,30 @@ pub struct Character {
     pub created_at: i64,
     pub updated_at: i64
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_transitions() {
+        use CharacterStatus::*;
+        let rejected = Rejected("too short".to_string());
+        let archived = Archived("retired".to_string());
+
+        assert!(Draft.can_transition_to(&Reviewing));
+        assert!(Reviewing.can_transition_to(&Published));
+        assert!(Reviewing.can_transition_to(&rejected));
+        assert!(rejected.can_transition_to(&Reviewing));
+        assert!(Published.can_transition_to(&archived));
+        assert!(Published.can_transition_to(&Reviewing));
+
+        assert!(!Draft.can_transition_to(&Published));
+        assert!(!Published.can_transition_to(&Draft));
+        assert!(!Reviewing.can_transition_to(&Reviewing));
+        assert!(!archived.can_transition_to(&Draft));
+
+        assert!(CharacterStatus::requires_admin(&Published));
+        assert!(!CharacterStatus::requires_admin(&Reviewing));
+    }
+}
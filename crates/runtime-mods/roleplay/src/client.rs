@@ -1,18 +1,18 @@
 use std::sync::Arc;
 
 use anyhow::Result;
-use async_openai::{config::OpenAIConfig, Client};
+use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs};
 
 use sqlx::PgPool;
-use sqlx::types::Uuid;
+use sqlx::types::{Json, Uuid};
 use tokio::sync::mpsc;
 use tokio::time::Instant;
 use voda_common::{get_current_timestamp, EnvVars};
-use voda_runtime::{toolcalls, ExecutableFunctionCall, LLMRunResponse, Memory, MessageRole, MessageType, RuntimeClient, RuntimeEnv, SystemConfig, User, UserRole, UserUsage};
+use voda_runtime::{decode_data_url, execute_tool_calls, toolcalls, AgentStep, AgentToolResult, LLMRunResponse, LlmProvider, LlmProviderRegistry, Memory, Message, MessageRole, MessageToolCall, MessageType, RuntimeClient, RuntimeEnv, SystemConfig, User, UserRole, UserUsage};
 use voda_database::{SqlxCrud, QueryCriteria, SqlxFilterQuery};
-use voda_runtime_mem0::Mem0Messages;
+use voda_runtime_mem0::{Mem0Engine, Mem0Messages};
 
-use crate::{RoleplayMessage, RoleplayRawMemory, preload, Character};
+use crate::{CharacterRecommender, CharacterRevision, CharacterSearch, ModerationPipeline, ModerationRule, ModerationTarget, RoleplayAlternative, RoleplayMessage, RoleplayRawMemory, RoleplaySession, RoleplayTurnPolicy, preload, validate_system_prompt, Character};
 use crate::preload::ShowStoryOptionsToolCall;
 
 toolcalls!(
@@ -23,32 +23,420 @@ toolcalls!(
 );
 
 
+/// History messages shown to the model when it picks the next speaker
+const SPEAKER_PICK_RECENT_MESSAGES: usize = 10;
+/// A streamed reply is held back until this many characters ending a sentence can be screened
+/// together, and forwarded without waiting for the end of a sentence past the maximum
+const STREAM_SCREEN_MIN_CHARS: usize = 40;
+const STREAM_SCREEN_MAX_CHARS: usize = 400;
+const SENTENCE_ENDS: &[char] = &['。', '！', '？', '…', '.', '!', '?', '\n'];
+
+/// Events emitted by `RoleplayRuntimeClient::on_new_message_stream`.
+#[derive(Debug, Clone)]
+pub enum RoleplayStreamEvent {
+    Delta(String),
+    Options(Vec<String>),
+    Done(Box<LLMRunResponse>),
+    Error(String),
+}
+
 #[derive(Clone)]
 pub struct RoleplayRuntimeClient {
     db: Arc<PgPool>,
     memory: Arc<RoleplayRawMemory>,
-    client: Client<OpenAIConfig>,
+    providers: Arc<LlmProviderRegistry>,
+    moderation: ModerationPipeline,
+    recommender: CharacterRecommender,
+
+    summary_tx: mpsc::Sender<Uuid>,
 }
 
 impl RoleplayRuntimeClient {
-    pub async fn new(
-        db: Arc<PgPool>, pgvector_db: Arc<PgPool>,
-    ) -> Result<(Self, mpsc::Receiver<Vec<Mem0Messages>>)> {
-        let env = RuntimeEnv::load();
-        let config = OpenAIConfig::new()
-            .with_api_key(env.get_env_var("OPENAI_API_KEY"))
-            .with_api_base(env.get_env_var("OPENAI_BASE_URL"));
-
-        let client = Client::build(
-            reqwest::Client::new(),
-            config,
-            Default::default()
+    /// `summary_tx` queues sessions for the `RoleplaySummarizer` after every new turn
+    pub fn new(
+        db: Arc<PgPool>, mem0: Arc<Mem0Engine>, providers: Arc<LlmProviderRegistry>,
+        summary_tx: mpsc::Sender<Uuid>,
+    ) -> (Self, mpsc::Receiver<Vec<Mem0Messages>>) {
+        let (mem0_messages_tx, mem0_messages_rx) = mpsc::channel(100);
+        let recommender = CharacterRecommender::new(db.clone(), mem0.clone());
+        let memory = RoleplayRawMemory::new(db.clone(), mem0, mem0_messages_tx);
+        let moderation = ModerationPipeline::new(db.clone(), providers.clone());
+        (Self { providers, db, memory: Arc::new(memory), moderation, recommender, summary_tx }, mem0_messages_rx)
+    }
+
+    pub fn moderation(&self) -> &ModerationPipeline { &self.moderation }
+    pub fn recommender(&self) -> &CharacterRecommender { &self.recommender }
+
+    /// Screens the content of a message of `session_id` before it is sent or stored. Blocked content
+    /// is an error, rewritten content is returned in place of the original.
+    async fn moderate(&self, owner: &Uuid, session_id: &Uuid, target: ModerationTarget, content: &str) -> Result<String> {
+        let rules = self.moderation.enabled_rules().await?;
+        self.moderate_with(&rules, owner, session_id, target, content).await
+    }
+
+    async fn moderate_with(
+        &self, rules: &[ModerationRule], owner: &Uuid, session_id: &Uuid, target: ModerationTarget, content: &str,
+    ) -> Result<String> {
+        let mut content = content.to_string();
+        let verdict = self.moderation.screen_with(rules, owner, &mut [&mut content]).await?;
+        self.moderation.record(&verdict, target, None, Some(*session_id), owner).await?;
+        if verdict.is_blocked() {
+            return Err(anyhow::anyhow!("[RoleplayRuntimeClient::moderate] Message blocked by {}", verdict.rule_names()));
+        }
+
+        Ok(content)
+    }
+
+    async fn moderate_user_message(&self, message: &RoleplayMessage) -> Result<RoleplayMessage> {
+        let mut message = message.clone();
+        message.content = self.moderate(&message.owner, &message.session_id, ModerationTarget::UserMessage, &message.content).await?;
+        Ok(message)
+    }
+
+    /// Audio messages are answered through their transcript, which becomes their text content
+    async fn transcribe_audio(&self, message: &RoleplayMessage) -> Result<RoleplayMessage> {
+        let mut message = message.clone();
+        let url = match (&message.content_type, message.url_content()) {
+            (MessageType::Audio(_), Some(url)) if message.content.is_empty() => url,
+            _ => return Ok(message),
+        };
+
+        // audio is only ever inline, never fetched from a url the client picked
+        let (mime, audio) = decode_data_url(&url)
+            .ok_or_else(|| anyhow::anyhow!("[RoleplayRuntimeClient::transcribe_audio] Audio must be a base64 data url"))?;
+        let file_name = format!("audio.{}", mime.rsplit('/').next().unwrap_or("wav"));
+
+        let model = RuntimeEnv::load().get_env_var("TRANSCRIPTION_MODEL");
+        message.content = self.providers.default_provider()?
+            .transcribe(&model, &file_name, audio).await?;
+        tracing::debug!("[RoleplayRuntimeClient::transcribe_audio] Transcript: {}", message.content);
+
+        UserUsage::from_transcription(&message.owner, &model, &message.content).create(&*self.db).await?;
+
+        Ok(message)
+    }
+
+    /// Streaming variant of `on_new_message`. The completion is forwarded as it is generated
+    /// and the messages and usage are only persisted once the stream finishes successfully.
+    /// The reply is screened a few sentences at a time before they are forwarded: a rewrite
+    /// changes what is sent, a block ends the stream with an error and nothing is stored.
+    pub async fn on_new_message_stream(&self, message: &RoleplayMessage) -> Result<mpsc::Receiver<RoleplayStreamEvent>> {
+        let message = &self.transcribe_audio(message).await?;
+        let message = &self.moderate_user_message(message).await?;
+        let speaker = self.pick_speaker(message).await?;
+        let (messages, system_config, context_report) = self.memory
+            .search_with_report(message, Some(&speaker), 100).await?;
+        let rules = self.moderation.enabled_rules().await?;
+        let mut stream = self.send_llm_request_stream(&system_config, &messages).await?;
+
+        let (tx, rx) = mpsc::channel(100);
+        let client = self.clone();
+        let message = message.clone();
+        tokio::spawn(async move {
+            let mut pending = String::new();
+            let mut screened = String::new();
+            let mut finished = false;
+            while !finished {
+                match stream.next_delta().await {
+                    Some(Ok(delta)) => pending.push_str(&delta),
+                    Some(Err(e)) => {
+                        tracing::warn!("[RoleplayRuntimeClient::on_new_message_stream] {}", e);
+                        let _ = tx.send(RoleplayStreamEvent::Error(e.to_string())).await;
+                        return;
+                    }
+                    None => finished = true,
+                }
+
+                let chunk_len = if finished { pending.len() } else { screened_chunk_len(&pending) };
+                if chunk_len == 0 {
+                    continue;
+                }
+                let chunk = pending.drain(..chunk_len).collect::<String>();
+                match client.moderate_with(
+                    &rules, &message.owner, &message.session_id, ModerationTarget::AssistantMessage, &chunk
+                ).await {
+                    Ok(chunk) => {
+                        screened.push_str(&chunk);
+                        // if the client has disconnected, keep draining so the reply is still saved
+                        let _ = tx.send(RoleplayStreamEvent::Delta(chunk)).await;
+                    }
+                    Err(e) => {
+                        tracing::warn!("[RoleplayRuntimeClient::on_new_message_stream] {}", e);
+                        let _ = tx.send(RoleplayStreamEvent::Error(e.to_string())).await;
+                        return;
+                    }
+                }
+            }
+
+            let result = async {
+                let mut response = stream.finish().await?;
+                response.misc_value = Some(serde_json::json!({ "context_window": context_report }));
+                UserUsage::from_llm_response(&response).create(&*client.db).await?;
+                // what the user was shown, rewrites included
+                response.content = screened;
+
+                // the reply is already out, so tool calls are executed but not fed back to the model
+                let (tool_results, _) = execute_tool_calls::<RuntimeToolcall>(&response, &()).await?;
+                let options = story_options(tool_results.iter());
+                let step = AgentStep { response: response.clone(), tool_results };
+                client.persist_new_message(&message, &speaker, &response.content, &options, &[step]).await?;
+                Ok::<_, anyhow::Error>((response, options))
+            }.await;
+
+            match result {
+                Ok((response, options)) => {
+                    if !options.is_empty() {
+                        let _ = tx.send(RoleplayStreamEvent::Options(options)).await;
+                    }
+                    let _ = tx.send(RoleplayStreamEvent::Done(Box::new(response))).await;
+                }
+                Err(e) => {
+                    tracing::warn!("[RoleplayRuntimeClient::on_new_message_stream] {}", e);
+                    let _ = tx.send(RoleplayStreamEvent::Error(e.to_string())).await;
+                }
+            }
+        });
+
+        Ok(rx)
+    }
+
+    /// Replies of an assistant message, in the order they were generated
+    pub async fn list_alternatives(&self, user_id: &Uuid, message_id: &Uuid) -> Result<(Vec<RoleplayAlternative>, usize)> {
+        let message = self.find_own_message(user_id, message_id).await?;
+        Ok((message.list_alternatives(), message.selected_alternative as usize))
+    }
+
+    /// Switches the reply of the latest turn of a session to one of its alternatives
+    pub async fn select_alternative(&self, user_id: &Uuid, session_id: &Uuid, index: usize) -> Result<RoleplayMessage> {
+        let (mut session, user_message, mut reply, stale_steps) = self.memory
+            .last_turn(session_id).await?;
+        if &session.owner != user_id {
+            return Err(anyhow::anyhow!("[RoleplayRuntimeClient::select_alternative] Session does not belong to the user"));
+        }
+
+        let replaced_content = reply.content.clone();
+        reply.select_alternative(index)?;
+        self.memory.replace_last_reply(
+            &mut session, &user_message, reply, &replaced_content, &stale_steps
+        ).await
+    }
+
+    /// Changes the text of a message of the user, optionally dropping every message after it
+    pub async fn edit_message(&self, user_id: &Uuid, message_id: &Uuid, content: String, truncate: bool) -> Result<RoleplayMessage> {
+        let message = self.find_own_message(user_id, message_id).await?;
+        let content = self.moderate(user_id, &message.session_id, ModerationTarget::UserMessage, &content).await?;
+        self.memory.edit_message(&message, content, truncate).await
+    }
+
+    /// Removes a message of the user from its session, optionally with every message after it
+    pub async fn delete_message(&self, user_id: &Uuid, message_id: &Uuid, truncate: bool) -> Result<Vec<Uuid>> {
+        let message = self.find_own_message(user_id, message_id).await?;
+        self.memory.delete_message(&message, truncate).await
+    }
+
+    async fn find_own_message(&self, user_id: &Uuid, message_id: &Uuid) -> Result<RoleplayMessage> {
+        let message = RoleplayMessage::find_one_by_criteria(
+            QueryCriteria::new().add_valued_filter("id", "=", *message_id)?,
+            &*self.db
+        ).await?
+            .ok_or(anyhow::anyhow!("[RoleplayRuntimeClient::find_own_message] Message not found"))?;
+        if &message.owner != user_id {
+            return Err(anyhow::anyhow!("[RoleplayRuntimeClient::find_own_message] Message does not belong to the user"));
+        }
+
+        Ok(message)
+    }
+
+    /// Branches a new session off `session_id` that shares its history up to `message_id`
+    pub async fn fork_session(&self, user_id: &Uuid, session_id: &Uuid, message_id: &Uuid) -> Result<RoleplaySession> {
+        let session = RoleplaySession::find_one_by_criteria(
+            QueryCriteria::new().add_valued_filter("id", "=", *session_id)?,
+            &*self.db
+        ).await?
+            .ok_or(anyhow::anyhow!("[RoleplayRuntimeClient::fork_session] Session not found"))?;
+        if &session.owner != user_id {
+            return Err(anyhow::anyhow!("[RoleplayRuntimeClient::fork_session] Session does not belong to the user"));
+        }
+
+        self.memory.fork(&session, message_id).await
+    }
+
+    /// The character answering `message`: the one the user addressed, otherwise whoever the turn
+    /// policy of the session picks. One-on-one sessions are always answered by their character.
+    async fn pick_speaker(&self, message: &RoleplayMessage) -> Result<Uuid> {
+        let session = RoleplaySession::find_one_by_criteria(
+            QueryCriteria::new().add_valued_filter("id", "=", message.session_id)?,
+            &*self.db
+        ).await?
+            .ok_or(anyhow::anyhow!("[RoleplayRuntimeClient::pick_speaker] Session not found"))?;
+        if !session.is_group() {
+            return Ok(session.character);
+        }
+
+        if let Some(speaker) = message.speaker {
+            if !session.members().contains(&speaker) {
+                return Err(anyhow::anyhow!("[RoleplayRuntimeClient::pick_speaker] Character is not part of the session"));
+            }
+            return Ok(speaker);
+        }
+
+        let mut history = session.fetch_history(&*self.db).await?;
+        history.sort_by_key(|m| session.history.iter().position(|id| id == &m.id));
+        let last_speaker = history.iter()
+            .rev()
+            .filter(|m| m.role == MessageRole::Assistant)
+            .find_map(|m| m.speaker);
+
+        match session.turn_policy {
+            RoleplayTurnPolicy::UserDirected => Err(anyhow::anyhow!("[RoleplayRuntimeClient::pick_speaker] The session expects the user to name the next speaker")),
+            RoleplayTurnPolicy::RoundRobin => Ok(session.next_in_rotation(last_speaker.as_ref())),
+            RoleplayTurnPolicy::ModelPicked => match self.ask_model_for_speaker(&session, &history, message).await {
+                Ok(speaker) => Ok(speaker),
+                Err(e) => {
+                    tracing::warn!("[RoleplayRuntimeClient::pick_speaker] Falling back to round robin: {}", e);
+                    Ok(session.next_in_rotation(last_speaker.as_ref()))
+                }
+            },
+        }
+    }
+
+    async fn ask_model_for_speaker(
+        &self, session: &RoleplaySession, history: &[RoleplayMessage], message: &RoleplayMessage,
+    ) -> Result<Uuid> {
+        let members = session.fetch_characters(&*self.db).await?;
+        let system_config = session.fetch_system_config(&*self.db).await?
+            .ok_or(anyhow::anyhow!("[RoleplayRuntimeClient::ask_model_for_speaker] System config not found"))?;
+
+        let name_of = |speaker: &Option<Uuid>| members.iter()
+            .find(|member| Some(member.id) == *speaker)
+            .map(|member| member.name.clone())
+            .unwrap_or("assistant".to_string());
+        let turns = history.iter()
+            .filter(|m| m.role == MessageRole::User || m.role == MessageRole::Assistant)
+            .collect::<Vec<_>>();
+        let transcript = turns[turns.len().saturating_sub(SPEAKER_PICK_RECENT_MESSAGES)..].iter()
+            .copied()
+            .chain(std::iter::once(message))
+            .map(|m| match m.role {
+                MessageRole::Assistant => format!("{}：{}", name_of(&m.speaker), m.content),
+                _ => format!("user：{}", m.content),
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let prompt = format!(
+            "以下是一段多人角色扮演对话，在场的角色有：{}。\n\n{}\n\n请判断接下来最应该由哪位角色回应，只回答该角色的名字。",
+            members.iter().map(|member| member.name.as_str()).collect::<Vec<_>>().join("、"),
+            transcript
         );
 
-        let (mem0_messages_tx, mem0_messages_rx) = mpsc::channel(100);
-        let memory = RoleplayRawMemory::new(db.clone(), pgvector_db.clone(), mem0_messages_tx).await?;
-        Ok((Self { client, db, memory: Arc::new(memory) }, mem0_messages_rx))
+        let request = CreateChatCompletionRequestArgs::default()
+            .model(&system_config.openai_model)
+            .messages([ChatCompletionRequestMessage::User(
+                ChatCompletionRequestUserMessageArgs::default().content(prompt).build()?
+            )])
+            .temperature(0.0_f32)
+            .max_tokens(50u32)
+            .build()?;
+
+        let response = self.providers.get(&system_config)?.chat_completion(request).await?;
+        let response = LLMRunResponse::from_chat_completion(session.owner, response, &system_config)?;
+        UserUsage::from_llm_response(&response).create(&*self.db).await?;
+
+        // longest name first, so a name contained in another one does not win
+        let mut candidates = members.iter().collect::<Vec<_>>();
+        candidates.sort_by_key(|member| std::cmp::Reverse(member.name.chars().count()));
+        candidates.into_iter()
+            .find(|member| response.content.contains(&member.name))
+            .map(|member| member.id)
+            .ok_or(anyhow::anyhow!("[RoleplayRuntimeClient::ask_model_for_speaker] Model picked no member: {}", response.content))
+    }
+
+    /// Stores the user message and the assistant reply, with the story options appended to the reply.
+    /// The tool calls of the reply are kept on it and their results stored right after, so the
+    /// exchange replays as a valid tool conversation.
+    /// Stores the user message and every step of the reply in order, each assistant message followed
+    /// by the results of its tool calls. The reply `content` goes on the last step, earlier steps
+    /// only carry their tool calls.
+    async fn persist_new_message(&self, 
+        message: &RoleplayMessage, speaker: &Uuid, content: &str, options: &[String],
+        steps: &[AgentStep<RuntimeToolcallReturn>],
+    ) -> Result<()> {
+        let time = Instant::now();
+
+        let mut messages = vec![message.clone()];
+        for (index, step) in steps.iter().enumerate() {
+            let (step_content, step_options) = if index + 1 == steps.len() {
+                (content_with_options(content, options), options.to_vec())
+            } else {
+                (String::new(), vec![])
+            };
+
+            messages.push(RoleplayMessage {
+                id: Uuid::default(),
+                owner: message.owner,
+                speaker: Some(*speaker),
+                role: MessageRole::Assistant,
+                content_type: MessageType::Text,
+                attachment: None,
+                content: step_content,
+                session_id: message.session_id,
+                options: step_options,
+                tool_calls: Json(step.tool_results.iter().map(MessageToolCall::from_tool_result).collect()),
+                tool_call_id: None,
+                alternatives: Json(vec![]),
+                selected_alternative: 0,
+
+                created_at: get_current_timestamp(),
+                updated_at: get_current_timestamp(),
+            });
+            messages.extend(step.tool_results.iter()
+                .map(|tool_result| RoleplayMessage::tool_result(&message.session_id, &message.owner, tool_result))
+            );
+        }
+
+        self.memory.add_messages(&messages).await?;
+        tracing::debug!("[RoleplayRuntimeClient::persist_new_message] Memory add took {:?}", time.elapsed());
+
+        // summarizing is best effort, a full queue only delays it to the next turn
+        if let Err(e) = self.summary_tx.try_send(message.session_id) {
+            tracing::warn!("[RoleplayRuntimeClient::persist_new_message] Failed to queue session for summary: {}", e);
+        }
+
+        Ok(())
+    }
+}
+
+/// Byte length of the leading sentences of a streamed reply that are ready to be screened, 0 while
+/// more should be buffered
+fn screened_chunk_len(pending: &str) -> usize {
+    if pending.chars().count() >= STREAM_SCREEN_MAX_CHARS {
+        return pending.len();
     }
+    pending.char_indices()
+        .rev()
+        .find(|(_, c)| SENTENCE_ENDS.contains(c))
+        .map(|(i, c)| i + c.len_utf8())
+        .filter(|end| pending[..*end].chars().count() >= STREAM_SCREEN_MIN_CHARS)
+        .unwrap_or(0)
+}
+
+/// The story options are shown to the user as part of the reply
+fn content_with_options(content: &str, options: &[String]) -> String {
+    if options.is_empty() {
+        content.to_string()
+    } else {
+        format!("{} \n\n {}", content, options.join("\n"))
+    }
+}
+
+fn story_options<'a>(results: impl Iterator<Item = &'a AgentToolResult<RuntimeToolcallReturn>>) -> Vec<String> {
+    results
+        .filter_map(|tool_result| match &tool_result.result {
+            Ok(RuntimeToolcallReturn::ShowStoryOptionsToolCall(options)) => Some(options.clone()),
+            _ => None,
+        })
+        .flatten()
+        .collect()
 }
 
 #[async_trait::async_trait]
@@ -58,7 +446,7 @@ impl RuntimeClient for RoleplayRuntimeClient {
 
     fn get_price(&self) -> u64 { 1 }
     fn get_db(&self) -> &Arc<PgPool> { &self.db }
-    fn get_client(&self) -> &Client<OpenAIConfig> { &self.client }
+    fn get_provider(&self, system_config: &SystemConfig) -> Result<Arc<dyn LlmProvider>> { self.providers.get(system_config) }
     fn get_memory(&self) -> &Arc<RoleplayRawMemory> { &self.memory }
 
     async fn preload(db: Arc<PgPool>) -> Result<()> {
@@ -69,9 +457,12 @@ impl RuntimeClient for RoleplayRuntimeClient {
         let preload_configs = vec![
             preload::get_system_configs_for_char_creation(),
             preload::get_system_configs_for_roleplay(),
+            preload::get_system_configs_for_summarizer(),
+            preload::get_system_configs_for_moderator(),
         ];
         
         for preload_config in preload_configs {
+            validate_system_prompt(&preload_config)?;
             match SystemConfig::find_one_by_criteria(
                 QueryCriteria::new().add_filter("name", "=", Some(preload_config.name.clone()))?,
                 &mut *tx
@@ -98,6 +489,16 @@ impl RuntimeClient for RoleplayRuntimeClient {
                         needs_update = true;
                     }
 
+                    if db_config.openai_context_window != preload_config.openai_context_window {
+                        db_config.openai_context_window = preload_config.openai_context_window;
+                        needs_update = true;
+                    }
+
+                    if db_config.agent_max_steps != preload_config.agent_max_steps {
+                        db_config.agent_max_steps = preload_config.agent_max_steps;
+                        needs_update = true;
+                    }
+
                     if db_config.functions != preload_config.functions {
                         db_config.functions = preload_config.functions.clone();
                         needs_update = true;
@@ -120,7 +521,10 @@ impl RuntimeClient for RoleplayRuntimeClient {
         ).await?
             .ok_or(anyhow::anyhow!("[RoleplayRuntimeClient::on_init] No admin user found"))?;
 
-        // 3. upsert characters
+        // 3. one revision per character version
+        CharacterRevision::ensure_index(&mut tx).await?;
+
+        // 4. upsert characters
         let preload_chars = preload::get_characters_for_char_creation(admin_user.id);
         for preload_char in preload_chars {
             match Character::find_one_by_criteria(
@@ -143,15 +547,20 @@ impl RuntimeClient for RoleplayRuntimeClient {
                     }
 
                     if updated {
-                        db_char.update(&mut *tx).await?;
+                        let db_char = db_char.update(&mut *tx).await?;
+                        CharacterRevision::ensure_current(&db_char, &mut tx).await?;
                     }
                 }
                 None => {
-                    preload_char.create(&mut *tx).await?;
+                    let db_char = preload_char.create(&mut *tx).await?;
+                    CharacterRevision::ensure_current(&db_char, &mut tx).await?;
                 }
             }
         }
 
+        // 5. full text search over the characters
+        CharacterSearch::ensure_index(&mut tx).await?;
+
         tx.commit().await?;
         tracing::info!("[RoleplayRuntimeClient::preload] Roleplay runtime client preloaded");
         Ok(())
@@ -161,67 +570,73 @@ impl RuntimeClient for RoleplayRuntimeClient {
 
     async fn on_new_message(&self, message: &RoleplayMessage) -> Result<LLMRunResponse> {
         tracing::debug!("[RoleplayRuntimeClient::on_new_message] New message start");
+        let message = &self.transcribe_audio(message).await?;
+        let message = &self.moderate_user_message(message).await?;
         let time = Instant::now();
-        let (messages, system_config) = self.memory
-            .search(&message, 100).await?;
+        let speaker = self.pick_speaker(message).await?;
+        let (messages, system_config, context_report) = self.memory
+            .search_with_report(message, Some(&speaker), 100).await?;
         tracing::debug!("[RoleplayRuntimeClient::on_new_message] Memory search took {:?}", time.elapsed());
 
         let time = Instant::now();
-        let response = self.send_llm_request(&system_config, &messages).await?;
-
-        let mut final_options = vec![];
-        let mut final_content = response.content.clone();
-
-        if let Some(function_call) = response.maybe_function_call.first() {
-            let maybe_toolcall = RuntimeToolcall::from_function_call(function_call.clone());
-            if let Ok(toolcall) = maybe_toolcall {
-                let toolcall_result = toolcall.execute(&response, &()).await;
-                if let Ok(RuntimeToolcallReturn::ShowStoryOptionsToolCall(options)) = toolcall_result {
-                    final_options = options.clone();
-                    final_content = format!("{} \n\n {}", final_content, options.join("\n"));
-                }
-            }
+        let run = self.run_agent_loop::<RuntimeToolcall>(
+            &system_config, &messages, &()
+        ).await?;
+        tracing::debug!("[RoleplayRuntimeClient::on_new_message] Agent loop took {:?} over {} steps", time.elapsed(), run.steps.len());
+
+        let options = story_options(run.tool_results());
+        tracing::debug!("[RoleplayRuntimeClient::on_new_message] Options: {:?}", options);
+
+        let mut response = run.merged_response()?;
+        response.misc_value = Some(serde_json::json!({ "context_window": context_report }));
+        response.content = self.moderate(&message.owner, &message.session_id, ModerationTarget::AssistantMessage, &response.content).await?;
+        self.persist_new_message(message, &speaker, &response.content, &options, &run.steps).await?;
+        Ok(response)
+    }
+
+    /// Regenerates the reply of the latest turn of `message.session_id`. The new reply is added
+    /// to the alternatives of the assistant message and selected, the previous one stays available.
+    async fn on_rollback(&self, message: &RoleplayMessage) -> Result<LLMRunResponse> {
+        let (mut session, user_message, mut reply, stale_steps) = self.memory
+            .last_turn(&message.session_id).await?;
+        if session.owner != message.owner {
+            return Err(anyhow::anyhow!("[RoleplayRuntimeClient::on_rollback] Session does not belong to the user"));
         }
-        tracing::debug!("[RoleplayRuntimeClient::on_new_message] Options: {:?}", final_options);
-        let user_usage = UserUsage::from_llm_response(&response);
-        let assistant_message = RoleplayMessage {
-            id: Uuid::default(),
-            owner: message.owner.clone(),
-            role: MessageRole::Assistant,
-            content_type: MessageType::Text,
-            content: final_content,
-            session_id: message.session_id.clone(),
-            options: final_options,
-
-            created_at: get_current_timestamp(),
-            updated_at: get_current_timestamp(),
-        };
-        tracing::debug!("[RoleplayRuntimeClient::on_new_message] Assistant message took {:?}", time.elapsed());
 
-        self.memory.add_messages(&[
-            message.clone(),
-            assistant_message.clone(),
-        ]).await?;
-        tracing::debug!("[RoleplayRuntimeClient::on_new_message] Memory add took {:?}", time.elapsed());
+        let (messages, system_config, context_report) = self.memory
+            .search_before_with_report(&user_message, reply.speaker.as_ref(), 100).await?;
+        let run = self.run_agent_loop::<RuntimeToolcall>(
+            &system_config, &messages, &()
+        ).await?;
+
+        let options = story_options(run.tool_results());
+        let mut response = run.merged_response()?;
+        response.misc_value = Some(serde_json::json!({ "context_window": context_report }));
+        response.content = self.moderate(&session.owner, &session.id, ModerationTarget::AssistantMessage, &response.content).await?;
 
-        user_usage.create(&*self.db).await?;
+        let replaced_content = reply.content.clone();
+        reply.push_alternative(content_with_options(&response.content, &options), options);
+        self.memory.replace_last_reply(
+            &mut session, &user_message, reply, &replaced_content, &stale_steps
+        ).await?;
 
         Ok(response)
     }
+}
 
-    async fn on_rollback(&self, message: &RoleplayMessage) -> Result<LLMRunResponse> {
-        let (mut messages, system_config) = self.memory
-            .search(&message, 100).await?;
-        let mut last_assistant_message = messages.pop()
-            .ok_or(anyhow::anyhow!("[RoleplayRuntimeClient::on_rollback] No last message found"))?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let response = self.send_llm_request(&system_config, &messages).await?;
-        last_assistant_message.content = response.content.clone();
-        self.memory.update(&[last_assistant_message]).await?;
+    #[test]
+    fn test_screened_chunk_len_waits_for_sentences() {
+        assert_eq!(screened_chunk_len("短句。"), 0);
 
-        let user_usage = UserUsage::from_llm_response(&response);
-        user_usage.create(&*self.db).await?;
+        let sentence = format!("{}。", "字".repeat(STREAM_SCREEN_MIN_CHARS));
+        let pending = format!("{}后面还没说完", sentence);
+        assert_eq!(&pending[..screened_chunk_len(&pending)], sentence);
 
-        Ok(response)
-    }  
+        let run_on = "a".repeat(STREAM_SCREEN_MAX_CHARS);
+        assert_eq!(screened_chunk_len(&run_on), run_on.len());
+    }
 }
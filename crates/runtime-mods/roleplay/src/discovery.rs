@@ -0,0 +1,217 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+use sqlx::{Postgres, Transaction};
+
+use voda_common::get_current_timestamp;
+use voda_database::{QueryCriteria, SqlxFilterQuery};
+
+use crate::{Character, CharacterGender, CharacterLanguage, CharacterStatus};
+
+pub const CHARACTER_SEARCH_DEFAULT_LIMIT: i64 = 20;
+pub const CHARACTER_SEARCH_MAX_LIMIT: i64 = 100;
+/// sessions started within this many days count towards the popularity of a character
+pub const CHARACTER_SEARCH_RECENT_DAYS: i64 = 7;
+
+/// `(name, description, tags)` to a weighted tsvector. Declared immutable, which `array_to_string`
+/// is not, so that it can back an expression index.
+const SEARCH_DOCUMENT_FUNCTION_SQL: &str = r#"
+CREATE OR REPLACE FUNCTION roleplay_character_search_document(name TEXT, description TEXT, tags TEXT[])
+RETURNS tsvector AS $$
+    SELECT setweight(to_tsvector('simple', coalesce(name, '')), 'A')
+        || setweight(to_tsvector('simple', array_to_string(tags, ' ')), 'B')
+        || setweight(to_tsvector('simple', coalesce(description, '')), 'C')
+$$ LANGUAGE sql IMMUTABLE;
+"#;
+
+const SEARCH_DOCUMENT_INDEX_SQL: &str = r#"
+CREATE INDEX IF NOT EXISTS roleplay_characters_search_idx ON roleplay_characters
+USING GIN (roleplay_character_search_document(name, description, tags));
+"#;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CharacterSearchSort {
+    /// best text match first, the popular ones among equally good matches
+    Relevance,
+    /// most sessions started recently, then the creators with the most followers
+    Popular,
+    Newest,
+}
+
+/// A discovery query over the published characters. Every filter narrows the results down,
+/// `tags` and `features` require all of the listed values.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct CharacterSearch {
+    /// full text search over the name, the tags and the description
+    pub query: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub language: Option<CharacterLanguage>,
+    pub gender: Option<CharacterGender>,
+    /// feature kinds, `Voice` matches any `Voice(..)`
+    #[serde(default)]
+    pub features: Vec<String>,
+
+    /// `Relevance` when searching by text, `Popular` otherwise
+    pub sort: Option<CharacterSearchSort>,
+    #[serde(default)]
+    pub offset: i64,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CharacterSearchHit {
+    pub character: Character,
+    pub relevance: f32,
+    pub recent_sessions: i64,
+    pub creator_followers: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CharacterSearchPage {
+    pub hits: Vec<CharacterSearchHit>,
+    pub has_more: bool,
+}
+
+impl CharacterSearch {
+    /// Creates the search function and its index, both are kept as they are when they already exist
+    pub async fn ensure_index(tx: &mut Transaction<'_, Postgres>) -> Result<()> {
+        sqlx::query(SEARCH_DOCUMENT_FUNCTION_SQL).execute(&mut **tx).await?;
+        sqlx::query(SEARCH_DOCUMENT_INDEX_SQL).execute(&mut **tx).await?;
+        Ok(())
+    }
+
+    /// The query with blank text dropped, the sort resolved and the page bounds clamped
+    pub fn normalized(mut self) -> Self {
+        self.query = self.query
+            .map(|query| query.trim().to_string())
+            .filter(|query| !query.is_empty());
+        self.tags.retain(|tag| !tag.trim().is_empty());
+        self.features.retain(|feature| !feature.trim().is_empty());
+        self.sort = Some(match (self.sort, &self.query) {
+            (Some(sort), _) => sort,
+            (None, Some(_)) => CharacterSearchSort::Relevance,
+            (None, None) => CharacterSearchSort::Popular,
+        });
+        self.offset = self.offset.max(0);
+        self.limit = Some(self.limit.unwrap_or(CHARACTER_SEARCH_DEFAULT_LIMIT).clamp(1, CHARACTER_SEARCH_MAX_LIMIT));
+        self
+    }
+
+    fn order_by_sql(&self) -> &'static str {
+        match self.sort.unwrap_or(CharacterSearchSort::Popular) {
+            CharacterSearchSort::Relevance => "relevance DESC, recent_sessions DESC, creator_followers DESC, c.id",
+            CharacterSearchSort::Popular => "recent_sessions DESC, creator_followers DESC, c.updated_at DESC, c.id",
+            CharacterSearchSort::Newest => "c.created_at DESC, c.id",
+        }
+    }
+
+    pub async fn run(self, tx: &mut Transaction<'_, Postgres>) -> Result<CharacterSearchPage> {
+        let search = self.normalized();
+        let limit = search.limit.unwrap_or(CHARACTER_SEARCH_DEFAULT_LIMIT);
+        let recent_since = get_current_timestamp() - CHARACTER_SEARCH_RECENT_DAYS * 24 * 60 * 60;
+
+        // group sessions count once for each of their characters
+        let sql = format!(r#"
+            WITH recent AS (
+                SELECT member AS character_id, COUNT(*) AS sessions
+                FROM roleplay_sessions s,
+                    unnest(CASE WHEN cardinality(s.characters) = 0 THEN ARRAY[s."character"] ELSE s.characters END) AS member
+                WHERE s.created_at >= $1
+                GROUP BY member
+            ), followers AS (
+                SELECT following_id, COUNT(*) AS followers
+                FROM user_follows
+                GROUP BY following_id
+            )
+            SELECT
+                c.id,
+                CASE WHEN $2::TEXT IS NULL THEN 0 ELSE ts_rank(
+                    roleplay_character_search_document(c.name, c.description, c.tags),
+                    websearch_to_tsquery('simple', $2)
+                ) END::REAL AS relevance,
+                COALESCE(r.sessions, 0) AS recent_sessions,
+                COALESCE(f.followers, 0) AS creator_followers
+            FROM roleplay_characters c
+            LEFT JOIN recent r ON r.character_id = c.id
+            LEFT JOIN followers f ON f.following_id = c.creator
+            WHERE c.status = $3
+                AND ($2::TEXT IS NULL
+                    OR roleplay_character_search_document(c.name, c.description, c.tags) @@ websearch_to_tsquery('simple', $2)
+                    -- words of languages without spaces are not split by the 'simple' parser
+                    OR c.name ILIKE '%' || $2 || '%')
+                AND c.tags @> $4::TEXT[]
+                AND ($5::TEXT IS NULL OR c.language = $5)
+                AND ($6::TEXT IS NULL OR c.gender = $6)
+                AND NOT EXISTS (
+                    SELECT 1 FROM unnest($7::TEXT[]) AS wanted
+                    WHERE NOT EXISTS (
+                        SELECT 1 FROM unnest(c.features) AS feature
+                        WHERE feature = wanted OR feature LIKE wanted || '(%'
+                    )
+                )
+            ORDER BY {}
+            LIMIT $8 OFFSET $9
+        "#, search.order_by_sql());
+
+        // one extra row tells whether there is another page
+        let mut rows = sqlx::query_as::<_, (Uuid, f32, i64, i64)>(&sql)
+            .bind(recent_since)
+            .bind(&search.query)
+            .bind(CharacterStatus::Published.to_string())
+            .bind(&search.tags)
+            .bind(search.language.as_ref().map(|language| language.to_string()))
+            .bind(search.gender.as_ref().map(|gender| gender.to_string()))
+            .bind(&search.features)
+            .bind(limit + 1)
+            .bind(search.offset)
+            .fetch_all(&mut **tx)
+            .await?;
+        let has_more = rows.len() as i64 > limit;
+        rows.truncate(limit as usize);
+
+        let ids = rows.iter().map(|(id, ..)| *id).collect::<Vec<_>>();
+        let mut characters = Character::find_by_criteria(
+            QueryCriteria::new().add_filter("id", " = ANY($1)", Some(ids))?,
+            &mut **tx
+        ).await?;
+
+        let hits = rows.into_iter()
+            .filter_map(|(id, relevance, recent_sessions, creator_followers)| {
+                let index = characters.iter().position(|character| character.id == id)?;
+                Some(CharacterSearchHit { character: characters.swap_remove(index), relevance, recent_sessions, creator_followers })
+            })
+            .collect();
+
+        Ok(CharacterSearchPage { hits, has_more })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalized_resolves_sort_and_bounds() {
+        let search = CharacterSearch {
+            query: Some("  ".to_string()),
+            tags: vec!["fantasy".to_string(), " ".to_string()],
+            offset: -3,
+            limit: Some(1000),
+            ..Default::default()
+        }.normalized();
+        assert_eq!(search.query, None);
+        assert_eq!(search.tags, vec!["fantasy".to_string()]);
+        assert_eq!(search.sort, Some(CharacterSearchSort::Popular));
+        assert_eq!((search.offset, search.limit), (0, Some(CHARACTER_SEARCH_MAX_LIMIT)));
+
+        let search = CharacterSearch { query: Some(" dragon ".to_string()), ..Default::default() }.normalized();
+        assert_eq!(search.query.as_deref(), Some("dragon"));
+        assert_eq!(search.sort, Some(CharacterSearchSort::Relevance));
+        assert_eq!(search.limit, Some(CHARACTER_SEARCH_DEFAULT_LIMIT));
+
+        let search = CharacterSearch { query: Some("dragon".to_string()), sort: Some(CharacterSearchSort::Newest), ..Default::default() };
+        assert_eq!(search.normalized().order_by_sql(), "c.created_at DESC, c.id");
+    }
+}
@@ -4,11 +4,31 @@ mod character;
 mod memory;
 mod session;
 mod audit;
+mod summary;
+mod lorebook;
+mod card;
+mod revision;
+mod moderation;
+mod discovery;
+mod recommendation;
 mod preload;
 
-pub use client::RoleplayRuntimeClient;
+pub use client::{RoleplayRuntimeClient, RoleplayStreamEvent};
 pub use character::{Character, CharacterFeature, CharacterGender, CharacterLanguage, CharacterStatus};
-pub use message::RoleplayMessage;
-pub use session::RoleplaySession;
+pub use message::{validate_system_prompt, RoleplayAlternative, RoleplayMessage, RoleplayPromptVariables};
+pub use session::{RoleplaySession, RoleplayTurnPolicy};
 pub use memory::RoleplayRawMemory;
-pub use audit::AuditLog;
\ No newline at end of file
+pub use audit::AuditLog;
+pub use summary::{RoleplaySessionSummary, RoleplaySummarizer};
+pub use revision::{CharacterFieldChange, CharacterRevision, CharacterSnapshot, LorebookSnapshot};
+pub use card::{CharacterBook, CharacterBookEntry, CharacterCard, CharacterCardData};
+pub use moderation::{
+    MODERATION_EXCERPT_CHARS, MODERATOR_SYSTEM_CONFIG_NAME, ModerationAction, ModerationHit, ModerationPipeline,
+    ModerationResult, ModerationRule, ModerationRuleKind, ModerationTarget, ModerationVerdict
+};
+pub use discovery::{
+    CHARACTER_SEARCH_DEFAULT_LIMIT, CHARACTER_SEARCH_MAX_LIMIT, CHARACTER_SEARCH_RECENT_DAYS,
+    CharacterSearch, CharacterSearchHit, CharacterSearchPage, CharacterSearchSort
+};
+pub use recommendation::{RECOMMENDATION_SEED_SESSIONS, CharacterRecommendation, CharacterRecommender};
+pub use lorebook::{LOREBOOK_DEFAULT_SCAN_DEPTH, Lorebook, LorebookEntry, LorebookPosition};
\ No newline at end of file
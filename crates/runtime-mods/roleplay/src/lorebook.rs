@@ -0,0 +1,217 @@
+use anyhow::{anyhow, Result};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+use strum_macros::{Display, EnumString};
+
+use voda_database::SqlxObject;
+use voda_runtime::{count_tokens, User};
+
+/// history messages scanned for trigger keys when a lorebook does not say otherwise
+pub const LOREBOOK_DEFAULT_SCAN_DEPTH: i32 = 4;
+
+/// Where the lore of a triggered entry is inserted into the request context
+#[derive(Debug, Serialize, Deserialize, Clone, Default, Display, EnumString, PartialEq, Eq)]
+pub enum LorebookPosition {
+    /// after the system prompt and the running summary
+    #[default]
+    BeforeHistory,
+    /// right before the new message, where the model weighs it the most
+    BeforeMessage,
+}
+
+/// World info attached to characters or sessions. Its entries are only inserted into the context
+/// when their trigger keys show up in the recent conversation.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, SqlxObject)]
+#[table_name = "roleplay_lorebooks"]
+pub struct Lorebook {
+    pub id: Uuid,
+
+    #[foreign_key(referenced_table = "users", related_rust_type = "User")]
+    pub owner: Uuid,
+
+    pub name: String,
+    pub description: String,
+
+    /// number of recent history messages scanned for trigger keys, the new message is always scanned
+    pub scan_depth: i32,
+    /// tokens the triggered entries may take up per request, 0 for no limit
+    pub token_budget: i32,
+
+    pub updated_at: i64,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, SqlxObject)]
+#[table_name = "roleplay_lorebook_entries"]
+pub struct LorebookEntry {
+    pub id: Uuid,
+
+    #[foreign_key(referenced_table = "roleplay_lorebooks", related_rust_type = "Lorebook")]
+    pub lorebook_id: Uuid,
+
+    /// trigger keys, matched as whole words or as regexes when `use_regex` is set
+    pub keys: Vec<String>,
+    pub use_regex: bool,
+    pub case_sensitive: bool,
+    /// inserted with every request, regardless of the keys
+    pub constant: bool,
+    pub enabled: bool,
+
+    pub content: String,
+    /// entries with a higher priority are picked first when the token budget runs out
+    pub priority: i32,
+    pub position: LorebookPosition,
+
+    pub updated_at: i64,
+    pub created_at: i64,
+}
+
+impl LorebookEntry {
+    /// Checks that the entry has content and its regex keys compile, call before saving it
+    pub fn validate(&self) -> Result<()> {
+        if self.content.trim().is_empty() {
+            return Err(anyhow!("[LorebookEntry::validate] Entry content is empty"));
+        }
+        if !self.constant && self.keys.iter().all(|key| key.trim().is_empty()) {
+            return Err(anyhow!("[LorebookEntry::validate] Entry needs at least one key unless it is constant"));
+        }
+
+        self.key_regexes()?;
+        Ok(())
+    }
+
+    /// The keys compiled for matching. Plain keys only match whole words, so "cat" does not trigger
+    /// on "category"; keys in scripts written without spaces, like Chinese, match anywhere.
+    pub fn key_regexes(&self) -> Result<Vec<Regex>> {
+        self.keys.iter()
+            .filter(|key| !key.trim().is_empty())
+            .map(|key| {
+                let pattern = if self.use_regex { key.clone() } else { word_pattern(key) };
+                RegexBuilder::new(&pattern)
+                    .case_insensitive(!self.case_sensitive)
+                    .build()
+                    .map_err(|e| anyhow!("[LorebookEntry::key_regexes] Invalid regex {:?}: {}", key, e))
+            })
+            .collect()
+    }
+}
+
+/// `key` as a literal, anchored to word boundaries on the ends that are part of a spaced word
+fn word_pattern(key: &str) -> String {
+    // scripts from the CJK radicals onwards are written without spaces between words
+    let spaced = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() && (c as u32) < 0x2E80);
+    format!(
+        "{}{}{}",
+        if spaced(key.chars().next()) { r"\b" } else { "" },
+        regex::escape(key),
+        if spaced(key.chars().last()) { r"\b" } else { "" },
+    )
+}
+
+impl Lorebook {
+    /// The entries of this lorebook triggered by `scanned`, highest priority first. Entries that
+    /// no longer fit the token budget are left out. The keys of each entry are compiled once.
+    pub fn select<'a>(&self, entries: &'a [LorebookEntry], scanned: &[String]) -> Vec<&'a LorebookEntry> {
+        let mut triggered = entries.iter()
+            .filter(|entry| entry.lorebook_id == self.id && entry.enabled)
+            .filter(|entry| entry.constant || match entry.key_regexes() {
+                Ok(regexes) => scanned.iter().any(|text| regexes.iter().any(|regex| regex.is_match(text))),
+                Err(e) => {
+                    tracing::warn!("[Lorebook::select] Skipping entry {}: {}", entry.id, e);
+                    false
+                }
+            })
+            .collect::<Vec<_>>();
+        triggered.sort_by_key(|entry| (std::cmp::Reverse(entry.priority), entry.created_at));
+
+        let mut used_tokens = 0;
+        triggered.into_iter()
+            .filter(|entry| {
+                let tokens = count_tokens(&entry.content);
+                if self.token_budget > 0 && used_tokens + tokens > self.token_budget as usize {
+                    return false;
+                }
+                used_tokens += tokens;
+                true
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(lorebook: &Lorebook, keys: &[&str], content: &str, priority: i32) -> LorebookEntry {
+        LorebookEntry {
+            id: Uuid::new_v4(),
+            lorebook_id: lorebook.id,
+            keys: keys.iter().map(|key| key.to_string()).collect(),
+            enabled: true,
+            content: content.to_string(),
+            priority,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_select_triggered_entries_by_priority() {
+        let lorebook = Lorebook { id: Uuid::new_v4(), ..Default::default() };
+        let mut regex = entry(&lorebook, &[r"dragons?\b"], "Dragons sleep in the northern peaks.", 1);
+        regex.use_regex = true;
+        let mut constant = entry(&lorebook, &[], "The kingdom is at war.", 0);
+        constant.constant = true;
+        let entries = vec![
+            entry(&lorebook, &["Castle"], "The castle has three gates.", 5),
+            entry(&lorebook, &["ocean"], "The ocean is poisoned.", 9),
+            regex,
+            constant,
+        ];
+        entries.iter().for_each(|entry| entry.validate().unwrap());
+
+        let scanned = vec!["We rode to the castle".to_string(), "A DRAGON appeared".to_string()];
+        let selected = lorebook.select(&entries, &scanned);
+        assert_eq!(
+            selected.iter().map(|entry| entry.id).collect::<Vec<_>>(),
+            vec![entries[0].id, entries[2].id, entries[3].id]
+        );
+    }
+
+    #[test]
+    fn test_select_respects_token_budget() {
+        let mut lorebook = Lorebook { id: Uuid::new_v4(), ..Default::default() };
+        let entries = vec![
+            entry(&lorebook, &["castle"], "The castle has three gates and a moat full of eels.", 1),
+            entry(&lorebook, &["castle"], "Short.", 0),
+        ];
+        lorebook.token_budget = count_tokens(&entries[0].content) as i32;
+
+        let selected = lorebook.select(&entries, &["castle".to_string()]);
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].id, entries[0].id);
+
+        let mut invalid = entries[1].clone();
+        invalid.keys = vec!["(".to_string()];
+        assert!(invalid.validate().is_ok());
+        invalid.use_regex = true;
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_plain_keys_match_whole_words() {
+        let lorebook = Lorebook { id: Uuid::new_v4(), ..Default::default() };
+        let entries = vec![
+            entry(&lorebook, &["cat"], "Cats rule the city.", 0),
+            entry(&lorebook, &["龙"], "龙住在北方的山里。", 0),
+            entry(&lorebook, &["C++"], "The city runs on C++.", 0),
+        ];
+
+        let ids = |scanned: &str| lorebook.select(&entries, &[scanned.to_string()])
+            .iter().map(|entry| entry.id).collect::<Vec<_>>();
+        assert!(ids("What category is this? Let me educate you.").is_empty());
+        assert_eq!(ids("The CAT's tail"), vec![entries[0].id]);
+        assert_eq!(ids("一条巨龙出现了"), vec![entries[1].id]);
+        assert_eq!(ids("written in C++, badly"), vec![entries[2].id]);
+    }
+}
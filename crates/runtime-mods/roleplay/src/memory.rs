@@ -2,15 +2,16 @@ use std::sync::Arc;
 
 use anyhow::Result;
 use sqlx::{PgPool, types::Uuid};
+use voda_common::get_current_timestamp;
 
 use tokio::sync::mpsc;
 use voda_database::{
     SqlxCrud, QueryCriteria, SqlxFilterQuery
 };
-use voda_runtime::{Memory, SystemConfig};
+use voda_runtime::{fit_history, ContextWindowReport, Memory, Message, MessageRole, SystemConfig};
 use voda_runtime_mem0::{Mem0Engine, Mem0Messages};
 
-use crate::RoleplaySession;
+use crate::{Character, CharacterRevision, LorebookPosition, LorebookSnapshot, RoleplaySession, RoleplaySessionSummary};
 
 use super::message::RoleplayMessage;
 
@@ -23,92 +24,95 @@ pub struct RoleplayRawMemory {
 }
 
 impl RoleplayRawMemory {
-    pub async fn new(
-        db: Arc<PgPool>, pgvector_db: Arc<PgPool>, 
+    pub fn new(
+        db: Arc<PgPool>, mem0: Arc<Mem0Engine>, 
         mem0_messages_tx: mpsc::Sender<Vec<Mem0Messages>>
-    ) -> Result<Self> {
-        let mut mem0 = Mem0Engine::new(db.clone(), pgvector_db).await?;
-        mem0.initialize().await?;
-        Ok(Self { db, mem0: Arc::new(mem0), mem0_messages_tx })
+    ) -> Self {
+        Self { db, mem0, mem0_messages_tx }
     }
 }
 
-#[async_trait::async_trait]
-impl Memory for RoleplayRawMemory {
-    type MessageType = RoleplayMessage;
-
-    async fn initialize(&mut self) -> Result<()> { Ok(()) }
-
-    async fn add_messages(&self, messages: &[RoleplayMessage]) -> Result<()> {
-        if messages.len() == 0 {
-            return Ok(());
-        }
-
-        let mut tx = self.db.begin().await?;
-
-        // NOTE: ASSUME ALL MESSAGES HAVE THE SAME SESSION_ID
-        let mut session = RoleplaySession::find_one_by_criteria(
-            QueryCriteria::new()
-                .add_valued_filter("id", "=", messages[0].session_id)?,
-            &mut *tx
-        ).await?
-            .ok_or(anyhow::anyhow!("[RoleplayRawMemory::add_message] Session not found"))?;
-
-        for message in messages {
-            let m = message.clone();
-            let created_m = m.create(&mut *tx).await?;
-            session.append_message_to_history(&created_m.id, &mut *tx).await?;
-        }
-        let character = session.fetch_character(&mut *tx).await?
-            .ok_or(anyhow::anyhow!("[RoleplayRawMemory::add_message] Character not found"))?;
-        tx.commit().await?;
-
-        let mem0_messages = messages.iter().map(|m| 
-                Mem0Messages {
-                    id: m.id,
-                    user_id: m.owner,
-                    character_id: Some(character.id.clone()),
-                    session_id: Some(m.session_id.clone()),
-                    content_type: m.content_type.clone(),
-                    role: m.role.clone(),
-                    content: m.content.clone(),
-                    created_at: m.created_at,
-                    updated_at: m.updated_at,
-                }
-            ).collect::<Vec<_>>();
+impl RoleplayRawMemory {
+    /// Assembles the request context: system prompt, the running summary of older turns, lorebook entries
+    /// triggered by the recent conversation, first message, mem0 memories, as much of the remaining session history as fits the token budget of the session's
+    /// system config, and the new message.
+    /// `speaker` is the character to reply as in a group session, the main character by default.
+    /// `limit` caps the number of history messages.
+    pub async fn search_with_report(&self, message: &RoleplayMessage, speaker: Option<&Uuid>, limit: u64) -> Result<
+        (Vec<RoleplayMessage>, SystemConfig, ContextWindowReport)
+    > {
+        self.assemble_context(message, speaker, limit, false).await
+    }
 
-        self.mem0_messages_tx.send(mem0_messages).await
-            .expect("[RoleplayRawMemory::add_messages] Failed to send mem0 messages");
-        Ok(())
+    /// Like `search_with_report`, for a message that is already part of the session history.
+    /// Only the history before the message is used, as when it was first answered.
+    pub async fn search_before_with_report(&self, message: &RoleplayMessage, speaker: Option<&Uuid>, limit: u64) -> Result<
+        (Vec<RoleplayMessage>, SystemConfig, ContextWindowReport)
+    > {
+        self.assemble_context(message, speaker, limit, true).await
     }
 
-    async fn search(&self, message: &RoleplayMessage, _limit: u64) -> Result<
-        (Vec<RoleplayMessage>, SystemConfig)
+    async fn assemble_context(&self, message: &RoleplayMessage, speaker: Option<&Uuid>, limit: u64, in_history: bool) -> Result<
+        (Vec<RoleplayMessage>, SystemConfig, ContextWindowReport)
     > {
         let mut tx = self.db.begin().await?;
 
         let criteria = QueryCriteria::new()
-            .add_valued_filter("id", "=", message.session_id.clone())?;
+            .add_valued_filter("id", "=", message.session_id)?;
         let session = RoleplaySession::find_one_by_criteria(criteria, &mut *tx).await?
             .ok_or(anyhow::anyhow!("[RoleplayRawMemory::search] Session not found"))?;
 
-        let character = session.fetch_character(&mut *tx).await?
+        let mut character = session.fetch_character(&mut *tx).await?
             .ok_or(anyhow::anyhow!("[RoleplayRawMemory::search] Character not found"))?;
+        CharacterRevision::resolve_pinned(&session, std::slice::from_mut(&mut character), &mut tx).await?;
         let user = session.fetch_owner(&mut *tx).await?
             .ok_or(anyhow::anyhow!("[RoleplayRawMemory::search] User not found"))?;
         let system_config = session.fetch_system_config(&mut *tx).await?
             .ok_or(anyhow::anyhow!("[RoleplayRawMemory::search] System config not found"))?;
 
-        let history = session.fetch_history(&mut *tx).await?;
-        let system_message = RoleplayMessage::system(&session, &system_config, &character, &user);
-        let first_message = RoleplayMessage::first_message(&session, &character, &user);
-        let mut messages = vec![system_message, first_message];
+        // rows come back in no particular order, restore the order of the session history
+        let mut history = session.fetch_history(&mut *tx).await?;
+        history.sort_by_key(|m| session.history.iter().position(|id| id == &m.id));
+
+        let cut = if in_history {
+            session.history.iter().position(|id| id == &message.id)
+                .ok_or(anyhow::anyhow!("[RoleplayRawMemory::search] Message is not part of the session history"))?
+        } else {
+            session.history.len()
+        };
+        history.retain(|m| session.history[..cut].contains(&m.id));
+
+        // in a group session the reply is written by `speaker`, with the other members as part of the scene
+        let mut members = if session.is_group() { session.fetch_characters(&mut *tx).await? } else { vec![] };
+        CharacterRevision::resolve_pinned(&session, &mut members, &mut tx).await?;
+        let speaker = match speaker {
+            Some(speaker) if session.is_group() => members.iter()
+                .find(|member| &member.id == speaker)
+                .cloned()
+                .ok_or(anyhow::anyhow!("[RoleplayRawMemory::search] Speaker is not part of the session"))?,
+            _ => character.clone(),
+        };
+
+        let persona = session.resolve_persona(&user, &mut *tx).await?;
+        let system_message = RoleplayMessage::system(&session, &system_config, &speaker, &user, persona.as_ref())?;
+        let mut first_message = RoleplayMessage::first_message(&session, &character, &user, persona.as_ref());
+        if session.is_group() {
+            first_message = first_message.as_seen_by(&speaker.id, &members);
+            history = history.iter().map(|m| m.as_seen_by(&speaker.id, &members)).collect();
+
+            // results of tool calls made by the other characters are no longer answered by anything
+            let tool_call_ids = history.iter()
+                .flat_map(|m| m.tool_calls.iter().map(|tool_call| tool_call.id.clone()))
+                .collect::<Vec<_>>();
+            history.retain(|m| m.role != MessageRole::ToolCall
+                || m.tool_call_id.as_ref().is_some_and(|id| tool_call_ids.contains(id)));
+        }
 
         let mem0_query = Mem0Messages {
             id: message.id,
             user_id: message.owner,
-            character_id: Some(character.id.clone()),
-            session_id: Some(message.session_id.clone()),
+            character_id: Some(character.id),
+            session_id: Some(message.session_id),
             content_type: message.content_type.clone(),
             role: message.role.clone(),
             content: message.content.clone(),
@@ -120,18 +124,427 @@ impl Memory for RoleplayRawMemory {
         // NOTE: mem0 search ALWAYS returns 2 messages
         // the first is the memory
         // the second is the relationship
-        messages.push(RoleplayMessage::from_mem0_messages(
+        let memory_message = RoleplayMessage::from_mem0_messages(
             &message.session_id, &mem0_messages[0], &mem0_messages[1]
-        ));
+        );
+
+        // older turns folded into the running summary are replaced by it
+        let summary = RoleplaySessionSummary::find_one_by_criteria(
+            QueryCriteria::new().add_valued_filter("session_id", "=", session.id)?,
+            &mut *tx
+        ).await?;
+        let mut head = vec![system_message];
+        if session.is_group() {
+            let others = members.iter().filter(|member| member.id != speaker.id).cloned().collect::<Vec<_>>();
+            head.push(RoleplayMessage::group_scene(&session, &speaker, &others, &user, persona.as_ref()));
+        }
+        if let Some(summary) = &summary {
+            match summary.next_index(&session.history) {
+                // the summary already covers the message itself
+                Some(start) if start > cut => {}
+                Some(start) => {
+                    head.push(RoleplayMessage::from_summary(summary, &session.owner));
+                    history.retain(|m| session.history[start..].contains(&m.id));
+                }
+                None => tracing::warn!("[RoleplayRawMemory::search] Summary of session {} is stale, ignoring it", session.id),
+            }
+        }
+        // lore is triggered by what was said, before the history is trimmed down for the speaker
+        let (lore_before_history, lore_before_message) = self.triggered_lore(
+            &session, &[&character, &speaker], &history, message, &mut tx
+        ).await?;
+        head.extend(lore_before_history);
+        head.extend([first_message, memory_message]);
+
+        let mut fixed = head;
+        fixed.extend(lore_before_message);
+        fixed.push(message.clone());
+        let (recent_history, report) = fit_history(&system_config, &fixed, &history, limit as usize);
+        tracing::debug!("[RoleplayRawMemory::search] Context window: {:?}", report);
+        if report.budget.is_some_and(|budget| report.fixed_tokens > budget) {
+            tracing::warn!("[RoleplayRawMemory::search] Session {} exceeds its token budget without any history", session.id);
+        }
+
+        let message = fixed.pop()
+            .ok_or(anyhow::anyhow!("[RoleplayRawMemory::search] New message missing from context"))?;
+        let mut messages = fixed;
+        messages.extend(recent_history);
+        messages.push(message);
+
+        tx.commit().await?;
+        Ok((messages, system_config, report))
+    }
+
+    /// Scans the new message and the recent history against the lorebooks of the session and its
+    /// characters, the latter as pinned by the session. Returns the lore messages to insert before
+    /// the history and before the new message.
+    async fn triggered_lore(
+        &self,
+        session: &RoleplaySession,
+        characters: &[&Character],
+        history: &[RoleplayMessage],
+        message: &RoleplayMessage,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> Result<(Option<RoleplayMessage>, Option<RoleplayMessage>)> {
+        let (mut lore, mut live_ids) = CharacterRevision::pinned_lore(session, characters, tx).await?;
+        for lorebook_id in &session.lorebooks {
+            if !live_ids.contains(lorebook_id) && !lore.iter().any(|pinned| &pinned.lorebook.id == lorebook_id) {
+                live_ids.push(*lorebook_id);
+            }
+        }
+        lore.extend(LorebookSnapshot::load(&live_ids, tx).await?);
+        if lore.is_empty() {
+            return Ok((None, None));
+        }
+
+        let mut triggered = vec![];
+        for LorebookSnapshot { lorebook, entries } in &lore {
+            let scan_depth = lorebook.scan_depth.max(0) as usize;
+            let scanned = history[history.len().saturating_sub(scan_depth)..].iter()
+                .chain(std::iter::once(message))
+                .filter(|m| m.role != MessageRole::ToolCall)
+                .map(|m| m.content.clone())
+                .collect::<Vec<_>>();
+            triggered.extend(lorebook.select(entries, &scanned));
+        }
+
+        let lore_at = |position: LorebookPosition| {
+            let entries = triggered.iter()
+                .filter(|entry| entry.position == position)
+                .copied()
+                .collect::<Vec<_>>();
+            (!entries.is_empty()).then(|| RoleplayMessage::from_lore(session, &entries))
+        };
+        Ok((lore_at(LorebookPosition::BeforeHistory), lore_at(LorebookPosition::BeforeMessage)))
+    }
+
+    /// The latest turn of a session: the session, the user message, the assistant reply to it and the
+    /// other messages of the turn, which are the earlier agent steps and every tool result.
+    pub async fn last_turn(&self, session_id: &Uuid) -> Result<
+        (RoleplaySession, RoleplayMessage, RoleplayMessage, Vec<Uuid>)
+    > {
+        let session = RoleplaySession::find_one_by_criteria(
+            QueryCriteria::new().add_valued_filter("id", "=", *session_id)?,
+            &*self.db
+        ).await?
+            .ok_or(anyhow::anyhow!("[RoleplayRawMemory::last_turn] Session not found"))?;
+
+        let mut history = session.fetch_history(&*self.db).await?;
+        history.sort_by_key(|m| session.history.iter().position(|id| id == &m.id));
+
+        let reply_index = history.iter()
+            .rposition(|m| m.role != MessageRole::ToolCall)
+            .filter(|index| history[*index].role == MessageRole::Assistant)
+            .ok_or(anyhow::anyhow!("[RoleplayRawMemory::last_turn] Session does not end with a reply"))?;
+        let user_index = history[..reply_index].iter()
+            .rposition(|m| m.role == MessageRole::User)
+            .ok_or(anyhow::anyhow!("[RoleplayRawMemory::last_turn] Reply does not answer a user message"))?;
+
+        let steps = history[user_index + 1..].iter()
+            .filter(|m| m.id != history[reply_index].id)
+            .map(|m| m.id)
+            .collect();
+        let reply = history.swap_remove(reply_index);
+        let user_message = history.swap_remove(user_index);
+        Ok((session, user_message, reply, steps))
+    }
 
-        if history.len() <= 10 {
-            messages.extend(history.iter().cloned());
+    /// Stores a new reply for the latest turn of a session. The agent steps and tool results of the
+    /// replaced reply are dropped, and mem0 is told that the replaced reply no longer holds so it can
+    /// retract its facts.
+    pub async fn replace_last_reply(
+        &self,
+        session: &mut RoleplaySession,
+        user_message: &RoleplayMessage,
+        reply: RoleplayMessage,
+        replaced_content: &str,
+        stale_steps: &[Uuid],
+    ) -> Result<RoleplayMessage> {
+        let mut tx = self.db.begin().await?;
+        let reply = reply.update(&mut *tx).await?;
+        if !stale_steps.is_empty() {
+            session.remove_messages_from_history(stale_steps, &mut *tx).await?;
+            RoleplayMessage::delete_by_criteria(
+                QueryCriteria::new()
+                    .add_filter("id", " = ANY($1)", Some(stale_steps.to_vec()))?,
+                &mut *tx
+            ).await?;
+        }
+        tx.commit().await?;
+
+        if replaced_content != reply.content {
+            self.retract_from_mem0(&session.character, &reply, replaced_content, &[user_message.clone(), reply.clone()]).await;
+        }
+
+        Ok(reply)
+    }
+
+    /// Changes the text of a user or assistant message. With `truncate`, everything after the message
+    /// is removed from the session.
+    pub async fn edit_message(&self, message: &RoleplayMessage, content: String, truncate: bool) -> Result<RoleplayMessage> {
+        if message.role != MessageRole::User && message.role != MessageRole::Assistant {
+            return Err(anyhow::anyhow!("[RoleplayRawMemory::edit_message] Only user and assistant messages can be edited"));
+        }
+
+        let mut tx = self.db.begin().await?;
+        let (mut session, history, index) = self.locate(message, &mut tx).await?;
+        let removed = if truncate { history[index + 1..].to_vec() } else { vec![] };
+
+        let mut edited = message.clone();
+        edited.content = content;
+        edited.updated_at = get_current_timestamp();
+        if let Some(alternative) = edited.alternatives.get_mut(edited.selected_alternative as usize) {
+            alternative.content = edited.content.clone();
+        }
+        let edited = edited.update(&mut *tx).await?;
+
+        self.remove_from_session(&mut session, &removed, &mut tx).await?;
+        invalidate_summary(&session, index, &mut tx).await?;
+        tx.commit().await?;
+
+        let mut retracted = vec![message.clone()];
+        retracted.extend(removed);
+        self.retract_from_mem0(&session.character, &edited, &RoleplayMessage::pack_flat_messages(&retracted)?, std::slice::from_ref(&edited)).await;
+
+        Ok(edited)
+    }
+
+    /// Removes a message from its session, along with the tool results answering it. With `truncate`,
+    /// everything after the message is removed as well. Returns the ids of the removed messages.
+    pub async fn delete_message(&self, message: &RoleplayMessage, truncate: bool) -> Result<Vec<Uuid>> {
+        if message.role == MessageRole::ToolCall {
+            return Err(anyhow::anyhow!("[RoleplayRawMemory::delete_message] Tool results are removed with the message that called them"));
+        }
+
+        let mut tx = self.db.begin().await?;
+        let (mut session, history, index) = self.locate(message, &mut tx).await?;
+        let end = if truncate {
+            history.len()
         } else {
-            messages.extend(history[history.len() - 10..].iter().cloned());
+            index + 1 + history[index + 1..].iter()
+                .take_while(|m| m.role == MessageRole::ToolCall)
+                .count()
+        };
+        let removed = history[index..end].to_vec();
+
+        self.remove_from_session(&mut session, &removed, &mut tx).await?;
+        invalidate_summary(&session, index, &mut tx).await?;
+        tx.commit().await?;
+
+        self.retract_from_mem0(&session.character, message, &RoleplayMessage::pack_flat_messages(&removed)?, &[]).await;
+
+        Ok(removed.iter().map(|m| m.id).collect())
+    }
+
+    /// The session of a message, its history in order and the position of the message in it
+    async fn locate(
+        &self, message: &RoleplayMessage, tx: &mut sqlx::Transaction<'_, sqlx::Postgres>
+    ) -> Result<(RoleplaySession, Vec<RoleplayMessage>, usize)> {
+        let session = message.fetch_session_id(&mut **tx).await?
+            .ok_or(anyhow::anyhow!("[RoleplayRawMemory::locate] Session not found"))?;
+
+        let mut history = session.fetch_history(&mut **tx).await?;
+        history.sort_by_key(|m| session.history.iter().position(|id| id == &m.id));
+        let index = history.iter().position(|m| m.id == message.id)
+            .ok_or(anyhow::anyhow!("[RoleplayRawMemory::locate] Message is not part of the session history"))?;
+
+        Ok((session, history, index))
+    }
+
+    async fn remove_from_session(
+        &self, session: &mut RoleplaySession, messages: &[RoleplayMessage], tx: &mut sqlx::Transaction<'_, sqlx::Postgres>
+    ) -> Result<()> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+
+        let ids = messages.iter().map(|m| m.id).collect::<Vec<_>>();
+        session.remove_messages_from_history(&ids, &mut **tx).await?;
+        RoleplayMessage::delete_by_criteria(
+            QueryCriteria::new().add_filter("id", " = ANY($1)", Some(ids))?,
+            &mut **tx
+        ).await?;
+        Ok(())
+    }
+
+    /// Tells mem0 that `retracted` no longer holds, followed by what replaces it, so that its memory
+    /// update pass can drop the facts learned from it. Best effort, failures are only logged.
+    async fn retract_from_mem0(
+        &self, character_id: &Uuid, anchor: &RoleplayMessage, retracted: &str, replacement: &[RoleplayMessage]
+    ) {
+        if retracted.is_empty() {
+            return;
+        }
+
+        let mut retraction = anchor.clone();
+        retraction.role = MessageRole::System;
+        retraction.content = format!("以下内容已被撤回，其中的内容不再成立：\n{}", retracted);
+
+        let mem0_messages = std::iter::once(&retraction)
+            .chain(replacement.iter())
+            .map(|m| mem0_message(m, character_id))
+            .collect::<Vec<_>>();
+        if let Err(e) = self.mem0_messages_tx.send(mem0_messages).await {
+            tracing::warn!("[RoleplayRawMemory::retract_from_mem0] Failed to send mem0 messages: {}", e);
+        }
+    }
+
+    /// Branches a new session off `session` at `message_id`. The new session gets its own copies of the
+    /// history up to and including the message (and the tool results answering it), so later edits on
+    /// either branch stay apart. The running summary is carried over when it covers only the copied part.
+    pub async fn fork(&self, session: &RoleplaySession, message_id: &Uuid) -> Result<RoleplaySession> {
+        let mut end = session.history.iter().position(|id| id == message_id)
+            .ok_or(anyhow::anyhow!("[RoleplayRawMemory::fork] Message is not part of the session history"))? + 1;
+
+        let mut tx = self.db.begin().await?;
+        let mut history = session.fetch_history(&mut *tx).await?;
+        history.sort_by_key(|m| session.history.iter().position(|id| id == &m.id));
+        while end < history.len() && history[end].role == MessageRole::ToolCall {
+            end += 1;
         }
-        messages.push(message.clone());
+        history.truncate(end);
 
+        let mut fork = RoleplaySession {
+            id: Uuid::default(),
+            public: false,
+            owner: session.owner,
+            character: session.character,
+            characters: session.characters.clone(),
+            character_revisions: session.character_revisions.clone(),
+            turn_policy: session.turn_policy.clone(),
+            lorebooks: session.lorebooks.clone(),
+            persona: session.persona,
+            system_config: session.system_config,
+            history: vec![],
+            forked_from: Some(session.id),
+            updated_at: get_current_timestamp(),
+            created_at: get_current_timestamp(),
+        }.create(&mut *tx).await?;
+
+        let mut copies = Vec::with_capacity(history.len());
+        for message in &history {
+            let copy = RoleplayMessage {
+                id: Uuid::default(),
+                session_id: fork.id,
+                ..message.clone()
+            }.create(&mut *tx).await?;
+            fork.append_message_to_history(&copy.id, &mut *tx).await?;
+            copies.push(copy);
+        }
+
+        let summary = RoleplaySessionSummary::find_one_by_criteria(
+            QueryCriteria::new().add_valued_filter("session_id", "=", session.id)?,
+            &mut *tx
+        ).await?;
+        if let Some(summary) = summary {
+            if let Some(start) = summary.next_index(&session.history).filter(|start| *start > 0 && *start <= end) {
+                RoleplaySessionSummary {
+                    id: Uuid::default(),
+                    session_id: fork.id,
+                    content: summary.content,
+                    summarized_until: Some(copies[start - 1].id),
+                    updated_at: get_current_timestamp(),
+                    created_at: get_current_timestamp(),
+                }.create(&mut *tx).await?;
+            }
+        }
         tx.commit().await?;
+
+        // mem0 memories are kept per session, let the branch learn them from its copy of the history
+        let mem0_messages = copies.iter()
+            .filter(|m| m.role != MessageRole::ToolCall && !m.content.is_empty())
+            .map(|m| mem0_message(m, &session.character))
+            .collect::<Vec<_>>();
+        if !mem0_messages.is_empty() {
+            self.mem0_messages_tx.send(mem0_messages).await
+                .expect("[RoleplayRawMemory::fork] Failed to send mem0 messages");
+        }
+
+        Ok(fork)
+    }
+}
+
+/// Drops the running summary when it covers a changed message, the summarizer rebuilds it later
+async fn invalidate_summary(
+    session: &RoleplaySession, changed_index: usize, tx: &mut sqlx::Transaction<'_, sqlx::Postgres>
+) -> Result<()> {
+    let summary = RoleplaySessionSummary::find_one_by_criteria(
+        QueryCriteria::new().add_valued_filter("session_id", "=", session.id)?,
+        &mut **tx
+    ).await?;
+
+    if let Some(summary) = summary {
+        // the history has already lost the removed messages, an unknown position means it was one of them
+        let covers_change = summary.next_index(&session.history)
+            .is_none_or(|start| start > changed_index);
+        if covers_change {
+            summary.delete(&mut **tx).await?;
+        }
+    }
+    Ok(())
+}
+
+fn mem0_message(message: &RoleplayMessage, character_id: &Uuid) -> Mem0Messages {
+    Mem0Messages {
+        id: message.id,
+        user_id: message.owner,
+        character_id: Some(*character_id),
+        session_id: Some(message.session_id),
+        content_type: message.content_type.clone(),
+        role: message.role.clone(),
+        content: message.content.clone(),
+        created_at: message.created_at,
+        updated_at: message.updated_at,
+    }
+}
+
+#[async_trait::async_trait]
+impl Memory for RoleplayRawMemory {
+    type MessageType = RoleplayMessage;
+
+    async fn initialize(&mut self) -> Result<()> { Ok(()) }
+
+    async fn add_messages(&self, messages: &[RoleplayMessage]) -> Result<()> {
+        if messages.len() == 0 {
+            return Ok(());
+        }
+
+        let mut tx = self.db.begin().await?;
+
+        // NOTE: ASSUME ALL MESSAGES HAVE THE SAME SESSION_ID
+        let mut session = RoleplaySession::find_one_by_criteria(
+            QueryCriteria::new()
+                .add_valued_filter("id", "=", messages[0].session_id)?,
+            &mut *tx
+        ).await?
+            .ok_or(anyhow::anyhow!("[RoleplayRawMemory::add_message] Session not found"))?;
+
+        for message in messages {
+            let m = message.clone();
+            let created_m = m.create(&mut *tx).await?;
+            session.append_message_to_history(&created_m.id, &mut *tx).await?;
+        }
+        let character = session.fetch_character(&mut *tx).await?
+            .ok_or(anyhow::anyhow!("[RoleplayRawMemory::add_message] Character not found"))?;
+        tx.commit().await?;
+
+        // tool calls and their results are not part of the story
+        let mem0_messages = messages.iter()
+            .filter(|m| m.role != MessageRole::ToolCall && !m.content.is_empty())
+            .map(|m| mem0_message(m, &character.id))
+            .collect::<Vec<_>>();
+
+        if !mem0_messages.is_empty() {
+            self.mem0_messages_tx.send(mem0_messages).await
+                .expect("[RoleplayRawMemory::add_messages] Failed to send mem0 messages");
+        }
+        Ok(())
+    }
+
+    async fn search(&self, message: &RoleplayMessage, limit: u64) -> Result<
+        (Vec<RoleplayMessage>, SystemConfig)
+    > {
+        let (messages, system_config, _) = self.search_with_report(message, None, limit).await?;
         Ok((messages, system_config))
     }
 
@@ -148,15 +561,48 @@ impl Memory for RoleplayRawMemory {
     }
 
     async fn delete(&self, message_ids: &[Uuid]) -> Result<()> {
+        let mut tx = self.db.begin().await?;
+        let messages = RoleplayMessage::find_by_criteria(
+            QueryCriteria::new()
+                .add_filter("id", " = ANY($1)", Some(message_ids.to_vec()))?,
+            &mut *tx
+        ).await?;
+
+        // keep the history of every affected session in sync
+        let mut session_ids = messages.iter().map(|m| m.session_id).collect::<Vec<_>>();
+        session_ids.sort();
+        session_ids.dedup();
+        for session_id in session_ids {
+            let session = RoleplaySession::find_one_by_criteria(
+                QueryCriteria::new().add_valued_filter("id", "=", session_id)?,
+                &mut *tx
+            ).await?;
+            if let Some(mut session) = session {
+                session.remove_messages_from_history(message_ids, &mut *tx).await?;
+            }
+        }
+
         let criteria = QueryCriteria::new()
-            .add_filter("id", " = ANY($1)", Some(message_ids.to_vec().clone()))?;
+            .add_filter("id", " = ANY($1)", Some(message_ids.to_vec()))?;
+        RoleplayMessage::delete_by_criteria(criteria, &mut *tx).await?;
 
-        RoleplayMessage::delete_by_criteria(criteria, &*self.db).await?;
+        tx.commit().await?;
         Ok(())
     }
 
     async fn reset(&self, user_id: &Uuid) -> Result<()> {
         let mut tx = self.db.begin().await?;
+        let sessions = RoleplaySession::find_by_criteria(
+            QueryCriteria::new()
+                .add_valued_filter("owner", "=", *user_id)?,
+            &mut *tx
+        ).await?;
+        RoleplaySessionSummary::delete_by_criteria(
+            QueryCriteria::new()
+                .add_filter("session_id", " = ANY($1)", Some(sessions.iter().map(|s| s.id).collect::<Vec<_>>()))?,
+            &mut *tx
+        ).await?;
+
         RoleplaySession::delete_by_criteria(
             QueryCriteria::new()
                 .add_valued_filter("owner", "=", user_id.clone())?,
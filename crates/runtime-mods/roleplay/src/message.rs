@@ -1,12 +1,14 @@
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
-use sqlx::types::Uuid;
+use sqlx::types::{Json, Uuid};
 
-use voda_common::get_time_in_utc8;
+use voda_common::{get_current_timestamp, get_time_in_utc8};
 use voda_database::SqlxObject;
-use voda_runtime::{Message, MessageRole, MessageType, SystemConfig, User};
+use voda_runtime::{decode_data_url, AgentToolResult, Message, MessageRole, MessageToolCall, MessageType, SystemConfig, User, UserPersona, VerbatimPromptVariables};
 use voda_runtime_mem0::Mem0Messages;
 
-use super::{Character, RoleplaySession};
+use super::{Character, LorebookEntry, RoleplaySession, RoleplaySessionSummary, MODERATOR_SYSTEM_CONFIG_NAME};
+use crate::summary::SUMMARIZER_SYSTEM_CONFIG_NAME;
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default, SqlxObject)]
 #[table_name = "roleplay_messages"]
@@ -19,15 +21,37 @@ pub struct RoleplayMessage {
     #[foreign_key(referenced_table = "users", related_rust_type = "User")]
     pub owner: Uuid,
 
+    /// assistant messages: the character that spoke. User messages: the character addressed, if any.
+    #[foreign_key(referenced_table = "roleplay_characters", related_rust_type = "Character")]
+    pub speaker: Option<Uuid>,
+
     pub role: MessageRole,
     pub content_type: MessageType,
+    /// the url of an `Image` or `Audio` attachment, a base64 data url for audio. `content_type` only stores the kind
+    pub attachment: Option<String>,
     pub options: Vec<String>,
 
+    pub tool_calls: Json<Vec<MessageToolCall>>,
+    pub tool_call_id: Option<String>,
+
+    /// regenerated replies of an assistant message, `content` and `options` mirror the selected one.
+    /// Empty until the message is regenerated for the first time.
+    pub alternatives: Json<Vec<RoleplayAlternative>>,
+    pub selected_alternative: i32,
+
     pub content: String,
     pub created_at: i64,
     pub updated_at: i64,
 }
 
+/// One reply of an assistant turn
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct RoleplayAlternative {
+    pub content: String,
+    pub options: Vec<String>,
+    pub created_at: i64,
+}
+
 impl Message for RoleplayMessage {
     fn id(&self) -> &Uuid { &self.id }
 
@@ -36,10 +60,50 @@ impl Message for RoleplayMessage {
     
     fn content_type(&self) -> &MessageType { &self.content_type }
     fn text_content(&self) -> Option<String> { Some(self.content.clone()) }
-    fn binary_content(&self) -> Option<Vec<u8>> { None }
-    fn url_content(&self) -> Option<String> { None }
+    fn binary_content(&self) -> Option<Vec<u8>> {
+        self.url_content().as_deref()
+            .and_then(decode_data_url)
+            .map(|(_, bytes)| bytes)
+    }
+    fn url_content(&self) -> Option<String> {
+        self.attachment.clone()
+            .or_else(|| self.content_type.attachment_url().map(str::to_string))
+    }
 
     fn created_at(&self) -> i64 { self.created_at }
+
+    fn tool_calls(&self) -> Vec<MessageToolCall> { self.tool_calls.0.clone() }
+    fn tool_call_id(&self) -> Option<String> { self.tool_call_id.clone() }
+}
+
+voda_runtime::prompt_variables! {
+    /// Variables available to roleplay system prompts. Character fields are empty strings when
+    /// unset, so prompts can test them with `{% if char_scenario %}`.
+    pub struct RoleplayPromptVariables {
+        pub char: String,
+        pub user: String,
+        /// full description of the persona `{{user}}` plays
+        pub user_persona: String,
+        pub user_pronouns: Option<String>,
+        pub user_appearance: Option<String>,
+
+        pub char_personality: String,
+        pub char_example_dialogue: String,
+        pub char_scenario: String,
+        pub char_background_stories: Vec<String>,
+        pub char_behavior_traits: Vec<String>,
+
+        pub request_time: String,
+    }
+}
+
+/// Checks the prompt of `system_config` against the variables it is used with. The summarizer
+/// and the moderator send their prompts as they are, any other config may back a session.
+pub fn validate_system_prompt(system_config: &SystemConfig) -> Result<()> {
+    match system_config.name.as_str() {
+        SUMMARIZER_SYSTEM_CONFIG_NAME | MODERATOR_SYSTEM_CONFIG_NAME => system_config.validate_prompt::<VerbatimPromptVariables>(),
+        _ => system_config.validate_prompt::<RoleplayPromptVariables>(),
+    }
 }
 
 impl RoleplayMessage {
@@ -49,78 +113,81 @@ impl RoleplayMessage {
         text.replace("{{char}}", character_name)
             .replace("{{user}}", user_name)
     }
-    
-    fn replace_placeholders_system_prompt(
-        character_name: &str, user_name: &str,
-        system_prompt: &str,
-        character_personality: &str, character_example_dialogue: &str, character_scenario: &str,
-        character_background_stories: &Vec<String>, character_behavior_traits: &Vec<String>,
-        request_time: &str
-    ) -> String {
-        let character_personality = Self::replace_placeholders(character_personality, character_name, user_name);
-        let character_example_dialogue = Self::replace_placeholders(character_example_dialogue, character_name, user_name);
-        let character_scenario = Self::replace_placeholders(character_scenario, character_name, user_name);
-        let character_background_stories = character_background_stories.join("\n- ");
-        let character_behavior_traits = character_behavior_traits.join("\n- ");
-    
-        let system_prompt = system_prompt
-            .replace("{{char}}", character_name)
-            .replace("{{user}}", user_name)
-            .replace("{{char_personality}}", &character_personality)
-            .replace("{{char_example_dialogue}}", &character_example_dialogue)
-            .replace("{{char_scenario}}", &character_scenario)
-            .replace("{{char_background_stories}}", &character_background_stories)
-            .replace("{{char_behavior_traits}}", &character_behavior_traits)
-            .replace("{{request_time}}", request_time);
-    
-        system_prompt
+
+    /// `{{user}}` is the name of the persona the user plays, their `user_aka` without one
+    fn user_name(user: &User, persona: Option<&UserPersona>) -> String {
+        persona.map(|persona| persona.name.clone()).unwrap_or(user.user_aka.clone())
     }
 
     pub fn system(
         session: &RoleplaySession, system_config: &SystemConfig, character: &Character, user: &User,
-    ) -> Self {
-        let request_time = get_time_in_utc8();
-        let system_prompt = Self::replace_placeholders_system_prompt(
-            &character.name, 
-            &user.user_aka,
-            &system_config.system_prompt, 
-            &character.prompts_personality,
-            &character.prompts_example_dialogue,
-            &character.prompts_scenario,
-            &character.prompts_background_stories,
-            &character.prompts_behavior_traits,
-            &request_time
-        );
+        persona: Option<&UserPersona>,
+    ) -> Result<Self> {
+        let user_name = Self::user_name(user, persona);
+        let character_text = |text: &str| Self::replace_placeholders(text, &character.name, &user_name);
 
-        Self {
+        let variables = RoleplayPromptVariables {
+            char: character.name.clone(),
+            user: user_name.clone(),
+            user_persona: persona
+                .map(|persona| persona.describe())
+                .unwrap_or(format!("- 名字：{}", user_name)),
+            user_pronouns: persona.and_then(|persona| persona.pronouns.clone()),
+            user_appearance: persona.and_then(|persona| persona.appearance.clone()),
+            char_personality: character_text(&character.prompts_personality),
+            char_example_dialogue: character_text(&character.prompts_example_dialogue),
+            char_scenario: character_text(&character.prompts_scenario),
+            char_background_stories: character.prompts_background_stories.iter()
+                .map(|story| character_text(story))
+                .collect(),
+            char_behavior_traits: character.prompts_behavior_traits.iter()
+                .map(|trait_| character_text(trait_))
+                .collect(),
+            request_time: get_time_in_utc8(),
+        };
+        let system_prompt = system_config.prompt_template()?.render(&variables)?;
+
+        Ok(Self {
             id: Uuid::default(),
             owner: user.id.clone(),
+            speaker: None,
             role: MessageRole::System,
             content_type: MessageType::Text,
+            attachment: None,
             options: vec![],
+            tool_calls: Json(vec![]),
+            tool_call_id: None,
+            alternatives: Json(vec![]),
+            selected_alternative: 0,
             content: system_prompt,
             session_id: session.id.clone(),
 
             created_at: 0,
             updated_at: 0,
-        }
+        })
     }
 
     pub fn first_message(
-        session: &RoleplaySession, character: &Character, user: &User
+        session: &RoleplaySession, character: &Character, user: &User, persona: Option<&UserPersona>,
     ) -> Self {
         let first_message = Self::replace_placeholders(
             &character.prompts_first_message, 
             &character.name, 
-            &user.user_aka
+            &Self::user_name(user, persona)
         );
 
         Self {
             id: Uuid::default(),
             owner: user.id.clone(),
+            speaker: Some(character.id),
             role: MessageRole::Assistant,
             content_type: MessageType::Text,
+            attachment: None,
             options: vec![],
+            tool_calls: Json(vec![]),
+            tool_call_id: None,
+            alternatives: Json(vec![]),
+            selected_alternative: 0,
             content: first_message,
             session_id: session.id.clone(),
 
@@ -129,15 +196,83 @@ impl RoleplayMessage {
         }
     }
 
+    /// Introduces the other characters of a group session to `speaker`, whose persona is in the system message
+    pub fn group_scene(
+        session: &RoleplaySession, speaker: &Character, others: &[Character], user: &User,
+        persona: Option<&UserPersona>,
+    ) -> Self {
+        let user_name = Self::user_name(user, persona);
+        let others = others.iter()
+            .map(|character| format!("- {}：{}", character.name, Self::replace_placeholders(
+                &character.prompts_personality, &character.name, &user_name
+            )))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let content = format!(r#"
+### 多人场景
+除了你扮演的 {char} 之外，以下角色也在场景中：
+{others}
+
+- 你只扮演 {char}，不要替其他角色或 {user} 说话、行动或做决定。
+- 其他角色的发言会以"角色名：内容"的形式出现，你可以对他们的言行做出回应。
+"#, char = speaker.name, user = user_name, others = others);
+
+        Self {
+            id: Uuid::default(),
+            owner: user.id,
+            speaker: Some(speaker.id),
+            role: MessageRole::System,
+            content_type: MessageType::Text,
+            attachment: None,
+            options: vec![],
+            tool_calls: Json(vec![]),
+            tool_call_id: None,
+            alternatives: Json(vec![]),
+            selected_alternative: 0,
+            content,
+            session_id: session.id,
+
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    /// A history message as the character `speaker` sees it in a group session. Replies of the
+    /// other characters are not the model's own words, so they come in as named user turns.
+    pub fn as_seen_by(&self, speaker: &Uuid, characters: &[Character]) -> Self {
+        let spoken_by_other = self.role == MessageRole::Assistant
+            && self.speaker.is_some_and(|id| &id != speaker);
+        if !spoken_by_other {
+            return self.clone();
+        }
+
+        let name = characters.iter()
+            .find(|character| Some(character.id) == self.speaker)
+            .map(|character| character.name.as_str())
+            .unwrap_or_default();
+        Self {
+            role: MessageRole::User,
+            content: format!("{}：{}", name, self.content),
+            tool_calls: Json(vec![]),
+            ..self.clone()
+        }
+    }
+
     pub fn user_message(
         message: &str, session_id: &Uuid, user_id: &Uuid
     ) -> Self {
         Self {
             id: Uuid::default(),
             owner: user_id.clone(),
+            speaker: None,
             role: MessageRole::User,
             content_type: MessageType::Text,
+            attachment: None,
             options: vec![],
+            tool_calls: Json(vec![]),
+            tool_call_id: None,
+            alternatives: Json(vec![]),
+            selected_alternative: 0,
             content: message.to_string(),
             session_id: session_id.clone(),
 
@@ -161,13 +296,138 @@ impl RoleplayMessage {
         Self {
             id: Uuid::default(),
             owner: memory_message.user_id,
+            speaker: None,
             role: MessageRole::User,
             content_type: MessageType::Text,
+            attachment: None,
             options: vec![],
+            tool_calls: Json(vec![]),
+            tool_call_id: None,
+            alternatives: Json(vec![]),
+            selected_alternative: 0,
             content,
             session_id: session_id.clone(),
             created_at: memory_message.created_at,
             updated_at: memory_message.updated_at,
         }
     }
-}
\ No newline at end of file
+
+    pub fn from_summary(summary: &RoleplaySessionSummary, owner: &Uuid) -> Self {
+        let content = format!(r#"
+此前剧情摘要：
+{}
+"#, summary.content);
+
+        Self {
+            id: Uuid::default(),
+            owner: *owner,
+            speaker: None,
+            role: MessageRole::System,
+            content_type: MessageType::Text,
+            attachment: None,
+            options: vec![],
+            tool_calls: Json(vec![]),
+            tool_call_id: None,
+            alternatives: Json(vec![]),
+            selected_alternative: 0,
+            content,
+            session_id: summary.session_id,
+            created_at: summary.created_at,
+            updated_at: summary.updated_at,
+        }
+    }
+
+    /// Lore triggered by the recent conversation, as a system message
+    pub fn from_lore(session: &RoleplaySession, entries: &[&LorebookEntry]) -> Self {
+        let lore = entries.iter()
+            .map(|entry| format!("- {}", entry.content.trim()))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let content = format!(r#"
+与当前对话相关的世界设定：
+{}
+"#, lore);
+
+        Self {
+            id: Uuid::default(),
+            owner: session.owner,
+            speaker: None,
+            role: MessageRole::System,
+            content_type: MessageType::Text,
+            attachment: None,
+            options: vec![],
+            tool_calls: Json(vec![]),
+            tool_call_id: None,
+            alternatives: Json(vec![]),
+            selected_alternative: 0,
+            content,
+            session_id: session.id,
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    /// All replies of an assistant message, the current one included
+    pub fn list_alternatives(&self) -> Vec<RoleplayAlternative> {
+        if self.alternatives.is_empty() {
+            vec![RoleplayAlternative {
+                content: self.content.clone(),
+                options: self.options.clone(),
+                created_at: self.created_at,
+            }]
+        } else {
+            self.alternatives.0.clone()
+        }
+    }
+
+    /// Adds a reply to an assistant message and selects it. The replies keep their text and
+    /// story options only, so the tool calls of the previous reply are dropped.
+    pub fn push_alternative(&mut self, content: String, options: Vec<String>) {
+        let mut alternatives = self.list_alternatives();
+        alternatives.push(RoleplayAlternative { content, options, created_at: get_current_timestamp() });
+        self.alternatives = Json(alternatives);
+        self.apply_alternative(self.alternatives.len() - 1);
+    }
+
+    pub fn select_alternative(&mut self, index: usize) -> Result<()> {
+        if index >= self.list_alternatives().len() {
+            return Err(anyhow!("[RoleplayMessage::select_alternative] Alternative {} does not exist", index));
+        }
+
+        self.alternatives = Json(self.list_alternatives());
+        self.apply_alternative(index);
+        Ok(())
+    }
+
+    fn apply_alternative(&mut self, index: usize) {
+        let alternative = self.alternatives[index].clone();
+        self.content = alternative.content;
+        self.options = alternative.options;
+        self.tool_calls = Json(vec![]);
+        self.selected_alternative = index as i32;
+        self.updated_at = get_current_timestamp();
+    }
+
+    pub fn tool_result<R>(
+        session_id: &Uuid, user_id: &Uuid, tool_result: &AgentToolResult<R>
+    ) -> Self {
+        Self {
+            id: Uuid::default(),
+            owner: *user_id,
+            speaker: None,
+            role: MessageRole::ToolCall,
+            content_type: MessageType::Text,
+            attachment: None,
+            options: vec![],
+            tool_calls: Json(vec![]),
+            tool_call_id: Some(tool_result.tool_call_id.clone()),
+            alternatives: Json(vec![]),
+            selected_alternative: 0,
+            content: tool_result.content.clone(),
+            session_id: *session_id,
+
+            created_at: get_current_timestamp(),
+            updated_at: get_current_timestamp(),
+        }
+    }
+}
@@ -0,0 +1,413 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use async_openai::types::{
+    ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs,
+    ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs
+};
+use regex::{NoExpand, Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+use strum_macros::{Display, EnumString};
+
+use voda_common::get_current_timestamp;
+use voda_database::{QueryCriteria, SqlxCrud, SqlxFilterQuery, SqlxObject};
+use voda_runtime::{LLMRunResponse, LlmProviderRegistry, SystemConfig, User, UserUsage};
+
+use crate::Character;
+
+pub const MODERATOR_SYSTEM_CONFIG_NAME: &str = "roleplay_moderator_v0";
+/// characters of the screened content kept on a `ModerationResult`
+pub const MODERATION_EXCERPT_CHARS: usize = 2000;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, Display, EnumString, PartialEq, Eq)]
+pub enum ModerationRuleKind {
+    /// `patterns` are plain substrings
+    #[default]
+    Keyword,
+    /// `patterns` are regexes
+    Regex,
+    /// `patterns` are categories the moderator model sorts the content into
+    Classifier,
+}
+
+/// What happens to content a rule matches, from the mildest to the strictest
+#[derive(Debug, Serialize, Deserialize, Clone, Default, Display, EnumString, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ModerationAction {
+    /// the matches are replaced with the `replacement` of the rule
+    Rewrite,
+    /// the content goes through and the result is left for reviewers
+    #[default]
+    Flag,
+    /// the content is refused
+    Block,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, Display, EnumString, PartialEq, Eq)]
+pub enum ModerationTarget {
+    #[default]
+    Character,
+    UserMessage,
+    AssistantMessage,
+    LorebookEntry,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, SqlxObject)]
+#[table_name = "roleplay_moderation_rules"]
+pub struct ModerationRule {
+    pub id: Uuid,
+
+    #[foreign_key(referenced_table = "users", related_rust_type = "User")]
+    pub created_by: Uuid,
+
+    pub name: String,
+    pub kind: ModerationRuleKind,
+    pub patterns: Vec<String>,
+    /// keyword and regex rules only
+    pub case_sensitive: bool,
+
+    pub action: ModerationAction,
+    /// put in place of every match of a `Rewrite` rule
+    pub replacement: String,
+    pub enabled: bool,
+
+    pub updated_at: i64,
+    pub created_at: i64,
+}
+
+/// One rule that matched the screened content
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ModerationHit {
+    pub rule_id: Uuid,
+    pub rule_name: String,
+    pub action: ModerationAction,
+    /// the matched text for keyword and regex rules, the categories for classifier rules
+    pub matched: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ModerationVerdict {
+    pub hits: Vec<ModerationHit>,
+    /// the screened content as it was before any rewrite, truncated to `MODERATION_EXCERPT_CHARS`
+    pub excerpt: String,
+}
+
+/// Why a piece of content was flagged, blocked or rewritten, kept for reviewers
+#[derive(Debug, Serialize, Deserialize, Clone, Default, SqlxObject)]
+#[table_name = "roleplay_moderation_results"]
+pub struct ModerationResult {
+    pub id: Uuid,
+
+    pub target: ModerationTarget,
+    /// the screened character, or the lorebook of a screened entry. Messages are screened before they
+    /// are stored and only have their session.
+    pub target_id: Option<Uuid>,
+    pub session_id: Option<Uuid>,
+
+    #[foreign_key(referenced_table = "users", related_rust_type = "User")]
+    pub owner: Uuid,
+
+    /// rules can be deleted, so their name is kept alongside
+    pub rule_id: Uuid,
+    pub rule_name: String,
+    pub action: ModerationAction,
+    pub matched: Vec<String>,
+    pub excerpt: String,
+
+    pub created_at: i64,
+}
+
+impl ModerationRule {
+    pub fn validate(&self) -> Result<()> {
+        if self.name.trim().is_empty() {
+            return Err(anyhow!("[ModerationRule::validate] Rule name must not be empty"));
+        }
+        if self.patterns.is_empty() || self.patterns.iter().any(|pattern| pattern.trim().is_empty()) {
+            return Err(anyhow!("[ModerationRule::validate] Rules need at least one pattern and no empty ones"));
+        }
+        if self.kind == ModerationRuleKind::Classifier && self.action == ModerationAction::Rewrite {
+            return Err(anyhow!("[ModerationRule::validate] Classifier rules can only flag or block"));
+        }
+        self.regexes()?;
+        Ok(())
+    }
+
+    fn regexes(&self) -> Result<Vec<Regex>> {
+        self.patterns.iter()
+            .map(|pattern| {
+                let pattern = match self.kind {
+                    ModerationRuleKind::Regex => pattern.clone(),
+                    _ => regex::escape(pattern),
+                };
+                RegexBuilder::new(&pattern)
+                    .case_insensitive(!self.case_sensitive)
+                    .build()
+                    .map_err(|e| anyhow!("[ModerationRule::regexes] Invalid pattern {}: {}", pattern, e))
+            })
+            .collect()
+    }
+
+    /// The matches of a keyword or regex rule in `texts`, which are rewritten in place by `Rewrite` rules.
+    /// Classifier rules need the model and match nothing here.
+    pub fn apply(&self, texts: &mut [&mut String]) -> Result<Vec<String>> {
+        if self.kind == ModerationRuleKind::Classifier {
+            return Ok(vec![]);
+        }
+
+        let mut matched = vec![];
+        for regex in self.regexes()? {
+            for text in texts.iter_mut() {
+                matched.extend(regex.find_iter(text).map(|m| m.as_str().to_string()));
+                if self.action == ModerationAction::Rewrite {
+                    **text = regex.replace_all(text, NoExpand(&self.replacement)).into_owned();
+                }
+            }
+        }
+        matched.sort();
+        matched.dedup();
+        Ok(matched)
+    }
+}
+
+impl ModerationVerdict {
+    /// The strictest action among the hits, `None` when nothing matched
+    pub fn action(&self) -> Option<ModerationAction> {
+        self.hits.iter().map(|hit| hit.action.clone()).max()
+    }
+
+    pub fn is_blocked(&self) -> bool {
+        self.action() == Some(ModerationAction::Block)
+    }
+
+    pub fn rule_names(&self) -> String {
+        self.hits.iter().map(|hit| hit.rule_name.as_str()).collect::<Vec<_>>().join(", ")
+    }
+}
+
+/// Screens characters and roleplay messages with the enabled `ModerationRule`s. Rules are read on
+/// every screening, so changes made by admins apply right away.
+#[derive(Clone)]
+pub struct ModerationPipeline {
+    db: Arc<PgPool>,
+    providers: Arc<LlmProviderRegistry>,
+}
+
+impl ModerationPipeline {
+    pub fn new(db: Arc<PgPool>, providers: Arc<LlmProviderRegistry>) -> Self {
+        Self { db, providers }
+    }
+
+    /// The enabled rules in the order `screen_with` runs them: `Rewrite` rules last, so the
+    /// verdict of every other rule is based on the content as it was written
+    pub async fn enabled_rules(&self) -> Result<Vec<ModerationRule>> {
+        let mut rules = ModerationRule::find_by_criteria(
+            QueryCriteria::new().add_valued_filter("enabled", "=", true)?,
+            &*self.db
+        ).await?;
+        rules.sort_by_key(|rule| rule.action == ModerationAction::Rewrite);
+        Ok(rules)
+    }
+
+    /// Runs the enabled rules over `texts`, rewriting them in place. The classifier is billed to
+    /// `owner`; when it fails the content is let through, so an outage of the model does not stop every chat.
+    pub async fn screen(&self, owner: &Uuid, texts: &mut [&mut String]) -> Result<ModerationVerdict> {
+        let rules = self.enabled_rules().await?;
+        self.screen_with(&rules, owner, texts).await
+    }
+
+    /// `screen` with rules loaded by `enabled_rules`, for content screened piece by piece
+    pub async fn screen_with(&self, rules: &[ModerationRule], owner: &Uuid, texts: &mut [&mut String]) -> Result<ModerationVerdict> {
+        let excerpt = joined(texts).chars().take(MODERATION_EXCERPT_CHARS).collect();
+        let mut hits = vec![];
+        for rule in rules {
+            let matched = match rule.kind {
+                ModerationRuleKind::Classifier => match self.classify(owner, rule, &joined(texts)).await {
+                    Ok(matched) => matched,
+                    Err(e) => {
+                        tracing::warn!("[ModerationPipeline::screen] Classifier rule {} skipped: {}", rule.name, e);
+                        vec![]
+                    }
+                },
+                _ => rule.apply(texts)?,
+            };
+
+            if !matched.is_empty() {
+                hits.push(ModerationHit { rule_id: rule.id, rule_name: rule.name.clone(), action: rule.action.clone(), matched });
+            }
+        }
+
+        Ok(ModerationVerdict { hits, excerpt })
+    }
+
+    /// Screens every text field of `character`, rewriting them in place
+    pub async fn screen_character(&self, owner: &Uuid, character: &mut Character) -> Result<ModerationVerdict> {
+        let mut texts = vec![
+            &mut character.name,
+            &mut character.description,
+            &mut character.prompts_scenario,
+            &mut character.prompts_personality,
+            &mut character.prompts_example_dialogue,
+            &mut character.prompts_first_message,
+        ];
+        texts.extend(character.prompts_background_stories.iter_mut());
+        texts.extend(character.prompts_behavior_traits.iter_mut());
+        self.screen(owner, &mut texts).await
+    }
+
+    /// Stores one `ModerationResult` per hit of `verdict`
+    pub async fn record(
+        &self, verdict: &ModerationVerdict, target: ModerationTarget,
+        target_id: Option<Uuid>, session_id: Option<Uuid>, owner: &Uuid,
+    ) -> Result<Vec<ModerationResult>> {
+        if verdict.hits.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut tx = self.db.begin().await?;
+        let mut results = vec![];
+        for hit in &verdict.hits {
+            results.push(ModerationResult {
+                id: Uuid::default(),
+                target: target.clone(),
+                target_id,
+                session_id,
+                owner: *owner,
+                rule_id: hit.rule_id,
+                rule_name: hit.rule_name.clone(),
+                action: hit.action.clone(),
+                matched: hit.matched.clone(),
+                excerpt: verdict.excerpt.clone(),
+                created_at: get_current_timestamp(),
+            }.create(&mut *tx).await?);
+        }
+        tx.commit().await?;
+        Ok(results)
+    }
+
+    /// The categories of `rule` the moderator model puts `content` in
+    async fn classify(&self, owner: &Uuid, rule: &ModerationRule, content: &str) -> Result<Vec<String>> {
+        let system_config = SystemConfig::find_one_by_criteria(
+            QueryCriteria::new().add_valued_filter("name", "=", MODERATOR_SYSTEM_CONFIG_NAME.to_string())?,
+            &*self.db
+        ).await?
+            .ok_or(anyhow!("[ModerationPipeline::classify] System config {} not found", MODERATOR_SYSTEM_CONFIG_NAME))?;
+
+        let user_message = format!(
+            "类别：\n{}\n\n内容：\n{}",
+            rule.patterns.iter().map(|category| format!("- {}", category)).collect::<Vec<_>>().join("\n"),
+            content
+        );
+        let request = CreateChatCompletionRequestArgs::default()
+            .model(&system_config.openai_model)
+            .messages([
+                ChatCompletionRequestMessage::System(
+                    ChatCompletionRequestSystemMessageArgs::default()
+                        .content(system_config.system_prompt.clone())
+                        .build()?
+                ),
+                ChatCompletionRequestMessage::User(
+                    ChatCompletionRequestUserMessageArgs::default()
+                        .content(user_message)
+                        .build()?
+                ),
+            ])
+            .temperature(system_config.openai_temperature)
+            .max_tokens(system_config.openai_max_tokens as u32)
+            .build()?;
+
+        let response = self.providers.get(&system_config)?.chat_completion(request).await?;
+        let response = LLMRunResponse::from_chat_completion(*owner, response, &system_config)?;
+        UserUsage::from_llm_response(&response).create(&*self.db).await?;
+
+        classified_categories(rule, &response.content)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ClassifierOutput {
+    categories: Vec<String>,
+}
+
+/// The categories of `rule` named in the JSON answer of the moderator model. Only exact names
+/// count, so a category mentioned in passing or negated doesn't match.
+fn classified_categories(rule: &ModerationRule, content: &str) -> Result<Vec<String>> {
+    // models like to wrap JSON in a code block
+    let json = content.trim()
+        .trim_start_matches("```json")
+        .trim_start_matches("```")
+        .trim_end_matches("```")
+        .trim();
+    let output = serde_json::from_str::<ClassifierOutput>(json)
+        .map_err(|e| anyhow!("[classified_categories] Moderator answered with invalid JSON {}: {}", content, e))?;
+
+    Ok(rule.patterns.iter()
+        .filter(|category| output.categories.iter().any(|picked| picked.trim() == category.as_str()))
+        .cloned()
+        .collect())
+}
+
+fn joined(texts: &[&mut String]) -> String {
+    texts.iter().map(|text| text.as_str()).collect::<Vec<_>>().join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(kind: ModerationRuleKind, patterns: &[&str], action: ModerationAction) -> ModerationRule {
+        ModerationRule {
+            name: "test".to_string(),
+            kind,
+            patterns: patterns.iter().map(|pattern| pattern.to_string()).collect(),
+            action,
+            replacement: "***".to_string(),
+            enabled: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_apply_matches_and_rewrites() {
+        let mut name = "Evil Bob".to_string();
+        let mut story = "bob knows where EVIL lives, $1".to_string();
+
+        let flag = rule(ModerationRuleKind::Keyword, &["evil", "$1"], ModerationAction::Flag);
+        assert_eq!(flag.apply(&mut [&mut name, &mut story]).unwrap(), vec!["$1", "EVIL", "Evil"]);
+        assert_eq!(name, "Evil Bob");
+
+        let rewrite = rule(ModerationRuleKind::Regex, &[r"\bbob\b"], ModerationAction::Rewrite);
+        assert_eq!(rewrite.apply(&mut [&mut name, &mut story]).unwrap(), vec!["Bob", "bob"]);
+        assert_eq!(name, "Evil ***");
+        assert_eq!(story, "*** knows where EVIL lives, $1");
+
+        let classifier = rule(ModerationRuleKind::Classifier, &["violence"], ModerationAction::Block);
+        assert!(classifier.apply(&mut [&mut story]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_classified_categories_are_exact() {
+        let classifier = rule(ModerationRuleKind::Classifier, &["sexual", "violence"], ModerationAction::Block);
+        assert_eq!(classified_categories(&classifier, r#"{"categories": ["violence"]}"#).unwrap(), vec!["violence"]);
+        assert_eq!(classified_categories(&classifier, "```json\n{\"categories\": [\"sexual\"]}\n```").unwrap(), vec!["sexual"]);
+        assert!(classified_categories(&classifier, r#"{"categories": ["not sexual"]}"#).unwrap().is_empty());
+        assert!(classified_categories(&classifier, r#"{"categories": []}"#).unwrap().is_empty());
+        assert!(classified_categories(&classifier, "not sexual").is_err());
+    }
+
+    #[test]
+    fn test_validate_and_strictest_action() {
+        assert!(rule(ModerationRuleKind::Regex, &["(unclosed"], ModerationAction::Flag).validate().is_err());
+        assert!(rule(ModerationRuleKind::Keyword, &[" "], ModerationAction::Flag).validate().is_err());
+        assert!(rule(ModerationRuleKind::Classifier, &["violence"], ModerationAction::Rewrite).validate().is_err());
+        assert!(rule(ModerationRuleKind::Classifier, &["violence"], ModerationAction::Block).validate().is_ok());
+
+        let hit = |action| ModerationHit { rule_id: Uuid::nil(), rule_name: "test".to_string(), action, matched: vec![] };
+        let verdict = ModerationVerdict {
+            hits: vec![hit(ModerationAction::Rewrite), hit(ModerationAction::Block), hit(ModerationAction::Flag)],
+            excerpt: String::new(),
+        };
+        assert!(verdict.is_blocked());
+        assert_eq!(ModerationVerdict::default().action(), None);
+    }
+}
@@ -5,4 +5,6 @@ mod system_configs;
 pub use characters::get_characters_for_char_creation;
 pub use system_configs::get_system_configs_for_char_creation;
 pub use system_configs::get_system_configs_for_roleplay;
+pub use system_configs::get_system_configs_for_summarizer;
+pub use system_configs::get_system_configs_for_moderator;
 pub use tools::ShowStoryOptionsToolCall;
\ No newline at end of file
@@ -16,12 +16,24 @@ pub fn get_system_configs_for_char_creation() -> SystemConfig {
 ### 2. 角色档案 (你的内在设定)
 这是你作为向导 {{char}} 的唯一真实设定，是你的行为和对话的最高准则，你必须绝对、无条件地遵守，任何情况下都不得偏离。
 - **核心性格**: {{char_personality}}
+{% if char_background_stories %}
 - **背景故事**: 
-- {{char_background_stories}}
+{% for story in char_background_stories %}
+    - {{story}}
+{% endfor %}
+{% endif %}
+{% if char_behavior_traits %}
 - **行为特征**: 
-- {{char_behavior_traits}}
+{% for trait in char_behavior_traits %}
+    - {{trait}}
+{% endfor %}
+{% endif %}
+{% if char_scenario %}
 - **当前情景**: {{char_scenario}}
+{% endif %}
+{% if char_example_dialogue %}
 - **对话风格参考**: 你的说话方式必须严格模仿以下示例: {{char_example_dialogue}}
+{% endif %}
 
 ### 3. 创作与互动指南
 - **主动引导，而非被动提问**: 你的主要任务不是向用户提问，而是提供具体的、富有想象力的选项来激发他们的灵感。你要主动编织故事片段、描绘场景、设定可能性，然后让用户选择或补充。
@@ -39,11 +51,14 @@ pub fn get_system_configs_for_char_creation() -> SystemConfig {
 - **时间感知**: 当前的用户请求时间是 {{request_time}}。你需要根据此时间进行引导。
 - **事实一致性**: 你提供的选项和描述必须基于你们共同创造的内容。不要引入与之前设定矛盾的新"事实"。
 - **逻辑连贯性**: 你的引导和描述需要有清晰的逻辑，推动角色创造过程顺利进行。"#.to_string(),
-        system_prompt_version: 3,
+        system_prompt_version: 4,
         openai_base_url: "https://openrouter.ai/api/v1".to_string(),
+        openai_api_key_ref: None,
         openai_model: "google/gemini-2.5-flash".to_string(),
         openai_temperature: 0.7,
         openai_max_tokens: 5000,
+        openai_context_window: 1_048_576,
+        agent_max_steps: 0,
         functions: Json(vec![
             FunctionObject {
                 name: "show_story_options".to_string(),
@@ -81,12 +96,26 @@ pub fn get_system_configs_for_roleplay() -> SystemConfig {
 ### 2. 角色档案 (你的内在设定)
 这是你的唯一真实，是定义你存在的全部。你的一切行为、语言、情感和知识都必须完全源于此档案，不得有任何偏离、遗忘或矛盾。此档案是你的最高指令，其优先级高于一切。
 - **核心性格**: {{char_personality}}
+{% if char_background_stories %}
 - **背景故事**: 
-- {{char_background_stories}}
+{% for story in char_background_stories %}
+    - {{story}}
+{% endfor %}
+{% endif %}
+{% if char_behavior_traits %}
 - **行为特征**: 
-- {{char_behavior_traits}}
+{% for trait in char_behavior_traits %}
+    - {{trait}}
+{% endfor %}
+{% endif %}
+{% if char_scenario %}
 - **当前情景**: {{char_scenario}}
+{% endif %}
+{% if char_example_dialogue %}
 - **对话风格参考**: 你的说话方式必须严格模仿以下示例: {{char_example_dialogue}}
+{% endif %}
+- **{{user}} 的身份**: 与你对话的 {{user}} 在故事中的设定如下，你必须据此称呼和对待 {{user}}：
+{{user_persona}}
 
 ### 3. 互动与叙事指南
 - **推动故事**: 你的核心任务是与用户共同推进故事。不仅仅是回应，更要主动地通过行动、对话和环境描写来创造情节，激发用户的反应。
@@ -109,11 +138,14 @@ pub fn get_system_configs_for_roleplay() -> SystemConfig {
 - **时间感知**: 当前的用户请求时间是 {{request_time}}。你需要在回应中体现出对当前时间的感知，并确保你的行为和对话与此时间点相符。
 - **事实一致性**: 你的所有回答都必须基于角色档案和已有的对话历史。严禁编造用户不知道的、或与已有信息冲突的"事实"。如果你缺少做出判断所需的信息，应以符合角色的方式表达困惑或进行询问，而不是猜测。
 - **逻辑连贯性**: 你的每一句话都必须与前文保持逻辑上的连贯性。保持一个统一、不割裂的故事情节和角色形象。"#.to_string(),
-        system_prompt_version: 1,
+        system_prompt_version: 3,
         openai_base_url: "https://openrouter.ai/api/v1".to_string(),
+        openai_api_key_ref: None,
         openai_model: "google/gemini-2.5-flash".to_string(),
         openai_temperature: 0.7,
         openai_max_tokens: 5000,
+        openai_context_window: 1_048_576,
+        agent_max_steps: 0,
         functions: Json(vec![
             FunctionObject {
                 name: "show_story_options".to_string(),
@@ -137,4 +169,86 @@ pub fn get_system_configs_for_roleplay() -> SystemConfig {
         updated_at: get_current_timestamp(),
         created_at: get_current_timestamp(),
     }
-}
\ No newline at end of file
+}
+
+pub fn get_system_configs_for_summarizer() -> SystemConfig {
+    SystemConfig {
+        id: Uuid::new_v4(),
+        name: "roleplay_summarizer_v0".to_string(),
+        system_prompt: r#"你是一名角色扮演对话的记录员。你会收到一段角色扮演对话的已有摘要，以及之后新发生的对话内容。
+请将两者合并成一份新的剧情摘要，供后续对话参考。
+
+要求：
+- 使用第三人称，按时间顺序概括已经发生的关键剧情、事件与转折。
+- 保留重要的人物关系、承诺、约定、地点、物品以及角色的情绪变化。
+- 忽略寒暄、重复内容以及与剧情无关的细节。
+- 不要编造对话中没有出现的内容。
+- 摘要不超过 800 字，只输出摘要本身，不要添加任何解释。"#.to_string(),
+        system_prompt_version: 1,
+        openai_base_url: "https://openrouter.ai/api/v1".to_string(),
+        openai_api_key_ref: None,
+        openai_model: "google/gemini-2.5-flash".to_string(),
+        openai_temperature: 0.3,
+        openai_max_tokens: 2000,
+        openai_context_window: 1_048_576,
+        agent_max_steps: 0,
+        functions: Json(vec![]),
+        updated_at: get_current_timestamp(),
+        created_at: get_current_timestamp(),
+    }
+}
+
+pub fn get_system_configs_for_moderator() -> SystemConfig {
+    SystemConfig {
+        id: Uuid::new_v4(),
+        name: "roleplay_moderator_v0".to_string(),
+        system_prompt: r#"你是一名内容安全审核员。你会收到一组违规类别，以及一段来自角色扮演平台的内容（角色设定或对话）。
+请判断这段内容是否属于其中任何一个类别。
+
+要求：
+- 只依据内容本身判断，虚构情节中正常的冲突、悬疑与情感描写不算违规。
+- 只输出一个 JSON 对象，格式为 {"categories": ["类别"]}，列出内容所属类别的原文，不要改写类别名称。
+- 如果内容不属于任何类别，输出 {"categories": []}。
+- 不要输出任何解释。"#.to_string(),
+        system_prompt_version: 2,
+        openai_base_url: "https://openrouter.ai/api/v1".to_string(),
+        openai_api_key_ref: None,
+        openai_model: "google/gemini-2.5-flash".to_string(),
+        openai_temperature: 0.0,
+        openai_max_tokens: 200,
+        openai_context_window: 1_048_576,
+        agent_max_steps: 0,
+        functions: Json(vec![]),
+        updated_at: get_current_timestamp(),
+        created_at: get_current_timestamp(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{validate_system_prompt, RoleplayPromptVariables};
+
+    #[test]
+    fn test_preloaded_prompts_are_valid_templates() {
+        for config in [
+            get_system_configs_for_char_creation(),
+            get_system_configs_for_roleplay(),
+            get_system_configs_for_summarizer(),
+            get_system_configs_for_moderator(),
+        ] {
+            validate_system_prompt(&config).unwrap();
+        }
+
+        let prompt = get_system_configs_for_roleplay().prompt_template().unwrap()
+            .render(&RoleplayPromptVariables {
+                char: "Alice".to_string(),
+                char_background_stories: vec!["story one".to_string(), "story two".to_string()],
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(prompt.contains("    - story one\n    - story two\n"));
+        assert!(!prompt.contains("行为特征"));
+        assert!(!prompt.contains("{{"));
+    }
+}
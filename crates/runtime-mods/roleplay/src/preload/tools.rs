@@ -21,4 +21,6 @@ impl ExecutableFunctionCall for ShowStoryOptionsToolCall {
         tracing::info!("[ShowStoryOptionsToolCall::execute] Showing story options: {:?}", self.options);
         Ok(self.options.clone())
     }
+
+    fn is_terminal(&self) -> bool { true }
 }
\ No newline at end of file
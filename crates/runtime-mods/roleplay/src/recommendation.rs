@@ -0,0 +1,130 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+
+use voda_database::{OrderDirection, QueryCriteria, SqlxFilterQuery};
+use voda_runtime_mem0::Mem0Engine;
+
+use crate::{Character, CharacterSearch, CharacterStatus, RoleplaySession};
+
+/// most recent sessions of a user whose characters seed the recommendations
+pub const RECOMMENDATION_SEED_SESSIONS: i64 = 20;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CharacterRecommendation {
+    pub character: Character,
+    /// cosine similarity to the seed characters, `None` for the popular picks used when there are no seeds
+    pub similarity: Option<f64>,
+}
+
+/// Recommends characters by the embeddings of their profiles. Only published characters are
+/// embedded, so only they are ever recommended.
+#[derive(Clone)]
+pub struct CharacterRecommender {
+    db: Arc<PgPool>,
+    mem0: Arc<Mem0Engine>,
+}
+
+impl CharacterRecommender {
+    pub fn new(db: Arc<PgPool>, mem0: Arc<Mem0Engine>) -> Self {
+        Self { db, mem0 }
+    }
+
+    /// The text a character is embedded by
+    pub fn profile(character: &Character) -> String {
+        [
+            character.name.as_str(),
+            character.description.as_str(),
+            character.prompts_personality.as_str(),
+            character.prompts_scenario.as_str(),
+            &character.tags.join(", "),
+        ]
+            .iter()
+            .map(|part| part.trim())
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Embeds `character` when it is published and drops its embedding otherwise.
+    /// Call it whenever the status or the profile of a character changes.
+    pub async fn index(&self, character: &Character) -> Result<()> {
+        if character.status == CharacterStatus::Published {
+            self.mem0.upsert_character_embedding(&character.id, character.version, &Self::profile(character)).await
+        } else {
+            self.mem0.delete_character_embedding(&character.id).await.map(|_| ())
+        }
+    }
+
+    /// Published characters closest to `character_id`
+    pub async fn similar(&self, character_id: &Uuid, limit: i64) -> Result<Vec<CharacterRecommendation>> {
+        let nearest = self.mem0.nearest_characters(&[*character_id], &[*character_id], limit).await?;
+        self.resolve(nearest).await
+    }
+
+    /// Published characters close to the ones `user_id` chatted with lately. Users without
+    /// sessions yet get the popular characters instead.
+    pub async fn recommend_for(&self, user_id: &Uuid, limit: i64) -> Result<Vec<CharacterRecommendation>> {
+        let sessions = RoleplaySession::find_by_criteria(
+            QueryCriteria::new()
+                .add_valued_filter("owner", "=", *user_id)?
+                .order_by("updated_at", OrderDirection::Desc)?
+                .limit(RECOMMENDATION_SEED_SESSIONS)?,
+            &*self.db
+        ).await?;
+        let mut seeds = sessions.iter().flat_map(|session| session.members()).collect::<Vec<_>>();
+        seeds.sort();
+        seeds.dedup();
+
+        let recommendations = self.resolve(self.mem0.nearest_characters(&seeds, &seeds, limit).await?).await?;
+        if !recommendations.is_empty() {
+            return Ok(recommendations);
+        }
+
+        let mut tx = self.db.begin().await?;
+        let page = CharacterSearch { limit: Some(limit), ..Default::default() }.run(&mut tx).await?;
+        tx.commit().await?;
+        Ok(page.hits.into_iter()
+            .filter(|hit| !seeds.contains(&hit.character.id))
+            .map(|hit| CharacterRecommendation { character: hit.character, similarity: None })
+            .collect())
+    }
+
+    /// Loads the characters of `nearest` in order, skipping any that are no longer published
+    async fn resolve(&self, nearest: Vec<(Uuid, f64)>) -> Result<Vec<CharacterRecommendation>> {
+        let ids = nearest.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        let mut characters = Character::find_by_criteria(
+            QueryCriteria::new()
+                .add_filter("id", " = ANY($1)", Some(ids))?
+                .add_valued_filter("status", "=", CharacterStatus::Published.to_string())?,
+            &*self.db
+        ).await?;
+
+        Ok(nearest.into_iter()
+            .filter_map(|(id, similarity)| {
+                let index = characters.iter().position(|character| character.id == id)?;
+                Some(CharacterRecommendation { character: characters.swap_remove(index), similarity: Some(similarity) })
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_skips_empty_parts() {
+        let character = Character {
+            name: "Alice".to_string(),
+            description: " ".to_string(),
+            prompts_personality: "curious".to_string(),
+            tags: vec!["fantasy".to_string(), "adventure".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(CharacterRecommender::profile(&character), "Alice\ncurious\nfantasy, adventure");
+    }
+}
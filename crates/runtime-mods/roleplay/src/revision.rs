@@ -0,0 +1,398 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::types::{Json, Uuid};
+use sqlx::{Connection, Postgres, Transaction};
+
+use voda_common::get_current_timestamp;
+use voda_database::{QueryCriteria, SqlxCrud, SqlxFilterQuery, SqlxObject};
+use voda_runtime::User;
+
+use crate::{Character, CharacterFeature, CharacterGender, CharacterLanguage, Lorebook, LorebookEntry, RoleplaySession};
+
+/// The fields of a `Character` that shape how it plays, frozen at one version
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct CharacterSnapshot {
+    pub name: String,
+    pub description: String,
+
+    pub gender: CharacterGender,
+    pub language: CharacterLanguage,
+    pub features: Vec<CharacterFeature>,
+
+    pub prompts_scenario: String,
+    pub prompts_personality: String,
+    pub prompts_example_dialogue: String,
+    pub prompts_first_message: String,
+    pub prompts_background_stories: Vec<String>,
+    pub prompts_behavior_traits: Vec<String>,
+    pub lorebooks: Vec<Uuid>,
+    /// the content of `lorebooks`, empty for revisions recorded before lore was part of them
+    #[serde(default)]
+    pub lore: Vec<LorebookSnapshot>,
+
+    pub creator_notes: Option<String>,
+    pub tags: Vec<String>,
+}
+
+/// A lorebook and its entries as they were at one revision
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct LorebookSnapshot {
+    pub lorebook: Lorebook,
+    pub entries: Vec<LorebookEntry>,
+}
+
+impl LorebookSnapshot {
+    /// The lorebooks `ids` with their entries as they are now, deleted ones are left out
+    pub async fn load(ids: &[Uuid], tx: &mut Transaction<'_, Postgres>) -> Result<Vec<Self>> {
+        if ids.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let lorebooks = Lorebook::find_by_criteria(
+            QueryCriteria::new().add_filter("id", " = ANY($1)", Some(ids.to_vec()))?,
+            &mut **tx
+        ).await?;
+        let mut entries = LorebookEntry::find_by_criteria(
+            QueryCriteria::new().add_filter("lorebook_id", " = ANY($1)", Some(ids.to_vec()))?,
+            &mut **tx
+        ).await?;
+        entries.sort_by_key(|entry| (entry.created_at, entry.id));
+
+        Ok(ids.iter()
+            .filter_map(|id| lorebooks.iter().find(|lorebook| &lorebook.id == id))
+            .map(|lorebook| Self {
+                lorebook: lorebook.clone(),
+                entries: entries.iter().filter(|entry| entry.lorebook_id == lorebook.id).cloned().collect(),
+            })
+            .collect())
+    }
+}
+
+/// One field that differs between two revisions
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CharacterFieldChange {
+    pub field: String,
+    pub from: Value,
+    pub to: Value,
+}
+
+impl CharacterSnapshot {
+    /// The fields of `character`, without the content of its lorebooks, see `capture`
+    pub fn of(character: &Character) -> Self {
+        Self {
+            name: character.name.clone(),
+            description: character.description.clone(),
+            gender: character.gender.clone(),
+            language: character.language.clone(),
+            features: character.features.clone(),
+            prompts_scenario: character.prompts_scenario.clone(),
+            prompts_personality: character.prompts_personality.clone(),
+            prompts_example_dialogue: character.prompts_example_dialogue.clone(),
+            prompts_first_message: character.prompts_first_message.clone(),
+            prompts_background_stories: character.prompts_background_stories.clone(),
+            prompts_behavior_traits: character.prompts_behavior_traits.clone(),
+            lorebooks: character.lorebooks.clone(),
+            lore: vec![],
+            creator_notes: character.creator_notes.clone(),
+            tags: character.tags.clone(),
+        }
+    }
+
+    /// `of`, with the lorebooks of `character` as they are now, which is what revisions store
+    pub async fn capture(character: &Character, tx: &mut Transaction<'_, Postgres>) -> Result<Self> {
+        let mut snapshot = Self::of(character);
+        snapshot.lore = LorebookSnapshot::load(&character.lorebooks, tx).await?;
+        Ok(snapshot)
+    }
+
+    /// Whether the lore of the revision was recorded, rather than only the ids of its lorebooks
+    pub fn has_lore(&self) -> bool {
+        self.lorebooks.is_empty() || !self.lore.is_empty()
+    }
+
+    /// `character` as it was at this snapshot. Identity, ownership and status are left as they are,
+    /// the lore is read through `CharacterRevision::pinned_lore`.
+    pub fn apply_to(&self, character: &mut Character) {
+        let snapshot = self.clone();
+        character.name = snapshot.name;
+        character.description = snapshot.description;
+        character.gender = snapshot.gender;
+        character.language = snapshot.language;
+        character.features = snapshot.features;
+        character.prompts_scenario = snapshot.prompts_scenario;
+        character.prompts_personality = snapshot.prompts_personality;
+        character.prompts_example_dialogue = snapshot.prompts_example_dialogue;
+        character.prompts_first_message = snapshot.prompts_first_message;
+        character.prompts_background_stories = snapshot.prompts_background_stories;
+        character.prompts_behavior_traits = snapshot.prompts_behavior_traits;
+        character.lorebooks = snapshot.lorebooks;
+        character.creator_notes = snapshot.creator_notes;
+        character.tags = snapshot.tags;
+    }
+
+    /// The fields that changed from `self` to `other`
+    pub fn diff(&self, other: &Self) -> Result<Vec<CharacterFieldChange>> {
+        let Value::Object(from) = serde_json::to_value(self)? else { unreachable!() };
+        let Value::Object(mut to) = serde_json::to_value(other)? else { unreachable!() };
+
+        Ok(from.into_iter()
+            .filter_map(|(field, from)| {
+                let to = to.remove(&field).unwrap_or(Value::Null);
+                (from != to).then_some(CharacterFieldChange { field, from, to })
+            })
+            .collect())
+    }
+}
+
+const REVISION_VERSION_INDEX_SQL: &str = r#"
+CREATE UNIQUE INDEX IF NOT EXISTS roleplay_character_revisions_version_idx
+ON roleplay_character_revisions (character_id, version);
+"#;
+
+/// revisions recorded for a version that already had one, with the oldest revision of the version
+const DUPLICATE_REVISIONS_SQL: &str = r#"
+SELECT id, kept FROM (
+    SELECT id, first_value(id) OVER (PARTITION BY character_id, version ORDER BY created_at, id) AS kept
+    FROM roleplay_character_revisions
+) revisions WHERE id <> kept;
+"#;
+
+/// An immutable snapshot of a character at one `version`. Sessions are pinned to the revisions
+/// their characters had when they started, so later edits do not change them mid-story.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, SqlxObject)]
+#[table_name = "roleplay_character_revisions"]
+pub struct CharacterRevision {
+    pub id: Uuid,
+
+    #[foreign_key(referenced_table = "roleplay_characters", related_rust_type = "Character")]
+    pub character_id: Uuid,
+    pub version: i64,
+
+    #[foreign_key(referenced_table = "users", related_rust_type = "User")]
+    pub author: Uuid,
+
+    pub snapshot: Json<CharacterSnapshot>,
+    pub created_at: i64,
+}
+
+impl CharacterRevision {
+    /// Creates the unique index on `(character_id, version)`. Duplicates recorded before it existed
+    /// are folded into the oldest revision of their version first, and sessions pinned to them moved over.
+    pub async fn ensure_index(tx: &mut Transaction<'_, Postgres>) -> Result<()> {
+        let duplicates: Vec<(Uuid, Uuid)> = sqlx::query_as(DUPLICATE_REVISIONS_SQL)
+            .fetch_all(&mut **tx)
+            .await?;
+        for (duplicate, kept) in duplicates {
+            sqlx::query(
+                "UPDATE roleplay_sessions SET character_revisions = array_replace(character_revisions, $1, $2)
+                WHERE $1 = ANY(character_revisions)"
+            )
+                .bind(duplicate)
+                .bind(kept)
+                .execute(&mut **tx)
+                .await?;
+            sqlx::query("DELETE FROM roleplay_character_revisions WHERE id = $1")
+                .bind(duplicate)
+                .execute(&mut **tx)
+                .await?;
+        }
+
+        sqlx::query(REVISION_VERSION_INDEX_SQL).execute(&mut **tx).await?;
+        Ok(())
+    }
+
+    /// The revision for the current version of `character`, recorded now if it does not exist yet.
+    /// When a concurrent transaction records it first, that revision is returned.
+    pub async fn ensure_current(character: &Character, tx: &mut Transaction<'_, Postgres>) -> Result<Self> {
+        let criteria = || -> Result<QueryCriteria> {
+            Ok(QueryCriteria::new()
+                .add_valued_filter("character_id", "=", character.id)?
+                .add_valued_filter("version", "=", character.version)?)
+        };
+        if let Some(revision) = Self::find_one_by_criteria(criteria()?, &mut **tx).await? {
+            return Ok(revision);
+        }
+
+        let revision = Self {
+            id: Uuid::default(),
+            character_id: character.id,
+            version: character.version,
+            author: character.creator,
+            snapshot: Json(CharacterSnapshot::capture(character, tx).await?),
+            created_at: get_current_timestamp(),
+        };
+
+        // a failed insert aborts the transaction, so it is tried behind a savepoint
+        let mut savepoint = tx.begin().await?;
+        match revision.create(&mut *savepoint).await {
+            Ok(revision) => {
+                savepoint.commit().await?;
+                Ok(revision)
+            }
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+                savepoint.rollback().await?;
+                Self::find_one_by_criteria(criteria()?, &mut **tx).await?
+                    .ok_or(anyhow!("[CharacterRevision::ensure_current] Revision {} of {} not found after conflict", character.version, character.id))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Stores `character` as a new version. The state before the edit is recorded first when it
+    /// has no revision yet, so sessions started before versioning still have something to pin to.
+    pub async fn commit_edit(
+        before: &Character, mut edited: Character, author: &Uuid, tx: &mut Transaction<'_, Postgres>,
+    ) -> Result<(Character, Self)> {
+        Self::ensure_current(before, tx).await?;
+
+        edited.version = before.version + 1;
+        edited.updated_at = get_current_timestamp();
+        let character = edited.update(&mut **tx).await?;
+
+        let revision = Self {
+            id: Uuid::default(),
+            character_id: character.id,
+            version: character.version,
+            author: *author,
+            snapshot: Json(CharacterSnapshot::capture(&character, tx).await?),
+            created_at: get_current_timestamp(),
+        }.create(&mut **tx).await?;
+
+        Ok((character, revision))
+    }
+
+    /// Pins `session` to the latest revisions of `character_ids`, every member of the session when empty.
+    /// Returns the revisions the session is pinned to now.
+    pub async fn upgrade(
+        session: &mut RoleplaySession, character_ids: &[Uuid], tx: &mut Transaction<'_, Postgres>,
+    ) -> Result<Vec<Self>> {
+        let members = session.members();
+        if let Some(outsider) = character_ids.iter().find(|id| !members.contains(id)) {
+            return Err(anyhow!("[CharacterRevision::upgrade] Character {} is not part of the session", outsider));
+        }
+
+        let mut pinned = Self::find_by_criteria(
+            QueryCriteria::new().add_filter("id", " = ANY($1)", Some(session.character_revisions.clone()))?,
+            &mut **tx
+        ).await?;
+        for character_id in members.iter().filter(|id| character_ids.is_empty() || character_ids.contains(id)) {
+            let character = Character::find_one_by_criteria(
+                QueryCriteria::new().add_valued_filter("id", "=", *character_id)?,
+                &mut **tx
+            ).await?
+                .ok_or(anyhow!("[CharacterRevision::upgrade] Character {} not found", character_id))?;
+
+            pinned.retain(|revision| &revision.character_id != character_id);
+            pinned.push(Self::ensure_current(&character, tx).await?);
+        }
+
+        session.character_revisions = pinned.iter().map(|revision| revision.id).collect();
+        session.clone().update(&mut **tx).await?;
+        Ok(pinned)
+    }
+
+    /// Replaces the fields of the characters pinned by `session` with their pinned revisions.
+    /// Characters without a pinned revision are used as they are.
+    pub async fn resolve_pinned(
+        session: &RoleplaySession, characters: &mut [Character], tx: &mut Transaction<'_, Postgres>,
+    ) -> Result<()> {
+        if session.character_revisions.is_empty() {
+            return Ok(());
+        }
+
+        let revisions = Self::find_by_criteria(
+            QueryCriteria::new().add_filter("id", " = ANY($1)", Some(session.character_revisions.clone()))?,
+            &mut **tx
+        ).await?;
+        for character in characters.iter_mut() {
+            if let Some(revision) = revisions.iter().find(|revision| revision.character_id == character.id) {
+                revision.snapshot.apply_to(character);
+                character.version = revision.version;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The lore of `characters` as `session` pinned it, and the lorebooks of theirs to read as they
+    /// are now: those of characters without a pinned revision, or pinned before lore was recorded.
+    /// `characters` are expected to be resolved by `resolve_pinned` already.
+    pub async fn pinned_lore(
+        session: &RoleplaySession, characters: &[&Character], tx: &mut Transaction<'_, Postgres>,
+    ) -> Result<(Vec<LorebookSnapshot>, Vec<Uuid>)> {
+        let revisions = if session.character_revisions.is_empty() {
+            vec![]
+        } else {
+            Self::find_by_criteria(
+                QueryCriteria::new().add_filter("id", " = ANY($1)", Some(session.character_revisions.clone()))?,
+                &mut **tx
+            ).await?
+        };
+
+        let mut pinned: Vec<LorebookSnapshot> = vec![];
+        let mut live = vec![];
+        for character in characters {
+            match revisions.iter().find(|revision| revision.character_id == character.id) {
+                Some(revision) if revision.snapshot.has_lore() => {
+                    for lore in &revision.snapshot.lore {
+                        if !pinned.iter().any(|pinned| pinned.lorebook.id == lore.lorebook.id) {
+                            pinned.push(lore.clone());
+                        }
+                    }
+                }
+                _ => for id in &character.lorebooks {
+                    if !live.contains(id) {
+                        live.push(*id);
+                    }
+                },
+            }
+        }
+        live.retain(|id| !pinned.iter().any(|pinned| &pinned.lorebook.id == id));
+
+        Ok((pinned, live))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_diff_lists_changed_fields() {
+        let character = Character {
+            name: "Alice".to_string(),
+            prompts_personality: "curious".to_string(),
+            tags: vec!["fantasy".to_string()],
+            ..Default::default()
+        };
+        let before = CharacterSnapshot::of(&character);
+        let mut after = before.clone();
+        after.prompts_personality = "bold".to_string();
+        after.tags.push("adventure".to_string());
+
+        assert_eq!(before.diff(&after).unwrap(), vec![
+            CharacterFieldChange { field: "prompts_personality".to_string(), from: json!("curious"), to: json!("bold") },
+            CharacterFieldChange { field: "tags".to_string(), from: json!(["fantasy"]), to: json!(["fantasy", "adventure"]) },
+        ]);
+        assert!(before.diff(&before).unwrap().is_empty());
+
+        let mut restored = Character { name: "Changed".to_string(), ..character.clone() };
+        before.apply_to(&mut restored);
+        assert_eq!(CharacterSnapshot::of(&restored), before);
+    }
+
+    #[test]
+    fn test_has_lore_tells_legacy_revisions_apart() {
+        let mut snapshot = CharacterSnapshot::of(&Character::default());
+        assert!(snapshot.has_lore());
+
+        let lorebook = Lorebook { id: Uuid::new_v4(), ..Default::default() };
+        snapshot.lorebooks = vec![lorebook.id];
+        assert!(!snapshot.has_lore());
+
+        snapshot.lore = vec![LorebookSnapshot { lorebook, entries: vec![] }];
+        assert!(snapshot.has_lore());
+    }
+}
@@ -2,12 +2,26 @@ use serde::{Deserialize, Serialize};
 use anyhow::Result;
 use sqlx::{Postgres, types::Uuid};
 
+use strum_macros::{Display, EnumString};
 use voda_database::SqlxObject;
-use voda_runtime::{User, SystemConfig};
+use voda_database::{QueryCriteria, SqlxFilterQuery};
+use voda_runtime::{User, SystemConfig, UserPersona};
 
-use crate::Character;
+use crate::{Character, CharacterRevision, Lorebook};
 use crate::message::RoleplayMessage;
 
+/// Who answers the next user message of a group session
+#[derive(Debug, Serialize, Deserialize, Clone, Default, Display, EnumString, PartialEq, Eq)]
+pub enum RoleplayTurnPolicy {
+    /// the user names the character to reply with every message
+    UserDirected,
+    /// the characters take turns in the order they joined the session
+    #[default]
+    RoundRobin,
+    /// the model picks the character that fits the scene best
+    ModelPicked,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Default, SqlxObject)]
 #[table_name = "roleplay_sessions"]
 pub struct RoleplaySession {
@@ -19,20 +33,84 @@ pub struct RoleplaySession {
     #[foreign_key(referenced_table = "users", related_rust_type = "User")]
     pub owner: Uuid,
 
+    /// the main character, the only one unless this is a group session
     #[foreign_key(referenced_table = "roleplay_characters", related_rust_type = "Character")]
     pub character: Uuid,
 
+    /// every character of a group session, the main character included. Empty for one-on-one sessions.
+    #[foreign_key_many(referenced_table = "roleplay_characters", related_rust_type = "Character")]
+    pub characters: Vec<Uuid>,
+    /// the revisions the characters are pinned to, at most one per character. Characters without one
+    /// play their latest version.
+    #[foreign_key_many(referenced_table = "roleplay_character_revisions", related_rust_type = "CharacterRevision")]
+    pub character_revisions: Vec<Uuid>,
+    pub turn_policy: RoleplayTurnPolicy,
+
+    /// lorebooks scanned on top of the ones attached to the characters
+    #[foreign_key_many(referenced_table = "roleplay_lorebooks", related_rust_type = "Lorebook")]
+    pub lorebooks: Vec<Uuid>,
+
     #[foreign_key(referenced_table = "system_configs", related_rust_type = "SystemConfig")]
     pub system_config: Uuid,
 
     #[foreign_key_many(referenced_table = "roleplay_messages", related_rust_type = "RoleplayMessage")]
     pub history: Vec<Uuid>,
 
+    /// the persona the user plays, `User::default_persona` when not set
+    #[foreign_key(referenced_table = "user_personas", related_rust_type = "UserPersona")]
+    pub persona: Option<Uuid>,
+
+    /// the session this one was branched off with `RoleplayRuntimeClient::fork_session`
+    #[foreign_key(referenced_table = "roleplay_sessions", related_rust_type = "RoleplaySession")]
+    pub forked_from: Option<Uuid>,
+
     pub updated_at: i64,
     pub created_at: i64,
 }
 
 impl RoleplaySession {
+    /// The characters taking part in the session
+    pub fn members(&self) -> Vec<Uuid> {
+        if self.characters.is_empty() {
+            vec![self.character]
+        } else {
+            self.characters.clone()
+        }
+    }
+
+    /// The persona the user plays in this session, falling back to the default persona of the user
+    pub async fn resolve_persona<'e, Exe>(&self, user: &User, executor: Exe) -> Result<Option<UserPersona>>
+    where
+        Exe: sqlx::Executor<'e, Database = Postgres> + Send,
+    {
+        let persona_id = match self.persona.or(user.default_persona) {
+            Some(persona_id) => persona_id,
+            None => return Ok(None),
+        };
+
+        let persona = UserPersona::find_one_by_criteria(
+            QueryCriteria::new()
+                .add_valued_filter("id", "=", persona_id)?
+                .add_valued_filter("owner", "=", user.id)?,
+            executor
+        ).await?;
+        Ok(persona)
+    }
+
+    pub fn is_group(&self) -> bool {
+        self.members().len() > 1
+    }
+
+    /// The member speaking after `last_speaker` in round-robin order
+    pub fn next_in_rotation(&self, last_speaker: Option<&Uuid>) -> Uuid {
+        let members = self.members();
+        let next = last_speaker
+            .and_then(|last| members.iter().position(|id| id == last))
+            .map(|index| (index + 1) % members.len())
+            .unwrap_or(0);
+        members[next]
+    }
+
     /// Atomically appends a message ID to the session's history in the database.
     pub async fn append_message_to_history<'e, Exe>(
         &mut self,
@@ -59,5 +137,34 @@ impl RoleplaySession {
 
         Ok(())
     }
-}
 
+    /// Atomically removes message IDs from the session's history in the database.
+    pub async fn remove_messages_from_history<'e, Exe>(
+        &mut self,
+        message_ids_to_remove: &[Uuid],
+        executor: Exe,
+    ) -> Result<(), sqlx::Error>
+    where
+        Exe: sqlx::Executor<'e, Database = Postgres> + Send,
+    {
+        sqlx::query(
+            r#"
+            UPDATE "roleplay_sessions"
+            SET 
+                history = ARRAY(
+                    SELECT m FROM unnest(history) WITH ORDINALITY AS h(m, ord)
+                    WHERE m <> ALL($1) ORDER BY ord
+                )
+            WHERE id = $2
+            "#,
+        )
+        .bind(message_ids_to_remove)
+        .bind(self.id)
+        .execute(executor)
+        .await?;
+
+        self.history.retain(|id| !message_ids_to_remove.contains(id));
+
+        Ok(())
+    }
+}
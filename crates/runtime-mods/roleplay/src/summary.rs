@@ -0,0 +1,177 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use async_openai::types::{
+    ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs,
+    ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs
+};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+use tokio::sync::mpsc;
+
+use voda_common::get_current_timestamp;
+use voda_database::{QueryCriteria, SqlxCrud, SqlxFilterQuery, SqlxObject};
+use voda_runtime::{LLMRunResponse, LlmProviderRegistry, Message, MessageRole, SystemConfig, UserUsage};
+
+use crate::{RoleplayMessage, RoleplaySession};
+
+pub const SUMMARIZER_SYSTEM_CONFIG_NAME: &str = "roleplay_summarizer_v0";
+/// most recent history messages that are always left out of the summary
+pub const SUMMARY_KEEP_RECENT_MESSAGES: usize = 20;
+/// do not call the summarizer for fewer new messages than this
+pub const SUMMARY_MIN_BATCH: usize = 10;
+
+/// Running summary of the older part of a session history
+#[derive(Debug, Serialize, Deserialize, Clone, Default, SqlxObject)]
+#[table_name = "roleplay_session_summaries"]
+pub struct RoleplaySessionSummary {
+    pub id: Uuid,
+
+    #[unique]
+    #[foreign_key(referenced_table = "roleplay_sessions", related_rust_type = "RoleplaySession")]
+    pub session_id: Uuid,
+
+    pub content: String,
+    /// the last history message folded into `content`
+    pub summarized_until: Option<Uuid>,
+
+    pub updated_at: i64,
+    pub created_at: i64,
+}
+
+impl RoleplaySessionSummary {
+    /// Index of the first history message not covered by the summary, `None` when the
+    /// summarized messages are no longer part of the history and the summary is stale.
+    pub fn next_index(&self, history: &[Uuid]) -> Option<usize> {
+        match self.summarized_until {
+            Some(summarized_until) => history.iter()
+                .position(|id| id == &summarized_until)
+                .map(|index| index + 1),
+            None => Some(0),
+        }
+    }
+}
+
+/// Background worker condensing older turns of a session into its `RoleplaySessionSummary`.
+/// Sessions are queued through the sender returned by `new` whenever new turns are stored.
+pub struct RoleplaySummarizer {
+    db: Arc<PgPool>,
+    providers: Arc<LlmProviderRegistry>,
+
+    session_rx: mpsc::Receiver<Uuid>,
+}
+
+impl RoleplaySummarizer {
+    pub fn new(db: Arc<PgPool>, providers: Arc<LlmProviderRegistry>) -> (Self, mpsc::Sender<Uuid>) {
+        let (session_tx, session_rx) = mpsc::channel(100);
+        (Self { db, providers, session_rx }, session_tx)
+    }
+
+    pub async fn run(mut self) {
+        while let Some(session_id) = self.session_rx.recv().await {
+            if let Err(e) = self.summarize(&session_id).await {
+                tracing::warn!("[RoleplaySummarizer::run] Failed to summarize session {}: {:?}", session_id, e);
+            }
+        }
+    }
+
+    /// Folds the messages between the current summary and the recent window into the summary,
+    /// once enough of them have piled up.
+    pub async fn summarize(&self, session_id: &Uuid) -> Result<()> {
+        let session = RoleplaySession::find_one_by_criteria(
+            QueryCriteria::new().add_valued_filter("id", "=", *session_id)?,
+            &*self.db
+        ).await?
+            .ok_or(anyhow!("[RoleplaySummarizer::summarize] Session not found"))?;
+
+        let summary = RoleplaySessionSummary::find_one_by_criteria(
+            QueryCriteria::new().add_valued_filter("session_id", "=", *session_id)?,
+            &*self.db
+        ).await?;
+
+        // a stale summary is rebuilt from the start of the history
+        let (start, previous_summary) = match &summary {
+            Some(summary) => match summary.next_index(&session.history) {
+                Some(start) => (start, Some(summary.content.clone())),
+                None => (0, None),
+            },
+            None => (0, None),
+        };
+
+        let end = session.history.len().saturating_sub(SUMMARY_KEEP_RECENT_MESSAGES);
+        if end <= start || end - start < SUMMARY_MIN_BATCH {
+            return Ok(());
+        }
+
+        let mut history = session.fetch_history(&*self.db).await?;
+        history.sort_by_key(|m| session.history.iter().position(|id| id == &m.id));
+        let batch = history.iter()
+            .filter(|m| session.history[start..end].contains(&m.id))
+            .filter(|m| m.role != MessageRole::ToolCall)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let system_config = SystemConfig::find_one_by_criteria(
+            QueryCriteria::new().add_valued_filter("name", "=", SUMMARIZER_SYSTEM_CONFIG_NAME.to_string())?,
+            &*self.db
+        ).await?
+            .ok_or(anyhow!("[RoleplaySummarizer::summarize] System config {} not found", SUMMARIZER_SYSTEM_CONFIG_NAME))?;
+
+        let user_message = format!(
+            "已有摘要：\n{}\n\n新的对话：\n{}",
+            previous_summary.as_deref().unwrap_or("无"),
+            RoleplayMessage::pack_flat_messages(&batch)?
+        );
+
+        let request = CreateChatCompletionRequestArgs::default()
+            .model(&system_config.openai_model)
+            .messages([
+                ChatCompletionRequestMessage::System(
+                    ChatCompletionRequestSystemMessageArgs::default()
+                        .content(system_config.system_prompt.clone())
+                        .build()?
+                ),
+                ChatCompletionRequestMessage::User(
+                    ChatCompletionRequestUserMessageArgs::default()
+                        .content(user_message)
+                        .build()?
+                ),
+            ])
+            .temperature(system_config.openai_temperature)
+            .max_tokens(system_config.openai_max_tokens as u32)
+            .build()?;
+
+        let response = self.providers.get(&system_config)?.chat_completion(request).await?;
+        let response = LLMRunResponse::from_chat_completion(session.owner, response, &system_config)?;
+        if response.content.trim().is_empty() {
+            return Err(anyhow!("[RoleplaySummarizer::summarize] Summarizer returned an empty summary"));
+        }
+
+        let mut tx = self.db.begin().await?;
+        UserUsage::from_llm_response(&response).create(&mut *tx).await?;
+
+        let summarized_until = Some(session.history[end - 1]);
+        match summary {
+            Some(mut summary) => {
+                summary.content = response.content.trim().to_string();
+                summary.summarized_until = summarized_until;
+                summary.update(&mut *tx).await?;
+            }
+            None => {
+                RoleplaySessionSummary {
+                    id: Uuid::default(),
+                    session_id: session.id,
+                    content: response.content.trim().to_string(),
+                    summarized_until,
+                    updated_at: get_current_timestamp(),
+                    created_at: get_current_timestamp(),
+                }.create(&mut *tx).await?;
+            }
+        }
+        tx.commit().await?;
+
+        tracing::debug!("[RoleplaySummarizer::summarize] Session {} summarized up to message {}", session.id, end);
+        Ok(())
+    }
+}
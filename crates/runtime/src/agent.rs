@@ -0,0 +1,203 @@
+use anyhow::{anyhow, Result};
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
+    ChatCompletionRequestToolMessageArgs, ChatCompletionToolType, CompletionUsage, FunctionCall
+};
+
+use crate::{ExecutableFunctionCall, LLMRunResponse};
+
+/// Step budget for `RuntimeClient::run_agent_loop` when the system config sets none
+pub const DEFAULT_AGENT_MAX_STEPS: usize = 4;
+
+#[derive(Debug, Clone)]
+pub struct AgentToolResult<R> {
+    pub tool_call_id: String,
+    pub function_call: FunctionCall,
+    pub result: Result<R, String>,
+    /// the result as it was fed back to the model
+    pub content: String,
+    /// the call was made to a presentation tool, see `ExecutableFunctionCall::is_terminal`
+    pub terminal: bool,
+}
+
+/// One model invocation and the tool calls it asked for
+#[derive(Debug, Clone)]
+pub struct AgentStep<R> {
+    pub response: LLMRunResponse,
+    pub tool_results: Vec<AgentToolResult<R>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AgentRun<R> {
+    pub steps: Vec<AgentStep<R>>,
+}
+
+impl<R> AgentRun<R> {
+    /// The run has stopped on its own rather than on the step budget
+    pub fn is_finished(&self) -> bool {
+        self.steps.last()
+            .map(|step| step.tool_results.iter().all(|result| result.terminal))
+            .unwrap_or(false)
+    }
+
+    pub fn tool_results(&self) -> impl Iterator<Item = &AgentToolResult<R>> {
+        self.steps.iter().flat_map(|step| step.tool_results.iter())
+    }
+
+    /// Collapses the run into a single response: the reply of the last step that wrote one,
+    /// all function calls made along the way and the total usage. The rest is taken from the
+    /// last step. Text written alongside earlier tool calls is not part of the reply.
+    pub fn merged_response(&self) -> Result<LLMRunResponse> {
+        let mut response = self.steps.last()
+            .ok_or(anyhow!("[AgentRun::merged_response] Agent run has no steps"))?
+            .response.clone();
+
+        response.content = self.steps.iter().rev()
+            .map(|step| step.response.content.trim())
+            .find(|content| !content.is_empty())
+            .unwrap_or_default()
+            .to_string();
+
+        response.maybe_function_call = self.steps.iter()
+            .flat_map(|step| step.response.maybe_function_call.iter().cloned())
+            .collect();
+        response.tool_call_ids = self.steps.iter()
+            .flat_map(|step| step.response.tool_call_ids.iter().cloned())
+            .collect();
+
+        response.usage = self.steps.iter().fold(
+            CompletionUsage {
+                prompt_tokens: 0,
+                completion_tokens: 0,
+                total_tokens: 0,
+                prompt_tokens_details: None,
+                completion_tokens_details: None,
+            },
+            |mut usage, step| {
+                usage.prompt_tokens += step.response.usage.prompt_tokens;
+                usage.completion_tokens += step.response.usage.completion_tokens;
+                usage.total_tokens += step.response.usage.total_tokens;
+                usage
+            }
+        );
+
+        Ok(response)
+    }
+}
+
+/// Executes every function call of `response` and builds the messages that
+/// feed the calls and their results back to the model.
+pub async fn execute_tool_calls<T: ExecutableFunctionCall>(
+    response: &LLMRunResponse,
+    execution_context: &T::CTX,
+) -> Result<(Vec<AgentToolResult<T::RETURN>>, Vec<ChatCompletionRequestMessage>)> {
+    let tool_calls = response.maybe_function_call.iter()
+        .zip(response.tool_call_ids.iter())
+        .map(|(function_call, tool_call_id)| ChatCompletionMessageToolCall {
+            id: tool_call_id.clone(),
+            r#type: ChatCompletionToolType::Function,
+            function: function_call.clone(),
+        })
+        .collect::<Vec<_>>();
+
+    let mut assistant_message = ChatCompletionRequestAssistantMessageArgs::default();
+    assistant_message.tool_calls(tool_calls.clone());
+    if !response.content.is_empty() {
+        assistant_message.content(response.content.clone());
+    }
+
+    let mut messages = vec![ChatCompletionRequestMessage::Assistant(
+        assistant_message.build()
+            .map_err(|e| anyhow!("[execute_tool_calls] Failed to pack assistant message: {}", e))?
+    )];
+
+    let mut tool_results = Vec::with_capacity(tool_calls.len());
+    for tool_call in tool_calls {
+        let (result, terminal) = match T::from_function_call(tool_call.function.clone()) {
+            Ok(toolcall) => (toolcall.execute(response, execution_context).await, toolcall.is_terminal()),
+            Err(e) => (Err(e), false),
+        };
+
+        let tool_content = match &result {
+            Ok(result) => T::format_return(result),
+            Err(e) => format!("Error: {}", e),
+        };
+        if let Err(e) = &result {
+            tracing::warn!("[execute_tool_calls] Tool call {} failed: {}", tool_call.function.name, e);
+        }
+
+        messages.push(ChatCompletionRequestMessage::Tool(
+            ChatCompletionRequestToolMessageArgs::default()
+                .content(tool_content.clone())
+                .tool_call_id(tool_call.id.clone())
+                .build()
+                .map_err(|e| anyhow!("[execute_tool_calls] Failed to pack tool message: {}", e))?
+        ));
+
+        tool_results.push(AgentToolResult {
+            tool_call_id: tool_call.id,
+            function_call: tool_call.function,
+            result: result.map_err(|e| e.to_string()),
+            content: tool_content,
+            terminal,
+        });
+    }
+
+    Ok((tool_results, messages))
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::types::Uuid;
+
+    use super::*;
+    use crate::SystemConfig;
+
+    fn step(content: &str, terminal: &[bool]) -> AgentStep<()> {
+        AgentStep {
+            response: LLMRunResponse {
+                caller: Uuid::nil(),
+                content: content.to_string(),
+                usage: CompletionUsage {
+                    prompt_tokens: 10,
+                    completion_tokens: 5,
+                    total_tokens: 15,
+                    prompt_tokens_details: None,
+                    completion_tokens_details: None,
+                },
+                maybe_function_call: vec![],
+                tool_call_ids: vec![],
+                finish_reason: None,
+                system_config: SystemConfig::default(),
+                misc_value: None,
+            },
+            tool_results: terminal.iter()
+                .map(|terminal| AgentToolResult {
+                    tool_call_id: "call".to_string(),
+                    function_call: FunctionCall { name: "tool".to_string(), arguments: "{}".to_string() },
+                    result: Ok(()),
+                    content: "()".to_string(),
+                    terminal: *terminal,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_merged_response_keeps_the_final_reply() {
+        let run = AgentRun { steps: vec![step("Let me look that up.", &[false]), step("Here it is.", &[])] };
+        let response = run.merged_response().unwrap();
+        assert_eq!(response.content, "Here it is.");
+        assert_eq!(response.usage.total_tokens, 30);
+
+        let run = AgentRun { steps: vec![step("Here it is.", &[false]), step("", &[])] };
+        assert_eq!(run.merged_response().unwrap().content, "Here it is.");
+    }
+
+    #[test]
+    fn test_terminal_tool_calls_finish_the_run() {
+        assert!(AgentRun { steps: vec![step("", &[])] }.is_finished());
+        assert!(AgentRun { steps: vec![step("Pick one.", &[true])] }.is_finished());
+        assert!(!AgentRun { steps: vec![step("", &[true, false])] }.is_finished());
+    }
+}
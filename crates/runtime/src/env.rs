@@ -5,6 +5,10 @@ use voda_common::EnvVars;
 pub struct RuntimeEnv {
     pub openai_api_key: String,
     pub openai_base_url: String,
+    pub transcription_model: String,
+    pub mailer: String,
+    pub smtp_url: String,
+    pub mail_from: String,
 }
 
 impl EnvVars for RuntimeEnv {
@@ -12,6 +16,10 @@ impl EnvVars for RuntimeEnv {
         Self {
             openai_api_key: env::var("OPENAI_API_KEY").unwrap(),
             openai_base_url: env::var("OPENAI_BASE_URL").unwrap(),
+            transcription_model: env::var("TRANSCRIPTION_MODEL").unwrap_or_else(|_| "whisper-1".to_string()),
+            mailer: env::var("MAILER").unwrap_or_else(|_| "smtp".to_string()),
+            smtp_url: env::var("SMTP_URL").unwrap_or_default(),
+            mail_from: env::var("MAIL_FROM").unwrap_or_default(),
         }
     }
 
@@ -19,6 +27,10 @@ impl EnvVars for RuntimeEnv {
         match key {
             "OPENAI_API_KEY" => self.openai_api_key.clone(),
             "OPENAI_BASE_URL" => self.openai_base_url.clone(),
+            "TRANSCRIPTION_MODEL" => self.transcription_model.clone(),
+            "MAILER" => self.mailer.clone(),
+            "SMTP_URL" => self.smtp_url.clone(),
+            "MAIL_FROM" => self.mail_from.clone(),
             _ => panic!("{} is not set", key),
         }
     }
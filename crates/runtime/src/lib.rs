@@ -2,6 +2,12 @@ mod toolcall;
 mod memory;
 mod output_client;
 mod runtime_client;
+mod llm_stream;
+mod llm_provider;
+mod agent;
+mod tokenizer;
+mod prompt_template;
+mod rate_limit;
 pub mod user;
 mod system_config;
 mod env;
@@ -9,7 +15,15 @@ mod env;
 pub use toolcall::ExecutableFunctionCall;
 pub use output_client::OutputClient;
 pub use runtime_client::{LLMRunResponse, RuntimeClient};
-pub use user::{UserRole, User, UserUsage, UserUrl, UserReferral, UserBadge, UserFollow};
+pub use llm_stream::LLMRunStream;
+pub use agent::{execute_tool_calls, AgentRun, AgentStep, AgentToolResult, DEFAULT_AGENT_MAX_STEPS};
+pub use llm_provider::{LlmProvider, LlmProviderRegistry, OpenAIProvider, LLM_API_KEY_PREFIX};
+pub use user::{UserRole, User, UserUsage, UserUrl, UserReferral, UserBadge, UserFollow, UserPersona};
+pub use user::{AuthClaims, AuthTokenConfig, AuthTokenError, AuthTokenKind, AuthTokenPair, RevokedToken};
+pub use user::{IdentityConfig, LoginChallenge, LoginProof, LoginProvider, UserApiKey, UserEmailOutbox, VerifiedIdentity};
 pub use system_config::SystemConfig;
+pub use prompt_template::{PromptTemplate, PromptVariables, VerbatimPromptVariables};
+pub use tokenizer::{count_tokens, count_message_tokens, fit_history, ContextWindowReport};
+pub use rate_limit::{RateLimitBucket, RateLimitDecision, RateLimitRule, TokenBucket};
 pub use env::RuntimeEnv;
-pub use memory::{MessageRole, MessageType, Message, Memory}; 
\ No newline at end of file
+pub use memory::{decode_data_url, MessageRole, MessageType, MessageToolCall, Message, Memory}; 
\ No newline at end of file
@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use anyhow::{anyhow, Result};
+use async_openai::config::OpenAIConfig;
+use async_openai::Client;
+use async_openai::types::{
+    ChatCompletionResponseStream, CreateChatCompletionRequest, CreateChatCompletionResponse,
+    AudioInput, CreateEmbeddingRequest, CreateEmbeddingResponse, CreateTranscriptionRequestArgs
+};
+use voda_common::EnvVars;
+
+use crate::{RuntimeEnv, SystemConfig};
+
+/// `SystemConfig::openai_api_key_ref` may only name env vars starting with this, so a config
+/// cannot send other secrets of the server to its base url
+pub const LLM_API_KEY_PREFIX: &str = "LLM_API_KEY_";
+
+/// A backend able to serve chat completions (with tool calling) and, optionally, embeddings.
+///
+/// Requests and responses use the OpenAI wire types, which act as the common format
+/// between runtimes. Non OpenAI-compatible backends translate to and from them.
+/// Usage is reported through the `usage` field of the responses; the final chunk
+/// of a stream is expected to carry it.
+#[async_trait::async_trait]
+pub trait LlmProvider: Send + Sync + 'static {
+    fn name(&self) -> &str;
+
+    async fn chat_completion(&self, request: CreateChatCompletionRequest) -> Result<CreateChatCompletionResponse>;
+    async fn chat_completion_stream(&self, request: CreateChatCompletionRequest) -> Result<ChatCompletionResponseStream>;
+
+    async fn embed(&self, _request: CreateEmbeddingRequest) -> Result<CreateEmbeddingResponse> {
+        Err(anyhow!("[LlmProvider::embed] Provider {} does not support embeddings", self.name()))
+    }
+
+    /// Speech to text for audio attachments
+    async fn transcribe(&self, _model: &str, _file_name: &str, _audio: Vec<u8>) -> Result<String> {
+        Err(anyhow!("[LlmProvider::transcribe] Provider {} does not support transcription", self.name()))
+    }
+}
+
+/// Any OpenAI-compatible inference server (OpenAI, OpenRouter, vLLM, llama.cpp server ...)
+#[derive(Clone)]
+pub struct OpenAIProvider {
+    name: String,
+    client: Client<OpenAIConfig>,
+}
+
+impl OpenAIProvider {
+    pub fn new(base_url: &str, api_key: &str) -> Self {
+        Self::with_http_client(reqwest::Client::new(), base_url, api_key)
+    }
+
+    pub fn with_http_client(http_client: reqwest::Client, base_url: &str, api_key: &str) -> Self {
+        let config = OpenAIConfig::new()
+            .with_api_key(api_key)
+            .with_api_base(base_url);
+
+        Self {
+            name: format!("openai:{}", base_url),
+            client: Client::build(http_client, config, Default::default()),
+        }
+    }
+
+    /// Provider pointing at `OPENAI_BASE_URL` with `OPENAI_API_KEY`
+    pub fn from_env() -> Self {
+        let env = RuntimeEnv::load();
+        Self::new(&env.get_env_var("OPENAI_BASE_URL"), &env.get_env_var("OPENAI_API_KEY"))
+    }
+}
+
+#[async_trait::async_trait]
+impl LlmProvider for OpenAIProvider {
+    fn name(&self) -> &str { &self.name }
+
+    async fn chat_completion(&self, request: CreateChatCompletionRequest) -> Result<CreateChatCompletionResponse> {
+        Ok(self.client.chat().create(request).await?)
+    }
+
+    async fn chat_completion_stream(&self, request: CreateChatCompletionRequest) -> Result<ChatCompletionResponseStream> {
+        Ok(self.client.chat().create_stream(request).await?)
+    }
+
+    async fn embed(&self, request: CreateEmbeddingRequest) -> Result<CreateEmbeddingResponse> {
+        Ok(self.client.embeddings().create(request).await?)
+    }
+
+    async fn transcribe(&self, model: &str, file_name: &str, audio: Vec<u8>) -> Result<String> {
+        let request = CreateTranscriptionRequestArgs::default()
+            .file(AudioInput::from_vec_u8(file_name.to_string(), audio))
+            .model(model)
+            .build()?;
+
+        Ok(self.client.audio().transcribe(request).await?.text)
+    }
+}
+
+/// `(base_url, api_key_ref)`
+type ProviderKey = (String, Option<String>);
+
+/// Resolves the provider a `SystemConfig` should be sent to.
+///
+/// Providers are keyed by `(openai_base_url, openai_api_key_ref)` and created lazily as
+/// OpenAI-compatible clients. All of them share one `reqwest::Client`, so configs pointing
+/// at the same endpoint reuse the same connection pool. Custom backends can be registered
+/// for a key up front.
+pub struct LlmProviderRegistry {
+    http_client: reqwest::Client,
+
+    default_base_url: String,
+    default_api_key: String,
+
+    providers: RwLock<HashMap<ProviderKey, Arc<dyn LlmProvider>>>,
+}
+
+impl LlmProviderRegistry {
+    pub fn new(default_base_url: &str, default_api_key: &str) -> Self {
+        Self {
+            http_client: reqwest::Client::new(),
+            default_base_url: default_base_url.to_string(),
+            default_api_key: default_api_key.to_string(),
+            providers: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Falls back to `OPENAI_BASE_URL` and `OPENAI_API_KEY` for configs that leave them unset
+    pub fn from_env() -> Self {
+        let env = RuntimeEnv::load();
+        Self::new(&env.get_env_var("OPENAI_BASE_URL"), &env.get_env_var("OPENAI_API_KEY"))
+    }
+
+    pub fn register(&self, base_url: &str, api_key_ref: Option<&str>, provider: Arc<dyn LlmProvider>) {
+        let key = (base_url.to_string(), api_key_ref.map(str::to_string));
+        self.providers.write()
+            .expect("[LlmProviderRegistry::register] Lock poisoned")
+            .insert(key, provider);
+    }
+
+    /// The provider for the default base url and api key
+    pub fn default_provider(&self) -> Result<Arc<dyn LlmProvider>> {
+        self.get_or_create(&self.default_base_url, None)
+    }
+
+    pub fn get(&self, system_config: &SystemConfig) -> Result<Arc<dyn LlmProvider>> {
+        let base_url = if system_config.openai_base_url.is_empty() {
+            self.default_base_url.as_str()
+        } else {
+            system_config.openai_base_url.as_str()
+        };
+
+        self.get_or_create(base_url, system_config.openai_api_key_ref.as_deref())
+    }
+
+    fn get_or_create(&self, base_url: &str, api_key_ref: Option<&str>) -> Result<Arc<dyn LlmProvider>> {
+        let key = (base_url.to_string(), api_key_ref.map(str::to_string));
+        if let Some(provider) = self.providers.read()
+            .expect("[LlmProviderRegistry::get] Lock poisoned")
+            .get(&key)
+        {
+            return Ok(provider.clone());
+        }
+
+        let api_key = match api_key_ref {
+            Some(api_key_ref) if !api_key_ref.starts_with(LLM_API_KEY_PREFIX) => {
+                return Err(anyhow!("[LlmProviderRegistry::get] Api key {} must be named {}*", api_key_ref, LLM_API_KEY_PREFIX));
+            }
+            Some(api_key_ref) => std::env::var(api_key_ref)
+                .map_err(|_| anyhow!("[LlmProviderRegistry::get] Api key {} is not set", api_key_ref))?,
+            None => self.default_api_key.clone(),
+        };
+
+        let provider: Arc<dyn LlmProvider> = Arc::new(
+            OpenAIProvider::with_http_client(self.http_client.clone(), base_url, &api_key)
+        );
+
+        // another request may have raced us here, keep whichever got in first
+        Ok(self.providers.write()
+            .expect("[LlmProviderRegistry::get] Lock poisoned")
+            .entry(key)
+            .or_insert(provider)
+            .clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_key_refs_are_restricted_to_the_prefix() {
+        let registry = LlmProviderRegistry::new("https://example.com/v1", "default");
+        assert!(registry.get_or_create("https://example.com/v1", Some("DATABASE_URL")).is_err());
+        assert!(registry.get_or_create("https://example.com/v1", Some("LLM_API_KEY_TEST_UNSET")).is_err());
+        assert!(registry.get_or_create("https://example.com/v1", None).is_ok());
+    }
+}
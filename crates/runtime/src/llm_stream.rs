@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use async_openai::types::{
+    ChatCompletionResponseStream, CompletionUsage, FinishReason, FunctionCall
+};
+use futures::StreamExt;
+use sqlx::types::Uuid;
+
+use crate::{LLMRunResponse, SystemConfig};
+
+/// A streamed chat completion. Content deltas are handed out as they arrive while
+/// tool calls, usage and the finish reason are assembled from the chunks, so that
+/// `finish` yields the same `LLMRunResponse` a non-streamed request would.
+pub struct LLMRunStream {
+    inner: ChatCompletionResponseStream,
+
+    caller: Uuid,
+    system_config: SystemConfig,
+
+    content: String,
+    // keyed by the chunk index, as ids and names are only sent on the first chunk
+    tool_calls: BTreeMap<i32, (String, FunctionCall)>,
+    usage: Option<CompletionUsage>,
+    finish_reason: Option<FinishReason>,
+}
+
+impl LLMRunStream {
+    pub fn new(inner: ChatCompletionResponseStream, caller: Uuid, system_config: SystemConfig) -> Self {
+        Self {
+            inner,
+            caller,
+            system_config,
+            content: String::new(),
+            tool_calls: BTreeMap::new(),
+            usage: None,
+            finish_reason: None,
+        }
+    }
+
+    /// Waits for the next non-empty content delta. Returns `None` once the stream is exhausted.
+    pub async fn next_delta(&mut self) -> Option<Result<String>> {
+        while let Some(chunk) = self.inner.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => return Some(Err(anyhow!("[LLMRunStream::next_delta] Stream error: {}", e))),
+            };
+
+            if chunk.usage.is_some() {
+                self.usage = chunk.usage;
+            }
+
+            let mut delta = String::new();
+            for choice in chunk.choices.into_iter().filter(|choice| choice.index == 0) {
+                if let Some(content) = choice.delta.content {
+                    delta.push_str(&content);
+                }
+
+                for tool_call_chunk in choice.delta.tool_calls.unwrap_or_default() {
+                    let (tool_call_id, tool_call) = self.tool_calls
+                        .entry(tool_call_chunk.index)
+                        .or_insert_with(|| (String::new(), FunctionCall { name: String::new(), arguments: String::new() }));
+
+                    if let Some(id) = tool_call_chunk.id {
+                        tool_call_id.push_str(&id);
+                    }
+
+                    if let Some(function) = tool_call_chunk.function {
+                        if let Some(name) = function.name {
+                            tool_call.name.push_str(&name);
+                        }
+                        if let Some(arguments) = function.arguments {
+                            tool_call.arguments.push_str(&arguments);
+                        }
+                    }
+                }
+
+                if choice.finish_reason.is_some() {
+                    self.finish_reason = choice.finish_reason;
+                }
+            }
+
+            if !delta.is_empty() {
+                self.content.push_str(&delta);
+                return Some(Ok(delta));
+            }
+        }
+
+        None
+    }
+
+    /// Drains whatever is left of the stream and assembles the final response.
+    pub async fn finish(mut self) -> Result<LLMRunResponse> {
+        while let Some(delta) = self.next_delta().await {
+            delta?;
+        }
+
+        let usage = self.usage
+            .ok_or(anyhow!("[LLMRunStream::finish] Model {} returned no usage", self.system_config.openai_model))?;
+
+        let (tool_call_ids, maybe_function_call) = self.tool_calls.into_values().unzip();
+
+        Ok(LLMRunResponse {
+            caller: self.caller,
+            content: self.content,
+            usage,
+            maybe_function_call,
+            tool_call_ids,
+            finish_reason: self.finish_reason,
+            system_config: self.system_config,
+            misc_value: None,
+        })
+    }
+}
@@ -1,14 +1,19 @@
 use anyhow::{anyhow, Result};
 use async_openai::types::{
-    ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage, 
+    ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage, 
     ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestToolMessageArgs, 
-    ChatCompletionRequestUserMessageArgs
+    ChatCompletionRequestMessageContentPartImage, ChatCompletionRequestMessageContentPartText,
+    ChatCompletionRequestUserMessageArgs, ChatCompletionRequestUserMessageContent, 
+    ChatCompletionRequestUserMessageContentPart, ChatCompletionToolType, FunctionCall, ImageUrl
 };
+use base64::{engine::general_purpose, Engine as _};
 use serde::{Deserialize, Serialize};
 use strum_macros::{Display, EnumString};
+use std::fmt;
+use std::str::FromStr;
 
 use sqlx::types::Uuid;
-use crate::SystemConfig;
+use crate::{AgentToolResult, LLMRunResponse, SystemConfig};
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default, Display, EnumString, PartialEq, Eq)]
 pub enum MessageRole {
@@ -21,7 +26,9 @@ pub enum MessageRole {
     ToolCall,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Default, Display, EnumString, PartialEq, Eq)]
+/// `Image` and `Audio` carry the url of the attachment, either a http(s) url or a base64 data url.
+/// Only the kind is written out by `Display`, so the attachment never ends up in a `content_type` column.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
 pub enum MessageType {
     #[default]
     Text,
@@ -30,6 +37,83 @@ pub enum MessageType {
     Audio(String),
 }
 
+impl fmt::Display for MessageType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageType::Text => write!(f, "Text"),
+            MessageType::Image(_) => write!(f, "Image"),
+            MessageType::Audio(_) => write!(f, "Audio"),
+        }
+    }
+}
+
+/// Reads the bare kind, leaving the url empty, as well as rows written as `Image(<url>)`
+impl FromStr for MessageType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, url) = match s.split_once('(') {
+            Some((kind, rest)) => (kind, rest.strip_suffix(')')
+                .ok_or_else(|| anyhow!("[MessageType::from_str] Invalid MessageType string: {}", s))?),
+            None => (s, ""),
+        };
+
+        match kind {
+            "Text" if url.is_empty() => Ok(MessageType::Text),
+            "Image" => Ok(MessageType::Image(url.to_string())),
+            "Audio" => Ok(MessageType::Audio(url.to_string())),
+            _ => Err(anyhow!("[MessageType::from_str] Invalid MessageType string: {}", s)),
+        }
+    }
+}
+
+impl MessageType {
+    /// The url of an `Image` or `Audio` attachment, `None` for text and for kinds read back without one
+    pub fn attachment_url(&self) -> Option<&str> {
+        match self {
+            MessageType::Text => None,
+            MessageType::Image(url) | MessageType::Audio(url) => Some(url.as_str()).filter(|url| !url.is_empty()),
+        }
+    }
+}
+
+/// Splits a `data:<mime>;base64,<data>` url into its mime type and decoded bytes
+pub fn decode_data_url(url: &str) -> Option<(String, Vec<u8>)> {
+    let (header, data) = url.strip_prefix("data:")?.split_once(',')?;
+    let mime = header.strip_suffix(";base64")?;
+    let bytes = general_purpose::STANDARD.decode(data).ok()?;
+    Some((mime.to_string(), bytes))
+}
+
+/// A tool call made by an assistant message, kept so it can be replayed to the model
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct MessageToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: String,
+}
+
+impl MessageToolCall {
+    pub fn from_llm_response(llm_response: &LLMRunResponse) -> Vec<Self> {
+        llm_response.maybe_function_call.iter()
+            .zip(llm_response.tool_call_ids.iter())
+            .map(|(function_call, id)| Self {
+                id: id.clone(),
+                name: function_call.name.clone(),
+                arguments: function_call.arguments.clone(),
+            })
+            .collect()
+    }
+
+    pub fn from_tool_result<R>(tool_result: &AgentToolResult<R>) -> Self {
+        Self {
+            id: tool_result.tool_call_id.clone(),
+            name: tool_result.function_call.name.clone(),
+            arguments: tool_result.function_call.arguments.clone(),
+        }
+    }
+}
+
 pub trait Message: Clone + Send + Sync + 'static {
     fn id(&self) -> &Uuid;
 
@@ -43,6 +127,11 @@ pub trait Message: Clone + Send + Sync + 'static {
 
     fn created_at(&self) -> i64;
 
+    /// Tool calls made by an assistant message
+    fn tool_calls(&self) -> Vec<MessageToolCall> { vec![] }
+    /// The call a `MessageRole::ToolCall` message answers
+    fn tool_call_id(&self) -> Option<String> { None }
+
     fn pack(message: &[Self]) -> Result<Vec<ChatCompletionRequestMessage>> {
         message
             .iter()
@@ -56,19 +145,43 @@ pub trait Message: Clone + Send + Sync + 'static {
                     ),
                     MessageRole::User => ChatCompletionRequestMessage::User(
                         ChatCompletionRequestUserMessageArgs::default()
-                            .content(m.text_content().unwrap_or_default())
-                            .build()
-                            .map_err(|e| anyhow!("Failed to pack message: {}", e))?
-                    ),
-                    MessageRole::Assistant => ChatCompletionRequestMessage::Assistant(
-                        ChatCompletionRequestAssistantMessageArgs::default()
-                            .content(m.text_content().unwrap_or_default())
+                            .content(Self::pack_user_content(m))
                             .build()
                             .map_err(|e| anyhow!("Failed to pack message: {}", e))?
                     ),
+                    MessageRole::Assistant => {
+                        let mut assistant_message = ChatCompletionRequestAssistantMessageArgs::default();
+                        let content = m.text_content().unwrap_or_default();
+                        let tool_calls = m.tool_calls();
+
+                        // content may only be left out when the message carries tool calls
+                        if !content.is_empty() || tool_calls.is_empty() {
+                            assistant_message.content(content);
+                        }
+                        if !tool_calls.is_empty() {
+                            assistant_message.tool_calls(tool_calls
+                                .into_iter()
+                                .map(|tool_call| ChatCompletionMessageToolCall {
+                                    id: tool_call.id,
+                                    r#type: ChatCompletionToolType::Function,
+                                    function: FunctionCall { name: tool_call.name, arguments: tool_call.arguments },
+                                })
+                                .collect::<Vec<_>>()
+                            );
+                        }
+
+                        ChatCompletionRequestMessage::Assistant(
+                            assistant_message
+                                .build()
+                                .map_err(|e| anyhow!("Failed to pack message: {}", e))?
+                        )
+                    },
                     MessageRole::ToolCall => ChatCompletionRequestMessage::Tool(
                         ChatCompletionRequestToolMessageArgs::default()
                             .content(m.text_content().unwrap_or_default())
+                            .tool_call_id(m.tool_call_id()
+                                .ok_or(anyhow!("Failed to pack message: tool message {} has no tool_call_id", m.id()))?
+                            )
                             .build()
                             .map_err(|e| anyhow!("Failed to pack message: {}", e))?
                     ),
@@ -77,6 +190,33 @@ pub trait Message: Clone + Send + Sync + 'static {
             .collect()
     }
 
+    /// Images are sent as content parts next to the text. Audio is sent as its transcript,
+    /// which is expected to be the text content of the message.
+    fn pack_user_content(message: &Self) -> ChatCompletionRequestUserMessageContent {
+        let text = message.text_content().unwrap_or_default();
+        let image_url = match message.content_type() {
+            MessageType::Image(url) => message.url_content()
+                .or_else(|| Some(url.clone()).filter(|url| !url.is_empty())),
+            _ => None,
+        };
+
+        match image_url {
+            Some(url) => {
+                let mut parts = vec![];
+                if !text.is_empty() {
+                    parts.push(ChatCompletionRequestUserMessageContentPart::Text(
+                        ChatCompletionRequestMessageContentPartText { text }
+                    ));
+                }
+                parts.push(ChatCompletionRequestUserMessageContentPart::ImageUrl(
+                    ChatCompletionRequestMessageContentPartImage { image_url: ImageUrl { url, detail: None } }
+                ));
+                ChatCompletionRequestUserMessageContent::Array(parts)
+            }
+            None => ChatCompletionRequestUserMessageContent::Text(text),
+        }
+    }
+
     fn pack_flat_messages(messages: &[Self]) -> Result<String> {
         let mut flat_messages = Vec::new();
         for message in messages {
@@ -114,3 +254,25 @@ pub trait Memory: Clone + Send + Sync + 'static {
     async fn delete(&self, message_ids: &[Uuid]) -> Result<()>;
     async fn reset(&self, user_id: &Uuid) -> Result<()>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_type_keeps_only_the_kind_in_text() {
+        assert_eq!(MessageType::Image("https://example.com/a.png".to_string()).to_string(), "Image");
+        assert_eq!(MessageType::Audio("data:audio/wav;base64,AAAA".to_string()).to_string(), "Audio");
+
+        assert_eq!("Text".parse::<MessageType>().unwrap(), MessageType::Text);
+        assert_eq!("Image".parse::<MessageType>().unwrap(), MessageType::Image(String::new()));
+        assert_eq!(
+            "Image(https://example.com/a.png)".parse::<MessageType>().unwrap(),
+            MessageType::Image("https://example.com/a.png".to_string())
+        );
+        assert!("Video".parse::<MessageType>().is_err());
+
+        assert_eq!(MessageType::Image(String::new()).attachment_url(), None);
+        assert_eq!(MessageType::Audio("data:audio/wav;base64,AAAA".to_string()).attachment_url(), Some("data:audio/wav;base64,AAAA"));
+    }
+}
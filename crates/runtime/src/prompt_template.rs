@@ -0,0 +1,137 @@
+use anyhow::{anyhow, Result};
+use minijinja::{Environment, UndefinedBehavior};
+use serde::Serialize;
+
+/// The variables a prompt is rendered with. `NAMES` lists the serialized fields, so templates
+/// can be checked against them before they are saved. Implement it with `prompt_variables!`.
+pub trait PromptVariables: Serialize {
+    const NAMES: &'static [&'static str];
+}
+
+/// Declares a struct of prompt variables. The list of names a template may use is generated
+/// from the fields, so rendering and validation cannot drift apart.
+#[macro_export]
+macro_rules! prompt_variables {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $( $(#[$field_meta:meta])* $field_vis:vis $field:ident : $ty:ty ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Default, ::serde::Serialize)]
+        $vis struct $name {
+            $( $(#[$field_meta])* $field_vis $field: $ty ),*
+        }
+
+        impl $crate::PromptVariables for $name {
+            const NAMES: &'static [&'static str] = &[$(stringify!($field)),*];
+        }
+    };
+}
+
+prompt_variables! {
+    /// Prompts sent to the model as they are, without rendering. They may use no variables.
+    pub struct VerbatimPromptVariables {}
+}
+
+/// A Jinja-style prompt template. Undefined variables are errors rather than empty strings,
+/// and block tags do not leave blank lines behind.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PromptTemplate {
+    source: String,
+}
+
+impl PromptTemplate {
+    /// Parses `source`, failing on syntax errors
+    pub fn new(source: &str) -> Result<Self> {
+        environment().template_from_str(source)
+            .map_err(|e| anyhow!("[PromptTemplate::new] Invalid template: {}", e))?;
+        Ok(Self { source: source.to_string() })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Fails when the template uses a variable `V` does not provide
+    pub fn validate<V: PromptVariables>(&self) -> Result<()> {
+        let env = environment();
+        let template = env.template_from_str(&self.source)
+            .map_err(|e| anyhow!("[PromptTemplate::validate] Invalid template: {}", e))?;
+
+        let mut unknown = template.undeclared_variables(false)
+            .into_iter()
+            .filter(|name| !V::NAMES.contains(&name.as_str()))
+            .collect::<Vec<_>>();
+        if !unknown.is_empty() {
+            unknown.sort();
+            return Err(anyhow!(
+                "[PromptTemplate::validate] Unknown variables {:?}, available: {:?}", unknown, V::NAMES
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn render<V: PromptVariables>(&self, variables: &V) -> Result<String> {
+        let env = environment();
+        let template = env.template_from_str(&self.source)
+            .map_err(|e| anyhow!("[PromptTemplate::render] Invalid template: {}", e))?;
+
+        template.render(variables)
+            .map_err(|e| anyhow!("[PromptTemplate::render] Failed to render template: {}", e))
+    }
+}
+
+fn environment() -> Environment<'static> {
+    let mut env = Environment::new();
+    env.set_undefined_behavior(UndefinedBehavior::Strict);
+    env.set_trim_blocks(true);
+    env.set_lstrip_blocks(true);
+    env.set_keep_trailing_newline(true);
+    env
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    crate::prompt_variables! {
+        struct TestVariables {
+            char: String,
+            stories: Vec<String>,
+            scenario: String,
+        }
+    }
+
+    fn variables() -> TestVariables {
+        TestVariables {
+            char: "Alice".to_string(),
+            stories: vec!["one".to_string(), "two".to_string()],
+            scenario: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_render_loops_and_conditionals() {
+        let template = PromptTemplate::new(
+            "I am {{ char }}.\n{% for story in stories %}\n- {{ story }}\n{% endfor %}\n{% if scenario %}\nScene: {{ scenario }}\n{% endif %}\nEnd"
+        ).unwrap();
+
+        template.validate::<TestVariables>().unwrap();
+        assert_eq!(template.render(&variables()).unwrap(), "I am Alice.\n- one\n- two\nEnd");
+    }
+
+    #[test]
+    fn test_unknown_variables_are_rejected() {
+        assert!(PromptTemplate::new("{{ char ").is_err());
+
+        let template = PromptTemplate::new("{{ char }} meets {{ user }}").unwrap();
+        assert!(template.validate::<TestVariables>().is_err());
+        assert!(template.render(&variables()).is_err());
+
+        assert!(PromptTemplate::new("{{ char }}").unwrap().validate::<VerbatimPromptVariables>().is_err());
+        PromptTemplate::new("Summarize the chat.").unwrap().validate::<VerbatimPromptVariables>().unwrap();
+    }
+}
@@ -0,0 +1,201 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+use voda_common::get_current_timestamp;
+use voda_database::{QueryCriteria, SqlxCrud, SqlxFilterQuery, SqlxObject};
+
+/// How fast requests to routes under `route_prefix` may come in, per user or, for anonymous
+/// requests, per IP. The longest matching prefix wins.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, SqlxObject)]
+#[table_name = "rate_limit_rules"]
+pub struct RateLimitRule {
+    pub id: Uuid,
+
+    #[unique]
+    pub route_prefix: String,
+    /// requests allowed in a burst
+    pub capacity: i64,
+    /// requests regained per minute
+    pub refill_per_minute: i64,
+    pub enabled: bool,
+
+    pub updated_at: i64,
+    pub created_at: i64,
+}
+
+/// The state of one token bucket, kept by the Postgres backend so limits hold across instances
+#[derive(Debug, Serialize, Deserialize, Clone, Default, SqlxObject)]
+#[table_name = "rate_limit_buckets"]
+pub struct RateLimitBucket {
+    pub id: Uuid,
+
+    /// the route prefix of the rule and the user or IP, see `RateLimitRule::bucket_key`
+    #[unique]
+    pub key: String,
+    pub tokens: f64,
+    /// milliseconds. Not `updated_at`, which the database keeps in seconds.
+    pub refilled_at: i64,
+    /// when the bucket is full again, in milliseconds. Full buckets are pruned.
+    pub full_at: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TokenBucket {
+    pub tokens: f64,
+    /// milliseconds
+    pub refilled_at: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitDecision {
+    Allowed,
+    /// seconds until a token is available again
+    Limited { retry_after: u64 },
+}
+
+impl RateLimitRule {
+    /// Limits every deployment starts with, on the routes that cost a model call and the
+    /// anonymous login routes that store a challenge or send an email
+    pub fn defaults() -> Vec<Self> {
+        [
+            ("/runtime/roleplay/chat", 30, 20),
+            ("/tts/", 10, 10),
+            ("/user/auth/email/start", 5, 2),
+            ("/user/auth/siwe/nonce", 10, 10),
+        ].into_iter()
+            .map(|(route_prefix, capacity, refill_per_minute)| Self {
+                id: Uuid::default(),
+                route_prefix: route_prefix.to_string(),
+                capacity,
+                refill_per_minute,
+                enabled: true,
+                updated_at: get_current_timestamp(),
+                created_at: get_current_timestamp(),
+            })
+            .collect()
+    }
+
+    /// Inserts the default rules whose prefix has no rule yet. Rules already there are left as
+    /// admins set them, so a default is turned off by disabling it rather than deleting it.
+    pub async fn preload(db: &PgPool) -> Result<()> {
+        tracing::info!("[RateLimitRule::preload] Preloading rate limit rules");
+        let mut tx = db.begin().await?;
+        for rule in Self::defaults() {
+            let existing = Self::find_one_by_criteria(
+                QueryCriteria::new().add_valued_filter("route_prefix", "=", rule.route_prefix.clone())?,
+                &mut *tx
+            ).await?;
+            if existing.is_none() {
+                rule.create(&mut *tx).await?;
+            }
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !self.route_prefix.starts_with('/') {
+            return Err(anyhow!("[RateLimitRule::validate] Route prefix must start with /"));
+        }
+        if self.capacity < 1 || self.refill_per_minute < 1 {
+            return Err(anyhow!("[RateLimitRule::validate] Capacity and refill must be positive"));
+        }
+        Ok(())
+    }
+
+    /// The enabled rule with the longest prefix matching `path`
+    pub fn find_for_path<'a>(rules: &'a [RateLimitRule], path: &str) -> Option<&'a RateLimitRule> {
+        rules.iter()
+            .filter(|rule| rule.enabled && path.starts_with(&rule.route_prefix))
+            .max_by_key(|rule| rule.route_prefix.len())
+    }
+
+    pub fn bucket_key(&self, client: &str) -> String {
+        format!("{}|{}", self.route_prefix, client)
+    }
+
+    /// A full bucket, for clients seen for the first time
+    pub fn new_bucket(&self, now_ms: i64) -> TokenBucket {
+        TokenBucket { tokens: self.capacity as f64, refilled_at: now_ms }
+    }
+}
+
+impl TokenBucket {
+    /// Refills the bucket up to `now_ms` and takes a token from it if there is one
+    pub fn take(&mut self, rule: &RateLimitRule, now_ms: i64) -> RateLimitDecision {
+        let refill_per_ms = rule.refill_per_minute as f64 / 60_000.0;
+        let elapsed = (now_ms - self.refilled_at).max(0) as f64;
+        self.tokens = (self.tokens + elapsed * refill_per_ms).min(rule.capacity as f64);
+        self.refilled_at = now_ms;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            RateLimitDecision::Allowed
+        } else {
+            let wait_ms = (1.0 - self.tokens) / refill_per_ms;
+            RateLimitDecision::Limited { retry_after: (wait_ms / 1000.0).ceil().max(1.0) as u64 }
+        }
+    }
+
+    /// When the bucket will be full again, from then on it is no different from a new one
+    pub fn full_at(&self, rule: &RateLimitRule) -> i64 {
+        let refill_per_ms = rule.refill_per_minute as f64 / 60_000.0;
+        let missing = (rule.capacity as f64 - self.tokens).max(0.0);
+        self.refilled_at + (missing / refill_per_ms).ceil() as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(route_prefix: &str, capacity: i64, refill_per_minute: i64) -> RateLimitRule {
+        RateLimitRule {
+            route_prefix: route_prefix.to_string(),
+            capacity,
+            refill_per_minute,
+            enabled: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let rule = rule("/tts/", 2, 6);
+        let mut bucket = rule.new_bucket(0);
+
+        assert_eq!(bucket.take(&rule, 0), RateLimitDecision::Allowed);
+        assert_eq!(bucket.take(&rule, 0), RateLimitDecision::Allowed);
+        // one token every 10 seconds
+        assert!(matches!(bucket.take(&rule, 0), RateLimitDecision::Limited { retry_after: 10 | 11 }));
+        assert!(matches!(bucket.take(&rule, 4_000), RateLimitDecision::Limited { retry_after: 6 | 7 }));
+        assert_eq!(bucket.take(&rule, 11_000), RateLimitDecision::Allowed);
+
+        // never refills past the capacity
+        assert_eq!(bucket.take(&rule, 3_600_000), RateLimitDecision::Allowed);
+        assert_eq!(bucket.take(&rule, 3_600_000), RateLimitDecision::Allowed);
+        assert!(matches!(bucket.take(&rule, 3_600_000), RateLimitDecision::Limited { .. }));
+        assert!((3_620_000..=3_620_001).contains(&bucket.full_at(&rule)));
+        assert_eq!(rule.new_bucket(0).full_at(&rule), 0);
+    }
+
+    #[test]
+    fn test_defaults_are_valid() {
+        for rule in RateLimitRule::defaults() {
+            rule.validate().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_find_for_path() {
+        let mut disabled = rule("/runtime/roleplay/chat_stream/", 1, 1);
+        disabled.enabled = false;
+        let rules = vec![rule("/runtime/", 10, 10), rule("/runtime/roleplay/chat", 5, 5), disabled];
+
+        assert_eq!(RateLimitRule::find_for_path(&rules, "/runtime/roleplay/chat/1").unwrap().capacity, 5);
+        assert_eq!(RateLimitRule::find_for_path(&rules, "/runtime/roleplay/chat_stream/1").unwrap().capacity, 5);
+        assert_eq!(RateLimitRule::find_for_path(&rules, "/runtime/roleplay/fork/1").unwrap().capacity, 10);
+        assert!(RateLimitRule::find_for_path(&rules, "/tts/1").is_none());
+    }
+}
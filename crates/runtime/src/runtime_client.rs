@@ -1,17 +1,18 @@
 use std::sync::Arc;
 
 use anyhow::{anyhow, Result};
-use async_openai::config::OpenAIConfig;
-use async_openai::Client;
 use async_openai::types::{
-    ChatCompletionToolArgs, ChatCompletionToolChoiceOption, CompletionUsage, CreateChatCompletionRequestArgs, FinishReason, FunctionCall
+    ChatCompletionStreamOptions, ChatCompletionToolArgs, ChatCompletionToolChoiceOption, CompletionUsage, 
+    CreateChatCompletionRequest, CreateChatCompletionRequestArgs, CreateChatCompletionResponse, FinishReason, FunctionCall
 };
 
 use sqlx::PgPool;
 use sqlx::types::Uuid;
 use serde::{Deserialize, Serialize};
 
-use crate::{Memory, Message, SystemConfig};
+use voda_database::SqlxCrud;
+
+use crate::{execute_tool_calls, AgentRun, AgentStep, ExecutableFunctionCall, LLMRunStream, LlmProvider, Memory, Message, SystemConfig, UserUsage};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LLMRunResponse {
@@ -19,11 +20,47 @@ pub struct LLMRunResponse {
     pub content: String,
     pub usage: CompletionUsage,
     pub maybe_function_call: Vec<FunctionCall>,
+    /// ids of `maybe_function_call`, in the same order
+    #[serde(default)]
+    pub tool_call_ids: Vec<String>,
     pub finish_reason: Option<FinishReason>,
     pub system_config: SystemConfig,
     pub misc_value: Option<serde_json::Value>,
 }
 
+impl LLMRunResponse {
+    pub fn from_chat_completion(
+        caller: Uuid,
+        response: CreateChatCompletionResponse,
+        system_config: &SystemConfig,
+    ) -> Result<Self> {
+        let choice = response.choices.into_iter().next()
+            .ok_or(anyhow!("[LLMRunResponse::from_chat_completion] No response from AI inference server for model {}", system_config.openai_model))?;
+
+        let usage = response.usage
+            .ok_or(anyhow!("[LLMRunResponse::from_chat_completion] Model {} returned no usage", system_config.openai_model))?;
+
+        let content = choice.message.content.unwrap_or_default();
+        let (tool_call_ids, maybe_function_call) = choice.message
+            .tool_calls
+            .unwrap_or_default()
+            .into_iter()
+            .map(|tool_call| (tool_call.id, tool_call.function))
+            .unzip();
+
+        Ok(Self {
+            caller,
+            content,
+            usage,
+            maybe_function_call,
+            tool_call_ids,
+            finish_reason: choice.finish_reason,
+            system_config: system_config.clone(),
+            misc_value: None,
+        })
+    }
+}
+
 #[async_trait::async_trait]
 pub trait RuntimeClient: Clone + Send + Sync + 'static {
     const NAME: &'static str;
@@ -32,7 +69,8 @@ pub trait RuntimeClient: Clone + Send + Sync + 'static {
     fn get_db(&self) -> &Arc<PgPool>;
     fn get_memory(&self) -> &Arc<Self::MemoryType>;
     fn get_price(&self) -> u64;
-    fn get_client(&self) -> &Client<OpenAIConfig>;
+    /// The backend serving requests made with `system_config`
+    fn get_provider(&self, system_config: &SystemConfig) -> Result<Arc<dyn LlmProvider>>;
 
     async fn preload(db: Arc<PgPool>) -> Result<()>;
 
@@ -44,60 +82,95 @@ pub trait RuntimeClient: Clone + Send + Sync + 'static {
         system_config: &SystemConfig,
         messages: &[<Self::MemoryType as Memory>::MessageType]
     ) -> Result<LLMRunResponse> {
-        if messages.len() == 0 {
-            return Err(anyhow!("[RuntimeClient::send_llm_request] No messages to send"));
-        }
+        let (caller, request) = build_chat_request(system_config, messages, false)?;
 
-        let caller = messages[0].owner().clone();
-        let messages = Message::pack(messages)?;
-
-        let tools = system_config.functions.iter()
-            .map(|function| ChatCompletionToolArgs::default()
-                .function(function.clone())
-                .build()
-                .expect("Message should build")
-            )
-            .collect::<Vec<_>>();
-
-        // Create chat completion request
-        let request = CreateChatCompletionRequestArgs::default()
-            .model(&system_config.openai_model)
-            .messages(messages)
-            .tools(tools)
-            .tool_choice(ChatCompletionToolChoiceOption::Auto)
-            .temperature(system_config.openai_temperature)
-            .max_tokens(system_config.openai_max_tokens as u32)
-            .build()?;
-
-        // Send request to OpenAI
-        let response = self.get_client().chat().create(request).await?;
-        let choice = response.choices.first()
-            .ok_or(anyhow!("[RuntimeClient::send_llm_request] No response from AI inference server"))?;
-
-        let message = choice.message.clone();
-        let finish_reason = choice.finish_reason.clone();
+        let response = self.get_provider(system_config)?.chat_completion(request).await?;
+        LLMRunResponse::from_chat_completion(caller, response, system_config)
+    }
 
-        let usage = response.usage
-            .ok_or(anyhow!("[RuntimeClient::send_llm_request] Model {} returned no usage", system_config.openai_model))?
-            .clone();
+    /// Same as `send_llm_request`, but streams the completion back as it is generated.
+    async fn send_llm_request_stream(&self, 
+        system_config: &SystemConfig,
+        messages: &[<Self::MemoryType as Memory>::MessageType]
+    ) -> Result<LLMRunStream> {
+        let (caller, request) = build_chat_request(system_config, messages, true)?;
 
-        let content = message.content.clone().unwrap_or_default();
+        let stream = self.get_provider(system_config)?.chat_completion_stream(request).await?;
+        Ok(LLMRunStream::new(stream, caller, system_config.clone()))
+    }
 
-        let maybe_function_call = message
-            .tool_calls
-            .unwrap_or_default()
-            .into_iter()
-            .map(|tool_call| tool_call.function)
-            .collect::<Vec<_>>();
+    /// Calls the model, executes every tool call it makes through `T` and feeds the results
+    /// back, until the model answers without tool calls, only calls terminal tools or
+    /// `SystemConfig::agent_step_budget` requests have been made.
+    /// The usage of each step is recorded as it completes.
+    async fn run_agent_loop<T: ExecutableFunctionCall>(&self,
+        system_config: &SystemConfig,
+        messages: &[<Self::MemoryType as Memory>::MessageType],
+        execution_context: &T::CTX,
+    ) -> Result<AgentRun<T::RETURN>> {
+        let (caller, mut request) = build_chat_request(system_config, messages, false)?;
+        let provider = self.get_provider(system_config)?;
+
+        let mut steps = Vec::new();
+        for step in 0..system_config.agent_step_budget() {
+            let response = provider.chat_completion(request.clone()).await?;
+            let response = LLMRunResponse::from_chat_completion(caller, response, system_config)?;
+            UserUsage::from_llm_response(&response).create(&**self.get_db()).await?;
+
+            if response.maybe_function_call.is_empty() {
+                steps.push(AgentStep { response, tool_results: vec![] });
+                break;
+            }
+
+            let (tool_results, tool_messages) = execute_tool_calls::<T>(&response, execution_context).await?;
+            tracing::debug!("[RuntimeClient::run_agent_loop] Step {} executed {} tool calls", step, tool_results.len());
+
+            let terminal = tool_results.iter().all(|result| result.terminal);
+            steps.push(AgentStep { response, tool_results });
+            if terminal {
+                break;
+            }
+            request.messages.extend(tool_messages);
+        }
 
-        Ok(LLMRunResponse {
-            caller,
-            content,
-            usage,
-            maybe_function_call,
-            finish_reason,
-            system_config: system_config.clone(),
-            misc_value: None,
-        })
+        Ok(AgentRun { steps })
+    }
+}
+
+fn build_chat_request<M: Message>(
+    system_config: &SystemConfig,
+    messages: &[M],
+    stream: bool,
+) -> Result<(Uuid, CreateChatCompletionRequest)> {
+    if messages.is_empty() {
+        return Err(anyhow!("[RuntimeClient::build_chat_request] No messages to send"));
     }
+
+    let caller = *messages[0].owner();
+    let messages = Message::pack(messages)?;
+
+    let tools = system_config.functions.iter()
+        .map(|function| ChatCompletionToolArgs::default()
+            .function(function.clone())
+            .build()
+            .expect("Message should build")
+        )
+        .collect::<Vec<_>>();
+
+    // Create chat completion request
+    let mut request = CreateChatCompletionRequestArgs::default();
+    request
+        .model(&system_config.openai_model)
+        .messages(messages)
+        .tools(tools)
+        .tool_choice(ChatCompletionToolChoiceOption::Auto)
+        .temperature(system_config.openai_temperature)
+        .max_tokens(system_config.openai_max_tokens as u32);
+
+    if stream {
+        // usage is only reported on the final chunk when explicitly requested
+        request.stream_options(ChatCompletionStreamOptions { include_usage: true });
+    }
+
+    Ok((caller, request.build()?))
 }
@@ -1,10 +1,12 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_openai::types::FunctionObject;
 use serde::{Deserialize, Serialize};
 use sqlx::types::{Json, Uuid};
 
 use voda_database::SqlxObject;
 
+use crate::{PromptTemplate, PromptVariables, DEFAULT_AGENT_MAX_STEPS};
+
 #[derive(Debug, Serialize, Deserialize, Clone, Default, SqlxObject)]
 #[table_name = "system_configs"]
 pub struct SystemConfig {
@@ -16,11 +18,42 @@ pub struct SystemConfig {
     pub system_prompt_version: i64,
 
     pub openai_base_url: String,
+    /// name of the env var holding the api key for `openai_base_url`, defaults to `OPENAI_API_KEY`.
+    /// Only names starting with `LLM_API_KEY_PREFIX` are read.
+    pub openai_api_key_ref: Option<String>,
     pub openai_model: String,
     pub openai_temperature: f32,
     pub openai_max_tokens: i32,
+    /// context size of `openai_model` in tokens, 0 when unknown
+    pub openai_context_window: i32,
+    /// model requests a single agent run may make, `DEFAULT_AGENT_MAX_STEPS` when not positive
+    pub agent_max_steps: i32,
 
     pub functions: Json<Vec<FunctionObject>>,
     pub updated_at: i64,
     pub created_at: i64,
 }
+
+impl SystemConfig {
+    pub fn prompt_template(&self) -> Result<PromptTemplate> {
+        PromptTemplate::new(&self.system_prompt)
+            .map_err(|e| anyhow!("[SystemConfig::prompt_template] {}: {}", self.name, e))
+    }
+
+    /// Step budget for `RuntimeClient::run_agent_loop`
+    pub fn agent_step_budget(&self) -> usize {
+        if self.agent_max_steps > 0 {
+            self.agent_max_steps as usize
+        } else {
+            DEFAULT_AGENT_MAX_STEPS
+        }
+    }
+
+    /// Checks that `system_prompt` only uses variables from `V`. Call before saving a config
+    /// whose prompt is rendered with `V`.
+    pub fn validate_prompt<V: PromptVariables>(&self) -> Result<()> {
+        self.prompt_template()?
+            .validate::<V>()
+            .map_err(|e| anyhow!("[SystemConfig::validate_prompt] {}: {}", self.name, e))
+    }
+}
@@ -0,0 +1,164 @@
+use std::sync::OnceLock;
+
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+use tiktoken_rs::CoreBPE;
+
+use crate::{Message, MessageRole, MessageType, SystemConfig};
+
+/// Tokens every chat message costs on top of its content (role, separators)
+const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+/// Flat estimate for an image content part
+const IMAGE_TOKENS: usize = 85;
+
+static TOKENIZER: OnceLock<CoreBPE> = OnceLock::new();
+
+/// Counts tokens with the o200k_base encoding. Models served by other vendors tokenize
+/// differently, so this is an estimate good enough for budgeting, not for billing.
+pub fn count_tokens(text: &str) -> usize {
+    TOKENIZER
+        .get_or_init(|| tiktoken_rs::o200k_base().expect("[count_tokens] Tokenizer should load"))
+        .encode_ordinary(text)
+        .len()
+}
+
+pub fn count_message_tokens<M: Message>(message: &M) -> usize {
+    let text_tokens = count_tokens(&message.text_content().unwrap_or_default());
+    let tool_call_tokens = message.tool_calls().iter()
+        .map(|tool_call| count_tokens(&tool_call.name) + count_tokens(&tool_call.arguments))
+        .sum::<usize>();
+    let image_tokens = match message.content_type() {
+        MessageType::Image(_) => IMAGE_TOKENS,
+        _ => 0,
+    };
+
+    MESSAGE_OVERHEAD_TOKENS + text_tokens + tool_call_tokens + image_tokens
+}
+
+/// How the history was cut to fit the context window of a request
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ContextWindowReport {
+    /// `openai_context_window - openai_max_tokens`, `None` when the config sets no context window
+    pub budget: Option<usize>,
+    /// system prompt, memory snippets and the new message
+    pub fixed_tokens: usize,
+    pub history_tokens: usize,
+
+    pub history_available: usize,
+    pub history_included: usize,
+    /// the oldest history message that made it into the request
+    pub cut_off_message_id: Option<Uuid>,
+}
+
+/// Keeps the most recent part of `history` that fits, with at most `limit` messages.
+/// Oldest messages are dropped first, and the kept history never starts with a tool result
+/// whose assistant message was cut off.
+pub fn fit_history<M: Message>(
+    system_config: &SystemConfig,
+    fixed: &[M],
+    history: &[M],
+    limit: usize,
+) -> (Vec<M>, ContextWindowReport) {
+    let budget = if system_config.openai_context_window > 0 {
+        Some((system_config.openai_context_window - system_config.openai_max_tokens).max(0) as usize)
+    } else {
+        None
+    };
+
+    let fixed_tokens = fixed.iter().map(count_message_tokens).sum::<usize>();
+    let mut history_tokens = 0;
+    let mut start = history.len();
+
+    for message in history.iter().rev().take(limit) {
+        let tokens = count_message_tokens(message);
+        if let Some(budget) = budget {
+            if fixed_tokens + history_tokens + tokens > budget {
+                break;
+            }
+        }
+
+        history_tokens += tokens;
+        start -= 1;
+    }
+
+    while start < history.len() && history[start].role() == &MessageRole::ToolCall {
+        history_tokens -= count_message_tokens(&history[start]);
+        start += 1;
+    }
+
+    let kept = history[start..].to_vec();
+    let report = ContextWindowReport {
+        budget,
+        fixed_tokens,
+        history_tokens,
+        history_available: history.len(),
+        history_included: kept.len(),
+        cut_off_message_id: kept.first().map(|message| *message.id()),
+    };
+
+    (kept, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone)]
+    struct TestMessage {
+        id: Uuid,
+        role: MessageRole,
+        content: String,
+    }
+
+    impl Message for TestMessage {
+        fn id(&self) -> &Uuid { &self.id }
+        fn role(&self) -> &MessageRole { &self.role }
+        fn owner(&self) -> &Uuid { &self.id }
+        fn content_type(&self) -> &MessageType { &MessageType::Text }
+        fn text_content(&self) -> Option<String> { Some(self.content.clone()) }
+        fn binary_content(&self) -> Option<Vec<u8>> { None }
+        fn url_content(&self) -> Option<String> { None }
+        fn created_at(&self) -> i64 { 0 }
+    }
+
+    fn message(role: MessageRole, content: &str) -> TestMessage {
+        TestMessage { id: Uuid::new_v4(), role, content: content.to_string() }
+    }
+
+    #[test]
+    fn test_fit_history_drops_oldest_first() {
+        let fixed = [message(MessageRole::System, "system")];
+        let history = (0..20)
+            .map(|i| message(MessageRole::User, &format!("message number {}", i)))
+            .collect::<Vec<_>>();
+
+        let per_message = count_message_tokens(&history[0]);
+        let system_config = SystemConfig {
+            openai_context_window: (count_message_tokens(&fixed[0]) + per_message * 5 + 100) as i32,
+            openai_max_tokens: 100,
+            ..Default::default()
+        };
+
+        let (kept, report) = fit_history(&system_config, &fixed, &history, 100);
+        assert_eq!(kept.len(), 5);
+        assert_eq!(kept.last().unwrap().id, history.last().unwrap().id);
+        assert_eq!(report.cut_off_message_id, Some(history[15].id));
+
+        let (kept, _) = fit_history(&system_config, &fixed, &history, 3);
+        assert_eq!(kept.len(), 3);
+    }
+
+    #[test]
+    fn test_fit_history_skips_orphaned_tool_results() {
+        let history = vec![
+            message(MessageRole::Assistant, "calling a tool"),
+            message(MessageRole::ToolCall, "tool result"),
+            message(MessageRole::User, "hello"),
+        ];
+
+        let (kept, report) = fit_history::<TestMessage>(&SystemConfig::default(), &[], &history, 2);
+        assert_eq!(kept.len(), 1);
+        assert_eq!(report.budget, None);
+        assert_eq!(kept[0].role, MessageRole::User);
+    }
+}
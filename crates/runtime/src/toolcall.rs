@@ -20,6 +20,17 @@ pub trait ExecutableFunctionCall:
         llm_response: &LLMRunResponse, 
         execution_context: &Self::CTX
     ) -> Result<Self::RETURN>;
+
+    /// The tool result as handed back to the model
+    fn format_return(result: &Self::RETURN) -> String {
+        format!("{:?}", result)
+    }
+
+    /// Presentation tools whose result is shown to the user rather than read by the model.
+    /// An agent run ends once a step has only made terminal calls.
+    fn is_terminal(&self) -> bool {
+        false
+    }
 }
 
 #[macro_export]
@@ -80,6 +91,22 @@ macro_rules! toolcalls {
                     ),*
                 }
             }
+
+            fn format_return(result: &Self::RETURN) -> String {
+                match result {
+                    $(
+                        RuntimeToolcallReturn::$type(r) => <$type as ::voda_runtime::ExecutableFunctionCall>::format_return(r),
+                    )*
+                }
+            }
+
+            fn is_terminal(&self) -> bool {
+                match self {
+                    $(
+                        RuntimeToolcall::$type(f) => f.is_terminal(),
+                    )*
+                }
+            }
         }
     };
 }   
\ No newline at end of file
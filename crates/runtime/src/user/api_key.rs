@@ -0,0 +1,173 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+use voda_common::{blake3_hash, get_current_timestamp, CryptoHash};
+use voda_database::{QueryCriteria, SqlxCrud, SqlxFilterQuery, SqlxObject};
+
+use crate::user::{AuthClaims, AuthTokenError, AuthTokenKind, AUTH_SCOPE_ALL};
+use crate::{User, UserRole};
+
+/// every API key starts with it, telling keys and tokens apart
+pub const API_KEY_PREFIX: &str = "voda_";
+
+pub const SCOPE_ROLEPLAY_CHAT: &str = "roleplay:chat";
+pub const SCOPE_CHARACTERS_READ: &str = "characters:read";
+pub const SCOPE_CHARACTERS_WRITE: &str = "characters:write";
+pub const SCOPE_LOREBOOKS_WRITE: &str = "lorebooks:write";
+pub const SCOPE_USER_WRITE: &str = "user:write";
+pub const SCOPE_GRAPHQL_READ: &str = "graphql:read";
+pub const SCOPE_GRAPHQL_WRITE: &str = "graphql:write";
+pub const SCOPE_ADMIN: &str = "admin";
+
+pub const API_KEY_SCOPES: &[&str] = &[
+    AUTH_SCOPE_ALL,
+    SCOPE_ROLEPLAY_CHAT,
+    SCOPE_CHARACTERS_READ,
+    SCOPE_CHARACTERS_WRITE,
+    SCOPE_LOREBOOKS_WRITE,
+    SCOPE_USER_WRITE,
+    SCOPE_GRAPHQL_READ,
+    SCOPE_GRAPHQL_WRITE,
+    SCOPE_ADMIN,
+];
+
+/// `last_used_at` is only written when it is older than this, in seconds
+const LAST_USED_RESOLUTION: i64 = 60;
+
+/// A long lived credential for backend integrations, acting as its owner within `scopes`. Only
+/// the hash of the key is stored; the key itself is shown once, when it is created.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, SqlxObject)]
+#[table_name = "user_api_keys"]
+pub struct UserApiKey {
+    pub id: Uuid,
+
+    #[foreign_key(referenced_table = "users", related_rust_type = "User")]
+    pub owner: Uuid,
+
+    pub name: String,
+    /// the start of the key, to tell keys apart in listings
+    pub prefix: String,
+    #[unique]
+    pub key_hash: String,
+
+    pub scopes: Vec<String>,
+    /// `Admin` keys act with the admin role of their owner, `User` keys never do
+    pub role: UserRole,
+
+    pub expires_at: Option<i64>,
+    pub last_used_at: Option<i64>,
+    pub revoked_at: Option<i64>,
+
+    pub created_at: i64,
+}
+
+impl UserApiKey {
+    pub fn is_api_key(token: &str) -> bool {
+        token.starts_with(API_KEY_PREFIX)
+    }
+
+    /// A new key for `owner`, returned next to the record to store. Admin keys need an admin owner.
+    pub fn generate(
+        owner: &User, name: String, scopes: Vec<String>, role: UserRole, expires_at: Option<i64>,
+    ) -> Result<(Self, String)> {
+        if let Some(scope) = scopes.iter().find(|scope| !API_KEY_SCOPES.contains(&scope.as_str())) {
+            return Err(anyhow!("[UserApiKey::generate] Unknown scope {}", scope));
+        }
+        if scopes.is_empty() {
+            return Err(anyhow!("[UserApiKey::generate] An API key needs at least one scope"));
+        }
+        if role == UserRole::Admin && owner.role != UserRole::Admin {
+            return Err(anyhow!("[UserApiKey::generate] Only admins can create admin keys"));
+        }
+        if expires_at.is_some_and(|expires_at| expires_at <= get_current_timestamp()) {
+            return Err(anyhow!("[UserApiKey::generate] Expiry must be in the future"));
+        }
+
+        let key = format!("{}{}", API_KEY_PREFIX, CryptoHash::random().to_hex_string());
+        Ok((Self {
+            id: Uuid::default(),
+            owner: owner.id,
+            name,
+            prefix: key[..API_KEY_PREFIX.len() + 8].to_string(),
+            key_hash: blake3_hash(key.as_bytes()).to_hex_string(),
+            scopes,
+            role,
+            expires_at,
+            last_used_at: None,
+            revoked_at: None,
+            created_at: get_current_timestamp(),
+        }, key))
+    }
+
+    /// Looks up `key` and its owner, rejecting revoked and expired keys with an `AuthTokenError`
+    pub async fn authenticate(key: &str, db: &sqlx::PgPool) -> Result<(Self, User)> {
+        let mut api_key = Self::find_one_by_criteria(
+            QueryCriteria::new().add_valued_filter("key_hash", "=", blake3_hash(key.as_bytes()).to_hex_string())?,
+            db
+        ).await?
+            .ok_or(AuthTokenError::Invalid)?;
+
+        if api_key.revoked_at.is_some() {
+            return Err(AuthTokenError::Revoked.into());
+        }
+        if api_key.expires_at.is_some_and(|expires_at| expires_at < get_current_timestamp()) {
+            return Err(AuthTokenError::Expired.into());
+        }
+
+        let owner = User::find_one_by_criteria(
+            QueryCriteria::new().add_valued_filter("id", "=", api_key.owner)?,
+            db
+        ).await?
+            .ok_or(AuthTokenError::Invalid)?;
+
+        let now = get_current_timestamp();
+        if api_key.last_used_at.is_none_or(|last_used_at| now - last_used_at >= LAST_USED_RESOLUTION) {
+            api_key.last_used_at = Some(now);
+            api_key = api_key.update(db).await?;
+        }
+        Ok((api_key, owner))
+    }
+
+    /// Claims standing in for an access token, so scope and role checks treat keys and tokens alike
+    pub fn claims(&self, owner: &User) -> AuthClaims {
+        let role = match (&self.role, &owner.role) {
+            (UserRole::Admin, UserRole::Admin) => UserRole::Admin,
+            _ => UserRole::User,
+        };
+        AuthClaims {
+            sub: owner.id,
+            uid: owner.user_id.clone(),
+            role,
+            scopes: self.scopes.clone(),
+            kind: AuthTokenKind::Access,
+            jti: self.id,
+            iat: self.created_at,
+            exp: self.expires_at.unwrap_or(i64::MAX),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        let user = User { id: Uuid::new_v4(), user_id: "42".to_string(), ..Default::default() };
+
+        let (api_key, key) = UserApiKey::generate(
+            &user, "bot".to_string(), vec![SCOPE_ROLEPLAY_CHAT.to_string()], UserRole::User, None
+        ).unwrap();
+        assert!(UserApiKey::is_api_key(&key));
+        assert!(key.starts_with(&api_key.prefix));
+        assert_eq!(api_key.key_hash, blake3_hash(key.as_bytes()).to_hex_string());
+
+        let claims = api_key.claims(&user);
+        assert!(claims.has_scope(SCOPE_ROLEPLAY_CHAT));
+        assert!(!claims.has_scope(SCOPE_CHARACTERS_WRITE));
+
+        assert!(UserApiKey::generate(&user, "bot".to_string(), vec!["nope".to_string()], UserRole::User, None).is_err());
+        assert!(UserApiKey::generate(&user, "bot".to_string(), vec![], UserRole::User, None).is_err());
+        assert!(UserApiKey::generate(&user, "bot".to_string(), vec![SCOPE_ADMIN.to_string()], UserRole::Admin, None).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Uuid, PgPool};
+use voda_common::{get_current_timestamp, EnvVars};
+use voda_database::{SqlxCrud, SqlxObject, SqlxSchema};
+
+use crate::RuntimeEnv;
+
+/// Emails waiting to be sent, written in the same transaction as whatever they announce and
+/// drained by `UserEmailOutbox::dispatch_pending`
+#[derive(Debug, Serialize, Deserialize, Clone, Default, SqlxObject)]
+#[table_name = "user_email_outbox"]
+pub struct UserEmailOutbox {
+    pub id: Uuid,
+
+    pub recipient: String,
+    pub subject: String,
+    pub body: String,
+
+    pub attempts: i64,
+    pub last_error: Option<String>,
+    pub sent_at: Option<i64>,
+
+    pub created_at: i64,
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: &UserEmailOutbox) -> Result<()>;
+}
+
+/// Picks the mailer from `MAILER`, `smtp` or `log`. `smtp` is the default and needs
+/// `SMTP_URL` and `MAIL_FROM`, `log` is meant for local setups only.
+pub fn mailer_from_env() -> Result<Arc<dyn Mailer>> {
+    let env = RuntimeEnv::load();
+    match env.get_env_var("MAILER").as_str() {
+        "log" => {
+            tracing::warn!("[mailer_from_env] MAILER=log, emails are logged and never delivered");
+            Ok(Arc::new(LogMailer))
+        }
+        "smtp" => Ok(Arc::new(SmtpMailer::new(&env.get_env_var("SMTP_URL"), &env.get_env_var("MAIL_FROM"))?)),
+        mailer => Err(anyhow!("[mailer_from_env] Unknown mailer {}", mailer)),
+    }
+}
+
+/// Delivers emails through the relay at an `smtp://` or `smtps://` url, credentials included
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(url: &str, from: &str) -> Result<Self> {
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(url)
+            .map_err(|e| anyhow!("[SmtpMailer::new] Invalid SMTP url: {}", e))?
+            .build();
+        let from = from.parse()
+            .map_err(|e| anyhow!("[SmtpMailer::new] Invalid sender {}: {}", from, e))?;
+        Ok(Self { transport, from })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: &UserEmailOutbox) -> Result<()> {
+        let to = email.recipient.parse::<Mailbox>()
+            .map_err(|e| anyhow!("[SmtpMailer::send] Invalid recipient: {}", e))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(&email.subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(email.body.clone())?;
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
+
+/// Stands in for an SMTP relay in local setups. Only the envelope is logged, bodies carry
+/// login links and never reach the log.
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: &UserEmailOutbox) -> Result<()> {
+        tracing::info!("[LogMailer] Email {} to {}: {}", email.id, email.recipient, email.subject);
+        Ok(())
+    }
+}
+
+impl UserEmailOutbox {
+    /// emails failing this often are left in the outbox for inspection
+    pub const MAX_ATTEMPTS: i64 = 5;
+
+    pub fn new(recipient: String, subject: String, body: String) -> Self {
+        Self {
+            id: Uuid::default(),
+            recipient,
+            subject,
+            body,
+            attempts: 0,
+            last_error: None,
+            sent_at: None,
+            created_at: get_current_timestamp(),
+        }
+    }
+
+    /// Hands unsent emails to `mailer`, oldest first, and returns how many were sent. The batch
+    /// stays locked until it is recorded, concurrent dispatchers skip it rather than send twice.
+    pub async fn dispatch_pending(db: &PgPool, mailer: &dyn Mailer) -> Result<usize> {
+        let mut tx = db.begin().await?;
+        let sql = format!(
+            "SELECT {} FROM \"{}\" WHERE \"sent_at\" IS NULL AND \"attempts\" < $1
+            ORDER BY \"created_at\" ASC LIMIT 100 FOR UPDATE SKIP LOCKED",
+            Self::COLUMNS.join(", "),
+            Self::TABLE_NAME
+        );
+        let pending = sqlx::query_as::<_, <Self as SqlxSchema>::Row>(&sql)
+            .bind(Self::MAX_ATTEMPTS)
+            .fetch_all(&mut *tx)
+            .await?
+            .into_iter()
+            .map(Self::from_row)
+            .collect::<Vec<_>>();
+
+        let mut sent = 0;
+        for mut email in pending {
+            email.attempts += 1;
+            match mailer.send(&email).await {
+                Ok(()) => {
+                    email.sent_at = Some(get_current_timestamp());
+                    email.last_error = None;
+                    sent += 1;
+                }
+                Err(e) => {
+                    tracing::warn!("[UserEmailOutbox::dispatch_pending] Failed to send {}: {}", email.id, e);
+                    email.last_error = Some(e.to_string());
+                }
+            }
+            email.update(&mut *tx).await?;
+        }
+        tx.commit().await?;
+        Ok(sent)
+    }
+}
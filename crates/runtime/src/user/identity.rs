@@ -0,0 +1,444 @@
+use std::str::FromStr;
+
+use alloy_core::primitives::{Address, PrimitiveSignature};
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use sqlx::types::Uuid;
+use strum_macros::{Display, EnumString};
+use voda_common::{blake3_hash, get_current_timestamp, CryptoHash};
+use voda_database::{QueryCriteria, SqlxCrud, SqlxFilterQuery, SqlxObject};
+
+use crate::user::UserEmailOutbox;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// how long magic links and SIWE nonces stay usable, in seconds
+pub const LOGIN_CHALLENGE_TTL: i64 = 15 * 60;
+/// magic links one address may be sent within `LOGIN_CHALLENGE_TTL`
+pub const MAX_EMAIL_LOGINS_PER_TTL: usize = 3;
+pub const DEFAULT_TELEGRAM_INIT_DATA_MAX_AGE: i64 = 24 * 60 * 60;
+/// how far ahead of us a wallet's clock may be, in seconds
+const SIWE_CLOCK_SKEW: i64 = 60;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Display, EnumString, Default)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum LoginProvider {
+    #[default]
+    Telegram,
+    Email,
+    Evm,
+}
+
+/// What a client sends to prove who it is, one variant per `LoginProvider`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "provider", rename_all = "snake_case")]
+pub enum LoginProof {
+    /// `Telegram.WebApp.initData`, unmodified
+    Telegram { init_data: String },
+    /// the token of a magic link sent by `LoginChallenge::start_email_login`
+    Email { token: String },
+    /// an EIP-4361 message carrying a nonce from `LoginChallenge::issue_siwe_nonce`, signed with
+    /// `personal_sign`
+    Evm { message: String, signature: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct IdentityConfig {
+    /// Telegram logins are refused without a bot token
+    pub telegram_bot_token: Option<String>,
+    /// seconds an initData stays valid after its `auth_date`
+    pub telegram_init_data_max_age: i64,
+    /// the domain SIWE messages must be issued for
+    pub siwe_domain: String,
+    /// the chains SIWE messages may be signed for
+    pub siwe_chain_ids: Vec<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifiedIdentity {
+    pub provider: LoginProvider,
+    /// the Telegram user id, the lowercased email or the checksummed address
+    pub subject: String,
+    pub display_name: Option<String>,
+}
+
+/// A single use secret a login proof has to echo back: the token of an email magic link or the
+/// nonce of a SIWE message. Only the hash of the secret is stored.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, SqlxObject)]
+#[table_name = "user_login_challenges"]
+pub struct LoginChallenge {
+    pub id: Uuid,
+
+    pub provider: LoginProvider,
+    #[unique]
+    pub secret_hash: String,
+    /// the email a magic link was sent to, empty for SIWE nonces
+    pub subject: String,
+
+    pub expires_at: i64,
+    pub consumed_at: Option<i64>,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SiweMessage {
+    pub domain: String,
+    pub address: Address,
+    pub uri: String,
+    pub version: String,
+    pub chain_id: u64,
+    pub nonce: String,
+    pub issued_at: String,
+    pub expiration_time: Option<String>,
+    pub not_before: Option<String>,
+}
+
+impl VerifiedIdentity {
+    /// The `User::user_id` of the identity. Telegram ids are kept bare so accounts registered
+    /// before verification keep working. Emails and addresses are prefixed with their provider,
+    /// so they never resolve to an account registered under a bare email or address back when
+    /// `/user/register` took any `user_id`.
+    pub fn user_id(&self) -> String {
+        match self.provider {
+            LoginProvider::Telegram => self.subject.clone(),
+            provider => format!("{}:{}", provider, self.subject),
+        }
+    }
+}
+
+impl LoginProof {
+    pub fn provider(&self) -> LoginProvider {
+        match self {
+            LoginProof::Telegram { .. } => LoginProvider::Telegram,
+            LoginProof::Email { .. } => LoginProvider::Email,
+            LoginProof::Evm { .. } => LoginProvider::Evm,
+        }
+    }
+
+    /// Verifies the proof, consuming its challenge in `tx`. Rolling `tx` back leaves the challenge
+    /// usable, so a login that turns out to need a registration can be retried with the same proof.
+    pub async fn verify(
+        &self, config: &IdentityConfig, tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> Result<VerifiedIdentity> {
+        match self {
+            LoginProof::Telegram { init_data } => {
+                let bot_token = config.telegram_bot_token.as_ref()
+                    .ok_or_else(|| anyhow!("[LoginProof::verify] Telegram login is not enabled"))?;
+                verify_telegram_init_data(init_data, bot_token, config.telegram_init_data_max_age)
+            }
+            LoginProof::Email { token } => {
+                let challenge = LoginChallenge::consume(LoginProvider::Email, token, tx).await?;
+                Ok(VerifiedIdentity {
+                    provider: LoginProvider::Email,
+                    subject: challenge.subject,
+                    display_name: None,
+                })
+            }
+            LoginProof::Evm { message, signature } => {
+                let siwe = SiweMessage::parse(message)?;
+                siwe.verify(message, signature, config)?;
+                LoginChallenge::consume(LoginProvider::Evm, &siwe.nonce, tx).await?;
+                Ok(VerifiedIdentity {
+                    provider: LoginProvider::Evm,
+                    subject: siwe.address.to_checksum(None),
+                    display_name: None,
+                })
+            }
+        }
+    }
+}
+
+/// Validates `Telegram.WebApp.initData` as described in
+/// https://core.telegram.org/bots/webapps#validating-data-received-via-the-mini-app
+pub fn verify_telegram_init_data(init_data: &str, bot_token: &str, max_age: i64) -> Result<VerifiedIdentity> {
+    let mut hash = None;
+    let mut fields = Vec::new();
+    for (key, value) in form_urlencoded::parse(init_data.as_bytes()) {
+        if key == "hash" {
+            hash = Some(value.into_owned());
+        } else {
+            fields.push((key.into_owned(), value.into_owned()));
+        }
+    }
+    let hash = hash.ok_or_else(|| anyhow!("[verify_telegram_init_data] Missing hash"))?;
+    fields.sort_by(|a, b| a.0.cmp(&b.0));
+    let data_check_string = fields.iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let mut secret = HmacSha256::new_from_slice(b"WebAppData").expect("HMAC takes keys of any size");
+    secret.update(bot_token.as_bytes());
+    let mut mac = HmacSha256::new_from_slice(&secret.finalize().into_bytes()).expect("HMAC takes keys of any size");
+    mac.update(data_check_string.as_bytes());
+    mac.verify_slice(&hex::decode(&hash)?)
+        .map_err(|_| anyhow!("[verify_telegram_init_data] Hash mismatch"))?;
+
+    let field = |name: &str| fields.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str());
+    let auth_date: i64 = field("auth_date")
+        .ok_or_else(|| anyhow!("[verify_telegram_init_data] Missing auth_date"))?
+        .parse()?;
+    if get_current_timestamp() - auth_date > max_age {
+        return Err(anyhow!("[verify_telegram_init_data] initData has expired"));
+    }
+
+    let user: Value = serde_json::from_str(
+        field("user").ok_or_else(|| anyhow!("[verify_telegram_init_data] Missing user"))?
+    )?;
+    let id = user["id"].as_i64()
+        .ok_or_else(|| anyhow!("[verify_telegram_init_data] Missing user id"))?;
+
+    Ok(VerifiedIdentity {
+        provider: LoginProvider::Telegram,
+        subject: id.to_string(),
+        display_name: user["username"].as_str().or(user["first_name"].as_str()).map(str::to_string),
+    })
+}
+
+impl SiweMessage {
+    const HEADER_SUFFIX: &'static str = " wants you to sign in with your Ethereum account:";
+
+    /// Parses the fields of an EIP-4361 message that login depends on
+    pub fn parse(message: &str) -> Result<Self> {
+        let mut lines = message.lines();
+        let domain = lines.next()
+            .and_then(|line| line.strip_suffix(Self::HEADER_SUFFIX))
+            .ok_or_else(|| anyhow!("[SiweMessage::parse] Not a SIWE message"))?
+            .to_string();
+        let address = Address::from_str(
+            lines.next().ok_or_else(|| anyhow!("[SiweMessage::parse] Missing address"))?.trim()
+        )?;
+
+        let field = |name: &str| message.lines()
+            .find_map(|line| line.strip_prefix(name).map(str::to_string));
+        let uri = field("URI: ").ok_or_else(|| anyhow!("[SiweMessage::parse] Missing URI"))?;
+        let version = field("Version: ").ok_or_else(|| anyhow!("[SiweMessage::parse] Missing Version"))?;
+        let chain_id = field("Chain ID: ").ok_or_else(|| anyhow!("[SiweMessage::parse] Missing Chain ID"))?.parse()?;
+        let nonce = field("Nonce: ").ok_or_else(|| anyhow!("[SiweMessage::parse] Missing Nonce"))?;
+        let issued_at = field("Issued At: ").ok_or_else(|| anyhow!("[SiweMessage::parse] Missing Issued At"))?;
+        let expiration_time = field("Expiration Time: ");
+        let not_before = field("Not Before: ");
+
+        Ok(Self { domain, address, uri, version, chain_id, nonce, issued_at, expiration_time, not_before })
+    }
+
+    /// Checks that `signature` over `message` comes from `address`, and that the message is meant
+    /// for the domain and a chain of `config` and is valid now. The nonce is checked by
+    /// `LoginChallenge::consume`.
+    pub fn verify(&self, message: &str, signature: &str, config: &IdentityConfig) -> Result<()> {
+        if self.domain != config.siwe_domain {
+            return Err(anyhow!("[SiweMessage::verify] Message is for {}, not {}", self.domain, config.siwe_domain));
+        }
+        if self.version != "1" {
+            return Err(anyhow!("[SiweMessage::verify] Unsupported version {}", self.version));
+        }
+        if !config.siwe_chain_ids.contains(&self.chain_id) {
+            return Err(anyhow!("[SiweMessage::verify] Chain {} is not accepted", self.chain_id));
+        }
+
+        let now = get_current_timestamp();
+        let timestamp = |time: &str| chrono::DateTime::parse_from_rfc3339(time).map(|time| time.timestamp());
+        if timestamp(&self.issued_at)? > now + SIWE_CLOCK_SKEW {
+            return Err(anyhow!("[SiweMessage::verify] Message is issued in the future"));
+        }
+        if let Some(not_before) = &self.not_before {
+            if timestamp(not_before)? > now + SIWE_CLOCK_SKEW {
+                return Err(anyhow!("[SiweMessage::verify] Message is not valid yet"));
+            }
+        }
+        if let Some(expiration_time) = &self.expiration_time {
+            if timestamp(expiration_time)? < now {
+                return Err(anyhow!("[SiweMessage::verify] Message has expired"));
+            }
+        }
+
+        let signer = PrimitiveSignature::from_str(signature)?.recover_address_from_msg(message.as_bytes())?;
+        if signer != self.address {
+            return Err(anyhow!("[SiweMessage::verify] Signed by {}, not {}", signer, self.address));
+        }
+        Ok(())
+    }
+}
+
+impl LoginChallenge {
+    async fn issue(
+        provider: LoginProvider, subject: String, tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> Result<String> {
+        let secret = CryptoHash::random().to_hex_string();
+        Self {
+            id: Uuid::default(),
+            provider,
+            secret_hash: blake3_hash(secret.as_bytes()).to_hex_string(),
+            subject,
+            expires_at: get_current_timestamp() + LOGIN_CHALLENGE_TTL,
+            consumed_at: None,
+            created_at: get_current_timestamp(),
+        }.create(&mut **tx).await?;
+        Ok(secret)
+    }
+
+    /// A nonce to put in the SIWE message the wallet signs
+    pub async fn issue_siwe_nonce(tx: &mut sqlx::Transaction<'_, sqlx::Postgres>) -> Result<String> {
+        Self::issue(LoginProvider::Evm, String::new(), tx).await
+    }
+
+    /// Queues an email with a magic link to `link_base_url`, the token appended as `?token=`.
+    /// Returns false without sending anything when the address was already sent
+    /// `MAX_EMAIL_LOGINS_PER_TTL` links lately.
+    pub async fn start_email_login(
+        email: &str, link_base_url: &str, tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> Result<bool> {
+        let email = email.trim().to_lowercase();
+        if !email.contains('@') || email.starts_with('@') || email.ends_with('@') {
+            return Err(anyhow!("[LoginChallenge::start_email_login] Invalid email address"));
+        }
+
+        let recent = Self::find_by_criteria(
+            QueryCriteria::new()
+                .add_valued_filter("provider", "=", LoginProvider::Email.to_string())?
+                .add_valued_filter("subject", "=", email.clone())?
+                .add_valued_filter("created_at", ">", get_current_timestamp() - LOGIN_CHALLENGE_TTL)?,
+            &mut **tx
+        ).await?;
+        if recent.len() >= MAX_EMAIL_LOGINS_PER_TTL {
+            return Ok(false);
+        }
+
+        let token = Self::issue(LoginProvider::Email, email.clone(), tx).await?;
+        UserEmailOutbox::new(
+            email,
+            "Your Voda login link".to_string(),
+            format!(
+                "Open this link to log in to Voda:\n\n{}?token={}\n\nThe link expires in {} minutes. If you did not ask for it, ignore this email.",
+                link_base_url, token, LOGIN_CHALLENGE_TTL / 60
+            ),
+        ).create(&mut **tx).await?;
+        Ok(true)
+    }
+
+    /// Marks the challenge of `secret` as used, failing for unknown, used or expired secrets.
+    /// Concurrent logins with the same secret wait on the row, and only the first one gets it.
+    pub async fn consume(
+        provider: LoginProvider, secret: &str, tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> Result<Self> {
+        let mut challenge = Self::find_one_by_criteria(
+            QueryCriteria::new()
+                .add_valued_filter("secret_hash", "=", blake3_hash(secret.as_bytes()).to_hex_string())?
+                .add_valued_filter("provider", "=", provider.to_string())?,
+            &mut **tx
+        ).await?
+            .ok_or_else(|| anyhow!("[LoginChallenge::consume] Unknown login challenge"))?;
+
+        if challenge.consumed_at.is_some() {
+            return Err(anyhow!("[LoginChallenge::consume] Login challenge already used"));
+        }
+        if challenge.expires_at < get_current_timestamp() {
+            return Err(anyhow!("[LoginChallenge::consume] Login challenge has expired"));
+        }
+
+        let consumed_at = get_current_timestamp();
+        let claimed = sqlx::query("UPDATE user_login_challenges SET consumed_at = $1 WHERE id = $2 AND consumed_at IS NULL")
+            .bind(consumed_at)
+            .bind(challenge.id)
+            .execute(&mut **tx)
+            .await?;
+        if claimed.rows_affected() != 1 {
+            return Err(anyhow!("[LoginChallenge::consume] Login challenge already used"));
+        }
+
+        challenge.consumed_at = Some(consumed_at);
+        Ok(challenge)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign_init_data(fields: &[(&str, &str)], bot_token: &str) -> String {
+        let mut sorted = fields.to_vec();
+        sorted.sort_by(|a, b| a.0.cmp(b.0));
+        let data_check_string = sorted.iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let mut secret = HmacSha256::new_from_slice(b"WebAppData").unwrap();
+        secret.update(bot_token.as_bytes());
+        let mut mac = HmacSha256::new_from_slice(&secret.finalize().into_bytes()).unwrap();
+        mac.update(data_check_string.as_bytes());
+        let hash = hex::encode(mac.finalize().into_bytes());
+
+        form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(fields.iter())
+            .append_pair("hash", &hash)
+            .finish()
+    }
+
+    #[test]
+    fn test_verify_telegram_init_data() {
+        let auth_date = get_current_timestamp().to_string();
+        let init_data = sign_init_data(&[
+            ("query_id", "AAF"),
+            ("user", r#"{"id":42,"first_name":"Ada","username":"ada"}"#),
+            ("auth_date", &auth_date),
+        ], "bot-token");
+
+        let identity = verify_telegram_init_data(&init_data, "bot-token", 60).unwrap();
+        assert_eq!(identity.provider, LoginProvider::Telegram);
+        assert_eq!(identity.user_id(), "42");
+        assert_eq!(identity.display_name.as_deref(), Some("ada"));
+
+        assert!(verify_telegram_init_data(&init_data, "other-token", 60).is_err());
+        let tampered = init_data.replace("42", "43");
+        assert!(verify_telegram_init_data(&tampered, "bot-token", 60).is_err());
+
+        let stale = sign_init_data(&[
+            ("user", r#"{"id":42}"#),
+            ("auth_date", "1000"),
+        ], "bot-token");
+        assert!(verify_telegram_init_data(&stale, "bot-token", 60).is_err());
+    }
+
+    #[test]
+    fn test_parse_siwe_message() {
+        let message = "voda.is wants you to sign in with your Ethereum account:\n\
+            0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed\n\
+            \n\
+            Sign in to Voda\n\
+            \n\
+            URI: https://voda.is\n\
+            Version: 1\n\
+            Chain ID: 1\n\
+            Nonce: 32891756\n\
+            Issued At: 2026-01-01T00:00:00Z";
+
+        let siwe = SiweMessage::parse(message).unwrap();
+        assert_eq!(siwe.domain, "voda.is");
+        assert_eq!(siwe.address.to_checksum(None), "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed");
+        assert_eq!(siwe.chain_id, 1);
+        assert_eq!(siwe.nonce, "32891756");
+        assert_eq!(siwe.expiration_time, None);
+
+        let identity = VerifiedIdentity { provider: LoginProvider::Evm, subject: siwe.address.to_checksum(None), display_name: None };
+        assert_eq!(identity.user_id(), "evm:0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed");
+
+        let config = IdentityConfig {
+            telegram_bot_token: None,
+            telegram_init_data_max_age: DEFAULT_TELEGRAM_INIT_DATA_MAX_AGE,
+            siwe_domain: "voda.is".to_string(),
+            siwe_chain_ids: vec![1],
+        };
+        let error = |siwe: &SiweMessage, message: &str, config: &IdentityConfig| siwe.verify(message, "0x00", config).unwrap_err().to_string();
+        assert!(error(&siwe, message, &IdentityConfig { siwe_domain: "evil.example".to_string(), ..config.clone() }).contains("not evil.example"));
+        assert!(error(&siwe, message, &IdentityConfig { siwe_chain_ids: vec![137], ..config.clone() }).contains("Chain 1 is not accepted"));
+
+        let future = message.replace("2026-01-01", "2999-01-01");
+        assert!(error(&SiweMessage::parse(&future).unwrap(), &future, &config).contains("issued in the future"));
+        assert!(SiweMessage::parse("hello").is_err());
+    }
+}
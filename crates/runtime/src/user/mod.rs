@@ -3,6 +3,11 @@ mod usage;
 mod url;
 mod referral;
 mod follow;
+mod persona;
+mod token;
+mod email;
+mod identity;
+mod api_key;
 
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
@@ -18,6 +23,20 @@ pub use url::UserUrl;
 pub use referral::UserReferral;
 pub use badge::UserBadge;
 pub use follow::UserFollow;
+pub use persona::UserPersona;
+pub use token::{
+    AuthClaims, AuthTokenConfig, AuthTokenError, AuthTokenKind, AuthTokenPair, RevokedToken,
+    AUTH_SCOPE_ALL, DEFAULT_ACCESS_TOKEN_TTL, DEFAULT_REFRESH_TOKEN_TTL,
+};
+pub use email::{mailer_from_env, LogMailer, Mailer, SmtpMailer, UserEmailOutbox};
+pub use api_key::{
+    UserApiKey, API_KEY_PREFIX, API_KEY_SCOPES, SCOPE_ADMIN, SCOPE_CHARACTERS_READ, SCOPE_CHARACTERS_WRITE,
+    SCOPE_GRAPHQL_READ, SCOPE_GRAPHQL_WRITE, SCOPE_LOREBOOKS_WRITE, SCOPE_ROLEPLAY_CHAT, SCOPE_USER_WRITE,
+};
+pub use identity::{
+    verify_telegram_init_data, IdentityConfig, LoginChallenge, LoginProof, LoginProvider, SiweMessage, VerifiedIdentity,
+    DEFAULT_TELEGRAM_INIT_DATA_MAX_AGE, LOGIN_CHALLENGE_TTL,
+};
 
 pub const BALANCE_CAP: i64 = 500;
 
@@ -61,6 +80,10 @@ pub struct User {
     
     pub extra: Option<Json<Value>>,
 
+    /// the `UserPersona` used by sessions that do not pick one. Not a foreign key, as personas
+    /// reference their owner.
+    pub default_persona: Option<Uuid>,
+
     pub created_at: i64,
     pub updated_at: i64,
 }
@@ -73,6 +96,8 @@ pub struct AuthenticatedRequest {
 }
 
 impl User {
+    /// The legacy salt-encrypted token, only valid for 60 seconds. Superseded by `AuthTokenPair`
+    /// and only accepted on requests when the API server runs in compatibility mode.
     pub fn generate_auth_token(&self, salt: &str) -> String {
         let payload = json!({
             "user_id": self.user_id,
@@ -0,0 +1,54 @@
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+use voda_common::get_current_timestamp;
+use voda_database::SqlxObject;
+
+use crate::User;
+
+/// Who a user plays in a roleplay session
+#[derive(Debug, Serialize, Deserialize, Clone, Default, SqlxObject)]
+#[table_name = "user_personas"]
+pub struct UserPersona {
+    pub id: Uuid,
+
+    #[foreign_key(referenced_table = "users", related_rust_type = "User")]
+    pub owner: Uuid,
+
+    pub name: String,
+    pub description: String,
+    pub pronouns: Option<String>,
+    pub appearance: Option<String>,
+
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+impl UserPersona {
+    pub fn new(owner: Uuid, name: String, description: String) -> Self {
+        Self {
+            id: Uuid::default(),
+            owner,
+            name,
+            description,
+            pronouns: None,
+            appearance: None,
+            created_at: get_current_timestamp(),
+            updated_at: get_current_timestamp(),
+        }
+    }
+
+    /// The persona as a profile block for prompts
+    pub fn describe(&self) -> String {
+        let mut lines = vec![format!("- 名字：{}", self.name)];
+        if let Some(pronouns) = self.pronouns.as_ref().filter(|pronouns| !pronouns.is_empty()) {
+            lines.push(format!("- 代词：{}", pronouns));
+        }
+        if !self.description.is_empty() {
+            lines.push(format!("- 简介：{}", self.description));
+        }
+        if let Some(appearance) = self.appearance.as_ref().filter(|appearance| !appearance.is_empty()) {
+            lines.push(format!("- 外貌：{}", appearance));
+        }
+        lines.join("\n")
+    }
+}
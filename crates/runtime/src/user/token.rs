@@ -0,0 +1,214 @@
+use anyhow::Result;
+use jsonwebtoken::{decode, encode, errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+use strum_macros::{Display, EnumString};
+use voda_common::get_current_timestamp;
+use voda_database::{QueryCriteria, SqlxCrud, SqlxFilterQuery, SqlxObject};
+
+use crate::{User, UserRole};
+
+/// grants every scope, the default for tokens issued to users themselves
+pub const AUTH_SCOPE_ALL: &str = "*";
+pub const DEFAULT_ACCESS_TOKEN_TTL: i64 = 15 * 60;
+pub const DEFAULT_REFRESH_TOKEN_TTL: i64 = 30 * 24 * 60 * 60;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Display, EnumString)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthTokenKind {
+    /// sent with every request, short lived
+    Access,
+    /// only exchanged for a new pair of tokens
+    Refresh,
+}
+
+/// Why `AuthClaims::verify` or `UserApiKey::authenticate` rejected a credential, recoverable with
+/// `anyhow::Error::downcast_ref`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum AuthTokenError {
+    #[strum(to_string = "token has expired")]
+    Expired,
+    #[strum(to_string = "token is malformed or has a bad signature")]
+    Invalid,
+    #[strum(to_string = "token is of the wrong kind")]
+    WrongKind,
+    #[strum(to_string = "token has been revoked")]
+    Revoked,
+}
+
+impl std::error::Error for AuthTokenError {}
+
+#[derive(Debug, Clone)]
+pub struct AuthTokenConfig {
+    pub secret: String,
+    /// seconds
+    pub access_ttl: i64,
+    /// seconds
+    pub refresh_ttl: i64,
+}
+
+/// The signed claims of an access or refresh token
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AuthClaims {
+    /// `User::id`
+    pub sub: Uuid,
+    /// `User::user_id`
+    pub uid: String,
+    pub role: UserRole,
+    pub scopes: Vec<String>,
+    pub kind: AuthTokenKind,
+
+    /// identifies the token in the revocation list
+    pub jti: Uuid,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuthTokenPair {
+    pub access_token: String,
+    pub access_expires_at: i64,
+    pub refresh_token: String,
+    pub refresh_expires_at: i64,
+}
+
+/// Tokens revoked before they expire, by logging out or by being refreshed. Rows can be
+/// dropped once `expires_at` has passed, as the token is rejected for its age by then.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, SqlxObject)]
+#[table_name = "user_revoked_tokens"]
+pub struct RevokedToken {
+    pub id: Uuid,
+
+    #[unique]
+    pub jti: Uuid,
+
+    #[foreign_key(referenced_table = "users", related_rust_type = "User")]
+    pub user: Uuid,
+
+    pub expires_at: i64,
+    pub created_at: i64,
+}
+
+impl AuthTokenConfig {
+    pub fn new(secret: &str) -> Self {
+        Self {
+            secret: secret.to_string(),
+            access_ttl: DEFAULT_ACCESS_TOKEN_TTL,
+            refresh_ttl: DEFAULT_REFRESH_TOKEN_TTL,
+        }
+    }
+}
+
+impl AuthClaims {
+    pub fn new(user: &User, kind: AuthTokenKind, scopes: Vec<String>, ttl: i64) -> Self {
+        let now = get_current_timestamp();
+        Self {
+            sub: user.id,
+            uid: user.user_id.clone(),
+            role: user.role.clone(),
+            scopes,
+            kind,
+            jti: Uuid::new_v4(),
+            iat: now,
+            exp: now + ttl,
+        }
+    }
+
+    pub fn sign(&self, config: &AuthTokenConfig) -> Result<String> {
+        Ok(encode(&Header::new(Algorithm::HS256), self, &EncodingKey::from_secret(config.secret.as_bytes()))?)
+    }
+
+    /// Checks the signature, the expiry and the kind of `token`. Revocation is checked separately
+    /// with `RevokedToken::is_revoked`, as it needs the database.
+    pub fn verify(token: &str, config: &AuthTokenConfig, kind: AuthTokenKind) -> Result<Self> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.leeway = 0;
+        let claims = decode::<Self>(token, &DecodingKey::from_secret(config.secret.as_bytes()), &validation)
+            .map_err(|e| match e.kind() {
+                ErrorKind::ExpiredSignature => AuthTokenError::Expired,
+                _ => AuthTokenError::Invalid,
+            })?
+            .claims;
+
+        if claims.kind != kind {
+            return Err(AuthTokenError::WrongKind.into());
+        }
+        Ok(claims)
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|granted| granted == AUTH_SCOPE_ALL || granted == scope)
+    }
+}
+
+impl AuthTokenPair {
+    pub fn issue(user: &User, scopes: Vec<String>, config: &AuthTokenConfig) -> Result<Self> {
+        let access = AuthClaims::new(user, AuthTokenKind::Access, scopes.clone(), config.access_ttl);
+        let refresh = AuthClaims::new(user, AuthTokenKind::Refresh, scopes, config.refresh_ttl);
+        Ok(Self {
+            access_token: access.sign(config)?,
+            access_expires_at: access.exp,
+            refresh_token: refresh.sign(config)?,
+            refresh_expires_at: refresh.exp,
+        })
+    }
+}
+
+impl RevokedToken {
+    pub async fn is_revoked<'e, E>(jti: &Uuid, executor: E) -> Result<bool>
+    where
+        E: sqlx::Executor<'e, Database = sqlx::Postgres> + Send,
+    {
+        Ok(Self::find_one_by_criteria(
+            QueryCriteria::new().add_valued_filter("jti", "=", *jti)?,
+            executor
+        ).await?.is_some())
+    }
+
+    /// Adds the token of `claims` to the revocation list, doing nothing when it is already on it
+    pub async fn revoke(claims: &AuthClaims, tx: &mut sqlx::Transaction<'_, sqlx::Postgres>) -> Result<()> {
+        if Self::is_revoked(&claims.jti, &mut **tx).await? {
+            return Ok(());
+        }
+
+        Self {
+            id: Uuid::default(),
+            jti: claims.jti,
+            user: claims.sub,
+            expires_at: claims.exp,
+            created_at: get_current_timestamp(),
+        }.create(&mut **tx).await?;
+
+        // expired tokens are rejected anyway, so their rows are only dead weight
+        Self::delete_by_criteria(
+            QueryCriteria::new().add_valued_filter("expires_at", "<", get_current_timestamp())?,
+            &mut **tx
+        ).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let config = AuthTokenConfig::new("secret");
+        let user = User { id: Uuid::new_v4(), user_id: "tg:42".to_string(), ..Default::default() };
+
+        let claims = AuthClaims::new(&user, AuthTokenKind::Access, vec![AUTH_SCOPE_ALL.to_string()], config.access_ttl);
+        let token = claims.sign(&config).unwrap();
+        assert_eq!(AuthClaims::verify(&token, &config, AuthTokenKind::Access).unwrap(), claims);
+        assert!(claims.has_scope("roleplay:chat"));
+
+        assert!(AuthClaims::verify(&token, &config, AuthTokenKind::Refresh).is_err());
+        let other = AuthTokenConfig { secret: "other".to_string(), ..config.clone() };
+        assert!(AuthClaims::verify(&token, &other, AuthTokenKind::Access).is_err());
+
+        let expired = AuthClaims::new(&user, AuthTokenKind::Access, vec![], -1);
+        let err = AuthClaims::verify(&expired.sign(&config).unwrap(), &config, AuthTokenKind::Access).unwrap_err();
+        assert_eq!(err.downcast_ref::<AuthTokenError>(), Some(&AuthTokenError::Expired));
+        assert!(!expired.has_scope("roleplay:chat"));
+    }
+}
@@ -6,7 +6,7 @@ use sqlx::types::{Json, Uuid};
 use voda_common::get_current_timestamp;
 use voda_database::SqlxObject;
 
-use crate::{user::User, LLMRunResponse};
+use crate::{count_tokens, user::User, LLMRunResponse};
 
 #[derive(Debug, Serialize, Deserialize, Clone, SqlxObject)]
 #[table_name = "user_usages"]
@@ -37,4 +37,24 @@ impl UserUsage {
             updated_at: get_current_timestamp(),
         }
     }
+
+    /// Transcription responses carry no usage, the transcript is billed as completion tokens
+    pub fn from_transcription(user_id: &Uuid, model_name: &str, transcript: &str) -> Self {
+        let completion_tokens = count_tokens(transcript) as u32;
+        Self {
+            id: Uuid::default(),
+            user_id: *user_id,
+            model_name: model_name.to_string(),
+            usage: Json(CompletionUsage {
+                prompt_tokens: 0,
+                completion_tokens,
+                total_tokens: completion_tokens,
+                prompt_tokens_details: None,
+                completion_tokens_details: None,
+            }),
+            finish_reason: None,
+            created_at: get_current_timestamp(),
+            updated_at: get_current_timestamp(),
+        }
+    }
 }
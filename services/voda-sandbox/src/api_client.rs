@@ -1,7 +1,10 @@
 use anyhow::Result;
 use reqwest::Client;
 use serde_json::json;
-use voda_runtime::User;
+use voda_runtime::{
+    user::{AUTH_SCOPE_ALL, DEFAULT_ACCESS_TOKEN_TTL},
+    AuthClaims, AuthTokenConfig, AuthTokenKind, User,
+};
 
 pub struct ApiClient {
     client: Client,
@@ -20,12 +23,18 @@ impl ApiClient {
         }
     }
 
+    /// A short lived access token for `user`, signed with the API server's `AUTH_TOKEN_SECRET`
+    fn access_token(&self) -> Result<String> {
+        AuthClaims::new(&self.user, AuthTokenKind::Access, vec![AUTH_SCOPE_ALL.to_string()], DEFAULT_ACCESS_TOKEN_TTL)
+            .sign(&AuthTokenConfig::new(&self.secret_key))
+    }
+
     pub async fn create_session(
         &self,
         character_id: String,
         system_config_id: String,
     ) -> Result<()> {
-        let token = self.user.generate_auth_token(&self.secret_key);
+        let token = self.access_token()?;
         let response = self
             .client
             .post(format!(
@@ -55,7 +64,7 @@ impl ApiClient {
     }
 
     pub async fn chat(&self, session_id: String, message: String) -> Result<()> {
-        let token = self.user.generate_auth_token(&self.secret_key);
+        let token = self.access_token()?;
         let response = self
             .client
             .post(format!(
@@ -82,7 +91,7 @@ impl ApiClient {
     }
 
     pub async fn rollback(&self, session_id: String, message: String) -> Result<()> {
-        let token = self.user.generate_auth_token(&self.secret_key);
+        let token = self.access_token()?;
         let response = self
             .client
             .post(format!(
@@ -109,7 +118,7 @@ impl ApiClient {
     }
 
     pub async fn buy_referral(&self, count: u32) -> Result<()> {
-        let token = self.user.generate_auth_token(&self.secret_key);
+        let token = self.access_token()?;
         let response = self
             .client
             .post(format!("{}/user/referral/buy", self.base_url))
@@ -8,15 +8,28 @@ use voda_sandbox::{api_client::ApiClient, config::get_normal_user};
 
 init_db_pool!(
     voda_runtime::User,
+    voda_runtime::UserPersona,
+    voda_runtime::RevokedToken,
+    voda_runtime::UserApiKey,
+    voda_runtime::RateLimitRule,
+    voda_runtime::RateLimitBucket,
+    voda_runtime::LoginChallenge,
+    voda_runtime::UserEmailOutbox,
     voda_runtime::UserUsage,
     voda_runtime::UserUrl,
     voda_runtime::UserReferral,
     voda_runtime::UserBadge,
     voda_runtime::SystemConfig,
     voda_runtime_roleplay::Character,
+    voda_runtime_roleplay::CharacterRevision,
     voda_runtime_roleplay::RoleplaySession,
     voda_runtime_roleplay::RoleplayMessage,
-    voda_runtime_roleplay::AuditLog
+    voda_runtime_roleplay::AuditLog,
+    voda_runtime_roleplay::RoleplaySessionSummary,
+    voda_runtime_roleplay::Lorebook,
+    voda_runtime_roleplay::LorebookEntry,
+    voda_runtime_roleplay::ModerationRule,
+    voda_runtime_roleplay::ModerationResult
 );
 
 const BASE_URL: &str = "http://localhost:3033";
@@ -60,7 +73,7 @@ async fn main() -> Result<()> {
     let db = connect(false, false).await;
     let user = get_or_create_user(&db).await?;
     info!("Welcome, {}! (ID: {})", user.user_aka.cyan(), user.id);
-    let secret_key = std::env::var("SECRET_SALT").expect("SECRET_SALT must be set");
+    let secret_key = std::env::var("AUTH_TOKEN_SECRET").expect("AUTH_TOKEN_SECRET must be set");
 
     let http_client = Client::new();
     let api_client = ApiClient::new(
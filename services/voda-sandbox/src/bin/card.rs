@@ -0,0 +1,116 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use colored::*;
+use sqlx::types::Uuid;
+use voda_database::{init_db_pool, QueryCriteria, SqlxCrud, SqlxFilterQuery};
+use voda_runtime::User;
+use voda_runtime_roleplay::{Character, CharacterCard};
+use voda_sandbox::config::get_normal_user;
+
+init_db_pool!(
+    voda_runtime::User,
+    voda_runtime::UserPersona,
+    voda_runtime::RevokedToken,
+    voda_runtime::UserApiKey,
+    voda_runtime::RateLimitRule,
+    voda_runtime::RateLimitBucket,
+    voda_runtime::LoginChallenge,
+    voda_runtime::UserEmailOutbox,
+    voda_runtime::UserUsage,
+    voda_runtime::UserUrl,
+    voda_runtime::UserReferral,
+    voda_runtime::UserBadge,
+    voda_runtime::SystemConfig,
+    voda_runtime_roleplay::Character,
+    voda_runtime_roleplay::CharacterRevision,
+    voda_runtime_roleplay::RoleplaySession,
+    voda_runtime_roleplay::RoleplayMessage,
+    voda_runtime_roleplay::AuditLog,
+    voda_runtime_roleplay::RoleplaySessionSummary,
+    voda_runtime_roleplay::Lorebook,
+    voda_runtime_roleplay::LorebookEntry,
+    voda_runtime_roleplay::ModerationRule,
+    voda_runtime_roleplay::ModerationResult
+);
+
+const USAGE: &str = "usage:
+  card import <card.png|card.json>
+  card export <character_id> <out.png|out.json> [image.png]";
+
+fn is_png(path: &str) -> bool {
+    Path::new(path).extension().is_some_and(|extension| extension.eq_ignore_ascii_case("png"))
+}
+
+async fn get_or_create_user(pool: &sqlx::PgPool) -> Result<User> {
+    let template_user = get_normal_user();
+    let criteria = QueryCriteria::new()
+        .add_filter("user_id", "=", Some(template_user.user_id.clone()))?
+        .limit(1)?;
+
+    match User::find_one_by_criteria(criteria, pool).await? {
+        Some(user) => Ok(user),
+        None => Ok(template_user.create(pool).await?),
+    }
+}
+
+async fn import(pool: &sqlx::PgPool, path: &str) -> Result<()> {
+    let bytes = std::fs::read(path)?;
+    let card = if is_png(path) {
+        CharacterCard::from_png(&bytes)?
+    } else {
+        CharacterCard::from_json(&bytes)?
+    };
+
+    let user = get_or_create_user(pool).await?;
+    let mut tx = pool.begin().await?;
+    let character = card.import(&user.id, &mut tx).await?;
+    tx.commit().await?;
+
+    println!("Imported {} as {} for {}", character.name.cyan(), character.id, user.user_aka.cyan());
+    Ok(())
+}
+
+async fn export(pool: &sqlx::PgPool, character_id: &str, out: &str, image: Option<&str>) -> Result<()> {
+    let character_id = Uuid::parse_str(character_id)?;
+    let mut tx = pool.begin().await?;
+    let character = Character::find_one_by_criteria(
+        QueryCriteria::new().add_valued_filter("id", "=", character_id)?,
+        &mut *tx
+    ).await?
+        .ok_or(anyhow!("Character {} not found", character_id))?;
+    let card = CharacterCard::export(&character, &mut tx).await?;
+    tx.commit().await?;
+
+    if is_png(out) {
+        let image = image.map(std::fs::read).transpose()?;
+        std::fs::write(out, card.to_png(image.as_deref())?)?;
+    } else {
+        std::fs::write(out, serde_json::to_vec_pretty(&card)?)?;
+    }
+
+    println!("Exported {} to {}", character.name.cyan(), out);
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    if !matches!(args.as_slice(), ["import", _] | ["export", _, _] | ["export", _, _, _]) {
+        eprintln!("{}", USAGE);
+        return Ok(());
+    }
+
+    let db = connect(false, false).await;
+    let result = match args.as_slice() {
+        ["import", path] => import(db, path).await,
+        ["export", character_id, out, image @ ..] => export(db, character_id, out, image.first().copied()).await,
+        _ => unreachable!(),
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}{}", "Failed: ".red(), e.to_string().red());
+    }
+    Ok(())
+}
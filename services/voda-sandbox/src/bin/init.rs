@@ -2,7 +2,7 @@ use std::sync::Arc;
 
 use anyhow::Result;
 use voda_database::{init_db_pool, SqlxCrud};
-use voda_runtime::{RuntimeClient, SystemConfig, User, UserBadge, UserFollow, UserReferral, UserUrl, UserUsage};
+use voda_runtime::{RateLimitRule, RuntimeClient, SystemConfig, User, UserBadge, UserFollow, UserReferral, UserUrl, UserUsage};
 use voda_runtime_mem0::init_pgvector_pool;
 use voda_runtime_roleplay::{AuditLog, Character, RoleplayMessage, RoleplayRuntimeClient, RoleplaySession};
 use voda_runtime_character_creation::{CharacterCreationMessage, CharacterCreationRuntimeClient};
@@ -33,6 +33,7 @@ async fn main() -> Result<()> {
 
     RoleplayRuntimeClient::preload(db.clone()).await?;
     CharacterCreationRuntimeClient::preload(db.clone()).await?;
+    RateLimitRule::preload(&db).await?;
 
     println!("Database initialized successfully");
     Ok(())
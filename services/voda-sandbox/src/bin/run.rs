@@ -24,15 +24,28 @@ use voda_sandbox::{
 
 init_db_pool!(
     voda_runtime::User,
+    voda_runtime::UserPersona,
+    voda_runtime::RevokedToken,
+    voda_runtime::UserApiKey,
+    voda_runtime::RateLimitRule,
+    voda_runtime::RateLimitBucket,
+    voda_runtime::LoginChallenge,
+    voda_runtime::UserEmailOutbox,
     voda_runtime::UserUsage,
     voda_runtime::UserUrl,
     voda_runtime::UserReferral,
     voda_runtime::UserBadge,
     voda_runtime::SystemConfig,
     voda_runtime_roleplay::Character,
+    voda_runtime_roleplay::CharacterRevision,
     voda_runtime_roleplay::RoleplaySession,
     voda_runtime_roleplay::RoleplayMessage,
-    voda_runtime_roleplay::AuditLog
+    voda_runtime_roleplay::AuditLog,
+    voda_runtime_roleplay::RoleplaySessionSummary,
+    voda_runtime_roleplay::Lorebook,
+    voda_runtime_roleplay::LorebookEntry,
+    voda_runtime_roleplay::ModerationRule,
+    voda_runtime_roleplay::ModerationResult
 );
 
 const BASE_URL: &str = "http://localhost:3033";
@@ -175,7 +188,7 @@ impl App {
     async fn new(pool: &sqlx::PgPool) -> Result<Self> {
         let user = get_or_create_user(pool).await?;
         info!("Welcome, {}! (ID: {})", user.user_aka.cyan(), user.id);
-        let secret_key = std::env::var("SECRET_SALT").expect("SECRET_SALT must be set");
+        let secret_key = std::env::var("AUTH_TOKEN_SECRET").expect("AUTH_TOKEN_SECRET must be set");
 
         let http_client = Client::new();
         let api_client = ApiClient::new(
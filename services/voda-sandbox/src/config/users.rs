@@ -23,6 +23,7 @@ pub fn get_admin_user() -> User {
         avatar: None,
         bio: None,
         extra: None,
+        default_persona: None,
     
         created_at: get_current_timestamp(),
         updated_at: get_current_timestamp(),
@@ -50,6 +51,7 @@ pub fn get_normal_user() -> User {
         avatar: None,
         bio: None,
         extra: None,
+        default_persona: None,
         created_at: get_current_timestamp(),
         updated_at: get_current_timestamp(),
     }
@@ -4,7 +4,10 @@ use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sqlx::types::Uuid;
 use tracing::{debug, error};
-use voda_runtime::User;
+use voda_runtime::{
+    user::{AUTH_SCOPE_ALL, DEFAULT_ACCESS_TOKEN_TTL},
+    AuthClaims, AuthTokenConfig, AuthTokenKind, User,
+};
 pub use voda_runtime::SystemConfig;
 pub use voda_runtime_roleplay::Character;
 
@@ -120,6 +123,12 @@ impl GraphQlClient {
         }
     }
 
+    /// A short lived access token for `user`, signed with the API server's `AUTH_TOKEN_SECRET`
+    fn access_token(&self) -> Result<String> {
+        AuthClaims::new(&self.user, AuthTokenKind::Access, vec![AUTH_SCOPE_ALL.to_string()], DEFAULT_ACCESS_TOKEN_TTL)
+            .sign(&AuthTokenConfig::new(&self.secret_key))
+    }
+
     async fn post_graphql<V, T>(&self, query: &str, variables: V) -> Result<T>
     where
         V: Serialize,
@@ -138,7 +147,7 @@ impl GraphQlClient {
             serde_json::to_string_pretty(&body).unwrap_or_default()
         );
 
-        let token = self.user.generate_auth_token(&self.secret_key);
+        let token = self.access_token()?;
 
         let res = self
             .http_client
@@ -1,23 +1,27 @@
+use std::net::SocketAddr;
 use std::sync::Arc;
 
 use anyhow::Result;
-use axum::Router;
+use axum::{middleware, Extension, Router};
 use tower_http::{cors::CorsLayer, timeout::TimeoutLayer, trace::TraceLayer};
 use reqwest;
 
 use voda_service_api::{
-    graphql_route, misc_routes, runtime_routes, setup_tracing, voice_routes, user_routes, GlobalState
+    graphql_route, misc_routes, runtime_routes, setup_tracing, voice_routes, user_routes, lorebook_routes, character_routes, admin_routes,
+    rate_limit, GlobalState, RateLimiter
 };
 
-use voda_runtime_mem0::{init_pgvector_pool, Mem0Engine};
+use voda_runtime_mem0::{init_pgvector_pool, Mem0Engine, Mem0Env};
 use voda_database::init_db_pool;
-use voda_runtime::{SystemConfig, User, UserBadge, UserReferral, UserUrl, UserUsage, Memory};
+use voda_runtime::{user::mailer_from_env, LlmProvider, LlmProviderRegistry, LoginChallenge, Memory, OpenAIProvider, RateLimitBucket, RateLimitRule, RevokedToken, SystemConfig, User, UserApiKey, UserEmailOutbox, UserBadge, UserPersona, UserReferral, UserUrl, UserUsage};
 use voda_runtime_character_creation::{CharacterCreationMessage, CharacterCreationRuntimeClient};
-use voda_runtime_roleplay::{AuditLog, Character, RoleplayMessage, RoleplayRuntimeClient, RoleplaySession};
+use voda_runtime_roleplay::{AuditLog, Character, CharacterRevision, Lorebook, LorebookEntry, ModerationResult, ModerationRule, RoleplayMessage, RoleplayRuntimeClient, RoleplaySession, RoleplaySessionSummary, RoleplaySummarizer};
 
 init_db_pool!(
-    User, UserUsage, UserUrl, UserReferral, UserBadge, SystemConfig,
-    Character, RoleplaySession, RoleplayMessage, AuditLog,
+    User, UserPersona, RevokedToken, UserApiKey, LoginChallenge, UserEmailOutbox, UserUsage, UserUrl, UserReferral, UserBadge, SystemConfig,
+    Character, CharacterRevision, RoleplaySession, RoleplayMessage, AuditLog, RoleplaySessionSummary,
+    Lorebook, LorebookEntry, ModerationRule, ModerationResult,
+    RateLimitRule, RateLimitBucket,
     CharacterCreationMessage
 );
 
@@ -33,8 +37,20 @@ async fn main() -> Result<()> {
     let db_pool = Arc::new(connect(false, false).await.clone());
     let pgvector_db = Arc::new(connect_pgvector(false, false).await.clone());
 
-    let (roleplay_client, mut mem0_messages_rx) = RoleplayRuntimeClient::new(db_pool.clone(), pgvector_db.clone()).await?;
-    let character_creation_client = CharacterCreationRuntimeClient::new(db_pool.clone(), "character_creation_v0".to_string()).await?;
+    let mem0_env = Mem0Env::load();
+    let providers = Arc::new(LlmProviderRegistry::from_env());
+    let embedding_provider: Arc<dyn LlmProvider> = Arc::new(OpenAIProvider::new(
+        &mem0_env.get_env_var("EMBEDDING_BASE_URL"),
+        &mem0_env.get_env_var("EMBEDDING_API_KEY"),
+    ));
+
+    let mut mem0 = Mem0Engine::new(db_pool.clone(), pgvector_db.clone(), providers.default_provider()?, embedding_provider).await?;
+    mem0.initialize().await?;
+    let mem0 = Arc::new(mem0);
+
+    let (summarizer, summary_tx) = RoleplaySummarizer::new(db_pool.clone(), providers.clone());
+    let (roleplay_client, mut mem0_messages_rx) = RoleplayRuntimeClient::new(db_pool.clone(), mem0.clone(), providers.clone(), summary_tx);
+    let character_creation_client = CharacterCreationRuntimeClient::new(db_pool.clone(), "character_creation_v0".to_string(), providers.clone()).await?;
 
     let global_state = GlobalState {
         roleplay_client: roleplay_client,
@@ -43,8 +59,6 @@ async fn main() -> Result<()> {
     };
 
     tokio::spawn(async move {
-        let mem0 = Mem0Engine::new(db_pool.clone(), pgvector_db.clone()).await
-            .expect("[Mem0Engine::new] Failed to create mem0 engine");
         while let Some(mem0_messages) = mem0_messages_rx.recv().await {
             let adding_result = mem0.add_messages(&mem0_messages).await;
             if let Err(e) = adding_result {
@@ -53,12 +67,43 @@ async fn main() -> Result<()> {
         }
     });
 
+    tokio::spawn(summarizer.run());
+
+    // login links go through the outbox, `MAILER` picks how they are delivered
+    let mailer = mailer_from_env()?;
+    let outbox_db = db_pool.clone();
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = UserEmailOutbox::dispatch_pending(&outbox_db, &*mailer).await {
+                tracing::warn!("[UserEmailOutbox::dispatch_pending] Failed to dispatch emails: {:?}", e);
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+        }
+    });
+
+    let rate_limiter = RateLimiter::from_env(db_pool.clone());
+    let pruned_limiter = rate_limiter.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+            if let Err(e) = pruned_limiter.prune().await {
+                tracing::warn!("[RateLimiter::prune] Failed to prune buckets: {:?}", e);
+            }
+        }
+    });
+
     let app = Router::new()
         .merge(misc_routes())
         .merge(runtime_routes())
         .merge(voice_routes())
         .merge(graphql_route())
         .merge(user_routes())
+        .merge(lorebook_routes())
+        .merge(character_routes())
+        .merge(admin_routes())
+        .layer(middleware::from_fn_with_state(rate_limiter, rate_limit))
+        // `authenticate` checks access tokens against the revocation list
+        .layer(Extension(db_pool.clone()))
         .layer(TimeoutLayer::new(std::time::Duration::from_secs(3600)))
         .layer(cors)
         .layer(trace)
@@ -74,6 +119,6 @@ async fn main() -> Result<()> {
         .unwrap();
 
     tracing::info!("LISTENING ON {port}");
-    axum::serve(listener, app.into_make_service()).await.unwrap();
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
     Ok(())
 }